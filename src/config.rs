@@ -1,4 +1,11 @@
-use std::{collections::HashMap, env, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
     Router,
@@ -9,6 +16,7 @@ use axum::{
 use chat_prompts::MergeRagContextPolicy;
 use clap::ValueEnum;
 use endpoints::chat::McpTransport;
+use futures_util::future::join_all;
 use rmcp::{
     model::{ClientCapabilities, ClientInfo, Implementation, Tool as RmcpTool},
     service::ServiceExt,
@@ -21,19 +29,30 @@ use rmcp::{
 };
 use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{AsyncWriteExt, BufWriter},
-    sync::{Mutex, RwLock as TokioRwLock, oneshot},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    process::{Child, Command},
+    sync::{Mutex, Notify, RwLock as TokioRwLock, oneshot},
 };
+use tokio_util::sync::{CancellationToken, DropGuard};
 
 use crate::{
-    dual_debug, dual_error, dual_info,
+    dual_debug, dual_error, dual_info, dual_warn,
     error::{ServerError, ServerResult},
-    mcp::{MCP_SERVICES, MCP_TOOLS, McpService},
+    mcp::{
+        MCP_SERVER_STATUS, MCP_SERVICES, MCP_SUPERVISORS, MCP_TOOL_NAMESPACE_SEPARATOR, MCP_TOOLS,
+        McpServerStatus, McpService, McpToolTarget, RawMcpService,
+    },
+    server::{HealthCheckConfig, RoutingPolicyKind, ServerKind},
 };
 
 const MCP_REDIRECT_URI: &str = "http://localhost:8080/callback";
 const CALLBACK_PORT: u16 = 8080;
 const CALLBACK_HTML: &str = include_str!("auth/callback.html");
+const MCP_RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const MCP_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+const MCP_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const MCP_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+const MCP_STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
@@ -46,6 +65,14 @@ pub struct Config {
     pub server_health_push_url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mcp: Option<McpConfig>,
+    #[serde(default)]
+    pub routing: RoutingConfig,
+    #[serde(default)]
+    pub failover: FailoverConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub downstream: Vec<DownstreamConfig>,
 }
 impl Config {
     pub async fn load(path: impl AsRef<std::path::Path>) -> ServerResult<Self> {
@@ -58,17 +85,43 @@ impl Config {
                 ServerError::Operation(err_msg)
             })?;
 
-        let mut config = config.try_deserialize::<Self>().map_err(|e| {
+        let config = config.try_deserialize::<Self>().map_err(|e| {
             let err_msg = format!("Failed to deserialize config: {e}");
             dual_error!("{}", &err_msg);
             ServerError::Operation(err_msg)
         })?;
 
-        if let Some(mcp_config) = config.mcp.as_mut()
+        if let Some(mcp_config) = config.mcp.as_ref()
+            && mcp_config.agent.max_tool_iterations == 0
+        {
+            let err_msg = "`mcp.agent.max_tool_iterations` must be greater than 0";
+            dual_error!("{}", err_msg);
+            return Err(ServerError::FailedToLoadConfig(err_msg.to_string()));
+        }
+
+        if let Some(mcp_config) = config.mcp.as_ref()
             && !mcp_config.server.tool_servers.is_empty()
         {
-            for server_config in mcp_config.server.tool_servers.iter_mut() {
-                server_config.connect_mcp_server().await?;
+            let mut connecting = Vec::new();
+            for (order, server_config) in mcp_config.server.tool_servers.iter().enumerate() {
+                if let Some(ready) = server_config
+                    .start_mcp_supervisor(mcp_config.server.namespace_tools, order)
+                    .await?
+                {
+                    connecting.push(ready);
+                }
+            }
+
+            // wait for the first connection attempts, so that the tools of the reachable mcp
+            // servers are available once the gateway starts
+            if tokio::time::timeout(MCP_STARTUP_TIMEOUT, join_all(connecting))
+                .await
+                .is_err()
+            {
+                dual_warn!(
+                    "Some mcp servers are still connecting after {} seconds, starting without them",
+                    MCP_STARTUP_TIMEOUT.as_secs()
+                );
             }
         }
 
@@ -90,6 +143,10 @@ impl Default for Config {
             server_info_push_url: None,
             server_health_push_url: None,
             mcp: None,
+            routing: RoutingConfig::default(),
+            failover: FailoverConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            downstream: Vec::new(),
         }
     }
 }
@@ -100,6 +157,180 @@ pub struct ServerConfig {
     pub port: u16,
 }
 
+/// Downstream server declared statically in the config file
+///
+/// The declared servers are registered at startup in the same way as the servers registered
+/// through `/admin/servers/register`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DownstreamConfig {
+    /// Server id. A random id is assigned if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub url: String,
+    pub kind: ServerKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// Name of the environment variable holding the api key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_env: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    /// Priority tier. Defaults to `0`, the most preferred tier.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u32>,
+    /// Maximum number of in-flight requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<u32>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub health: HealthCheckConfig,
+}
+impl DownstreamConfig {
+    /// Get the api key of the server, reading it from `api_key_env` if set
+    pub fn resolve_api_key(&self) -> ServerResult<Option<String>> {
+        match (&self.api_key, &self.api_key_env) {
+            (Some(_), Some(_)) => Err(ServerError::FailedToLoadConfig(format!(
+                "Only one of `api_key` and `api_key_env` can be set for the downstream server {}",
+                self.url
+            ))),
+            (Some(api_key), None) => Ok(Some(api_key.clone())),
+            (None, Some(var)) => env::var(var).map(Some).map_err(|e| {
+                ServerError::FailedToLoadConfig(format!(
+                    "Failed to read the api key of the downstream server {} from `{var}`: {e}",
+                    self.url
+                ))
+            }),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Routing policies of the downstream server groups
+///
+/// The policy of a server kind falls back to `default` if it is not set explicitly.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct RoutingConfig {
+    #[serde(default)]
+    pub default: RoutingPolicyKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chat: Option<RoutingPolicyKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embeddings: Option<RoutingPolicyKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<RoutingPolicyKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tts: Option<RoutingPolicyKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub translate: Option<RoutingPolicyKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transcribe: Option<RoutingPolicyKind>,
+    /// Request header carrying the session key of the consistent-hash policy. It takes
+    /// precedence over the `user` and `prompt_cache_key` fields of the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub routing_key_header: Option<String>,
+}
+impl RoutingConfig {
+    /// Get the routing policy of the given server kind
+    pub fn policy_for(&self, kind: ServerKind) -> RoutingPolicyKind {
+        let policy = if kind == ServerKind::chat {
+            self.chat
+        } else if kind == ServerKind::embeddings {
+            self.embeddings
+        } else if kind == ServerKind::image {
+            self.image
+        } else if kind == ServerKind::tts {
+            self.tts
+        } else if kind == ServerKind::translate {
+            self.translate
+        } else if kind == ServerKind::transcribe {
+            self.transcribe
+        } else {
+            None
+        };
+
+        policy.unwrap_or(self.default)
+    }
+}
+
+/// Failover of the proxied requests across the servers of the same kind
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FailoverConfig {
+    /// Maximum number of servers tried for a request. `1` disables failover.
+    #[serde(default = "FailoverConfig::default_max_attempts")]
+    pub max_attempts: usize,
+    /// Status codes of the downstream responses that trigger a failover
+    #[serde(default = "FailoverConfig::default_retryable_status_codes")]
+    pub retryable_status_codes: Vec<u16>,
+    /// Time in seconds to wait for the response headers of a server before failing over. For
+    /// non-streamed requests this includes the generation time.
+    #[serde(default = "FailoverConfig::default_timeout")]
+    pub timeout: u64,
+    /// Time in seconds to wait for the connection to a server to be established
+    #[serde(default = "FailoverConfig::default_connect_timeout")]
+    pub connect_timeout: u64,
+}
+impl FailoverConfig {
+    fn default_max_attempts() -> usize {
+        3
+    }
+
+    fn default_retryable_status_codes() -> Vec<u16> {
+        vec![502, 503, 504]
+    }
+
+    fn default_timeout() -> u64 {
+        300
+    }
+
+    fn default_connect_timeout() -> u64 {
+        5
+    }
+
+    /// Check if the status code of a downstream response triggers a failover
+    pub fn is_retryable_status(&self, status: u16) -> bool {
+        self.retryable_status_codes.contains(&status)
+    }
+}
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: Self::default_max_attempts(),
+            retryable_status_codes: Self::default_retryable_status_codes(),
+            timeout: Self::default_timeout(),
+            connect_timeout: Self::default_connect_timeout(),
+        }
+    }
+}
+
+/// Circuit breakers of the downstream servers
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive failed requests that opens the circuit of a server
+    #[serde(default = "CircuitBreakerConfig::default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Time in seconds during which an open circuit skips the server before a trial request
+    #[serde(default = "CircuitBreakerConfig::default_open_duration")]
+    pub open_duration: u64,
+}
+impl CircuitBreakerConfig {
+    fn default_failure_threshold() -> u32 {
+        3
+    }
+
+    fn default_open_duration() -> u64 {
+        30
+    }
+}
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: Self::default_failure_threshold(),
+            open_duration: Self::default_open_duration(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct RagConfig {
     pub enable: bool,
@@ -152,12 +383,56 @@ impl<'de> Deserialize<'de> for RagConfig {
 pub struct McpConfig {
     #[serde(rename = "server")]
     pub server: McpServerConfig,
+    #[serde(default)]
+    pub agent: AgentConfig,
+}
+
+/// Agent loop executing the MCP tools requested by the model
+///
+/// The gateway keeps executing the tool calls of the model and re-querying the chat server until
+/// the model answers without calling a tool, or one of the limits below is reached.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AgentConfig {
+    /// Maximum number of tool-calling rounds of a request. The tools are disabled in the
+    /// follow-up request of the last round, so that the model produces a final answer.
+    #[serde(default = "AgentConfig::default_max_tool_iterations")]
+    pub max_tool_iterations: usize,
+    /// Total time budget in seconds of the agent loop of a request, covering the tool calls and
+    /// the follow-up requests with the tools enabled. Once it has expired, the tools are disabled
+    /// in the next follow-up request, which is bounded by `failover.timeout` instead.
+    #[serde(default = "AgentConfig::default_timeout")]
+    pub timeout: u64,
+    /// Whether to return the executed tool calls in the `agent_steps` field of the responses
+    #[serde(default)]
+    pub return_steps: bool,
+}
+impl AgentConfig {
+    fn default_max_tool_iterations() -> usize {
+        5
+    }
+
+    fn default_timeout() -> u64 {
+        120
+    }
+}
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            max_tool_iterations: Self::default_max_tool_iterations(),
+            timeout: Self::default_timeout(),
+            return_steps: false,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct McpServerConfig {
     #[serde(rename = "tool")]
     pub tool_servers: Vec<McpToolServerConfig>,
+    /// Whether to prefix the names of the mcp tools with the name of their server, e.g.
+    /// `server__tool`, so that the tools of different servers do not collide
+    #[serde(default)]
+    pub namespace_tools: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -168,73 +443,194 @@ pub struct McpToolServerConfig {
     pub url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub oauth_url: Option<String>,
+    /// Command launching the mcp server, for the stdio transport
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Namespace of the tool names of the server, overriding its name when `namespace_tools` is
+    /// set. An empty prefix disables the namespace.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_prefix: Option<String>,
+    /// Patterns of the tools offered to the model, e.g. `search_*`. All the tools are offered if
+    /// it is empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include_tools: Vec<String>,
+    /// Patterns of the tools never offered to the model, even if they match `include_tools`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_tools: Vec<String>,
+    /// Overrides of the definitions of the tools, by tool name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tool_overrides: HashMap<String, McpToolOverride>,
     pub enable: bool,
     #[serde(skip_deserializing)]
     pub tools: Option<Vec<RmcpTool>>,
     pub fallback_message: Option<String>,
 }
 impl McpToolServerConfig {
-    /// Connect the mcp server if it is enabled
-    pub async fn connect_mcp_server(&mut self) -> ServerResult<()> {
-        if self.enable {
-            // Validate URL configuration: exactly one must be non-empty
-            let mut use_oauth = false;
-            let server_url = match (&self.url, &self.oauth_url) {
-                (Some(url), None) => url,
-                (None, Some(oauth_url)) => {
-                    use_oauth = true;
-                    oauth_url
-                }
-                (Some(_), Some(_)) => {
-                    let err_msg = format!(
-                        "Invalid configuration for mcp server '{}': Both url and oauth_url cannot be set at the same time",
-                        self.name
-                    );
-                    dual_error!("{}", err_msg);
-                    return Err(ServerError::Operation(err_msg));
-                }
-                (None, None) => {
-                    let err_msg = format!(
-                        "Invalid configuration for mcp server '{}': Either url or oauth_url must be set",
-                        self.name
-                    );
-                    dual_error!("{}", err_msg);
-                    return Err(ServerError::Operation(err_msg));
-                }
-            };
+    /// Check the configuration of the mcp server
+    pub(crate) fn validate(&self) -> ServerResult<()> {
+        if self.transport == McpTransport::Stdio {
+            if self.command.as_deref().is_none_or(|c| c.trim().is_empty()) {
+                let err_msg = format!(
+                    "Invalid configuration for mcp server '{}': command must be set for the stdio transport",
+                    self.name
+                );
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg));
+            }
 
-            match self.transport {
-                McpTransport::Sse => {
-                    let url = server_url.trim_end_matches('/');
+            return Ok(());
+        }
 
-                    let service = match use_oauth {
-                        false => {
-                            if !url.ends_with("/sse") {
-                                let err_msg = format!(
-                                    "Invalid mcp tools sse URL: {url}. The correct format should end with `/sse`",
-                                );
-                                dual_error!("{}", err_msg);
-                                return Err(ServerError::Operation(err_msg.to_string()));
-                            }
-                            dual_debug!("Sync mcp tools from mcp server: {}", url);
+        let (server_url, use_oauth) = self.server_url()?;
+        let url = server_url.trim_end_matches('/');
+        if !use_oauth {
+            if self.transport == McpTransport::Sse && !url.ends_with("/sse") {
+                let err_msg = format!(
+                    "Invalid mcp tools sse URL: {url}. The correct format should end with `/sse`",
+                );
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg.to_string()));
+            }
+            if self.transport == McpTransport::StreamHttp && !url.ends_with("/mcp") {
+                let err_msg = format!(
+                    "Invalid mcp tools stream-http URL: {url}. The correct format should end with `/mcp`",
+                );
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg.to_string()));
+            }
+        }
 
-                            // create a sse transport
-                            let transport = SseClientTransport::start(url).await.map_err(|e| {
-                                let err_msg = format!("Failed to create sse transport: {e}");
-                                dual_error!("{}", &err_msg);
-                                ServerError::McpOperation(err_msg)
-                            })?;
+        Ok(())
+    }
 
-                            // create a mcp client
-                            let client_info = ClientInfo {
-                                protocol_version: Default::default(),
-                                capabilities: ClientCapabilities::default(),
-                                client_info: Implementation {
-                                    name: env!("CARGO_PKG_NAME").to_string(),
-                                    version: env!("CARGO_PKG_VERSION").to_string(),
-                                },
-                            };
-                            client_info.into_dyn().serve(transport).await.map_err(|e| {
+    /// Get the url of the mcp server, and whether it is authorized with oauth
+    fn server_url(&self) -> ServerResult<(&str, bool)> {
+        // Validate URL configuration: exactly one must be non-empty
+        match (&self.url, &self.oauth_url) {
+            (Some(url), None) => Ok((url, false)),
+            (None, Some(oauth_url)) => Ok((oauth_url, true)),
+            (Some(_), Some(_)) => {
+                let err_msg = format!(
+                    "Invalid configuration for mcp server '{}': Both url and oauth_url cannot be set at the same time",
+                    self.name
+                );
+                dual_error!("{}", err_msg);
+                Err(ServerError::Operation(err_msg))
+            }
+            (None, None) => {
+                let err_msg = format!(
+                    "Invalid configuration for mcp server '{}': Either url or oauth_url must be set",
+                    self.name
+                );
+                dual_error!("{}", err_msg);
+                Err(ServerError::Operation(err_msg))
+            }
+        }
+    }
+
+    /// Start the supervisor keeping the mcp server connected, if it is enabled
+    ///
+    /// The returned receiver is notified once the first connection attempt has finished. An
+    /// unreachable server does not fail the start of the gateway: the supervisor keeps retrying
+    /// to connect it in the background.
+    ///
+    /// `order` is the position of the server in the config, which decides the server offering a
+    /// tool name provided by several servers.
+    pub async fn start_mcp_supervisor(
+        &self,
+        namespace_tools: bool,
+        order: usize,
+    ) -> ServerResult<Option<oneshot::Receiver<()>>> {
+        if !self.enable {
+            return Ok(None);
+        }
+
+        self.validate()?;
+
+        let mut supervisors = MCP_SUPERVISORS.lock().await;
+        if supervisors.contains_key(&self.name) {
+            dual_warn!("The mcp server '{}' is already started", self.name);
+            return Ok(None);
+        }
+
+        MCP_SERVER_STATUS.write().await.insert(
+            self.name.clone(),
+            McpServerStatus {
+                order,
+                ..McpServerStatus::new(self.name.clone(), self.transport)
+            },
+        );
+
+        // the supervisor advertises the tools with the resolved namespace
+        let mut config = self.clone();
+        config.tool_prefix = self.tool_namespace(namespace_tools);
+
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let supervisor = tokio::spawn(supervise_mcp_server(config, ready_tx));
+        supervisors.insert(self.name.clone(), supervisor);
+
+        Ok(Some(ready_rx))
+    }
+
+    /// Start the mcp server at runtime, and wait for its first connection attempt
+    pub(crate) async fn start_mcp_server(
+        &self,
+        namespace_tools: bool,
+        order: usize,
+    ) -> ServerResult<()> {
+        if let Some(ready) = self.start_mcp_supervisor(namespace_tools, order).await?
+            && tokio::time::timeout(MCP_STARTUP_TIMEOUT, ready)
+                .await
+                .is_err()
+        {
+            dual_warn!(
+                "The mcp server '{}' is still connecting after {} seconds",
+                self.name,
+                MCP_STARTUP_TIMEOUT.as_secs()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Connect the mcp server, spawning its child process for the stdio transport
+    async fn connect_mcp_server(&self) -> ServerResult<(RawMcpService, Option<Child>)> {
+        let service = match self.transport {
+            McpTransport::Stdio => {
+                let (service, child) = self.start_stdio_server().await?;
+                return Ok((service, Some(child)));
+            }
+            McpTransport::Sse => {
+                let (server_url, use_oauth) = self.server_url()?;
+                let url = server_url.trim_end_matches('/');
+
+                match use_oauth {
+                    false => {
+                        dual_debug!("Sync mcp tools from mcp server: {}", url);
+
+                        // create a sse transport
+                        let transport = SseClientTransport::start(url).await.map_err(|e| {
+                            let err_msg = format!("Failed to create sse transport: {e}");
+                            dual_error!("{}", &err_msg);
+                            ServerError::McpOperation(err_msg)
+                        })?;
+
+                        // create a mcp client
+                        let client_info = ClientInfo {
+                            protocol_version: Default::default(),
+                            capabilities: ClientCapabilities::default(),
+                            client_info: Implementation {
+                                name: env!("CARGO_PKG_NAME").to_string(),
+                                version: env!("CARGO_PKG_VERSION").to_string(),
+                            },
+                        };
+                        client_info.into_dyn().serve(transport).await.map_err(|e| {
                                 let err_msg = format!(
                                     "Failed to connect to mcp server (name: {}, url: {}, transport: {}). {e}. Please check if the mcp server is running.",
                                     self.name, url, self.transport
@@ -242,193 +638,168 @@ impl McpToolServerConfig {
                                 dual_error!("{}", &err_msg);
                                 ServerError::McpOperation(err_msg)
                             })?
-                        }
-                        true => {
-                            // it is a http server for handling callback
-                            // Create channel for receiving authorization code
-                            let (code_sender, code_receiver) = oneshot::channel::<String>();
-
-                            // Create app state
-                            let app_state = AppState {
-                                code_receiver: Arc::new(Mutex::new(Some(code_sender))),
-                            };
-
-                            // Start HTTP server for handling callbacks
-                            let app = Router::new()
-                                .route("/callback", get(callback_handler))
-                                .with_state(app_state);
-
-                            let addr = SocketAddr::from(([127, 0, 0, 1], CALLBACK_PORT));
-                            tracing::info!("Starting callback server at: http://{}", addr);
-
-                            // Start server in a separate task
-                            tokio::spawn(async move {
-                                let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-                                let result = axum::serve(listener, app).await;
-
-                                if let Err(e) = result {
-                                    tracing::error!("Callback server error: {}", e);
-                                }
-                            });
-
-                            // Get server URL
-                            tracing::info!("Using MCP server OAuth URL: {}", url);
-
-                            // Initialize oauth state machine
-                            let mut oauth_state =
-                                OAuthState::new(url, None).await.map_err(|e| {
-                                    let err_msg =
-                                        format!("Failed to initialize oauth state machine: {e}");
-                                    dual_error!("{}", err_msg);
-                                    ServerError::McpOperation(err_msg)
-                                })?;
-
-                            // Get metadata to view supported scopes
-                            if let OAuthState::Unauthorized(manager) = &mut oauth_state {
-                                let metadata = manager.discover_metadata().await.map_err(|e| {
-                                    let err_msg = format!("Failed to discover metadata: {e}");
-                                    dual_error!("{}", err_msg);
-                                    ServerError::McpOperation(err_msg.to_string())
-                                })?;
-                                if let Some(supported_scopes) = metadata.scopes_supported {
-                                    dual_debug!("Server supported scopes: {:?}", supported_scopes);
-                                    // Use server supported scopes
-                                    oauth_state
-                                        .start_authorization(
-                                            &supported_scopes
-                                                .iter()
-                                                .map(|s| s.as_str())
-                                                .collect::<Vec<_>>(),
-                                            MCP_REDIRECT_URI,
-                                        )
-                                        .await
-                                        .map_err(|e| {
-                                            let err_msg =
-                                                format!("Failed to start authorization: {e}");
-                                            dual_error!("{}", err_msg);
-                                            ServerError::McpOperation(err_msg)
-                                        })?;
-                                } else {
-                                    let err_msg = "Failed to get supported scopes from mcp server";
-                                    dual_error!("{}", err_msg);
-                                    return Err(ServerError::McpOperation(err_msg.to_string()));
-                                }
+                    }
+                    true => {
+                        // the callback server stops once the authorization is over
+                        let (code_receiver, _callback_server) = start_callback_server().await?;
+
+                        // Get server URL
+                        tracing::info!("Using MCP server OAuth URL: {}", url);
+
+                        // Initialize oauth state machine
+                        let mut oauth_state = OAuthState::new(url, None).await.map_err(|e| {
+                            let err_msg = format!("Failed to initialize oauth state machine: {e}");
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg)
+                        })?;
+
+                        // Get metadata to view supported scopes
+                        if let OAuthState::Unauthorized(manager) = &mut oauth_state {
+                            let metadata = manager.discover_metadata().await.map_err(|e| {
+                                let err_msg = format!("Failed to discover metadata: {e}");
+                                dual_error!("{}", err_msg);
+                                ServerError::McpOperation(err_msg.to_string())
+                            })?;
+                            if let Some(supported_scopes) = metadata.scopes_supported {
+                                dual_debug!("Server supported scopes: {:?}", supported_scopes);
+                                // Use server supported scopes
+                                oauth_state
+                                    .start_authorization(
+                                        &supported_scopes
+                                            .iter()
+                                            .map(|s| s.as_str())
+                                            .collect::<Vec<_>>(),
+                                        MCP_REDIRECT_URI,
+                                    )
+                                    .await
+                                    .map_err(|e| {
+                                        let err_msg = format!("Failed to start authorization: {e}");
+                                        dual_error!("{}", err_msg);
+                                        ServerError::McpOperation(err_msg)
+                                    })?;
+                            } else {
+                                let err_msg = "Failed to get supported scopes from mcp server";
+                                dual_error!("{}", err_msg);
+                                return Err(ServerError::McpOperation(err_msg.to_string()));
                             }
+                        }
 
-                            // Output authorization URL to user
-                            let mut output = BufWriter::new(tokio::io::stdout());
-                            output
-                                .write_all(b"\n=== MCP OAuth Client ===\n\n")
-                                .await
-                                .map_err(|e| {
-                                    let err_msg = format!("Failed to write to stdout: {e}");
-                                    dual_error!("{}", err_msg);
-                                    ServerError::McpOperation(err_msg)
-                                })?;
-                            output.write_all(b"Please open the following URL in your browser to authorize:\n\n")
-                            .await.map_err(|e| {
+                        // Output authorization URL to user
+                        let mut output = BufWriter::new(tokio::io::stdout());
+                        output
+                            .write_all(b"\n=== MCP OAuth Client ===\n\n")
+                            .await
+                            .map_err(|e| {
                                 let err_msg = format!("Failed to write to stdout: {e}");
                                 dual_error!("{}", err_msg);
                                 ServerError::McpOperation(err_msg)
                             })?;
-
-                            output
-                                .write_all(
-                                    oauth_state
-                                        .get_authorization_url()
-                                        .await
-                                        .map_err(|e| {
-                                            let err_msg =
-                                                format!("Failed to get authorization url: {e}");
-                                            dual_error!("{}", err_msg);
-                                            ServerError::McpOperation(err_msg)
-                                        })?
-                                        .as_bytes(),
-                                )
-                                .await
-                                .map_err(|e| {
-                                    let err_msg = format!("Failed to write to stdout: {e}");
-                                    dual_error!("{}", err_msg);
-                                    ServerError::McpOperation(err_msg)
-                                })?;
-                            output
-                                .write_all(b"\n\nWaiting for browser callback, please do not close this window...\n")
-                                .await.map_err(|e| {
-                                    let err_msg = format!("Failed to write to stdout: {e}");
-                                    dual_error!("{}", err_msg);
-                                    ServerError::McpOperation(err_msg)
-                                })?;
-                            output.flush().await.map_err(|e| {
-                                let err_msg = format!("Failed to flush stdout: {e}");
+                        output
+                            .write_all(
+                                b"Please open the following URL in your browser to authorize:\n\n",
+                            )
+                            .await
+                            .map_err(|e| {
+                                let err_msg = format!("Failed to write to stdout: {e}");
                                 dual_error!("{}", err_msg);
                                 ServerError::McpOperation(err_msg)
                             })?;
 
-                            // Wait for authorization code
-                            tracing::info!("Waiting for authorization code...");
-                            let auth_code = code_receiver.await.map_err(|e| {
-                                let err_msg = format!("Failed to get authorization code: {e}");
-                                dual_error!("{}", err_msg);
-                                ServerError::McpOperation(err_msg)
-                            })?;
-                            tracing::info!("Received authorization code: {}", auth_code);
-                            // Exchange code for access token
-                            tracing::info!("Exchanging authorization code for access token...");
-                            oauth_state.handle_callback(&auth_code).await.map_err(|e| {
-                                let err_msg = format!("Failed to handle callback: {e}");
+                        output
+                            .write_all(
+                                oauth_state
+                                    .get_authorization_url()
+                                    .await
+                                    .map_err(|e| {
+                                        let err_msg =
+                                            format!("Failed to get authorization url: {e}");
+                                        dual_error!("{}", err_msg);
+                                        ServerError::McpOperation(err_msg)
+                                    })?
+                                    .as_bytes(),
+                            )
+                            .await
+                            .map_err(|e| {
+                                let err_msg = format!("Failed to write to stdout: {e}");
                                 dual_error!("{}", err_msg);
                                 ServerError::McpOperation(err_msg)
                             })?;
-                            tracing::info!("Successfully obtained access token");
-
-                            output
-                                .write_all(
-                                    b"\nAuthorization successful! Access token obtained.\n\n",
-                                )
-                                .await
-                                .map_err(|e| {
+                        output
+                                .write_all(b"\n\nWaiting for browser callback, please do not close this window...\n")
+                                .await.map_err(|e| {
                                     let err_msg = format!("Failed to write to stdout: {e}");
                                     dual_error!("{}", err_msg);
                                     ServerError::McpOperation(err_msg)
                                 })?;
-                            output.flush().await.map_err(|e| {
-                                let err_msg = format!("Failed to flush stdout: {e}");
-                                dual_error!("{}", err_msg);
-                                ServerError::McpOperation(err_msg)
-                            })?;
-
-                            // Create authorized transport, this transport is authorized by the oauth state machine
-                            tracing::info!("Establishing authorized connection to MCP server...");
-                            let am = oauth_state.into_authorization_manager().ok_or_else(|| {
-                                let err_msg = "Failed to get authorization manager";
-                                dual_error!("{}", err_msg);
-                                ServerError::McpOperation(err_msg.to_string())
-                            })?;
-                            let client = AuthClient::new(reqwest::Client::default(), am);
-                            let transport = SseClientTransport::start_with_client(
-                                client,
-                                SseClientConfig {
-                                    sse_endpoint: url.into(),
-                                    ..Default::default()
-                                },
-                            )
+                        output.flush().await.map_err(|e| {
+                            let err_msg = format!("Failed to flush stdout: {e}");
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg)
+                        })?;
+
+                        // Wait for authorization code
+                        tracing::info!("Waiting for authorization code...");
+                        let auth_code = code_receiver.await.map_err(|e| {
+                            let err_msg = format!("Failed to get authorization code: {e}");
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg)
+                        })?;
+                        tracing::info!("Received authorization code: {}", auth_code);
+                        // Exchange code for access token
+                        tracing::info!("Exchanging authorization code for access token...");
+                        oauth_state.handle_callback(&auth_code).await.map_err(|e| {
+                            let err_msg = format!("Failed to handle callback: {e}");
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg)
+                        })?;
+                        tracing::info!("Successfully obtained access token");
+
+                        output
+                            .write_all(b"\nAuthorization successful! Access token obtained.\n\n")
                             .await
                             .map_err(|e| {
-                                let err_msg = format!("Failed to create authorized transport: {e}");
+                                let err_msg = format!("Failed to write to stdout: {e}");
                                 dual_error!("{}", err_msg);
                                 ServerError::McpOperation(err_msg)
                             })?;
-
-                            // Create client and connect to MCP server
-                            let client_info = ClientInfo {
-                                protocol_version: Default::default(),
-                                capabilities: ClientCapabilities::default(),
-                                client_info: Implementation {
-                                    name: env!("CARGO_PKG_NAME").to_string(),
-                                    version: env!("CARGO_PKG_VERSION").to_string(),
-                                },
-                            };
-                            let service =
+                        output.flush().await.map_err(|e| {
+                            let err_msg = format!("Failed to flush stdout: {e}");
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg)
+                        })?;
+
+                        // Create authorized transport, this transport is authorized by the oauth state machine
+                        tracing::info!("Establishing authorized connection to MCP server...");
+                        let am = oauth_state.into_authorization_manager().ok_or_else(|| {
+                            let err_msg = "Failed to get authorization manager";
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg.to_string())
+                        })?;
+                        let client = AuthClient::new(reqwest::Client::default(), am);
+                        let transport = SseClientTransport::start_with_client(
+                            client,
+                            SseClientConfig {
+                                sse_endpoint: url.into(),
+                                ..Default::default()
+                            },
+                        )
+                        .await
+                        .map_err(|e| {
+                            let err_msg = format!("Failed to create authorized transport: {e}");
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg)
+                        })?;
+
+                        // Create client and connect to MCP server
+                        let client_info = ClientInfo {
+                            protocol_version: Default::default(),
+                            capabilities: ClientCapabilities::default(),
+                            client_info: Implementation {
+                                name: env!("CARGO_PKG_NAME").to_string(),
+                                version: env!("CARGO_PKG_VERSION").to_string(),
+                            },
+                        };
+                        let service =
                                 client_info.into_dyn().serve(transport).await.map_err(|e| {
                                     let err_msg = format!(
                                         "Failed to connect to mcp server (name: {}, url: {}, transport: {}). {e}. Please check if the mcp server is running.",
@@ -437,106 +808,33 @@ impl McpToolServerConfig {
                                     dual_error!("{}", &err_msg);
                                     ServerError::McpOperation(err_msg)
                                 })?;
-                            tracing::info!("Successfully connected to MCP server");
-
-                            service
-                        }
-                    };
-
-                    // list tools
-                    let tools = service.list_all_tools().await.map_err(|e| {
-                        let err_msg = format!("Failed to list tools: {e}");
-                        dual_error!("{}", &err_msg);
-                        ServerError::McpOperation(err_msg)
-                    })?;
-                    dual_info!("Found {} tools from {} mcp server", tools.len(), self.name,);
-
-                    dual_debug!(
-                        "Retrieved mcp tools: {}",
-                        serde_json::to_string_pretty(&tools).unwrap()
-                    );
-
-                    // update tools
-                    self.tools = Some(tools.clone());
-
-                    let mut client = McpService::new(self.name.clone(), service);
-                    client.tools = tools.iter().map(|tool| tool.name.to_string()).collect();
-                    client.fallback_message = self.fallback_message.clone();
-
-                    // print name of all tools
-                    for (idx, tool) in tools.iter().enumerate() {
-                        dual_debug!(
-                            "Tool {} - name: {}, description: {}",
-                            idx,
-                            tool.name,
-                            tool.description.as_deref().unwrap_or("No description"),
-                        );
+                        tracing::info!("Successfully connected to MCP server");
 
-                        match MCP_TOOLS.get() {
-                            Some(mcp_tools) => {
-                                let mut tools = mcp_tools.write().await;
-                                tools.insert(tool.name.to_string(), self.name.clone());
-                            }
-                            None => {
-                                let tools =
-                                    HashMap::from([(tool.name.to_string(), self.name.clone())]);
-
-                                MCP_TOOLS.set(TokioRwLock::new(tools)).map_err(|_| {
-                                    let err_msg = "Failed to set MCP_TOOLS";
-                                    dual_error!("{}", err_msg);
-                                    ServerError::Operation(err_msg.to_string())
-                                })?;
-                            }
-                        }
-                    }
-
-                    // add mcp client to MCP_CLIENTS
-                    match MCP_SERVICES.get() {
-                        Some(clients) => {
-                            let mut clients = clients.write().await;
-                            clients.insert(self.name.clone(), TokioRwLock::new(client));
-                        }
-                        None => {
-                            MCP_SERVICES
-                                .set(TokioRwLock::new(HashMap::from([(
-                                    self.name.clone(),
-                                    TokioRwLock::new(client),
-                                )])))
-                                .map_err(|_| {
-                                    let err_msg = "Failed to set MCP_CLIENTS";
-                                    dual_error!("{}", err_msg);
-                                    ServerError::Operation(err_msg.to_string())
-                                })?;
-                        }
+                        service
                     }
                 }
-                McpTransport::StreamHttp => {
-                    let url = server_url.trim_end_matches('/');
-
-                    let service = match use_oauth {
-                        false => {
-                            if !url.ends_with("/mcp") {
-                                let err_msg = format!(
-                                    "Invalid mcp tools stream-http URL: {url}. The correct format should end with `/mcp`",
-                                );
-                                dual_error!("{}", err_msg);
-                                return Err(ServerError::Operation(err_msg.to_string()));
-                            }
-                            dual_debug!("Sync mcp tools from mcp server: {}", url);
-
-                            // create a stream-http transport
-                            let transport = StreamableHttpClientTransport::from_uri(url);
-
-                            // create a mcp client
-                            let client_info = ClientInfo {
-                                protocol_version: Default::default(),
-                                capabilities: ClientCapabilities::default(),
-                                client_info: Implementation {
-                                    name: env!("CARGO_PKG_NAME").to_string(),
-                                    version: env!("CARGO_PKG_VERSION").to_string(),
-                                },
-                            };
-                            client_info.into_dyn().serve(transport).await.map_err(|e| {
+            }
+            McpTransport::StreamHttp => {
+                let (server_url, use_oauth) = self.server_url()?;
+                let url = server_url.trim_end_matches('/');
+
+                match use_oauth {
+                    false => {
+                        dual_debug!("Sync mcp tools from mcp server: {}", url);
+
+                        // create a stream-http transport
+                        let transport = StreamableHttpClientTransport::from_uri(url);
+
+                        // create a mcp client
+                        let client_info = ClientInfo {
+                            protocol_version: Default::default(),
+                            capabilities: ClientCapabilities::default(),
+                            client_info: Implementation {
+                                name: env!("CARGO_PKG_NAME").to_string(),
+                                version: env!("CARGO_PKG_VERSION").to_string(),
+                            },
+                        };
+                        client_info.into_dyn().serve(transport).await.map_err(|e| {
                                 let err_msg = format!(
                                     "Failed to connect to mcp server (name: {}, url: {}, transport: {}). {e}. Please check if the mcp server is running.",
                                     self.name, server_url, self.transport
@@ -544,189 +842,164 @@ impl McpToolServerConfig {
                                 dual_error!("{}", &err_msg);
                                 ServerError::McpOperation(err_msg)
                             })?
-                        }
-                        true => {
-                            // it is a http server for handling callback
-                            // Create channel for receiving authorization code
-                            let (code_sender, code_receiver) = oneshot::channel::<String>();
-
-                            // Create app state
-                            let app_state = AppState {
-                                code_receiver: Arc::new(Mutex::new(Some(code_sender))),
-                            };
-
-                            // Start HTTP server for handling callbacks
-                            let app = Router::new()
-                                .route("/callback", get(callback_handler))
-                                .with_state(app_state);
-
-                            let addr = SocketAddr::from(([127, 0, 0, 1], CALLBACK_PORT));
-                            tracing::info!("Starting callback server at: http://{}", addr);
-
-                            // Start server in a separate task
-                            tokio::spawn(async move {
-                                let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-                                let result = axum::serve(listener, app).await;
-
-                                if let Err(e) = result {
-                                    tracing::error!("Callback server error: {}", e);
-                                }
-                            });
-
-                            // Get server URL
-                            tracing::info!("Using MCP server OAuth URL: {}", url);
-
-                            // Initialize oauth state machine
-                            let mut oauth_state =
-                                OAuthState::new(url, None).await.map_err(|e| {
-                                    let err_msg =
-                                        format!("Failed to initialize oauth state machine: {e}");
-                                    dual_error!("{}", err_msg);
-                                    ServerError::McpOperation(err_msg)
-                                })?;
-
-                            // Get metadata to view supported scopes
-                            if let OAuthState::Unauthorized(manager) = &mut oauth_state {
-                                let metadata = manager.discover_metadata().await.map_err(|e| {
-                                    let err_msg = format!("Failed to discover metadata: {e}");
-                                    dual_error!("{}", err_msg);
-                                    ServerError::McpOperation(err_msg.to_string())
-                                })?;
-                                if let Some(supported_scopes) = metadata.scopes_supported {
-                                    dual_debug!("Server supported scopes: {:?}", supported_scopes);
-                                    // Use server supported scopes
-                                    oauth_state
-                                        .start_authorization(
-                                            &supported_scopes
-                                                .iter()
-                                                .map(|s| s.as_str())
-                                                .collect::<Vec<_>>(),
-                                            MCP_REDIRECT_URI,
-                                        )
-                                        .await
-                                        .map_err(|e| {
-                                            let err_msg =
-                                                format!("Failed to start authorization: {e}");
-                                            dual_error!("{}", err_msg);
-                                            ServerError::McpOperation(err_msg)
-                                        })?;
-                                } else {
-                                    let err_msg = "Failed to get supported scopes from mcp server";
-                                    dual_error!("{}", err_msg);
-                                    return Err(ServerError::McpOperation(err_msg.to_string()));
-                                }
+                    }
+                    true => {
+                        // the callback server stops once the authorization is over
+                        let (code_receiver, _callback_server) = start_callback_server().await?;
+
+                        // Get server URL
+                        tracing::info!("Using MCP server OAuth URL: {}", url);
+
+                        // Initialize oauth state machine
+                        let mut oauth_state = OAuthState::new(url, None).await.map_err(|e| {
+                            let err_msg = format!("Failed to initialize oauth state machine: {e}");
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg)
+                        })?;
+
+                        // Get metadata to view supported scopes
+                        if let OAuthState::Unauthorized(manager) = &mut oauth_state {
+                            let metadata = manager.discover_metadata().await.map_err(|e| {
+                                let err_msg = format!("Failed to discover metadata: {e}");
+                                dual_error!("{}", err_msg);
+                                ServerError::McpOperation(err_msg.to_string())
+                            })?;
+                            if let Some(supported_scopes) = metadata.scopes_supported {
+                                dual_debug!("Server supported scopes: {:?}", supported_scopes);
+                                // Use server supported scopes
+                                oauth_state
+                                    .start_authorization(
+                                        &supported_scopes
+                                            .iter()
+                                            .map(|s| s.as_str())
+                                            .collect::<Vec<_>>(),
+                                        MCP_REDIRECT_URI,
+                                    )
+                                    .await
+                                    .map_err(|e| {
+                                        let err_msg = format!("Failed to start authorization: {e}");
+                                        dual_error!("{}", err_msg);
+                                        ServerError::McpOperation(err_msg)
+                                    })?;
+                            } else {
+                                let err_msg = "Failed to get supported scopes from mcp server";
+                                dual_error!("{}", err_msg);
+                                return Err(ServerError::McpOperation(err_msg.to_string()));
                             }
+                        }
 
-                            // Output authorization URL to user
-                            let mut output = BufWriter::new(tokio::io::stdout());
-                            output
-                                .write_all(b"\n=== MCP OAuth Client ===\n\n")
-                                .await
-                                .map_err(|e| {
-                                    let err_msg = format!("Failed to write to stdout: {e}");
-                                    dual_error!("{}", err_msg);
-                                    ServerError::McpOperation(err_msg)
-                                })?;
-                            output.write_all(b"Please open the following URL in your browser to authorize:\n\n")
-                            .await.map_err(|e| {
+                        // Output authorization URL to user
+                        let mut output = BufWriter::new(tokio::io::stdout());
+                        output
+                            .write_all(b"\n=== MCP OAuth Client ===\n\n")
+                            .await
+                            .map_err(|e| {
                                 let err_msg = format!("Failed to write to stdout: {e}");
                                 dual_error!("{}", err_msg);
                                 ServerError::McpOperation(err_msg)
                             })?;
-
-                            output
-                                .write_all(
-                                    oauth_state
-                                        .get_authorization_url()
-                                        .await
-                                        .map_err(|e| {
-                                            let err_msg =
-                                                format!("Failed to get authorization url: {e}");
-                                            dual_error!("{}", err_msg);
-                                            ServerError::McpOperation(err_msg)
-                                        })?
-                                        .as_bytes(),
-                                )
-                                .await
-                                .map_err(|e| {
-                                    let err_msg = format!("Failed to write to stdout: {e}");
-                                    dual_error!("{}", err_msg);
-                                    ServerError::McpOperation(err_msg)
-                                })?;
-                            output
-                                .write_all(b"\n\nWaiting for browser callback, please do not close this window...\n")
-                                .await.map_err(|e| {
-                                    let err_msg = format!("Failed to write to stdout: {e}");
-                                    dual_error!("{}", err_msg);
-                                    ServerError::McpOperation(err_msg)
-                                })?;
-                            output.flush().await.map_err(|e| {
-                                let err_msg = format!("Failed to flush stdout: {e}");
+                        output
+                            .write_all(
+                                b"Please open the following URL in your browser to authorize:\n\n",
+                            )
+                            .await
+                            .map_err(|e| {
+                                let err_msg = format!("Failed to write to stdout: {e}");
                                 dual_error!("{}", err_msg);
                                 ServerError::McpOperation(err_msg)
                             })?;
 
-                            // Wait for authorization code
-                            tracing::info!("Waiting for authorization code...");
-                            let auth_code = code_receiver.await.map_err(|e| {
-                                let err_msg = format!("Failed to get authorization code: {e}");
-                                dual_error!("{}", err_msg);
-                                ServerError::McpOperation(err_msg)
-                            })?;
-                            tracing::info!("Received authorization code: {}", auth_code);
-                            // Exchange code for access token
-                            tracing::info!("Exchanging authorization code for access token...");
-                            oauth_state.handle_callback(&auth_code).await.map_err(|e| {
-                                let err_msg = format!("Failed to handle callback: {e}");
+                        output
+                            .write_all(
+                                oauth_state
+                                    .get_authorization_url()
+                                    .await
+                                    .map_err(|e| {
+                                        let err_msg =
+                                            format!("Failed to get authorization url: {e}");
+                                        dual_error!("{}", err_msg);
+                                        ServerError::McpOperation(err_msg)
+                                    })?
+                                    .as_bytes(),
+                            )
+                            .await
+                            .map_err(|e| {
+                                let err_msg = format!("Failed to write to stdout: {e}");
                                 dual_error!("{}", err_msg);
                                 ServerError::McpOperation(err_msg)
                             })?;
-                            tracing::info!("Successfully obtained access token");
-
-                            output
-                                .write_all(
-                                    b"\nAuthorization successful! Access token obtained.\n\n",
-                                )
-                                .await
-                                .map_err(|e| {
+                        output
+                                .write_all(b"\n\nWaiting for browser callback, please do not close this window...\n")
+                                .await.map_err(|e| {
                                     let err_msg = format!("Failed to write to stdout: {e}");
                                     dual_error!("{}", err_msg);
                                     ServerError::McpOperation(err_msg)
                                 })?;
-                            output.flush().await.map_err(|e| {
-                                let err_msg = format!("Failed to flush stdout: {e}");
+                        output.flush().await.map_err(|e| {
+                            let err_msg = format!("Failed to flush stdout: {e}");
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg)
+                        })?;
+
+                        // Wait for authorization code
+                        tracing::info!("Waiting for authorization code...");
+                        let auth_code = code_receiver.await.map_err(|e| {
+                            let err_msg = format!("Failed to get authorization code: {e}");
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg)
+                        })?;
+                        tracing::info!("Received authorization code: {}", auth_code);
+                        // Exchange code for access token
+                        tracing::info!("Exchanging authorization code for access token...");
+                        oauth_state.handle_callback(&auth_code).await.map_err(|e| {
+                            let err_msg = format!("Failed to handle callback: {e}");
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg)
+                        })?;
+                        tracing::info!("Successfully obtained access token");
+
+                        output
+                            .write_all(b"\nAuthorization successful! Access token obtained.\n\n")
+                            .await
+                            .map_err(|e| {
+                                let err_msg = format!("Failed to write to stdout: {e}");
                                 dual_error!("{}", err_msg);
                                 ServerError::McpOperation(err_msg)
                             })?;
+                        output.flush().await.map_err(|e| {
+                            let err_msg = format!("Failed to flush stdout: {e}");
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg)
+                        })?;
+
+                        // Create authorized transport, this transport is authorized by the oauth state machine
+                        tracing::info!("Establishing authorized connection to MCP server...");
+                        let am = oauth_state.into_authorization_manager().ok_or_else(|| {
+                            let err_msg = "Failed to get authorization manager";
+                            dual_error!("{}", err_msg);
+                            ServerError::McpOperation(err_msg.to_string())
+                        })?;
+                        let client = AuthClient::new(reqwest::Client::default(), am);
+
+                        // Use StreamableHttpClientTransport
+                        let transport = StreamableHttpClientTransport::with_client(
+                            client,
+                            StreamableHttpClientTransportConfig {
+                                uri: url.into(),
+                                ..Default::default()
+                            },
+                        );
 
-                            // Create authorized transport, this transport is authorized by the oauth state machine
-                            tracing::info!("Establishing authorized connection to MCP server...");
-                            let am = oauth_state.into_authorization_manager().ok_or_else(|| {
-                                let err_msg = "Failed to get authorization manager";
-                                dual_error!("{}", err_msg);
-                                ServerError::McpOperation(err_msg.to_string())
-                            })?;
-                            let client = AuthClient::new(reqwest::Client::default(), am);
-
-                            // Use StreamableHttpClientTransport
-                            let transport = StreamableHttpClientTransport::with_client(
-                                client,
-                                StreamableHttpClientTransportConfig {
-                                    uri: url.into(),
-                                    ..Default::default()
-                                },
-                            );
-
-                            // Create client and connect to MCP server
-                            let client_info = ClientInfo {
-                                protocol_version: Default::default(),
-                                capabilities: ClientCapabilities::default(),
-                                client_info: Implementation {
-                                    name: env!("CARGO_PKG_NAME").to_string(),
-                                    version: env!("CARGO_PKG_VERSION").to_string(),
-                                },
-                            };
-                            client_info.into_dyn().serve(transport).await.map_err(|e| {
+                        // Create client and connect to MCP server
+                        let client_info = ClientInfo {
+                            protocol_version: Default::default(),
+                            capabilities: ClientCapabilities::default(),
+                            client_info: Implementation {
+                                name: env!("CARGO_PKG_NAME").to_string(),
+                                version: env!("CARGO_PKG_VERSION").to_string(),
+                            },
+                        };
+                        client_info.into_dyn().serve(transport).await.map_err(|e| {
                                 let err_msg = format!(
                                     "Failed to connect to mcp server (name: {}, url: {}, transport: {}). {e}. Please check if the mcp server is running.",
                                     self.name, url, self.transport
@@ -734,125 +1007,718 @@ impl McpToolServerConfig {
                                 dual_error!("{}", &err_msg);
                                 ServerError::McpOperation(err_msg)
                             })?
-                        }
-                    };
-
-                    // list tools
-                    let tools = service.list_all_tools().await.map_err(|e| {
-                        let err_msg = format!("Failed to list tools: {e}");
-                        dual_error!("{}", &err_msg);
-                        ServerError::McpOperation(err_msg)
-                    })?;
-                    dual_info!("Found {} tools from {} mcp server", tools.len(), self.name,);
-
-                    dual_debug!(
-                        "Retrieved mcp tools: {}",
-                        serde_json::to_string_pretty(&tools).unwrap()
-                    );
+                    }
+                }
+            }
+        };
 
-                    // update tools
-                    self.tools = Some(tools.clone());
+        Ok((service, None))
+    }
 
-                    let mut client = McpService::new(self.name.clone(), service);
-                    client.tools = tools.iter().map(|tool| tool.name.to_string()).collect();
-                    client.fallback_message = self.fallback_message.clone();
+    /// Spawn the child process of a stdio mcp server and connect it
+    async fn start_stdio_server(&self) -> ServerResult<(RawMcpService, Child)> {
+        let command = self.command.as_deref().unwrap_or_default();
+
+        let mut cmd = Command::new(command);
+        cmd.args(&self.args)
+            .envs(&self.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
 
-                    // print name of all tools
-                    for (idx, tool) in tools.iter().enumerate() {
-                        dual_debug!(
-                            "Tool {} - name: {}, description: {}",
-                            idx,
-                            tool.name,
-                            tool.description.as_deref().unwrap_or("No description"),
-                        );
+        let mut child = cmd.spawn().map_err(|e| {
+            let err_msg = format!(
+                "Failed to spawn mcp server (name: {}, command: {}). {e}",
+                self.name, command
+            );
+            dual_error!("{}", &err_msg);
+            ServerError::McpOperation(err_msg)
+        })?;
+        dual_info!(
+            "Spawned mcp server (name: {}, command: {}, pid: {})",
+            self.name,
+            command,
+            child.id().unwrap_or_default()
+        );
+
+        // forward the stderr of the mcp server to the logs
+        if let Some(stderr) = child.stderr.take() {
+            let name = self.name.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    dual_info!("[mcp server {}] {}", name, line);
+                }
+            });
+        }
 
-                        match MCP_TOOLS.get() {
-                            Some(mcp_tools) => {
-                                let mut tools = mcp_tools.write().await;
-                                tools.insert(tool.name.to_string(), self.name.clone());
-                            }
-                            None => {
-                                let tools =
-                                    HashMap::from([(tool.name.to_string(), self.name.clone())]);
+        let (stdout, stdin) = match (child.stdout.take(), child.stdin.take()) {
+            (Some(stdout), Some(stdin)) => (stdout, stdin),
+            _ => {
+                let err_msg = format!("Failed to open the stdio of mcp server '{}'", self.name);
+                dual_error!("{}", &err_msg);
+                return Err(ServerError::McpOperation(err_msg));
+            }
+        };
+
+        let client_info = ClientInfo {
+            protocol_version: Default::default(),
+            capabilities: ClientCapabilities::default(),
+            client_info: Implementation {
+                name: env!("CARGO_PKG_NAME").to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+        };
+        let service = client_info
+            .into_dyn()
+            .serve((stdout, stdin))
+            .await
+            .map_err(|e| {
+                let err_msg = format!(
+                    "Failed to connect to mcp server (name: {}, command: {}, transport: {}). {e}",
+                    self.name, command, self.transport
+                );
+                dual_error!("{}", &err_msg);
+                ServerError::McpOperation(err_msg)
+            })?;
 
-                                MCP_TOOLS.set(TokioRwLock::new(tools)).map_err(|_| {
-                                    let err_msg = "Failed to set MCP_TOOLS";
-                                    dual_error!("{}", err_msg);
-                                    ServerError::Operation(err_msg.to_string())
-                                })?;
-                            }
-                        }
-                    }
+        Ok((service, child))
+    }
 
-                    // add mcp client to MCP_CLIENTS
-                    match MCP_SERVICES.get() {
-                        Some(clients) => {
-                            let mut clients = clients.write().await;
-                            clients.insert(self.name.clone(), TokioRwLock::new(client));
-                        }
-                        None => {
-                            MCP_SERVICES
-                                .set(TokioRwLock::new(HashMap::from([(
-                                    self.name.clone(),
-                                    TokioRwLock::new(client),
-                                )])))
-                                .map_err(|_| {
-                                    let err_msg = "Failed to set MCP_CLIENTS";
-                                    dual_error!("{}", err_msg);
-                                    ServerError::Operation(err_msg.to_string())
-                                })?;
+    /// List the tools of a connected mcp server and register the server in `MCP_SERVICES`
+    async fn register_mcp_service(&mut self, service: RawMcpService) -> ServerResult<()> {
+        // list tools
+        let tools = service.list_all_tools().await.map_err(|e| {
+            let err_msg = format!("Failed to list tools: {e}");
+            dual_error!("{}", &err_msg);
+            ServerError::McpOperation(err_msg)
+        })?;
+
+        let mut client = McpService::new(self.name.clone(), service);
+        client.tools = tools.iter().map(|tool| tool.name.to_string()).collect();
+        client.fallback_message = self.fallback_message.clone();
+
+        // add mcp client to MCP_CLIENTS, which the supervisors may initialize concurrently
+        MCP_SERVICES
+            .get_or_init(|| TokioRwLock::new(HashMap::new()))
+            .write()
+            .await
+            .insert(self.name.clone(), TokioRwLock::new(client));
+
+        self.update_mcp_tools(tools).await
+    }
+
+    /// Publish the tools of the connected mcp server, replacing the ones listed before
+    ///
+    /// A tool name provided by several servers is given to the server coming first in the config,
+    /// regardless of the order in which the servers connect.
+    async fn update_mcp_tools(&mut self, tools: Vec<RmcpTool>) -> ServerResult<()> {
+        let found = tools
+            .iter()
+            .map(|tool| tool.name.to_string())
+            .collect::<Vec<_>>();
+        let tools = self.select_tools(tools);
+
+        let changed = self.tools.as_ref() != Some(&tools);
+        if changed {
+            dual_info!("Found {} tools from {} mcp server", found.len(), self.name,);
+            if tools.len() < found.len() {
+                dual_info!(
+                    "{} of the tools of {} mcp server are offered to the model",
+                    tools.len(),
+                    self.name
+                );
+            }
+            for name in self.tool_overrides.keys() {
+                if !found.contains(name) {
+                    dual_warn!(
+                        "The override of the tool `{}` is ignored, as the {} mcp server provides no such tool",
+                        name,
+                        self.name
+                    );
+                }
+            }
+
+            dual_debug!(
+                "Retrieved mcp tools: {}",
+                serde_json::to_string_pretty(&tools).unwrap()
+            );
+
+            // print name of all tools
+            for (idx, tool) in tools.iter().enumerate() {
+                dual_debug!(
+                    "Tool {} - name: {}, description: {}",
+                    idx,
+                    tool.name,
+                    tool.description.as_deref().unwrap_or("No description"),
+                );
+            }
+        }
+
+        let orders = MCP_SERVER_STATUS
+            .read()
+            .await
+            .iter()
+            .map(|(name, status)| (name.clone(), status.order))
+            .collect::<HashMap<_, _>>();
+        let order_of = |server: &str| orders.get(server).copied().unwrap_or(usize::MAX);
+
+        let mut published = Vec::with_capacity(tools.len());
+        // the tools taken over from the servers coming later in the config
+        let mut taken = Vec::new();
+        {
+            let mut mcp_tools = MCP_TOOLS
+                .get_or_init(|| TokioRwLock::new(HashMap::new()))
+                .write()
+                .await;
+            mcp_tools.retain(|_, target| target.server != self.name);
+
+            for tool in tools.iter() {
+                let name = self.advertised_tool_name(&tool.name);
+                if let Some(target) = mcp_tools.get(&name) {
+                    if order_of(&target.server) <= order_of(&self.name) {
+                        if changed {
+                            dual_warn!(
+                                "The tool `{}` of the mcp server '{}' is ignored, as the mcp server '{}' coming first in the config provides a tool of the same name. Set `namespace_tools` or the `tool_prefix` of the servers to use both.",
+                                name,
+                                self.name,
+                                target.server
+                            );
                         }
+                        continue;
                     }
+
+                    dual_warn!(
+                        "The tool `{}` of the mcp server '{}' is ignored, as the mcp server '{}' coming first in the config provides a tool of the same name. Set `namespace_tools` or the `tool_prefix` of the servers to use both.",
+                        name,
+                        target.server,
+                        self.name
+                    );
+                    taken.push((target.server.clone(), name.clone()));
                 }
-                _ => {
-                    let err_msg = format!("Unsupported transport: {}", self.transport);
-                    dual_error!("{}", err_msg);
-                    return Err(ServerError::Operation(err_msg.to_string()));
+
+                mcp_tools.insert(
+                    name.clone(),
+                    McpToolTarget {
+                        server: self.name.clone(),
+                        tool: tool.name.to_string(),
+                    },
+                );
+
+                let mut tool = tool.clone();
+                tool.name = name.into();
+                published.push(tool);
+            }
+        }
+
+        {
+            let mut statuses = MCP_SERVER_STATUS.write().await;
+            if let Some(status) = statuses.get_mut(&self.name) {
+                status.set_connected(&published);
+            }
+            for (server, name) in taken {
+                if let Some(status) = statuses.get_mut(&server) {
+                    status.remove_tool(&name);
                 }
             }
         }
 
+        // update tools
+        self.tools = Some(tools);
+
         Ok(())
     }
+
+    /// Select the tools offered to the model with `include_tools` and `exclude_tools`, and apply
+    /// the `tool_overrides`
+    fn select_tools(&self, tools: Vec<RmcpTool>) -> Vec<RmcpTool> {
+        tools
+            .into_iter()
+            .filter(|tool| {
+                let included = self.include_tools.is_empty()
+                    || self
+                        .include_tools
+                        .iter()
+                        .any(|pattern| matches_tool_pattern(pattern, &tool.name));
+                let excluded = self
+                    .exclude_tools
+                    .iter()
+                    .any(|pattern| matches_tool_pattern(pattern, &tool.name));
+                included && !excluded
+            })
+            .map(|mut tool| {
+                if let Some(tool_override) = self.tool_overrides.get(tool.name.as_ref()) {
+                    if let Some(description) = &tool_override.description {
+                        tool.description = Some(description.clone().into());
+                    }
+                    if let Some(input_schema) = &tool_override.input_schema {
+                        tool.input_schema = Arc::new(input_schema.clone());
+                    }
+                }
+                tool
+            })
+            .collect()
+    }
+
+    /// Get the namespace of the tools of the mcp server: its `tool_prefix`, or its name if the
+    /// tools of all the servers are namespaced
+    fn tool_namespace(&self, namespace_tools: bool) -> Option<String> {
+        let namespace = match &self.tool_prefix {
+            Some(tool_prefix) => tool_prefix,
+            None if namespace_tools => &self.name,
+            None => return None,
+        };
+
+        // the tool names of the chat completion api only allow these characters
+        let namespace = namespace
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+
+        Some(namespace)
+    }
+
+    /// Get the name of the tool advertised to the model
+    fn advertised_tool_name(&self, tool: &str) -> String {
+        match self.tool_prefix.as_deref() {
+            Some(namespace) if !namespace.is_empty() => {
+                format!("{namespace}{MCP_TOOL_NAMESPACE_SEPARATOR}{tool}")
+            }
+            _ => tool.to_string(),
+        }
+    }
+
+    /// Remove the disconnected mcp server from `MCP_SERVICES`, along with its tools
+    async fn unregister_mcp_service(&self) {
+        if let Some(mcp_tools) = MCP_TOOLS.get() {
+            mcp_tools
+                .write()
+                .await
+                .retain(|_, target| target.server != self.name);
+        }
+        if let Some(clients) = MCP_SERVICES.get() {
+            clients.write().await.remove(&self.name);
+        }
+    }
+
+    /// Refresh the tools of the connected mcp server, publishing them under the same names as its
+    /// supervisor
+    pub(crate) async fn refresh_mcp_tools(&self, namespace_tools: bool) -> ServerResult<()> {
+        let mut config = self.clone();
+        config.tool_prefix = self.tool_namespace(namespace_tools);
+        config.check_mcp_connection().await
+    }
+
+    /// Check that the connection to the mcp server is alive, and refresh its tools
+    async fn check_mcp_connection(&mut self) -> ServerResult<()> {
+        let tools = {
+            let clients = match MCP_SERVICES.get() {
+                Some(clients) => clients.read().await,
+                None => return Err(ServerError::McpOperation("Not connected".to_string())),
+            };
+            let client = match clients.get(&self.name) {
+                Some(client) => client.read().await,
+                None => return Err(ServerError::McpOperation("Not connected".to_string())),
+            };
+            if client.raw.peer().is_transport_closed() {
+                return Err(ServerError::McpOperation(
+                    "The connection is closed".to_string(),
+                ));
+            }
+
+            match tokio::time::timeout(MCP_CHECK_TIMEOUT, client.raw.list_all_tools()).await {
+                Ok(Ok(tools)) => tools,
+                Ok(Err(e)) => {
+                    return Err(ServerError::McpOperation(format!(
+                        "Failed to list tools: {e}"
+                    )));
+                }
+                Err(_) => {
+                    return Err(ServerError::McpOperation(format!(
+                        "Failed to list tools in {} seconds",
+                        MCP_CHECK_TIMEOUT.as_secs()
+                    )));
+                }
+            }
+        };
+
+        self.update_mcp_tools(tools).await
+    }
 }
 
-// #[derive(Debug, Deserialize, Serialize, Clone)]
-// pub enum Transport {
-//     #[serde(rename = "sse")]
-//     Sse,
-//     #[serde(rename = "stdio")]
-//     Stdio,
-//     #[serde(rename = "stream-http")]
-//     StreamHttp,
-// }
-// impl std::fmt::Display for Transport {
-//     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-//         match self {
-//             Transport::Sse => write!(f, "sse"),
-//             Transport::Stdio => write!(f, "stdio"),
-//             Transport::StreamHttp => write!(f, "streamable-http"),
-//         }
-//     }
-// }
+#[test]
+fn test_mcp_tool_namespace() {
+    let mut config: McpToolServerConfig = serde_json::from_value(serde_json::json!({
+        "name": "cardea.calculator server",
+        "transport": "stream-http",
+        "url": "http://localhost:8001/mcp",
+        "enable": true
+    }))
+    .unwrap();
+
+    // the name of the server is the namespace if the tools of all the servers are namespaced
+    assert_eq!(config.tool_namespace(false), None);
+    assert_eq!(
+        config.tool_namespace(true).as_deref(),
+        Some("cardea_calculator_server")
+    );
+
+    // the prefix takes precedence over the name of the server
+    config.tool_prefix = Some("calc-1.0".to_string());
+    assert_eq!(config.tool_namespace(false).as_deref(), Some("calc-1_0"));
+    assert_eq!(config.tool_namespace(true).as_deref(), Some("calc-1_0"));
+
+    config.tool_prefix = config.tool_namespace(false);
+    assert_eq!(config.advertised_tool_name("add"), "calc-1_0__add");
+
+    // an empty prefix leaves the tool names as they are
+    config.tool_prefix = Some(String::new());
+    assert_eq!(config.tool_namespace(true).as_deref(), Some(""));
+    assert_eq!(config.advertised_tool_name("add"), "add");
+    config.tool_prefix = None;
+    assert_eq!(config.advertised_tool_name("add"), "add");
+}
 
-// #[derive(Debug, Deserialize, Serialize, Clone)]
-// pub enum Transport {
-//     #[serde(rename = "sse")]
-//     Sse,
-//     #[serde(rename = "stdio")]
-//     Stdio,
-//     #[serde(rename = "stream-http")]
-//     StreamHttp,
-// }
-// impl std::fmt::Display for Transport {
-//     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-//         match self {
-//             Transport::Sse => write!(f, "sse"),
-//             Transport::Stdio => write!(f, "stdio"),
-//             Transport::StreamHttp => write!(f, "streamable-http"),
-//         }
-//     }
-// }
+#[tokio::test]
+async fn test_mcp_tool_name_collision() {
+    fn server_config(name: &str, tool_prefix: Option<&str>) -> McpToolServerConfig {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "transport": "stream-http",
+            "url": "http://localhost:8001/mcp",
+            "enable": true,
+            "tool_prefix": tool_prefix
+        }))
+        .unwrap()
+    }
+
+    fn tools(names: &[&'static str]) -> Vec<RmcpTool> {
+        names
+            .iter()
+            .map(|name| RmcpTool::new(*name, "", Arc::new(serde_json::Map::new())))
+            .collect()
+    }
+
+    async fn target(name: &str) -> Option<String> {
+        let mcp_tools = MCP_TOOLS.get().unwrap().read().await;
+        mcp_tools.get(name).map(|target| target.server.clone())
+    }
+
+    async fn offered(server: &str) -> Vec<String> {
+        MCP_SERVER_STATUS.read().await[server].tools.clone()
+    }
+
+    // the servers in the order of the config
+    let names = [
+        "collision-server-a",
+        "collision-server-b",
+        "collision-server-c",
+    ];
+    for (order, name) in names.iter().enumerate() {
+        MCP_SERVER_STATUS.write().await.insert(
+            name.to_string(),
+            McpServerStatus {
+                order,
+                ..McpServerStatus::new(name.to_string(), McpTransport::StreamHttp)
+            },
+        );
+    }
+
+    // the second server connects first
+    let mut server_b = server_config("collision-server-b", None);
+    server_b
+        .update_mcp_tools(tools(&["collision_search", "collision_lookup"]))
+        .await
+        .unwrap();
+    assert_eq!(
+        target("collision_search").await.as_deref(),
+        Some("collision-server-b")
+    );
+
+    // the first server of the config takes over the tool of the same name
+    let mut server_a = server_config("collision-server-a", None);
+    server_a
+        .update_mcp_tools(tools(&["collision_search", "collision_fetch"]))
+        .await
+        .unwrap();
+    assert_eq!(
+        target("collision_search").await.as_deref(),
+        Some("collision-server-a")
+    );
+    assert_eq!(
+        target("collision_fetch").await.as_deref(),
+        Some("collision-server-a")
+    );
+    assert_eq!(
+        target("collision_lookup").await.as_deref(),
+        Some("collision-server-b")
+    );
+    assert_eq!(offered("collision-server-b").await, ["collision_lookup"]);
+
+    // the second server keeps skipping it when its tools are listed again
+    server_b
+        .update_mcp_tools(tools(&["collision_search", "collision_lookup"]))
+        .await
+        .unwrap();
+    assert_eq!(
+        target("collision_search").await.as_deref(),
+        Some("collision-server-a")
+    );
+    assert_eq!(offered("collision-server-b").await, ["collision_lookup"]);
+
+    // listing the tools again replaces the tools of the server, not the ones of the others
+    server_a
+        .update_mcp_tools(tools(&["collision_search"]))
+        .await
+        .unwrap();
+    assert_eq!(
+        target("collision_search").await.as_deref(),
+        Some("collision-server-a")
+    );
+    assert_eq!(target("collision_fetch").await, None);
+
+    // both tools are published once namespaced
+    let mut server_c = server_config("collision-server-c", Some("c"));
+    server_c
+        .update_mcp_tools(tools(&["collision_search"]))
+        .await
+        .unwrap();
+    assert_eq!(
+        target("c__collision_search").await.as_deref(),
+        Some("collision-server-c")
+    );
+    assert_eq!(
+        target("collision_search").await.as_deref(),
+        Some("collision-server-a")
+    );
+
+    for server in [&server_a, &server_b, &server_c] {
+        server.unregister_mcp_service().await;
+        MCP_SERVER_STATUS.write().await.remove(&server.name);
+    }
+}
+
+/// Override of the definition of an mcp tool
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct McpToolOverride {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON schema of the arguments of the tool
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_schema: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// Check if the tool name matches the pattern, in which `*` matches any sequence of characters
+/// and `?` matches any single character
+fn matches_tool_pattern(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    let (mut p, mut n) = (0, 0);
+    // the last `*` of the pattern, and the position in the name it is matched up to
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // let the `*` match one more character
+            star = Some((star_p, star_n + 1));
+            p = star_p + 1;
+            n = star_n + 1;
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+/// Keep the mcp server connected
+///
+/// The connection is checked periodically, and when a tool call fails. If it fails or is lost,
+/// the server is reconnected with exponential backoff and its tools are listed again; in the
+/// meantime, its tools are not offered to the model. The stdio servers are restarted when their
+/// child process exits, and killed on gateway shutdown.
+async fn supervise_mcp_server(mut config: McpToolServerConfig, ready: oneshot::Sender<()>) {
+    let (check, stop) = match MCP_SERVER_STATUS.read().await.get(&config.name) {
+        Some(status) => (status.check.clone(), status.stop.clone()),
+        None => return,
+    };
+
+    let mut ready = Some(ready);
+    let mut backoff = McpReconnectBackoff::default();
+    loop {
+        let connected_at = Instant::now();
+        let result = tokio::select! {
+            result = config.connect_mcp_server() => result,
+            _ = stop.cancelled() => return,
+        };
+        let result = match result {
+            Ok((service, child)) => config.register_mcp_service(service).await.map(|_| child),
+            Err(e) => Err(e),
+        };
+        if let Some(ready) = ready.take() {
+            let _ = ready.send(());
+        }
+
+        let error = match result {
+            Ok(mut child) => {
+                let error = tokio::select! {
+                    error = watch_mcp_connection(&mut config, &mut child, &check) => error,
+                    _ = stop.cancelled() => {
+                        if let Some(child) = child.as_mut() {
+                            dual_info!("Stopping the mcp server '{}'", config.name);
+                            if let Err(e) = child.kill().await {
+                                dual_warn!("Failed to kill the mcp server '{}': {e}", config.name);
+                            }
+                        }
+                        config.unregister_mcp_service().await;
+                        return;
+                    }
+                };
+                dual_warn!(
+                    "Lost the connection to the mcp server '{}': {}",
+                    config.name,
+                    error
+                );
+                config.unregister_mcp_service().await;
+                backoff.connection_lost(connected_at.elapsed());
+
+                error
+            }
+            Err(e) => e.to_string(),
+        };
+
+        // connecting an oauth server asks the user for an authorization again, so it is only
+        // reconnected on request
+        let delay = match config.oauth_url.is_some() {
+            true => None,
+            false => Some(backoff.next_delay()),
+        };
+        if let Some(status) = MCP_SERVER_STATUS.write().await.get_mut(&config.name) {
+            status.set_unavailable(error, delay);
+        }
+
+        match delay {
+            // reconnect with exponential backoff
+            Some(delay) => dual_info!(
+                "Reconnecting the mcp server '{}' in {} seconds",
+                config.name,
+                delay.as_secs()
+            ),
+            None => dual_info!(
+                "The mcp server '{}' will be reconnected once its tools are refreshed",
+                config.name
+            ),
+        }
+        let retry = async {
+            match delay {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = retry => {}
+            // e.g. the tools of the server are refreshed by the admin api
+            _ = check.notified() => {}
+            _ = stop.cancelled() => return,
+        }
+    }
+}
+
+/// Exponential backoff of the reconnections of an mcp server
+#[derive(Debug)]
+struct McpReconnectBackoff {
+    delay: Duration,
+}
+impl Default for McpReconnectBackoff {
+    fn default() -> Self {
+        Self {
+            delay: MCP_RECONNECT_MIN_DELAY,
+        }
+    }
+}
+impl McpReconnectBackoff {
+    /// Get the delay before the next attempt, doubling the following one
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (delay * 2).min(MCP_RECONNECT_MAX_DELAY);
+        delay
+    }
+
+    /// Start over from the minimum delay if the lost connection had been up for a while,
+    /// instead of failing right after connecting
+    fn connection_lost(&mut self, uptime: Duration) {
+        if uptime > MCP_RECONNECT_MAX_DELAY {
+            self.delay = MCP_RECONNECT_MIN_DELAY;
+        }
+    }
+}
+
+#[test]
+fn test_mcp_reconnect_backoff() {
+    let mut backoff = McpReconnectBackoff::default();
+    let delays = (0..8)
+        .map(|_| backoff.next_delay().as_secs())
+        .collect::<Vec<_>>();
+    assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+
+    // a connection failing right after connecting keeps backing off
+    backoff.connection_lost(Duration::from_secs(5));
+    assert_eq!(backoff.next_delay(), MCP_RECONNECT_MAX_DELAY);
+
+    // a connection up for a while starts over
+    backoff.connection_lost(MCP_RECONNECT_MAX_DELAY + Duration::from_secs(1));
+    assert_eq!(backoff.next_delay(), MCP_RECONNECT_MIN_DELAY);
+    assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+}
+
+/// Wait until the connection to the mcp server is lost, and return the reason
+async fn watch_mcp_connection(
+    config: &mut McpToolServerConfig,
+    child: &mut Option<Child>,
+    check: &Notify,
+) -> String {
+    let mut interval = tokio::time::interval(MCP_CHECK_INTERVAL);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            status = wait_child(child) => {
+                return match status {
+                    Ok(status) => format!("The process exited ({status})"),
+                    Err(e) => format!("Failed to wait for the process: {e}"),
+                };
+            }
+            _ = interval.tick() => {}
+            _ = check.notified() => {}
+        }
+
+        if let Err(e) = config.check_mcp_connection().await {
+            return e.to_string();
+        }
+    }
+}
+
+/// Wait until the child process of a stdio mcp server exits, or forever if there is none
+async fn wait_child(child: &mut Option<Child>) -> std::io::Result<std::process::ExitStatus> {
+    match child {
+        Some(child) => child.wait().await,
+        None => std::future::pending().await,
+    }
+}
 
 #[derive(Debug, Clone)]
 struct AppState {
@@ -866,6 +1732,48 @@ struct CallbackParams {
     state: Option<String>,
 }
 
+/// Start the http server receiving the authorization code of the oauth flow
+///
+/// The server stops when the returned guard is dropped, releasing `CALLBACK_PORT`.
+async fn start_callback_server() -> ServerResult<(oneshot::Receiver<String>, DropGuard)> {
+    // Create channel for receiving authorization code
+    let (code_sender, code_receiver) = oneshot::channel::<String>();
+
+    // Create app state
+    let app_state = AppState {
+        code_receiver: Arc::new(Mutex::new(Some(code_sender))),
+    };
+
+    // Start HTTP server for handling callbacks
+    let app = Router::new()
+        .route("/callback", get(callback_handler))
+        .with_state(app_state);
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], CALLBACK_PORT));
+    tracing::info!("Starting callback server at: http://{}", addr);
+
+    let listener = tokio::net::TcpListener::bind(addr).await.map_err(|e| {
+        let err_msg = format!("Failed to start the oauth callback server at {addr}: {e}");
+        dual_error!("{}", err_msg);
+        ServerError::McpOperation(err_msg)
+    })?;
+
+    // Start server in a separate task
+    let shutdown = CancellationToken::new();
+    let stopped = shutdown.clone();
+    tokio::spawn(async move {
+        let result = axum::serve(listener, app)
+            .with_graceful_shutdown(stopped.cancelled_owned())
+            .await;
+
+        if let Err(e) = result {
+            tracing::error!("Callback server error: {}", e);
+        }
+    });
+
+    Ok((code_receiver, shutdown.drop_guard()))
+}
+
 async fn callback_handler(
     Query(params): Query<CallbackParams>,
     State(state): State<AppState>,
@@ -879,3 +1787,46 @@ async fn callback_handler(
     // Return success page
     Html(CALLBACK_HTML.to_string())
 }
+
+#[test]
+fn test_mcp_tool_selection() {
+    let config: McpToolServerConfig = serde_json::from_value(serde_json::json!({
+        "name": "zapier",
+        "transport": "stream-http",
+        "url": "http://localhost:8004/mcp",
+        "enable": true,
+        "include_tools": ["gmail_*", "slack_send_?m"],
+        "exclude_tools": ["*_delete*"],
+        "tool_overrides": {
+            "gmail_find_email": {
+                "description": "Find an email in the mailbox",
+                "input_schema": {"type": "object", "properties": {"query": {"type": "string"}}}
+            }
+        }
+    }))
+    .unwrap();
+
+    let tools = [
+        "gmail_find_email",
+        "gmail_delete_email",
+        "slack_send_dm",
+        "slack_send_message",
+        "trello_create_card",
+    ]
+    .into_iter()
+    .map(|name| RmcpTool::new(name, "", Arc::new(serde_json::Map::new())))
+    .collect();
+
+    let tools = config.select_tools(tools);
+    let names = tools
+        .iter()
+        .map(|tool| tool.name.as_ref())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["gmail_find_email", "slack_send_dm"]);
+    assert_eq!(
+        tools[0].description.as_deref(),
+        Some("Find an email in the mailbox")
+    );
+    assert!(tools[0].input_schema.contains_key("properties"));
+    assert_eq!(tools[1].description.as_deref(), Some(""));
+}
@@ -25,6 +25,11 @@ pub struct ResponseSession {
     pub usage_total_tokens: Option<i64>,
     pub error: Option<String>, // JSON string if error occurred
     pub incomplete_details: Option<String>, // JSON string
+    pub tools: Option<String>,              // JSON string
+    pub tool_choice: Option<String>,        // JSON string
+    pub parallel_tool_calls: Option<bool>,
+    pub truncation: Option<String>,
+    pub verbosity: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,6 +53,20 @@ pub struct OutputItem {
     pub created_at: i64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerRecord {
+    pub id: String,
+    pub url: String,
+    pub kind: String, // comma-separated server kinds, e.g. "chat,embeddings"
+    pub api_key: Option<String>,
+    pub weight: i64,
+    pub priority: i64,
+    pub max_connections: Option<i64>,
+    pub labels: Option<String>, // JSON string
+    pub health: Option<String>, // JSON string of the health check settings
+    pub created_at: i64,
+}
+
 pub struct DatabaseManager {
     pub pool: SqlitePool,
 }
@@ -65,7 +84,7 @@ impl DatabaseManager {
         let database_url = if database_path.starts_with("sqlite:") || database_path.starts_with("file:") {
             database_path.to_string()
         } else {
-            format!("sqlite:{}?mode=rwc", database_path)
+            format!("sqlite:{database_path}?mode=rwc")
         };
 
         let pool = SqlitePool::connect(&database_url).await?;
@@ -100,6 +119,11 @@ impl DatabaseManager {
                 usage_total_tokens INTEGER,
                 error TEXT,
                 incomplete_details TEXT,
+                tools TEXT,
+                tool_choice TEXT,
+                parallel_tool_calls BOOLEAN,
+                truncation TEXT,
+                verbosity TEXT,
                 FOREIGN KEY (previous_response_id) REFERENCES responses(id)
             )
             "#
@@ -107,6 +131,29 @@ impl DatabaseManager {
         .execute(&self.pool)
         .await?;
 
+        // Add the columns introduced after the first release to the existing responses tables
+        let columns: Vec<String> = sqlx::query("PRAGMA table_info(responses)")
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| row.get("name"))
+            .collect();
+        for (column, column_type) in [
+            ("tools", "TEXT"),
+            ("tool_choice", "TEXT"),
+            ("parallel_tool_calls", "BOOLEAN"),
+            ("truncation", "TEXT"),
+            ("verbosity", "TEXT"),
+        ] {
+            if !columns.iter().any(|c| c == column) {
+                sqlx::query(&format!(
+                    "ALTER TABLE responses ADD COLUMN {column} {column_type}"
+                ))
+                .execute(&self.pool)
+                .await?;
+            }
+        }
+
         // Create input_items table
         sqlx::query(
             r#"
@@ -142,6 +189,26 @@ impl DatabaseManager {
         .execute(&self.pool)
         .await?;
 
+        // Create servers table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS servers (
+                id TEXT PRIMARY KEY,
+                url TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                api_key TEXT,
+                weight INTEGER NOT NULL DEFAULT 1,
+                priority INTEGER NOT NULL DEFAULT 0,
+                max_connections INTEGER,
+                labels TEXT,
+                health TEXT,
+                created_at INTEGER NOT NULL
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await?;
+
         // Create indexes for better query performance
         sqlx::query("CREATE INDEX IF NOT EXISTS idx_responses_previous_id ON responses(previous_response_id)")
             .execute(&self.pool)
@@ -169,7 +236,7 @@ impl DatabaseManager {
     pub async fn store_response(&self, response: ResponseSession) -> Result<()> {
         // Convert metadata to JSON string if present
         let metadata_json = response.metadata.as_ref()
-            .map(|m| serde_json::to_string(m))
+            .map(serde_json::to_string)
             .transpose()?;
 
         sqlx::query(
@@ -179,8 +246,9 @@ impl DatabaseManager {
                 instructions, max_output_tokens, temperature, top_p, store, 
                 metadata, user_id, safety_identifier, prompt_cache_key,
                 usage_input_tokens, usage_output_tokens, usage_total_tokens,
-                error, incomplete_details
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20)
+                error, incomplete_details, tools, tool_choice, parallel_tool_calls,
+                truncation, verbosity
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25)
             "#
         )
         .bind(response.id)
@@ -203,6 +271,11 @@ impl DatabaseManager {
         .bind(response.usage_total_tokens)
         .bind(response.error)
         .bind(response.incomplete_details)
+        .bind(response.tools)
+        .bind(response.tool_choice)
+        .bind(response.parallel_tool_calls)
+        .bind(response.truncation)
+        .bind(response.verbosity)
         .execute(&self.pool)
         .await?;
 
@@ -261,7 +334,8 @@ impl DatabaseManager {
                    instructions, max_output_tokens, temperature, top_p, store, 
                    metadata, user_id, safety_identifier, prompt_cache_key,
                    usage_input_tokens, usage_output_tokens, usage_total_tokens,
-                   error, incomplete_details 
+                   error, incomplete_details, tools, tool_choice, parallel_tool_calls,
+                   truncation, verbosity
             FROM responses 
             WHERE id = ?1
             "#
@@ -298,6 +372,11 @@ impl DatabaseManager {
                 usage_total_tokens: row.get("usage_total_tokens"),
                 error: row.get("error"),
                 incomplete_details: row.get("incomplete_details"),
+                tools: row.get("tools"),
+                tool_choice: row.get("tool_choice"),
+                parallel_tool_calls: row.get("parallel_tool_calls"),
+                truncation: row.get("truncation"),
+                verbosity: row.get("verbosity"),
             }))
         } else {
             Ok(None)
@@ -328,7 +407,7 @@ impl DatabaseManager {
             SELECT id, response_id, item_type, role, content, created_at 
             FROM input_items 
             WHERE response_id = ?1 
-            ORDER BY created_at ASC
+            ORDER BY created_at ASC, rowid ASC
             "#
         )
         .bind(response_id)
@@ -362,7 +441,7 @@ impl DatabaseManager {
             SELECT id, response_id, item_type, role, content, status, created_at 
             FROM output_items 
             WHERE response_id = ?1 
-            ORDER BY created_at ASC
+            ORDER BY created_at ASC, rowid ASC
             "#
         )
         .bind(response_id)
@@ -413,4 +492,118 @@ impl DatabaseManager {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Update the outcome of a stored response, i.e. its status, model, usage and errors
+    pub async fn update_response(&self, response: ResponseSession) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE responses SET
+                status = ?1, model = ?2,
+                usage_input_tokens = ?3, usage_output_tokens = ?4, usage_total_tokens = ?5,
+                error = ?6, incomplete_details = ?7
+            WHERE id = ?8
+            "#
+        )
+        .bind(response.status)
+        .bind(response.model)
+        .bind(response.usage_input_tokens)
+        .bind(response.usage_output_tokens)
+        .bind(response.usage_total_tokens)
+        .bind(response.error)
+        .bind(response.incomplete_details)
+        .bind(response.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn store_server(&self, server: ServerRecord) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO servers (id, url, kind, api_key, weight, priority, max_connections, labels, health, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+            "#
+        )
+        .bind(server.id)
+        .bind(server.url)
+        .bind(server.kind)
+        .bind(server.api_key)
+        .bind(server.weight)
+        .bind(server.priority)
+        .bind(server.max_connections)
+        .bind(server.labels)
+        .bind(server.health)
+        .bind(server.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_servers(&self) -> Result<Vec<ServerRecord>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, url, kind, api_key, weight, priority, max_connections, labels, health, created_at
+            FROM servers
+            ORDER BY created_at ASC
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let servers = rows
+            .into_iter()
+            .map(|row| ServerRecord {
+                id: row.get("id"),
+                url: row.get("url"),
+                kind: row.get("kind"),
+                api_key: row.get("api_key"),
+                weight: row.get("weight"),
+                priority: row.get("priority"),
+                max_connections: row.get("max_connections"),
+                labels: row.get("labels"),
+                health: row.get("health"),
+                created_at: row.get("created_at"),
+            })
+            .collect();
+
+        Ok(servers)
+    }
+
+    /// Update a persisted server, keeping its creation time. Returns `false` if the server is not
+    /// persisted, e.g. it is declared in the config file.
+    pub async fn update_server(&self, server: ServerRecord) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE servers
+            SET url = ?2, kind = ?3, api_key = ?4, weight = ?5, priority = ?6, max_connections = ?7, labels = ?8, health = ?9
+            WHERE id = ?1
+            "#
+        )
+        .bind(server.id)
+        .bind(server.url)
+        .bind(server.kind)
+        .bind(server.api_key)
+        .bind(server.weight)
+        .bind(server.priority)
+        .bind(server.max_connections)
+        .bind(server.labels)
+        .bind(server.health)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete_server(&self, server_id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "DELETE FROM servers WHERE id = ?1"
+        )
+        .bind(server_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
@@ -1,6 +1,13 @@
-use axum::{Json, http::StatusCode, response::IntoResponse};
+use axum::{
+    Json,
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
+    response::IntoResponse,
+};
 use thiserror::Error;
 
+/// Response header listing the ids of the downstream servers tried for a request, in order
+pub(crate) const ATTEMPTED_SERVERS_HEADER: &str = "x-attempted-servers";
+
 pub type ServerResult<T> = std::result::Result<T, ServerError>;
 
 #[derive(Error, Debug, Clone)]
@@ -11,6 +18,19 @@ pub enum ServerError {
         "Not found available server. Please register a(n) {0} server via the `/admin/servers/register` endpoint."
     )]
     NotFoundServer(String),
+    #[error("Server `{0}` not found")]
+    ServerNotFound(String),
+    #[error("Server `{0}` is draining with {1} in-flight request(s). Retry after they finish.")]
+    ServerDraining(String, usize),
+    #[error("The model `{0}` does not exist or is not served by any registered server.")]
+    ModelNotFound(String),
+    /// No server can take the request for now. The client may retry after the given seconds.
+    #[error("{0}")]
+    ServerBusy(String, u64),
+    #[error("All the attempted downstream servers failed: {0}")]
+    DownstreamUnavailable(String, Vec<String>),
+    #[error("{0}")]
+    BadRequest(String),
     #[error("Invalid server kind: {0}")]
     InvalidServerKind(String),
     #[error("Failed to load config: {0}")]
@@ -21,12 +41,49 @@ pub enum ServerError {
     McpNotFoundClient,
     #[error("Mcp operation failed: {0}")]
     McpOperation(String),
+    #[error("MCP server `{0}` not found")]
+    McpServerNotFound(String),
 }
 impl IntoResponse for ServerError {
     fn into_response(self) -> axum::response::Response {
         let (status, err_response) = match &self {
             ServerError::Operation(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
             ServerError::NotFoundServer(e) => (StatusCode::NOT_FOUND, e.to_string()),
+            ServerError::ServerNotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
+            ServerError::ServerDraining(..) => (StatusCode::CONFLICT, self.to_string()),
+            ServerError::ModelNotFound(_) => {
+                // Follow the OpenAI error format so that clients can recognize the error code
+                let err_response = serde_json::json!({
+                    "error": {
+                        "message": self.to_string(),
+                        "type": "invalid_request_error",
+                        "param": "model",
+                        "code": "model_not_found",
+                    }
+                });
+                return (StatusCode::NOT_FOUND, Json(err_response)).into_response();
+            }
+            ServerError::DownstreamUnavailable(_, attempted) => {
+                // Let clients know which servers have been tried
+                let mut response =
+                    (StatusCode::BAD_GATEWAY, Json(self.to_string())).into_response();
+                if let Ok(value) = HeaderValue::from_str(&attempted.join(",")) {
+                    response
+                        .headers_mut()
+                        .insert(ATTEMPTED_SERVERS_HEADER, value);
+                }
+                return response;
+            }
+            ServerError::ServerBusy(_, retry_after) => {
+                // Let clients and load balancers know when to retry
+                let mut response =
+                    (StatusCode::SERVICE_UNAVAILABLE, Json(self.to_string())).into_response();
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(*retry_after));
+                return response;
+            }
+            ServerError::BadRequest(e) => (StatusCode::BAD_REQUEST, e.to_string()),
             ServerError::InvalidServerKind(e) => (StatusCode::BAD_REQUEST, e.to_string()),
             ServerError::FailedToLoadConfig(e) => (StatusCode::BAD_REQUEST, e.to_string()),
             ServerError::McpEmptyContent => (
@@ -38,6 +95,7 @@ impl IntoResponse for ServerError {
                 "Mcp server not found".to_string(),
             ),
             ServerError::McpOperation(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            ServerError::McpServerNotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
         };
 
         (status, Json(err_response)).into_response()
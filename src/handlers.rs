@@ -1,33 +1,48 @@
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use axum::{
     Json,
     body::Body,
-    extract::{Extension, State},
-    http::{HeaderMap, Response, StatusCode},
+    extract::{Extension, FromRequest, Multipart, Path, State},
+    http::{HeaderMap, HeaderValue, Response, StatusCode},
 };
 use bytes::Bytes;
 use endpoints::{
     chat::{
         ChatCompletionAssistantMessage, ChatCompletionChunk, ChatCompletionObject,
-        ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionToolMessage, Tool,
-        ToolCall, ToolChoice, ToolFunction,
+        ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionToolMessage,
+        McpTransport, Tool, ToolCall, ToolChoice, ToolFunction,
     },
     embeddings::EmbeddingRequest,
     models::{ListModelsResponse, Model},
 };
-use futures_util::StreamExt;
-use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use futures_util::{StreamExt, stream::BoxStream};
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE};
 use rmcp::model::{CallToolRequestParam, RawContent};
-use tokio::select;
+use serde::Serialize;
+use tokio::{
+    select,
+    time::{self, Duration, Instant, timeout_at},
+};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    AppState, dual_debug, dual_error, dual_info, dual_warn,
-    error::{ServerError, ServerResult},
+    AppState,
+    config::{AgentConfig, CircuitBreakerConfig, McpConfig, McpServerConfig, McpToolServerConfig},
+    dual_debug, dual_error, dual_info, dual_warn,
+    error::{ATTEMPTED_SERVERS_HEADER, ServerError, ServerResult},
     info::ApiServer,
-    mcp::{DEFAULT_SEARCH_FALLBACK_MESSAGE, MCP_SERVICES, MCP_TOOLS, SEARCH_MCP_SERVER_NAMES},
-    server::{RoutingPolicy, Server, ServerIdToRemove, ServerKind, TargetServerInfo},
+    mcp::{
+        self, DEFAULT_SEARCH_FALLBACK_MESSAGE, MCP_SERVICES, MCP_TOOLS, McpToolTarget,
+        SEARCH_MCP_SERVER_NAMES,
+    },
+    server::{
+        RoutingContext, RoutingPolicy, Server, ServerId, ServerIdToRemove, ServerKind,
+        ServerStatus, ServerUpdate, TargetServerInfo,
+    },
     types::Role,
 };
 
@@ -35,7 +50,7 @@ pub(crate) async fn chat_handler(
     State(state): State<Arc<AppState>>,
     Extension(cancel_token): Extension<CancellationToken>,
     headers: HeaderMap,
-    Json(mut request): Json<ChatCompletionRequest>,
+    Json(body): Json<serde_json::Value>,
 ) -> ServerResult<axum::response::Response> {
     let request_id = headers
         .get("x-request-id")
@@ -43,6 +58,18 @@ pub(crate) async fn chat_handler(
         .unwrap_or("unknown")
         .to_string();
 
+    // `parallel_tool_calls` is not a field of `ChatCompletionRequest`, so it is read from the
+    // raw body. As in the OpenAI API, it defaults to `true`.
+    let parallel_tool_calls = body
+        .get("parallel_tool_calls")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    let mut request = serde_json::from_value::<ChatCompletionRequest>(body).map_err(|e| {
+        let err = ServerError::BadRequest(format!("Invalid chat completion request: {e}"));
+        dual_error!("{} - request_id: {}", err, request_id);
+        err
+    })?;
+
     // check if the user id is provided
     if request.user.is_none() {
         request.user = Some(gen_chat_id());
@@ -53,6 +80,14 @@ pub(crate) async fn chat_handler(
         request_id
     );
 
+    // the function tools defined by the client are called by the client itself
+    let client_tools = request
+        .tools
+        .iter()
+        .flatten()
+        .map(|tool| tool.function.name.clone())
+        .collect::<HashSet<_>>();
+
     // update the request with MCP tools
     dual_info!("Updating the request with MCP tools");
     if let Some(mcp_config) = state.config.read().await.mcp.as_ref()
@@ -61,11 +96,30 @@ pub(crate) async fn chat_handler(
         let mut more_tools = Vec::new();
         for server_config in mcp_config.server.tool_servers.iter() {
             if server_config.enable {
-                server_config
-                    .tools
-                    .as_ref()
-                    .unwrap()
+                // the tools of a disconnected server are hidden until it is reconnected
+                let Some(mcp_tools) = mcp::available_mcp_tools(&server_config.name).await else {
+                    dual_warn!(
+                        "The MCP server `{}` is unavailable, its tools are not offered to the model - request_id: {}",
+                        server_config.name,
+                        request_id
+                    );
+                    continue;
+                };
+
+                mcp_tools
                     .iter()
+                    .filter(|mcp_tool| {
+                        // the client-defined function takes precedence over the MCP tool
+                        let shadowed = client_tools.contains(mcp_tool.name.as_ref());
+                        if shadowed {
+                            dual_warn!(
+                                "The MCP tool `{}` is shadowed by a function of the same name defined by the client - request_id: {}",
+                                mcp_tool.name,
+                                request_id
+                            );
+                        }
+                        !shadowed
+                    })
                     .for_each(|mcp_tool| {
                         let tool = Tool::new(ToolFunction {
                             name: mcp_tool.name.to_string(),
@@ -119,11 +173,29 @@ pub(crate) async fn chat_handler(
         // }
     }
 
+    // the time budget of the agent loop starts with the request
+    let (agent_config, response_timeout) = {
+        let config = state.config.read().await;
+        let agent_config = config
+            .mcp
+            .as_ref()
+            .map(|mcp_config| mcp_config.agent.clone())
+            .unwrap_or_default();
+        (agent_config, Duration::from_secs(config.failover.timeout))
+    };
+    let agent = AgentLoop::new(
+        agent_config,
+        response_timeout,
+        parallel_tool_calls,
+        client_tools,
+    );
+
     chat(
         State(state),
         Extension(cancel_token),
         headers,
         Json(request),
+        agent,
         &request_id,
     )
     .await
@@ -134,30 +206,36 @@ pub(crate) async fn chat(
     Extension(cancel_token): Extension<CancellationToken>,
     headers: HeaderMap,
     Json(mut request): Json<ChatCompletionRequest>,
+    agent: AgentLoop,
     request_id: impl AsRef<str>,
 ) -> ServerResult<axum::response::Response> {
     let request_id = request_id.as_ref();
-
-    // Get target server
-    let chat_server = get_chat_server(&state, request_id).await?;
-
-    // Send request and handle response
-    let response = send_request_with_retry(
-        &chat_server,
+    let routing_key = routing_key(&state, &headers, request.user.as_deref()).await;
+
+    // Send request to a chat server and handle response
+    let FailoverResponse {
+        server: chat_server,
+        response,
+        attempted,
+    } = send_request_with_retry(
+        &state,
         &mut request,
         &headers,
+        routing_key.as_deref(),
         request_id,
         cancel_token.clone(),
     )
     .await?;
 
     // Handle response based on stream mode
-    match request.stream {
+    let mut response = match request.stream {
         Some(true) => {
             // Handle stream response
             handle_stream_response(
+                &state,
                 response,
-                &mut request,
+                request,
+                agent,
                 &headers,
                 &chat_server,
                 request_id,
@@ -168,8 +246,10 @@ pub(crate) async fn chat(
         Some(false) | None => {
             // Handle non-stream response
             handle_non_stream_response(
+                &state,
                 response,
                 &mut request,
+                agent,
                 &headers,
                 &chat_server,
                 request_id,
@@ -177,7 +257,11 @@ pub(crate) async fn chat(
             )
             .await
         }
-    }
+    }?;
+
+    set_attempted_servers_header(&mut response, &attempted);
+
+    Ok(response)
 }
 
 pub(crate) async fn embeddings_handler(
@@ -198,33 +282,6 @@ pub(crate) async fn embeddings_handler(
         request_id
     );
 
-    // get the embeddings server
-    let servers = state.server_group.read().await;
-    let embeddings_servers = match servers.get(&ServerKind::embeddings) {
-        Some(servers) => servers,
-        None => {
-            let err_msg = "No embeddings server available";
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            return Err(ServerError::Operation(err_msg.to_string()));
-        }
-    };
-
-    let embedding_server = match embeddings_servers.next().await {
-        Ok(target_server_info) => target_server_info,
-        Err(e) => {
-            let err_msg = format!("Failed to get the embeddings server: {e}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            return Err(ServerError::Operation(err_msg));
-        }
-    };
-    let embeddings_service_url =
-        format!("{}/embeddings", embedding_server.url.trim_end_matches('/'));
-    dual_info!(
-        "Forward the embeddings request to {} - request_id: {}",
-        embeddings_service_url,
-        request_id
-    );
-
     // parse the content-type header
     let content_type = headers
         .get("content-type")
@@ -241,52 +298,61 @@ pub(crate) async fn embeddings_handler(
         request_id
     );
 
-    // Create request client
-    let ds_request = if let Some(api_key) = &embedding_server.api_key
-        && !api_key.is_empty()
-    {
-        reqwest::Client::new()
-            .post(embeddings_service_url)
-            .header("Content-Type", content_type)
-            .header(AUTHORIZATION, api_key)
-            .json(&request)
-    } else if headers.contains_key("authorization") {
-        let authorization = headers
-            .get("authorization")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
-
-        reqwest::Client::new()
-            .post(embeddings_service_url)
-            .header("Content-Type", content_type)
-            .header("Authorization", authorization)
-            .json(&request)
-    } else {
-        reqwest::Client::new()
-            .post(embeddings_service_url)
-            .header("Content-Type", content_type)
-            .json(&request)
-    };
+    // forward the request to an embeddings server
+    let FailoverResponse {
+        server: _embedding_server,
+        response: ds_response,
+        attempted,
+    } = send_with_failover(
+        &state,
+        ServerKind::embeddings,
+        request.model.as_deref(),
+        routing_key(&state, &headers, None).await.as_deref(),
+        &request_id,
+        &cancel_token,
+        |embedding_server| {
+            let embeddings_service_url =
+                format!("{}/embeddings", embedding_server.url.trim_end_matches('/'));
+            dual_info!(
+                "Forward the embeddings request to {} - request_id: {}",
+                embeddings_service_url,
+                request_id
+            );
 
-    // Use select! to handle request cancellation
-    let ds_response = select! {
-        response = ds_request.send() => {
-            response.map_err(|e| {
-                let err_msg = format!(
-                    "Failed to forward the request to the downstream server: {e}",
-                );
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?
-        }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled by client";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            return Err(ServerError::Operation(warn_msg.to_string()));
-        }
-    };
+            // Create request client
+            if let Some(api_key) = &embedding_server.api_key
+                && !api_key.is_empty()
+            {
+                state
+                    .http_client
+                    .post(embeddings_service_url)
+                    .header("Content-Type", &content_type)
+                    .header(AUTHORIZATION, api_key)
+                    .json(&request)
+            } else if headers.contains_key("authorization") {
+                let authorization = headers
+                    .get("authorization")
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+
+                state
+                    .http_client
+                    .post(embeddings_service_url)
+                    .header("Content-Type", &content_type)
+                    .header("Authorization", authorization)
+                    .json(&request)
+            } else {
+                state
+                    .http_client
+                    .post(embeddings_service_url)
+                    .header("Content-Type", &content_type)
+                    .json(&request)
+            }
+        },
+    )
+    .await?;
 
     let status = ds_response.status();
 
@@ -311,7 +377,8 @@ pub(crate) async fn embeddings_handler(
         .header("Content-Type", "application/json")
         .body(Body::from(bytes))
     {
-        Ok(response) => {
+        Ok(mut response) => {
+            set_attempted_servers_header(&mut response, &attempted);
             dual_info!(
                 "Embeddings request completed successfully - request_id: {}",
                 request_id
@@ -344,76 +411,53 @@ pub(crate) async fn audio_transcriptions_handler(
         request_id
     );
 
-    // get the transcribe server
-    let transcription_server = {
-        let servers = state.server_group.read().await;
-        let transcribe_servers = match servers.get(&ServerKind::transcribe) {
-            Some(servers) => servers,
-            None => {
-                let err_msg = "No transcribe server available";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-        };
-
-        match transcribe_servers.next().await {
-            Ok(target_server_info) => target_server_info,
-            Err(e) => {
-                let err_msg = format!("Failed to get the transcribe server: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg));
-            }
-        }
-    };
-
-    let transcription_server_url = format!(
-        "{}/audio/transcriptions",
-        transcription_server.url.trim_end_matches('/')
-    );
-    dual_info!(
-        "Forward the audio transcription request to {} - request_id: {}",
-        transcription_server_url,
-        request_id
-    );
-
-    // Create request client
-    let mut ds_request = reqwest::Client::new().post(transcription_server_url);
-    if let Some(api_key) = &transcription_server.api_key
-        && !api_key.is_empty()
-    {
-        ds_request = ds_request.header(AUTHORIZATION, api_key);
-    }
-    for (name, value) in req.headers().iter() {
-        ds_request = ds_request.header(name, value);
-    }
-
     // convert the request body into bytes
-    let body = req.into_body();
+    let (parts, body) = req.into_parts();
     let body_bytes = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
         let err_msg = format!("Failed to convert the request body into bytes: {e}");
         dual_error!("{err_msg} - request_id: {request_id}");
         ServerError::Operation(err_msg)
     })?;
 
-    ds_request = ds_request.body(body_bytes);
+    // forward the request to a transcribe server
+    let model = extract_model(&parts.headers, &body_bytes).await;
+    let FailoverResponse {
+        server: _transcription_server,
+        response: ds_response,
+        attempted,
+    } = send_with_failover(
+        &state,
+        ServerKind::transcribe,
+        model.as_deref(),
+        routing_key(&state, &parts.headers, None).await.as_deref(),
+        &request_id,
+        &cancel_token,
+        |transcription_server| {
+            let transcription_server_url = format!(
+                "{}/audio/transcriptions",
+                transcription_server.url.trim_end_matches('/')
+            );
+            dual_info!(
+                "Forward the audio transcription request to {} - request_id: {}",
+                transcription_server_url,
+                request_id
+            );
 
-    // Use select! to handle request cancellation
-    let ds_response = select! {
-        response = ds_request.send() => {
-            response.map_err(|e| {
-                let err_msg = format!(
-                    "Failed to forward the request to the downstream server: {e}"
-                );
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?
-        }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled by client";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            return Err(ServerError::Operation(warn_msg.to_string()));
-        }
-    };
+            // Create request client
+            let mut ds_request = state.http_client.post(transcription_server_url);
+            if let Some(api_key) = &transcription_server.api_key
+                && !api_key.is_empty()
+            {
+                ds_request = ds_request.header(AUTHORIZATION, api_key);
+            }
+            for (name, value) in parts.headers.iter() {
+                ds_request = ds_request.header(name, value);
+            }
+
+            ds_request.body(body_bytes.clone())
+        },
+    )
+    .await?;
 
     let status = ds_response.status();
 
@@ -438,7 +482,8 @@ pub(crate) async fn audio_transcriptions_handler(
         .header("Content-Type", "application/json")
         .body(Body::from(bytes))
     {
-        Ok(response) => {
+        Ok(mut response) => {
+            set_attempted_servers_header(&mut response, &attempted);
             dual_info!(
                 "Audio transcription request completed successfully - request_id: {}",
                 request_id
@@ -471,76 +516,53 @@ pub(crate) async fn audio_translations_handler(
         request_id
     );
 
-    // get the transcribe server
-    let translation_server = {
-        let servers = state.server_group.read().await;
-        let translate_servers = match servers.get(&ServerKind::translate) {
-            Some(servers) => servers,
-            None => {
-                let err_msg = "No translate server available";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-        };
-
-        match translate_servers.next().await {
-            Ok(target_server_info) => target_server_info,
-            Err(e) => {
-                let err_msg = format!("Failed to get the translate server: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg));
-            }
-        }
-    };
-
-    let translation_server_url = format!(
-        "{}/audio/translations",
-        translation_server.url.trim_end_matches('/')
-    );
-    dual_info!(
-        "Forward the audio translation request to {} - request_id: {}",
-        translation_server_url,
-        request_id
-    );
-
-    // Create request client
-    let mut ds_request = reqwest::Client::new().post(translation_server_url);
-    if let Some(api_key) = &translation_server.api_key
-        && !api_key.is_empty()
-    {
-        ds_request = ds_request.header(AUTHORIZATION, api_key);
-    }
-    for (name, value) in req.headers().iter() {
-        ds_request = ds_request.header(name, value);
-    }
-
     // convert the request body into bytes
-    let body = req.into_body();
+    let (parts, body) = req.into_parts();
     let body_bytes = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
         let err_msg = format!("Failed to convert the request body into bytes: {e}");
         dual_error!("{err_msg} - request_id: {request_id}");
         ServerError::Operation(err_msg)
     })?;
 
-    ds_request = ds_request.body(body_bytes);
+    // forward the request to a translate server
+    let model = extract_model(&parts.headers, &body_bytes).await;
+    let FailoverResponse {
+        server: _translation_server,
+        response: ds_response,
+        attempted,
+    } = send_with_failover(
+        &state,
+        ServerKind::translate,
+        model.as_deref(),
+        routing_key(&state, &parts.headers, None).await.as_deref(),
+        &request_id,
+        &cancel_token,
+        |translation_server| {
+            let translation_server_url = format!(
+                "{}/audio/translations",
+                translation_server.url.trim_end_matches('/')
+            );
+            dual_info!(
+                "Forward the audio translation request to {} - request_id: {}",
+                translation_server_url,
+                request_id
+            );
 
-    // Use select! to handle request cancellation
-    let ds_response = select! {
-        response = ds_request.send() => {
-            response.map_err(|e| {
-                let err_msg = format!(
-                    "Failed to forward the request to the downstream server: {e}"
-                );
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?
-        }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled by client";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            return Err(ServerError::Operation(warn_msg.to_string()));
-        }
-    };
+            // Create request client
+            let mut ds_request = state.http_client.post(translation_server_url);
+            if let Some(api_key) = &translation_server.api_key
+                && !api_key.is_empty()
+            {
+                ds_request = ds_request.header(AUTHORIZATION, api_key);
+            }
+            for (name, value) in parts.headers.iter() {
+                ds_request = ds_request.header(name, value);
+            }
+
+            ds_request.body(body_bytes.clone())
+        },
+    )
+    .await?;
 
     let status = ds_response.status();
 
@@ -565,7 +587,8 @@ pub(crate) async fn audio_translations_handler(
         .header("Content-Type", "application/json")
         .body(Body::from(bytes))
     {
-        Ok(response) => {
+        Ok(mut response) => {
+            set_attempted_servers_header(&mut response, &attempted);
             dual_info!(
                 "Audio translation request completed successfully - request_id: {}",
                 request_id
@@ -598,72 +621,50 @@ pub(crate) async fn audio_tts_handler(
         request_id
     );
 
-    // get the tts server
-    let tts_server = {
-        let servers = state.server_group.read().await;
-        let tts_servers = match servers.get(&ServerKind::tts) {
-            Some(servers) => servers,
-            None => {
-                let err_msg = "No tts server available";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-        };
-
-        match tts_servers.next().await {
-            Ok(target_server_info) => target_server_info,
-            Err(e) => {
-                let err_msg = format!("Failed to get the tts server: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg));
-            }
-        }
-    };
-
-    let tts_server_url = format!("{}/audio/speech", tts_server.url.trim_end_matches('/'));
-    dual_info!(
-        "Forward the audio speech request to {} - request_id: {}",
-        tts_server_url,
-        request_id
-    );
-
-    // Create request client
-    let mut ds_request = reqwest::Client::new().post(tts_server_url);
-    if let Some(api_key) = &tts_server.api_key
-        && !api_key.is_empty()
-    {
-        ds_request = ds_request.header(AUTHORIZATION, api_key);
-    }
-    for (name, value) in req.headers().iter() {
-        ds_request = ds_request.header(name, value);
-    }
-
-    let body = req.into_body();
+    // convert the request body into bytes
+    let (parts, body) = req.into_parts();
     let body_bytes = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
         let err_msg = format!("Failed to convert the request body into bytes: {e}");
         dual_error!("{err_msg} - request_id: {request_id}");
         ServerError::Operation(err_msg)
     })?;
 
-    ds_request = ds_request.body(body_bytes);
+    // forward the request to a tts server
+    let model = extract_model(&parts.headers, &body_bytes).await;
+    let FailoverResponse {
+        server: _tts_server,
+        response: ds_response,
+        attempted,
+    } = send_with_failover(
+        &state,
+        ServerKind::tts,
+        model.as_deref(),
+        routing_key(&state, &parts.headers, None).await.as_deref(),
+        &request_id,
+        &cancel_token,
+        |tts_server| {
+            let tts_server_url = format!("{}/audio/speech", tts_server.url.trim_end_matches('/'));
+            dual_info!(
+                "Forward the audio speech request to {} - request_id: {}",
+                tts_server_url,
+                request_id
+            );
 
-    // Use select! to handle request cancellation
-    let ds_response = select! {
-        response = ds_request.send() => {
-            response.map_err(|e| {
-                let err_msg = format!(
-                    "Failed to forward the request to the downstream server: {e}"
-                );
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?
-        }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled by client";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            return Err(ServerError::Operation(warn_msg.to_string()));
-        }
-    };
+            // Create request client
+            let mut ds_request = state.http_client.post(tts_server_url);
+            if let Some(api_key) = &tts_server.api_key
+                && !api_key.is_empty()
+            {
+                ds_request = ds_request.header(AUTHORIZATION, api_key);
+            }
+            for (name, value) in parts.headers.iter() {
+                ds_request = ds_request.header(name, value);
+            }
+
+            ds_request.body(body_bytes.clone())
+        },
+    )
+    .await?;
 
     // create a response builder with the status and headers of the downstream response
     let mut response_builder = Response::builder().status(ds_response.status());
@@ -688,7 +689,8 @@ pub(crate) async fn audio_tts_handler(
     };
 
     match response_builder.body(Body::from(bytes)) {
-        Ok(response) => {
+        Ok(mut response) => {
+            set_attempted_servers_header(&mut response, &attempted);
             dual_info!(
                 "Audio speech request completed successfully - request_id: {}",
                 request_id
@@ -718,76 +720,53 @@ pub(crate) async fn image_handler(
 
     dual_info!("Received a new image request - request_id: {}", request_id);
 
-    // get the image server
-    let image_server = {
-        let servers = state.server_group.read().await;
-        let image_servers = match servers.get(&ServerKind::image) {
-            Some(servers) => servers,
-            None => {
-                let err_msg = "No image server available";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-        };
-
-        match image_servers.next().await {
-            Ok(target_server_info) => target_server_info,
-            Err(e) => {
-                let err_msg = format!("Failed to get the image server: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg));
-            }
-        }
-    };
-
-    let image_server_url = format!(
-        "{}/images/generations",
-        image_server.url.trim_end_matches('/')
-    );
-    dual_info!(
-        "Forward the image request to {} - request_id: {}",
-        image_server_url,
-        request_id
-    );
-
-    // Create request client
-    let mut ds_request = reqwest::Client::new().post(image_server_url);
-    if let Some(api_key) = &image_server.api_key
-        && !api_key.is_empty()
-    {
-        ds_request = ds_request.header(AUTHORIZATION, api_key);
-    }
-    for (name, value) in req.headers().iter() {
-        ds_request = ds_request.header(name, value);
-    }
-
     // convert the request body into bytes
-    let body = req.into_body();
+    let (parts, body) = req.into_parts();
     let body_bytes = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
         let err_msg = format!("Failed to convert the request body into bytes: {e}");
         dual_error!("{err_msg} - request_id: {request_id}");
         ServerError::Operation(err_msg)
     })?;
 
-    ds_request = ds_request.body(body_bytes);
+    // forward the request to a image server
+    let model = extract_model(&parts.headers, &body_bytes).await;
+    let FailoverResponse {
+        server: _image_server,
+        response: ds_response,
+        attempted,
+    } = send_with_failover(
+        &state,
+        ServerKind::image,
+        model.as_deref(),
+        routing_key(&state, &parts.headers, None).await.as_deref(),
+        &request_id,
+        &cancel_token,
+        |image_server| {
+            let image_server_url = format!(
+                "{}/images/generations",
+                image_server.url.trim_end_matches('/')
+            );
+            dual_info!(
+                "Forward the image request to {} - request_id: {}",
+                image_server_url,
+                request_id
+            );
 
-    // Use select! to handle request cancellation
-    let ds_response = select! {
-        response = ds_request.send() => {
-            response.map_err(|e| {
-                let err_msg = format!(
-                    "Failed to forward the request to the downstream server: {e}"
-                );
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?
-        }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled by client";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            return Err(ServerError::Operation(warn_msg.to_string()));
-        }
-    };
+            // Create request client
+            let mut ds_request = state.http_client.post(image_server_url);
+            if let Some(api_key) = &image_server.api_key
+                && !api_key.is_empty()
+            {
+                ds_request = ds_request.header(AUTHORIZATION, api_key);
+            }
+            for (name, value) in parts.headers.iter() {
+                ds_request = ds_request.header(name, value);
+            }
+
+            ds_request.body(body_bytes.clone())
+        },
+    )
+    .await?;
 
     // create a response builder with the status and headers of the downstream response
     let mut response_builder = Response::builder().status(ds_response.status());
@@ -812,7 +791,8 @@ pub(crate) async fn image_handler(
     };
 
     match response_builder.body(Body::from(bytes)) {
-        Ok(response) => {
+        Ok(mut response) => {
+            set_attempted_servers_header(&mut response, &attempted);
             dual_info!(
                 "Image request completed successfully - request_id: {}",
                 request_id
@@ -921,12 +901,14 @@ pub(crate) async fn info_handler(
 }
 
 pub(crate) mod admin {
+    use serde::Deserialize;
+
     use super::*;
 
     pub(crate) async fn register_downstream_server_handler(
         State(state): State<Arc<AppState>>,
         headers: HeaderMap,
-        Json(mut server): Json<Server>,
+        Json(server): Json<Server>,
     ) -> ServerResult<axum::response::Response> {
         // Get request ID from headers
         let request_id = headers
@@ -956,12 +938,18 @@ pub(crate) mod admin {
         // update the model list
         update_model_list(State(state.clone()), &headers, &request_id, &server).await?;
 
-        // update health status of the server
-        server.health_status.is_healthy = true;
-        server.health_status.last_check = SystemTime::now();
+        // persist the server so that it is restored on the next startup
+        let record = server.to_record();
 
         // register the server
         state.register_downstream_server(server).await?;
+        if let Err(e) = state.database.store_server(record).await {
+            let err_msg = format!("Failed to store the server in database: {e}");
+            dual_error!("{err_msg} - request_id: {request_id}");
+
+            state.unregister_downstream_server(&server_id).await?;
+            return Err(ServerError::Operation(err_msg));
+        }
         dual_info!(
             "Registered successfully. Assigned Server Id: {} - request_id: {}",
             server_id,
@@ -1249,9 +1237,38 @@ pub(crate) mod admin {
             .unwrap_or("unknown")
             .to_string();
 
-        state
+        // let the in-flight requests of a draining server finish before removing it
+        if let Some(server) = state.find_downstream_server(&server_id.server_id).await
+            && server.stats().is_draining()
+            && server.stats().connections() > 0
+        {
+            let err = ServerError::ServerDraining(
+                server_id.server_id.clone(),
+                server.stats().connections(),
+            );
+            dual_warn!("{} - request_id: {}", err, request_id);
+            return Err(err);
+        }
+
+        // remove the server from database so that it is not restored on the next startup
+        let persisted = state
+            .database
+            .delete_server(&server_id.server_id)
+            .await
+            .map_err(|e| {
+                let err_msg = format!("Failed to remove the server from database: {e}");
+                dual_error!("{err_msg} - request_id: {request_id}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        // a persisted server may not be registered if it failed the verification on startup
+        if let Err(e) = state
             .unregister_downstream_server(&server_id.server_id)
-            .await?;
+            .await
+            && !persisted
+        {
+            return Err(e);
+        }
 
         // create a response with status code 200. Content-Type is JSON
         let json_body = serde_json::json!({
@@ -1293,6 +1310,18 @@ pub(crate) mod admin {
             request_id
         );
 
+        // attach the runtime state, e.g. the circuit breaker state, to each server
+        let servers = servers
+            .into_iter()
+            .map(|(kind, servers)| {
+                let servers = servers
+                    .into_iter()
+                    .map(ServerStatus::from)
+                    .collect::<Vec<_>>();
+                (kind, servers)
+            })
+            .collect::<HashMap<_, _>>();
+
         let json_body = serde_json::to_string(&servers).unwrap();
 
         let response = Response::builder()
@@ -1307,1288 +1336,3897 @@ pub(crate) mod admin {
 
         Ok(response)
     }
-}
-
-// Generate a unique chat id for the chat completion request
-fn gen_chat_id() -> String {
-    format!("chatcmpl-{}", uuid::Uuid::new_v4())
-}
 
-async fn get_chat_server(
-    state: &Arc<AppState>,
-    request_id: &str,
-) -> ServerResult<crate::server::TargetServerInfo> {
-    let servers = state.server_group.read().await;
-    let chat_servers = match servers.get(&ServerKind::chat) {
-        Some(servers) => servers,
-        None => {
-            let err_msg = "No chat server available";
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            return Err(ServerError::Operation(err_msg.to_string()));
-        }
-    };
+    pub(crate) async fn get_downstream_server_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Path(server_id): Path<String>,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
 
-    match chat_servers.next().await {
-        Ok(target_server_info) => Ok(target_server_info),
-        Err(e) => {
-            let err_msg = format!("Failed to get the chat server: {e}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            Err(ServerError::Operation(err_msg))
-        }
+        let server = find_server(&state, server_id, &request_id).await?;
+        server_status_response(&state, server, &request_id).await
     }
-}
 
-/// Send chat request to downstream server with intelligent retry mechanism
-///
-/// This function implements the following features:
-/// 1. First attempt to send request to downstream server
-/// 2. If tool call deserialization error occurs, intelligently retry:
-///    - Check if request contains tool definitions
-///    - Check if current tool choice is non-None state
-///    - If conditions are met, reset tool choice to None and retry
-/// 3. This retry mechanism solves cases where some downstream servers don't support tool calls
-///
-/// # Arguments
-///
-/// * `chat_server` - The downstream chat server to send request to
-/// * `request` - Chat completion request, may be modified (e.g., reset tool choice)
-/// * `headers` - HTTP request headers, including authentication info
-/// * `request_id` - Request ID for log tracking
-/// * `cancel_token` - Cancellation token for request cancellation support
-///
-/// # Returns
-/// * `Ok(response)` - Successfully obtained downstream server response
-/// * `Err(ServerError)` - Request failed or still failed after retry
-///
-/// # Error Handling Strategy
-/// * Tool call deserialization error: Try disabling tool choice and retry
-/// * Other errors: Return error directly, no retry
-/// * Retry logic: Maximum one retry to avoid infinite loops
-async fn send_request_with_retry(
-    chat_server: &TargetServerInfo,
-    request: &mut ChatCompletionRequest,
-    headers: &HeaderMap,
-    request_id: &str,
-    cancel_token: CancellationToken,
-) -> ServerResult<reqwest::Response> {
-    // First attempt to send request to downstream server
-    let response = build_and_send_request(
-        chat_server,
-        request,
-        headers,
-        cancel_token.clone(),
-        request_id,
-    )
-    .await;
+    pub(crate) async fn update_downstream_server_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Path(server_id): Path<String>,
+        Json(update): Json<ServerUpdate>,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
 
-    match response {
-        // If first request succeeds, return response directly
-        Ok(response) => Ok(response),
-        Err(e) => {
-            let err_str = e.to_string();
+        let previous = find_server(&state, server_id, &request_id).await?;
+        let server = previous
+            .updated(update)
+            .inspect_err(|e| dual_error!("{} - request_id: {}", e, request_id))?;
 
-            // Check if it's a tool call deserialization error
-            // This error usually occurs when downstream server doesn't support tool calls
-            if err_str.contains("Failed to deserialize generated tool calls") {
-                // Verify if retry is possible:
-                // 1. Request must contain tool definitions
-                // 2. Tool definitions cannot be empty
-                if let Some(tools) = &request.tools
-                    && !tools.is_empty()
-                {
-                    // Check if current tool choice is non-None state
-                    // Only non-None state needs to be reset to None for retry
-                    if let Some(tool_choice) = &request.tool_choice
-                        && *tool_choice != ToolChoice::None
-                    {
-                        // Reset tool choice to None, disable tool call functionality
-                        request.tool_choice = None;
-                        dual_info!(
-                            "Retrying request without tool choice - request_id: {}",
-                            request_id
-                        );
+        // the update is applied before it is persisted, so that a rejected update is not restored
+        // on restart
+        state
+            .update_downstream_server(server.clone())
+            .await
+            .inspect_err(|e| dual_error!("{} - request_id: {}", e, request_id))?;
 
-                        // Re-send with reset request
-                        let response = build_and_send_request(
-                            chat_server,
-                            request,
-                            headers,
-                            cancel_token,
-                            request_id,
-                        )
-                        .await
-                        .map_err(|e| {
-                            let err_msg = format!("Failed to send request: {e}");
-                            dual_error!("{} - request_id: {}", err_msg, request_id);
-                            ServerError::Operation(err_msg)
-                        })?;
+        // the servers declared in the config file are not persisted, so their changes are lost
+        // on restart
+        let persisted = match state.database.update_server(server.to_record()).await {
+            Ok(persisted) => persisted,
+            Err(e) => {
+                let err_msg = format!("Failed to update the server in database: {e}");
+                dual_error!("{err_msg} - request_id: {request_id}");
 
-                        return Ok(response);
-                    }
+                // keep the registered server consistent with the database
+                if let Err(e) = state.update_downstream_server(previous).await {
+                    dual_error!(
+                        "Failed to roll back the update of the server: {} - request_id: {}",
+                        e,
+                        request_id
+                    );
                 }
+                return Err(ServerError::Operation(err_msg));
             }
-
-            // Non-tool call related error, return directly, no retry
-            let err_msg = format!("Failed to send request: {e}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            Err(ServerError::Operation(err_msg))
+        };
+        if !persisted {
+            dual_warn!(
+                "The server {} is not persisted. The changes are kept until restart - request_id: {}",
+                &server.id,
+                request_id
+            );
         }
+
+        dual_info!(
+            "Updated server: {} - request_id: {}",
+            &server.id,
+            request_id
+        );
+
+        server_status_response(&state, server, &request_id).await
     }
-}
 
-/// Build and send HTTP request to downstream server with cancellation support
-///
-/// This function implements the following features:
-/// 1. Build HTTP client and set necessary request headers
-/// 2. Send JSON-formatted chat completion request to downstream server
-/// 3. Support cancellation of ongoing requests via CancellationToken
-/// 4. Provide detailed error information and cancellation logs
-///
-/// # Arguments
-///
-/// * `chat_server` - The downstream chat server to send request to
-/// * `request` - Chat completion request object
-/// * `headers` - HTTP request headers, including authentication info
-/// * `cancel_token` - Cancellation token for request cancellation support
-///
-/// # Returns
-/// * `Ok(response)` - Successfully obtained downstream server response
-/// * `Err(ServerError)` - Request failed or was cancelled
-///
-/// # Cancellation Features
-/// * When cancel_token is triggered, function immediately returns cancellation error
-/// * Cancellation logs warning messages for debugging and monitoring
-/// * Cancellation operation releases related resources to prevent leaks
-async fn build_and_send_request(
-    chat_server: &TargetServerInfo,
-    request: &ChatCompletionRequest,
-    headers: &HeaderMap,
-    cancel_token: CancellationToken,
-    request_id: &str,
-) -> ServerResult<reqwest::Response> {
-    let url = format!("{}/chat/completions", chat_server.url.trim_end_matches('/'));
-    let mut client = reqwest::Client::new().post(&url);
+    /// Stop routing new requests to the server, while the in-flight requests are completed
+    pub(crate) async fn drain_downstream_server_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Path(server_id): Path<String>,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
 
-    // Add common headers
-    client = client.header(CONTENT_TYPE, "application/json");
+        let server = find_server(&state, server_id, &request_id).await?;
+        if !server.stats().set_draining(true) {
+            dual_info!(
+                "Draining server {} with {} in-flight request(s) - request_id: {}",
+                &server.id,
+                server.stats().connections(),
+                request_id
+            );
+        }
 
-    // Add authorization header
-    if let Some(api_key) = &chat_server.api_key
-        && !api_key.is_empty()
-    {
-        client = client.header(AUTHORIZATION, api_key);
-    } else if let Some(auth) = headers.get("authorization")
-        && let Ok(auth_str) = auth.to_str()
-    {
-        client = client.header(AUTHORIZATION, auth_str);
+        server_status_response(&state, server, &request_id).await
     }
 
-    dual_info!(
-        "Request to downstream chat server - request_id: {}\n{}",
-        request_id,
-        serde_json::to_string_pretty(request).unwrap()
-    );
+    /// Resume routing requests to a drained server. The model list is fetched again, since the
+    /// server may have been upgraded while it was drained.
+    pub(crate) async fn resume_downstream_server_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Path(server_id): Path<String>,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
 
-    // Use select! to support cancellation
-    select! {
-        response = client.json(request).send() => {
-            response.map_err(|e| ServerError::Operation(format!("Failed to forward request: {e}")))
+        let server = find_server(&state, server_id, &request_id).await?;
+        update_model_list(State(state.clone()), &headers, &request_id, &server).await?;
+        if server.stats().set_draining(false) {
+            dual_info!("Resumed server {} - request_id: {}", &server.id, request_id);
         }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled by client";
-            dual_warn!("{}", warn_msg);
-            Err(ServerError::Operation(warn_msg.to_string()))
+
+        server_status_response(&state, server, &request_id).await
+    }
+
+    async fn find_server(
+        state: &Arc<AppState>,
+        server_id: String,
+        request_id: &str,
+    ) -> ServerResult<Server> {
+        match state.find_downstream_server(&server_id).await {
+            Some(server) => Ok(server),
+            None => {
+                let err = ServerError::ServerNotFound(server_id);
+                dual_error!("{} - request_id: {}", err, request_id);
+                Err(err)
+            }
         }
     }
-}
 
-/// Handle streaming chat responses, supporting tool calls and normal streaming responses
-///
-/// Choose processing path based on tool call identifier in response headers:
-/// - Tool call needed: Extract tool call information from stream and call MCP server
-/// - Normal streaming response: Directly process streaming data and return
-///
-/// # Arguments
-///
-/// * `response` - HTTP response from downstream server
-/// * `request` - Chat request, may be modified
-/// * `headers` - HTTP request headers
-/// * `chat_service_url` - Chat service URL
-/// * `request_id` - Request ID
-/// * `cancel_token` - Cancellation token
-async fn handle_stream_response(
-    response: reqwest::Response,
-    request: &mut ChatCompletionRequest,
-    headers: &HeaderMap,
-    chat_server: &TargetServerInfo,
-    request_id: &str,
-    cancel_token: CancellationToken,
-) -> ServerResult<axum::response::Response> {
+    /// Create the response describing the server, its runtime state and its models
+    async fn server_status_response(
+        state: &Arc<AppState>,
+        server: Server,
+        request_id: &str,
+    ) -> ServerResult<axum::response::Response> {
+        let models = state
+            .models
+            .read()
+            .await
+            .get(&server.id)
+            .map(|models| {
+                models
+                    .iter()
+                    .map(|model| model.id.clone())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let mut json_body = serde_json::to_value(ServerStatus::from(server)).map_err(|e| {
+            let err_msg = format!("Failed to serialize the server: {e}");
+            dual_error!("{err_msg} - request_id: {request_id}");
+            ServerError::Operation(err_msg)
+        })?;
+        json_body["models"] = serde_json::json!(models);
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg} - request_id: {request_id}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    pub(crate) async fn list_mcp_servers_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let server_configs = match state.config.read().await.mcp.as_ref() {
+            Some(mcp_config) => mcp_config.server.tool_servers.clone(),
+            None => Vec::new(),
+        };
+        let mut servers = Vec::with_capacity(server_configs.len());
+        for server_config in server_configs.iter() {
+            let status = mcp::mcp_server_status(&server_config.name, server_config.transport).await;
+            servers.push(status);
+        }
+        dual_info!(
+            "Found {} mcp servers - request_id: {}",
+            servers.len(),
+            request_id
+        );
+
+        let json_body = serde_json::to_string(&servers).unwrap();
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg} - request_id: {request_id}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    pub(crate) async fn get_mcp_server_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Path(name): Path<String>,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let server_config = find_mcp_server(&state, name, &request_id).await?;
+        mcp_server_status_response(&server_config, &request_id).await
+    }
+
+    /// Add an mcp server, and connect it if it is enabled
+    pub(crate) async fn add_mcp_server_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Json(server_config): Json<McpToolServerConfig>,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+
+        server_config
+            .validate()
+            .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+
+        // the admin api is not authenticated, so it must not launch commands on the host
+        if server_config.transport == McpTransport::Stdio {
+            let err_msg = format!(
+                "The mcp server '{}' uses the stdio transport, which can only be configured in the config file",
+                server_config.name
+            );
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::BadRequest(err_msg));
+        }
+
+        let (namespace_tools, order) = {
+            let mut config = state.config.write().await;
+            let mcp_config = config.mcp.get_or_insert_with(|| McpConfig {
+                server: McpServerConfig {
+                    tool_servers: Vec::new(),
+                    namespace_tools: false,
+                },
+                agent: AgentConfig::default(),
+            });
+            if mcp_config
+                .server
+                .tool_servers
+                .iter()
+                .any(|server| server.name == server_config.name)
+            {
+                let err_msg = format!("The mcp server '{}' already exists", server_config.name);
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                return Err(ServerError::BadRequest(err_msg));
+            }
+            mcp_config.server.tool_servers.push(server_config.clone());
+
+            (
+                mcp_config.server.namespace_tools,
+                mcp_config.server.tool_servers.len() - 1,
+            )
+        };
+        dual_info!(
+            "Added mcp server: {} - request_id: {}",
+            server_config.name,
+            request_id
+        );
+
+        if server_config.enable {
+            server_config
+                .start_mcp_server(namespace_tools, order)
+                .await?;
+        }
+
+        mcp_server_status_response(&server_config, &request_id).await
+    }
+
+    /// Changes of an mcp server
+    ///
+    /// Only its state can be changed, so that the commands of the stdio servers come from the
+    /// config file.
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct McpServerUpdate {
+        enable: bool,
+    }
+
+    /// Enable or disable an mcp server
+    pub(crate) async fn update_mcp_server_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Path(name): Path<String>,
+        Json(update): Json<McpServerUpdate>,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let (server_config, namespace_tools, order) = {
+            let mut config = state.config.write().await;
+            let namespace_tools = config
+                .mcp
+                .as_ref()
+                .is_some_and(|mcp_config| mcp_config.server.namespace_tools);
+            let server_config = config.mcp.as_mut().and_then(|mcp_config| {
+                mcp_config
+                    .server
+                    .tool_servers
+                    .iter_mut()
+                    .enumerate()
+                    .find(|(_, server)| server.name == name)
+            });
+            match server_config {
+                Some((order, server_config)) => {
+                    server_config.enable = update.enable;
+                    (server_config.clone(), namespace_tools, order)
+                }
+                None => {
+                    let err = ServerError::McpServerNotFound(name);
+                    dual_error!("{} - request_id: {}", err, request_id);
+                    return Err(err);
+                }
+            }
+        };
+
+        let action = match server_config.enable {
+            true => {
+                server_config
+                    .start_mcp_server(namespace_tools, order)
+                    .await?;
+                "Enabled"
+            }
+            false => {
+                mcp::stop_mcp_server(&server_config.name).await;
+                "Disabled"
+            }
+        };
+        dual_info!(
+            "{} mcp server: {} - request_id: {}",
+            action,
+            server_config.name,
+            request_id
+        );
+
+        mcp_server_status_response(&server_config, &request_id).await
+    }
+
+    /// List the tools of an mcp server again
+    pub(crate) async fn refresh_mcp_server_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Path(name): Path<String>,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let server_config = find_mcp_server(&state, name, &request_id).await?;
+        if !server_config.enable {
+            let err_msg = format!("The mcp server '{}' is disabled", server_config.name);
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::BadRequest(err_msg));
+        }
+
+        let namespace_tools = state
+            .config
+            .read()
+            .await
+            .mcp
+            .as_ref()
+            .is_some_and(|mcp_config| mcp_config.server.namespace_tools);
+        if let Err(e) = server_config.refresh_mcp_tools(namespace_tools).await {
+            // let the supervisor reconnect the server right away
+            mcp::check_mcp_server(&server_config.name).await;
+
+            dual_error!(
+                "Failed to refresh the tools of the mcp server '{}': {} - request_id: {}",
+                server_config.name,
+                e,
+                request_id
+            );
+            return Err(e);
+        }
+
+        mcp_server_status_response(&server_config, &request_id).await
+    }
+
+    /// Disconnect an mcp server and remove it
+    pub(crate) async fn remove_mcp_server_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Path(name): Path<String>,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let removed = {
+            let mut config = state.config.write().await;
+            config.mcp.as_mut().and_then(|mcp_config| {
+                let tool_servers = &mut mcp_config.server.tool_servers;
+                let idx = tool_servers.iter().position(|server| server.name == name)?;
+                Some(tool_servers.remove(idx))
+            })
+        };
+        let server_config = match removed {
+            Some(server_config) => server_config,
+            None => {
+                let err = ServerError::McpServerNotFound(name);
+                dual_error!("{} - request_id: {}", err, request_id);
+                return Err(err);
+            }
+        };
+
+        mcp::stop_mcp_server(&server_config.name).await;
+        dual_info!(
+            "Removed mcp server: {} - request_id: {}",
+            server_config.name,
+            request_id
+        );
+
+        let json_body = serde_json::json!({
+            "message": "MCP server removed successfully",
+            "name": server_config.name,
+        });
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg} - request_id: {request_id}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    async fn find_mcp_server(
+        state: &Arc<AppState>,
+        name: String,
+        request_id: &str,
+    ) -> ServerResult<McpToolServerConfig> {
+        let config = state.config.read().await;
+        let server_config = config.mcp.as_ref().and_then(|mcp_config| {
+            mcp_config
+                .server
+                .tool_servers
+                .iter()
+                .find(|server| server.name == name)
+                .cloned()
+        });
+        match server_config {
+            Some(server_config) => Ok(server_config),
+            None => {
+                let err = ServerError::McpServerNotFound(name);
+                dual_error!("{} - request_id: {}", err, request_id);
+                Err(err)
+            }
+        }
+    }
+
+    /// Create the response describing the mcp server, its connection state and its tools
+    async fn mcp_server_status_response(
+        server_config: &McpToolServerConfig,
+        request_id: &str,
+    ) -> ServerResult<axum::response::Response> {
+        let status = mcp::mcp_server_status(&server_config.name, server_config.transport).await;
+        let json_body = serde_json::to_string(&status).unwrap();
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg} - request_id: {request_id}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    pub(crate) async fn server_health_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Path(server_id): Path<String>,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let server = find_server(&state, server_id, &request_id).await?;
+
+        let history = server.stats().health_history();
+        let json_body = serde_json::json!({
+            "id": server.id,
+            "url": server.url,
+            "kind": server.kind,
+            "probe": server.health_probe(),
+            "probe_url": server.health_probe_url(),
+            "interval": server.health_interval().as_secs(),
+            "timeout": server.health_timeout().as_secs(),
+            "circuit_state": server.stats().circuit_state(),
+            "healthy": history.last().map(|record| record.healthy),
+            "history": history,
+        });
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg} - request_id: {request_id}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_update_persisted_server() {
+        let db_path =
+            std::env::temp_dir().join(format!("llama-nexus-test-{}.db", uuid::Uuid::new_v4()));
+        let state = AppState::new(
+            crate::config::Config::default(),
+            crate::info::ServerInfo::default(),
+            db_path.to_str().unwrap(),
+        )
+        .await
+        .unwrap();
+        let state = Arc::new(state);
+        let server: Server = serde_json::from_value(
+            serde_json::json!({"url": "http://localhost:8080/v1", "kind": "chat"}),
+        )
+        .unwrap();
+        state
+            .register_downstream_server(server.clone())
+            .await
+            .unwrap();
+        state
+            .database
+            .store_server(server.to_record())
+            .await
+            .unwrap();
+
+        async fn stored_weight(state: &AppState, server_id: &str) -> i64 {
+            let records = state.database.get_servers().await.unwrap();
+            let record = records.iter().find(|record| record.id == server_id);
+            record.unwrap().weight
+        }
+
+        // the update is applied and persisted
+        let update = serde_json::from_value(serde_json::json!({"weight": 3})).unwrap();
+        update_downstream_server_handler(
+            State(state.clone()),
+            HeaderMap::new(),
+            Path(server.id.clone()),
+            Json(update),
+        )
+        .await
+        .unwrap();
+        let updated = state.find_downstream_server(&server.id).await.unwrap();
+        assert_eq!(updated.weight, 3);
+        assert_eq!(stored_weight(&state, &server.id).await, 3);
+
+        // a rejected update is not persisted
+        let update = serde_json::from_value(serde_json::json!({"max_connections": 0})).unwrap();
+        let result = update_downstream_server_handler(
+            State(state.clone()),
+            HeaderMap::new(),
+            Path(server.id.clone()),
+            Json(update),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(stored_weight(&state, &server.id).await, 3);
+
+        // a server removed concurrently is not registered again
+        state
+            .unregister_downstream_server(&server.id)
+            .await
+            .unwrap();
+        let err = state.update_downstream_server(updated).await.unwrap_err();
+        assert!(matches!(err, ServerError::ServerNotFound(_)));
+        assert!(state.find_downstream_server(&server.id).await.is_none());
+
+        let _ = std::fs::remove_file(&db_path);
+    }
+
+    #[tokio::test]
+    async fn test_add_stdio_mcp_server() {
+        let state = AppState::new(
+            crate::config::Config::default(),
+            crate::info::ServerInfo::default(),
+            "sqlite::memory:",
+        )
+        .await
+        .unwrap();
+        let state = Arc::new(state);
+
+        let server_config: McpToolServerConfig = serde_json::from_value(serde_json::json!({
+            "name": "shell",
+            "transport": "stdio",
+            "command": "true",
+            "enable": true
+        }))
+        .unwrap();
+        let err =
+            add_mcp_server_handler(State(state.clone()), HeaderMap::new(), Json(server_config))
+                .await
+                .unwrap_err();
+        assert!(matches!(err, ServerError::BadRequest(_)));
+        assert!(
+            state
+                .config
+                .read()
+                .await
+                .mcp
+                .as_ref()
+                .is_none_or(|mcp_config| mcp_config.server.tool_servers.is_empty())
+        );
+
+        // the rejected server is reported as an unknown mcp server
+        let err = get_mcp_server_handler(State(state), HeaderMap::new(), Path("shell".to_string()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServerError::McpServerNotFound(_)));
+        assert_eq!(err.to_string(), "MCP server `shell` not found");
+    }
+}
+
+// Generate a unique chat id for the chat completion request
+fn gen_chat_id() -> String {
+    format!("chatcmpl-{}", uuid::Uuid::new_v4())
+}
+
+/// Select a downstream server of the given kind for the request
+///
+/// If `model` is provided, only the servers whose model list contains it are eligible, and
+/// `ServerError::ModelNotFound` is returned if there is none. If `model` is omitted, any
+/// server of the given kind may be selected. The servers in `excluded` are never selected.
+/// `routing_key` keeps the requests of a session on the same server under the consistent-hash
+/// policy.
+async fn get_target_server(
+    state: &Arc<AppState>,
+    kind: ServerKind,
+    model: Option<&str>,
+    routing_key: Option<&str>,
+    excluded: &[ServerId],
+    request_id: &str,
+) -> ServerResult<TargetServerInfo> {
+    let servers = state.server_group.read().await;
+    let group = match servers.get(&kind) {
+        Some(servers) => servers,
+        None => {
+            let err_msg = format!("No {kind} server available");
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::Operation(err_msg));
+        }
+    };
+
+    // restrict the selection to the servers serving the requested model
+    let ctx = match model.map(str::trim).filter(|model| !model.is_empty()) {
+        Some(model) => {
+            let candidates = {
+                let group_server_ids = group.healthy_servers.read().await;
+                state
+                    .servers_for_model(model)
+                    .await
+                    .into_iter()
+                    .filter(|server_id| group_server_ids.contains(server_id))
+                    .collect::<HashSet<_>>()
+            };
+
+            if candidates.is_empty() {
+                let err = ServerError::ModelNotFound(model.to_string());
+                dual_error!("{} - request_id: {}", err, request_id);
+                return Err(err);
+            }
+
+            dual_debug!(
+                "Candidate {} servers for model {}: {:?} - request_id: {}",
+                kind,
+                model,
+                candidates,
+                request_id
+            );
+
+            RoutingContext::with_candidates(candidates)
+        }
+        None => RoutingContext::default(),
+    }
+    .excluding(excluded.iter().cloned())
+    .with_routing_key(routing_key);
+
+    group.next(&ctx).await.inspect_err(|e| {
+        dual_error!(
+            "Failed to get the {} server: {} - request_id: {}",
+            kind,
+            e,
+            request_id
+        )
+    })
+}
+
+/// Get the session key of the request for the consistent-hash routing policy
+///
+/// The value of the header configured by `routing.routing_key_header` takes precedence over
+/// `fallback`, which is a field of the request body such as `user` or `prompt_cache_key`.
+async fn routing_key(
+    state: &Arc<AppState>,
+    headers: &HeaderMap,
+    fallback: Option<&str>,
+) -> Option<String> {
+    let header_value = match state
+        .config
+        .read()
+        .await
+        .routing
+        .routing_key_header
+        .as_deref()
+    {
+        Some(header) => headers
+            .get(header)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string()),
+        None => None,
+    };
+
+    header_value
+        .or_else(|| fallback.map(|v| v.trim().to_string()))
+        .filter(|key| !key.is_empty())
+}
+
+/// Extract the `model` field from a JSON or multipart/form-data request body
+async fn extract_model(headers: &HeaderMap, body: &Bytes) -> Option<String> {
+    let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok())?;
+
+    if content_type.starts_with("multipart/form-data") {
+        let request = axum::extract::Request::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body.clone()))
+            .ok()?;
+        let mut multipart = Multipart::from_request(request, &()).await.ok()?;
+        while let Ok(Some(field)) = multipart.next_field().await {
+            if field.name() == Some("model") {
+                return field.text().await.ok();
+            }
+        }
+
+        None
+    } else {
+        serde_json::from_slice::<serde_json::Value>(body)
+            .ok()?
+            .get("model")
+            .and_then(|model| model.as_str())
+            .map(|model| model.to_string())
+    }
+}
+
+/// Downstream response obtained by `send_with_failover`
+struct FailoverResponse {
+    /// The server that produced the response. The connection to the server is counted by the
+    /// routing policies until it is dropped.
+    server: TargetServerInfo,
+    response: reqwest::Response,
+    /// Ids of the servers tried for the request, in order
+    attempted: Vec<ServerId>,
+}
+
+/// Forward a request to a downstream server of the given kind with failover
+///
+/// This function implements the following features:
+/// 1. Select an eligible server of the given kind, excluding the servers already tried
+/// 2. Build the request for the selected server with `build_request` and send it
+/// 3. Fail over to the next eligible server on connect errors, timeouts and the status codes
+///    listed in `failover.retryable_status_codes`, up to `failover.max_attempts` servers. A server
+///    whose response headers do not arrive within `failover.timeout` seconds is timed out.
+///
+/// # Returns
+/// * `Ok(response)` - The first non-retryable response, or the last retryable response if no
+///   server is left to fail over to
+/// * `Err(ServerError::DownstreamUnavailable)` - None of the attempted servers responded
+/// * `Err(ServerError)` - No eligible server, non-retryable error or cancellation
+async fn send_with_failover(
+    state: &Arc<AppState>,
+    kind: ServerKind,
+    model: Option<&str>,
+    routing_key: Option<&str>,
+    request_id: &str,
+    cancel_token: &CancellationToken,
+    mut build_request: impl FnMut(&TargetServerInfo) -> reqwest::RequestBuilder,
+) -> ServerResult<FailoverResponse> {
+    let (failover, circuit_breaker) = {
+        let config = state.config.read().await;
+        (config.failover.clone(), config.circuit_breaker.clone())
+    };
+    let max_attempts = failover.max_attempts.max(1);
+    let response_timeout = Duration::from_secs(failover.timeout);
+
+    let mut attempted: Vec<ServerId> = Vec::new();
+    let mut last_response = None;
+    let mut last_error = String::new();
+    while attempted.len() < max_attempts {
+        let server = match get_target_server(
+            state,
+            kind,
+            model,
+            routing_key,
+            &attempted,
+            request_id,
+        )
+        .await
+        {
+            Ok(server) => server,
+            // no server left to fail over to
+            Err(_) if !attempted.is_empty() => break,
+            Err(e) => return Err(e),
+        };
+        attempted.push(server.id.clone());
+
+        // Use select! to handle request cancellation
+        let result = select! {
+            result = time::timeout(response_timeout, build_request(&server).send()) => result,
+            _ = cancel_token.cancelled() => {
+                let warn_msg = "Request was cancelled by client";
+                dual_warn!("{} - request_id: {}", warn_msg, request_id);
+                return Err(ServerError::Operation(warn_msg.to_string()));
+            }
+        };
+
+        // a server not responding within the timeout is treated as a failed server
+        let Ok(result) = result else {
+            dual_warn!(
+                "The {} server {} did not respond within {} seconds - request_id: {}",
+                kind,
+                server.id,
+                failover.timeout,
+                request_id
+            );
+            record_server_failure(&server, kind, &circuit_breaker, request_id);
+            last_error = format!("no response within {} seconds", failover.timeout);
+            continue;
+        };
+
+        match result {
+            Ok(response) if failover.is_retryable_status(response.status().as_u16()) => {
+                dual_warn!(
+                    "The {} server {} responded with {} - request_id: {}",
+                    kind,
+                    server.id,
+                    response.status(),
+                    request_id
+                );
+                record_server_failure(&server, kind, &circuit_breaker, request_id);
+                last_response = Some((server, response));
+            }
+            Ok(response) => {
+                record_server_success(&server, kind, request_id);
+
+                if attempted.len() > 1 {
+                    dual_info!(
+                        "Failed over to the {} server {}. Attempted servers: {} - request_id: {}",
+                        kind,
+                        server.id,
+                        attempted.join(", "),
+                        request_id
+                    );
+                }
+
+                return Ok(FailoverResponse {
+                    server,
+                    response,
+                    attempted,
+                });
+            }
+            Err(e) if e.is_connect() || e.is_timeout() => {
+                dual_warn!(
+                    "Failed to forward the request to the {} server {}: {} - request_id: {}",
+                    kind,
+                    server.id,
+                    e,
+                    request_id
+                );
+                record_server_failure(&server, kind, &circuit_breaker, request_id);
+                last_error = e.to_string();
+            }
+            Err(e) => {
+                let err_msg =
+                    format!("Failed to forward the request to the downstream server: {e}");
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                return Err(ServerError::Operation(err_msg));
+            }
+        }
+    }
+
+    dual_error!(
+        "All the attempted {} servers failed. Attempted servers: {} - request_id: {}",
+        kind,
+        attempted.join(", "),
+        request_id
+    );
+
+    match last_response {
+        Some((server, response)) => Ok(FailoverResponse {
+            server,
+            response,
+            attempted,
+        }),
+        None => Err(ServerError::DownstreamUnavailable(last_error, attempted)),
+    }
+}
+
+/// Record a failed request in the circuit breaker of the server
+fn record_server_failure(
+    server: &TargetServerInfo,
+    kind: ServerKind,
+    config: &CircuitBreakerConfig,
+    request_id: &str,
+) {
+    if server.stats().record_failure(config) {
+        dual_warn!(
+            "The circuit of the {} server {} is open for {} seconds - request_id: {}",
+            kind,
+            server.id,
+            config.open_duration,
+            request_id
+        );
+    }
+}
+
+/// Record a successful request in the circuit breaker of the server
+fn record_server_success(server: &TargetServerInfo, kind: ServerKind, request_id: &str) {
+    if server.stats().record_success() {
+        dual_info!(
+            "The circuit of the {} server {} is closed - request_id: {}",
+            kind,
+            server.id,
+            request_id
+        );
+    }
+}
+
+/// Record the ids of the attempted downstream servers in the response headers
+fn set_attempted_servers_header(response: &mut axum::response::Response, attempted: &[ServerId]) {
+    if let Ok(value) = HeaderValue::from_str(&attempted.join(",")) {
+        response
+            .headers_mut()
+            .insert(ATTEMPTED_SERVERS_HEADER, value);
+    }
+}
+
+#[tokio::test]
+async fn test_send_with_failover() {
+    use crate::server::CircuitState;
+
+    // a mock downstream server responding to every request with the given status
+    async fn mock_server(status: StatusCode) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = axum::Router::new().fallback(move || async move { status });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    // the servers are tried in the order of their priority tiers
+    async fn new_state(urls: &[String]) -> (Arc<AppState>, Vec<Server>) {
+        let mut config = crate::config::Config::default();
+        config.circuit_breaker.failure_threshold = 1;
+        config.failover.timeout = 1;
+        let state = AppState::new(
+            config,
+            crate::info::ServerInfo::default(),
+            "sqlite::memory:",
+        )
+        .await
+        .unwrap();
+        let mut servers = Vec::new();
+        for (priority, url) in urls.iter().enumerate() {
+            let server: Server = serde_json::from_value(
+                serde_json::json!({"url": url, "kind": "chat", "priority": priority}),
+            )
+            .unwrap();
+            state
+                .register_downstream_server(server.clone())
+                .await
+                .unwrap();
+            servers.push(server);
+        }
+        (Arc::new(state), servers)
+    }
+
+    async fn send(state: &Arc<AppState>) -> ServerResult<FailoverResponse> {
+        send_with_failover(
+            state,
+            ServerKind::chat,
+            None,
+            None,
+            "test",
+            &CancellationToken::new(),
+            |server| {
+                state
+                    .http_client
+                    .post(format!("{}/chat/completions", server.url))
+            },
+        )
+        .await
+    }
+
+    let closed_url = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+    let unavailable_url = mock_server(StatusCode::SERVICE_UNAVAILABLE).await;
+    let bad_request_url = mock_server(StatusCode::BAD_REQUEST).await;
+    // accepts the connections but never responds
+    let hung_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hung_url = format!("http://{}", hung_listener.local_addr().unwrap());
+
+    // fail over on the closed port and the retryable status, up to the non-retryable status
+    let (state, servers) = new_state(&[
+        closed_url.clone(),
+        unavailable_url.clone(),
+        bad_request_url.clone(),
+    ])
+    .await;
+    let response = send(&state).await.unwrap();
+    assert_eq!(response.response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.server.id, servers[2].id);
+    let ids = servers.iter().map(|s| s.id.clone()).collect::<Vec<_>>();
+    assert_eq!(response.attempted, ids);
+    assert_eq!(servers[0].stats().circuit_state(), CircuitState::Open);
+    assert_eq!(servers[1].stats().circuit_state(), CircuitState::Open);
+    assert_eq!(servers[2].stats().circuit_state(), CircuitState::Closed);
+
+    // fail over on a server not responding within the timeout
+    let (state, servers) = new_state(&[hung_url, bad_request_url.clone()]).await;
+    let response = send(&state).await.unwrap();
+    assert_eq!(response.response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        response.attempted,
+        [servers[0].id.clone(), servers[1].id.clone()]
+    );
+    assert_eq!(servers[0].stats().circuit_state(), CircuitState::Open);
+
+    // the last retryable response is returned if no server is left to fail over to
+    let (state, servers) = new_state(&[closed_url.clone(), unavailable_url]).await;
+    let response = send(&state).await.unwrap();
+    assert_eq!(response.response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        response.attempted,
+        [servers[0].id.clone(), servers[1].id.clone()]
+    );
+
+    // none of the attempted servers responded
+    let (state, servers) = new_state(&[closed_url]).await;
+    match send(&state).await {
+        Err(ServerError::DownstreamUnavailable(_, attempted)) => {
+            assert_eq!(attempted, [servers[0].id.clone()])
+        }
+        _ => panic!("expected DownstreamUnavailable"),
+    }
+    assert_eq!(servers[0].stats().circuit_state(), CircuitState::Open);
+
+    // a request failing the same way on every server is not retried
+    let (state, servers) = new_state(&[bad_request_url.clone(), bad_request_url]).await;
+    let result = send_with_failover(
+        &state,
+        ServerKind::chat,
+        None,
+        None,
+        "test",
+        &CancellationToken::new(),
+        |server| {
+            let body = futures_util::stream::once(async {
+                Err::<Bytes, _>(std::io::Error::other("broken body"))
+            });
+            state
+                .http_client
+                .post(format!("{}/chat/completions", server.url))
+                .body(reqwest::Body::wrap_stream(body))
+        },
+    )
+    .await;
+    assert!(matches!(result, Err(ServerError::Operation(_))));
+    assert_eq!(servers[0].stats().circuit_state(), CircuitState::Closed);
+    assert_eq!(servers[1].stats().circuit_state(), CircuitState::Closed);
+    drop(hung_listener);
+}
+
+/// Send chat request to downstream server with intelligent retry mechanism
+///
+/// This function implements the following features:
+/// 1. First attempt to send request to a downstream chat server, with failover
+/// 2. If tool call deserialization error occurs, intelligently retry:
+///    - Check if request contains tool definitions
+///    - Check if current tool choice is non-None state
+///    - If conditions are met, reset tool choice to None and retry
+/// 3. This retry mechanism solves cases where some downstream servers don't support tool calls
+///
+/// # Arguments
+///
+/// * `state` - Application state, used to select the downstream chat server
+/// * `request` - Chat completion request, may be modified (e.g., reset tool choice)
+/// * `headers` - HTTP request headers, including authentication info
+/// * `routing_key` - Session key of the request for the consistent-hash routing policy
+/// * `request_id` - Request ID for log tracking
+/// * `cancel_token` - Cancellation token for request cancellation support
+///
+/// # Returns
+/// * `Ok(response)` - Successfully obtained downstream server response
+/// * `Err(ServerError)` - Request failed or still failed after retry
+///
+/// # Error Handling Strategy
+/// * Tool call deserialization error: Try disabling tool choice and retry
+/// * Other errors: Return error directly, no retry
+/// * Retry logic: Maximum one retry to avoid infinite loops
+async fn send_request_with_retry(
+    state: &Arc<AppState>,
+    request: &mut ChatCompletionRequest,
+    headers: &HeaderMap,
+    routing_key: Option<&str>,
+    request_id: &str,
+    cancel_token: CancellationToken,
+) -> ServerResult<FailoverResponse> {
+    // First attempt to send request to downstream server
+    let response = send_with_failover(
+        state,
+        ServerKind::chat,
+        request.model.as_deref(),
+        routing_key,
+        request_id,
+        &cancel_token,
+        |chat_server| {
+            build_chat_request(
+                &state.http_client,
+                chat_server,
+                request,
+                headers,
+                request_id,
+            )
+        },
+    )
+    .await;
+
+    match response {
+        // If first request succeeds, return response directly
+        Ok(response) => Ok(response),
+        Err(e) => {
+            let err_str = e.to_string();
+
+            // Check if it's a tool call deserialization error
+            // This error usually occurs when downstream server doesn't support tool calls
+            if err_str.contains("Failed to deserialize generated tool calls") {
+                // Verify if retry is possible:
+                // 1. Request must contain tool definitions
+                // 2. Tool definitions cannot be empty
+                if let Some(tools) = &request.tools
+                    && !tools.is_empty()
+                {
+                    // Check if current tool choice is non-None state
+                    // Only non-None state needs to be reset to None for retry
+                    if let Some(tool_choice) = &request.tool_choice
+                        && *tool_choice != ToolChoice::None
+                    {
+                        // Reset tool choice to None, disable tool call functionality
+                        request.tool_choice = None;
+                        dual_info!(
+                            "Retrying request without tool choice - request_id: {}",
+                            request_id
+                        );
+
+                        // Re-send with reset request
+                        let response = send_with_failover(
+                            state,
+                            ServerKind::chat,
+                            request.model.as_deref(),
+                            routing_key,
+                            request_id,
+                            &cancel_token,
+                            |chat_server| {
+                                build_chat_request(
+                                    &state.http_client,
+                                    chat_server,
+                                    request,
+                                    headers,
+                                    request_id,
+                                )
+                            },
+                        )
+                        .await
+                        .inspect_err(|e| {
+                            dual_error!(
+                                "Failed to send request: {} - request_id: {}",
+                                e,
+                                request_id
+                            )
+                        })?;
+
+                        return Ok(response);
+                    }
+                }
+            }
+
+            // Non-tool call related error, return directly, no retry
+            dual_error!("Failed to send request: {} - request_id: {}", e, request_id);
+            Err(e)
+        }
+    }
+}
+
+/// Build HTTP request to downstream chat server
+///
+/// This function implements the following features:
+/// 1. Build HTTP client and set necessary request headers
+/// 2. Attach JSON-formatted chat completion request as request body
+///
+/// # Arguments
+///
+/// * `client` - HTTP client of the downstream requests
+/// * `chat_server` - The downstream chat server to send request to
+/// * `request` - Chat completion request object
+/// * `headers` - HTTP request headers, including authentication info
+/// * `request_id` - Request ID for log tracking
+fn build_chat_request(
+    client: &reqwest::Client,
+    chat_server: &TargetServerInfo,
+    request: &ChatCompletionRequest,
+    headers: &HeaderMap,
+    request_id: &str,
+) -> reqwest::RequestBuilder {
+    let url = format!("{}/chat/completions", chat_server.url.trim_end_matches('/'));
+    let mut client = client.post(&url);
+
+    // Add common headers
+    client = client.header(CONTENT_TYPE, "application/json");
+
+    // Add authorization header
+    if let Some(api_key) = &chat_server.api_key
+        && !api_key.is_empty()
+    {
+        client = client.header(AUTHORIZATION, api_key);
+    } else if let Some(auth) = headers.get("authorization")
+        && let Ok(auth_str) = auth.to_str()
+    {
+        client = client.header(AUTHORIZATION, auth_str);
+    }
+
+    dual_info!(
+        "Request to downstream chat server {} - request_id: {}\n{}",
+        chat_server.id,
+        request_id,
+        serde_json::to_string_pretty(request).unwrap()
+    );
+
+    client.json(request)
+}
+
+/// Handle streaming chat responses, supporting tool calls and normal streaming responses
+///
+/// Choose processing path based on tool call identifier in response headers:
+/// - Tool call needed: Extract tool call information from stream and call MCP server
+/// - Normal streaming response: Directly process streaming data and return
+///
+/// # Arguments
+///
+/// * `state` - Application state, used to send the follow-up requests to the chat server
+/// * `response` - HTTP response from downstream server
+/// * `request` - Chat request, extended with the tool call results if a tool is called
+/// * `agent` - Agent loop executing the tools requested by the model
+/// * `headers` - HTTP request headers
+/// * `chat_server` - Chat server information
+/// * `request_id` - Request ID
+/// * `cancel_token` - Cancellation token
+#[allow(clippy::too_many_arguments)]
+async fn handle_stream_response(
+    state: &Arc<AppState>,
+    response: reqwest::Response,
+    request: ChatCompletionRequest,
+    agent: AgentLoop,
+    headers: &HeaderMap,
+    chat_server: &TargetServerInfo,
+    request_id: &str,
+    cancel_token: CancellationToken,
+) -> ServerResult<axum::response::Response> {
+    let status = response.status();
+
+    // check the status code
+    match status {
+        StatusCode::OK => {
+            let response_headers = response.headers().clone();
+
+            // Check if the response requires tool call
+            let requires_tool_call = parse_requires_tool_call_header(&response_headers);
+
+            if requires_tool_call {
+                // Handle tool call in stream mode
+                handle_tool_call_stream(
+                    state,
+                    response,
+                    request,
+                    agent,
+                    headers,
+                    chat_server,
+                    request_id,
+                    cancel_token,
+                )
+                .await
+            } else {
+                // Handle normal response in stream mode
+                handle_normal_stream(
+                    response.bytes_stream().boxed(),
+                    status,
+                    response_headers,
+                    chat_server,
+                    request_id,
+                    cancel_token,
+                )
+                .await
+            }
+        }
+        _ => {
+            let err = response.error_for_status().unwrap_err();
+            let err_msg = format!("{err}");
+
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+
+            Err(ServerError::Operation(err_msg))
+        }
+    }
+}
+
+/// Handle non-streaming chat responses, supporting both tool calls and normal responses
+///
+/// This function implements the following features:
+/// 1. Read complete response data from downstream server (with cancellation support)
+/// 2. Parse tool call identifier from response headers
+/// 3. Choose different processing paths based on tool call requirements:
+///    - Tool call needed: Parse response and call MCP server
+///    - Normal response: Directly build and return response
+/// 4. Provide complete error handling and cancellation support
+///
+/// # Arguments
+///
+/// * `state` - Application state, used to send the follow-up requests to the chat server
+/// * `response` - HTTP response object from downstream server
+/// * `request` - Chat completion request, may be modified (e.g., add tool call results)
+/// * `agent` - Agent loop executing the tools requested by the model
+/// * `headers` - HTTP request headers for subsequent requests
+/// * `chat_service_url` - Chat service URL for re-requesting after tool calls
+/// * `request_id` - Request ID for log tracking and error handling
+/// * `cancel_token` - Cancellation token for request cancellation support
+///
+/// # Returns
+/// * `Ok(response)` - Successfully built HTTP response
+/// * `Err(ServerError)` - Error occurred during processing or was cancelled
+///
+/// # Processing Flow
+/// 1. Extract response status code and header information
+/// 2. Read response body data (with cancellation support)
+/// 3. Check if tool call is required
+/// 4. Choose processing path based on tool call requirements:
+///    - Tool call path: Parse response → Call MCP → Re-request → Return result
+///    - Normal path: Directly build response and return
+///
+/// # Error Handling
+/// * Response reading error: Return detailed error information
+/// * Cancellation operation: Log warning and return cancellation error
+/// * Tool call error: Decide whether to continue based on error type
+/// * Response building error: Return build failure error
+#[allow(clippy::too_many_arguments)]
+async fn handle_non_stream_response(
+    state: &Arc<AppState>,
+    response: reqwest::Response,
+    request: &mut ChatCompletionRequest,
+    agent: AgentLoop,
+    headers: &HeaderMap,
+    chat_server: &TargetServerInfo,
+    request_id: &str,
+    cancel_token: CancellationToken,
+) -> ServerResult<axum::response::Response> {
+    let status = response.status();
+
+    // check the status code
+    match status {
+        StatusCode::OK => {
+            let response_headers = response.headers().clone();
+
+            // Read the response body
+            let bytes = read_response_bytes(response, request_id, cancel_token.clone()).await?;
+            let chat_completion = parse_chat_completion(&bytes, request_id)?;
+
+            // Check if the response requires tool call
+            let tool_calls = &chat_completion.choices[0].message.tool_calls;
+            let (mcp_tool_calls, client_tool_calls) =
+                agent.partition_tool_calls(tool_calls.clone()).await;
+
+            if client_tool_calls.is_empty() && !mcp_tool_calls.is_empty() {
+                call_mcp_server(
+                    state,
+                    mcp_tool_calls,
+                    agent,
+                    request,
+                    headers,
+                    chat_server,
+                    request_id,
+                    cancel_token,
+                )
+                .await
+            } else {
+                // Handle normal response, or return the tool calls of the client-defined functions
+                let mut response_headers = response_headers;
+                let bytes = remove_mcp_tool_calls(
+                    bytes,
+                    &mcp_tool_calls,
+                    &mut response_headers,
+                    request_id,
+                );
+                build_response(status, response_headers, bytes, request_id)
+            }
+        }
+        _ => {
+            let err = response.error_for_status().unwrap_err();
+            let err_msg = format!("{err}");
+
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+
+            Err(ServerError::Operation(err_msg))
+        }
+    }
+}
+
+/// Handle tool calls in streaming responses
+///
+/// Parse tool call information from streaming response, and return a stream response in which
+/// the gateway announces the tool call and its status while executing the tool, and then
+/// forwards the answer of the model generated from the tool result. If the model calls a
+/// function defined by the client, the stream is forwarded to the client instead.
+///
+/// # Arguments
+///
+/// * `state` - Application state, used to send the follow-up requests to the chat server
+/// * `response` - HTTP response from downstream server
+/// * `request` - Chat request, will be extended with the tool call results
+/// * `agent` - Agent loop executing the tools requested by the model
+/// * `headers` - HTTP request headers
+/// * `chat_server` - Chat server information
+/// * `request_id` - Request ID
+/// * `cancel_token` - Cancellation token
+#[allow(clippy::too_many_arguments)]
+async fn handle_tool_call_stream(
+    state: &Arc<AppState>,
+    response: reqwest::Response,
+    request: ChatCompletionRequest,
+    agent: AgentLoop,
+    headers: &HeaderMap,
+    chat_server: &TargetServerInfo,
+    request_id: &str,
+    cancel_token: CancellationToken,
+) -> ServerResult<axum::response::Response> {
     let status = response.status();
+    let response_headers = response.headers().clone();
+    let StreamToolCalls {
+        tool_calls,
+        consumed,
+        rest,
+    } = extract_tool_calls_from_stream(response, request_id).await?;
+    if tool_calls.is_empty() {
+        let err_msg = "No tool call found in the stream of the chat server";
+        dual_error!("{} - request_id: {}", err_msg, request_id);
+        return Err(ServerError::Operation(err_msg.to_string()));
+    }
+
+    // the tool calls of the client-defined functions are returned to the client
+    let (mcp_tool_calls, client_tool_calls) = agent.partition_tool_calls(tool_calls).await;
+    if !client_tool_calls.is_empty() {
+        let chunks = replay_tool_call_stream(consumed, rest, &mcp_tool_calls, request_id);
+        return handle_normal_stream(
+            chunks,
+            status,
+            response_headers,
+            chat_server,
+            request_id,
+            cancel_token,
+        )
+        .await;
+    }
+
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    tokio::spawn(stream_tool_calls(
+        Arc::clone(state),
+        tx,
+        mcp_tool_calls,
+        agent,
+        request,
+        headers.clone(),
+        chat_server.clone(),
+        request_id.to_string(),
+        cancel_token,
+    ));
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (event, rx))
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header("cache-control", "no-cache")
+        .body(Body::from_stream(stream))
+        .map_err(|e| {
+            let err_msg = format!("Failed to create the response: {e}");
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            ServerError::Operation(err_msg)
+        })
+}
+
+/// Parse tool call identifier from HTTP response headers
+///
+/// Check if the "requires-tool-call" field exists in response headers and parse it as boolean.
+/// Returns false if the field doesn't exist or parsing fails.
+fn parse_requires_tool_call_header(headers: &HeaderMap) -> bool {
+    headers
+        .get("requires-tool-call")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false)
+}
+
+/// Forward the SSE chunks of the downstream chat server to the client as they arrive
+///
+/// The chunks are passed through unchanged, so the `data:` framing and the final `data: [DONE]`
+/// are preserved. The stream holds the connection to the chat server, so the connection slot is
+/// released once the stream ends, fails, is cancelled or is dropped by a disconnected client.
+async fn handle_normal_stream(
+    chunks: BoxStream<'static, reqwest::Result<Bytes>>,
+    status: StatusCode,
+    response_headers: HeaderMap,
+    chat_server: &TargetServerInfo,
+    request_id: &str,
+    cancel_token: CancellationToken,
+) -> ServerResult<axum::response::Response> {
+    let forward = SseForward {
+        chunks,
+        chat_server: chat_server.clone(),
+        request_id: request_id.to_string(),
+        cancel_token,
+        finished: false,
+    };
+    let stream = futures_util::stream::unfold(forward, |mut forward| async move {
+        // stop polling the downstream stream once it has failed
+        if forward.finished {
+            return None;
+        }
+
+        let chunk = select! {
+            chunk = forward.chunks.next() => chunk,
+            _ = forward.cancel_token.cancelled() => {
+                dual_warn!(
+                    "Request was cancelled while streaming response - request_id: {}",
+                    forward.request_id
+                );
+                forward.finished = true;
+                return None;
+            }
+        };
+
+        match chunk {
+            Some(Ok(bytes)) => Some((Ok(bytes), forward)),
+            Some(Err(e)) => {
+                let err_msg = format!(
+                    "Failed to read the stream from the chat server {}: {e}",
+                    forward.chat_server.id
+                );
+                dual_error!("{} - request_id: {}", err_msg, forward.request_id);
+                forward.finished = true;
+                Some((Err(std::io::Error::other(err_msg)), forward))
+            }
+            None => {
+                dual_info!(
+                    "Chat request completed successfully - request_id: {}",
+                    forward.request_id
+                );
+                forward.finished = true;
+                None
+            }
+        }
+    });
+
+    // build the response builder
+    let response_builder = Response::builder().status(status);
+
+    // copy the response headers
+    let response_builder = copy_response_headers(response_builder, &response_headers);
+
+    match response_builder.body(Body::from_stream(stream)) {
+        Ok(response) => {
+            dual_info!("Streaming the chat response - request_id: {}", request_id);
+            Ok(response)
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to create the response: {e}");
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            Err(ServerError::Operation(err_msg))
+        }
+    }
+}
+
+/// State of an SSE stream forwarded from a downstream chat server
+struct SseForward {
+    chunks: BoxStream<'static, reqwest::Result<Bytes>>,
+    /// Keeps the connection to the chat server counted until the stream is dropped
+    chat_server: TargetServerInfo,
+    request_id: String,
+    cancel_token: CancellationToken,
+    finished: bool,
+}
+impl Drop for SseForward {
+    fn drop(&mut self) {
+        if !self.finished {
+            dual_warn!(
+                "The client disconnected before the stream from the chat server {} ended - request_id: {}",
+                self.chat_server.id,
+                self.request_id
+            );
+        }
+    }
+}
+
+/// Read HTTP response body data with cancellation support
+///
+/// This function uses select! macro to simultaneously monitor response reading and cancellation signals.
+/// When the request is cancelled, it immediately returns an error to avoid resource waste.
+async fn read_response_bytes(
+    response: reqwest::Response,
+    request_id: &str,
+    cancel_token: CancellationToken,
+) -> ServerResult<Bytes> {
+    select! {
+        bytes = response.bytes() => {
+            bytes.map_err(|e| {
+                let err_msg = format!("Failed to get the full response as bytes: {e}");
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                ServerError::Operation(err_msg)
+            })
+        }
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled while reading response";
+            dual_warn!("{} - request_id: {}", warn_msg, request_id);
+            Err(ServerError::Operation(warn_msg.to_string()))
+        }
+    }
+}
+
+/// Build HTTP response object
+///
+/// Build complete HTTP response based on status code, response headers and response body data.
+/// Copy all response headers to the new response and log success message.
+///
+/// # Arguments
+///
+/// * `status` - HTTP status code
+/// * `response_headers` - Response headers
+/// * `bytes` - Response body data
+/// * `request_id` - Request ID for logging
+fn build_response(
+    status: StatusCode,
+    response_headers: HeaderMap,
+    bytes: Bytes,
+    request_id: &str,
+) -> ServerResult<axum::response::Response> {
+    // build the response builder
+    let mut response_builder = Response::builder().status(status);
+
+    // copy the response headers
+    response_builder = copy_response_headers(response_builder, &response_headers);
+
+    match response_builder.body(Body::from(bytes)) {
+        Ok(response) => {
+            dual_info!(
+                "Chat request completed successfully - request_id: {}",
+                request_id
+            );
+
+            Ok(response)
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to create the response: {e}");
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            Err(ServerError::Operation(err_msg))
+        }
+    }
+}
+
+/// Tool calls read from the beginning of a stream response
+struct StreamToolCalls {
+    tool_calls: Vec<ToolCall>,
+    /// Chunks read from the stream up to the tool calls
+    consumed: Vec<Bytes>,
+    /// Rest of the stream
+    rest: BoxStream<'static, reqwest::Result<Bytes>>,
+}
+
+/// Extract tool call information from streaming response
+///
+/// Parse streaming response data and extract tool call information.
+/// Process SSE format data stream, parse ChatCompletionChunk and extract tool_calls. The stream
+/// is read up to the chunk carrying the tool calls, so that it can still be forwarded to the
+/// client.
+async fn extract_tool_calls_from_stream(
+    response: reqwest::Response,
+    request_id: &str,
+) -> ServerResult<StreamToolCalls> {
+    let mut ds_stream = response.bytes_stream().boxed();
+    let mut tool_calls: Vec<ToolCall> = Vec::new();
+    let mut consumed = Vec::new();
+
+    while let Some(item) = ds_stream.next().await {
+        match item {
+            Ok(bytes) => {
+                let found = match String::from_utf8(bytes.to_vec()) {
+                    Ok(s) => {
+                        let x = s
+                            .trim_start_matches("data:")
+                            .trim()
+                            .split("data:")
+                            .collect::<Vec<_>>();
+                        let s = x[0];
+
+                        dual_debug!("s: {}", s);
+
+                        // convert the bytes to ChatCompletionChunk
+                        match serde_json::from_str::<ChatCompletionChunk>(s) {
+                            Ok(chunk) if !chunk.choices.is_empty() => {
+                                dual_debug!("chunk: {:?} - request_id: {}", &chunk, request_id);
+
+                                for tool in chunk.choices[0].delta.tool_calls.iter() {
+                                    let tool_call = tool.clone().into();
+
+                                    dual_debug!("tool_call: {:?}", &tool_call);
+
+                                    tool_calls.push(tool_call);
+                                }
+
+                                true
+                            }
+                            _ => false,
+                        }
+                    }
+                    Err(e) => {
+                        let err_msg = format!(
+                            "Failed to convert bytes from downstream server into string: {e}"
+                        );
+                        dual_error!("{} - request_id: {}", err_msg, request_id);
+                        return Err(ServerError::Operation(err_msg));
+                    }
+                };
+
+                consumed.push(bytes);
+                if found {
+                    break;
+                }
+            }
+            Err(e) => {
+                let err_msg = format!("Failed to get the full response as bytes: {e}");
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                return Err(ServerError::Operation(err_msg));
+            }
+        }
+    }
+
+    Ok(StreamToolCalls {
+        tool_calls,
+        consumed,
+        rest: ds_stream,
+    })
+}
+
+/// Rebuild the stream of a response whose tool calls are returned to the client
+///
+/// The chunks consumed to find the tool calls are replayed before the rest of the stream. The
+/// calls of the MCP tools requested along with the client-defined functions are removed, since
+/// the client cannot execute them.
+fn replay_tool_call_stream(
+    consumed: Vec<Bytes>,
+    rest: BoxStream<'static, reqwest::Result<Bytes>>,
+    mcp_tool_calls: &[ToolCall],
+    request_id: &str,
+) -> BoxStream<'static, reqwest::Result<Bytes>> {
+    let consumed = if mcp_tool_calls.is_empty() {
+        consumed
+    } else {
+        dual_warn!(
+            "Dropping {} MCP tool call(s) requested along with the client-defined functions - request_id: {}",
+            mcp_tool_calls.len(),
+            request_id
+        );
+
+        let names = tool_names(mcp_tool_calls);
+        consumed
+            .iter()
+            .map(|bytes| remove_tool_calls_from_chunks(bytes, &names))
+            .collect()
+    };
+
+    futures_util::stream::iter(consumed.into_iter().map(Ok))
+        .chain(rest)
+        .boxed()
+}
+
+/// Remove the calls of the given tools from the SSE chunks in `bytes`
+fn remove_tool_calls_from_chunks(bytes: &Bytes, names: &HashSet<&str>) -> Bytes {
+    let Ok(text) = std::str::from_utf8(bytes) else {
+        return bytes.clone();
+    };
+
+    let lines = text
+        .split('\n')
+        .map(|line| {
+            if let Some(data) = line.strip_prefix("data:")
+                && let Ok(mut chunk) = serde_json::from_str::<serde_json::Value>(data.trim())
+                && let Some(delta) = chunk.pointer_mut("/choices/0/delta")
+            {
+                remove_tool_calls(delta, names);
+                format!("data: {chunk}")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Bytes::from(lines.join("\n"))
+}
+
+/// Remove the calls of the MCP tools from a chat completion returned to the client along with the
+/// calls of the client-defined functions, since the client cannot execute them
+fn remove_mcp_tool_calls(
+    bytes: Bytes,
+    mcp_tool_calls: &[ToolCall],
+    response_headers: &mut HeaderMap,
+    request_id: &str,
+) -> Bytes {
+    if mcp_tool_calls.is_empty() {
+        return bytes;
+    }
+
+    let Ok(mut chat_completion) = serde_json::from_slice::<serde_json::Value>(&bytes) else {
+        return bytes;
+    };
+    let Some(message) = chat_completion.pointer_mut("/choices/0/message") else {
+        return bytes;
+    };
+
+    dual_warn!(
+        "Dropping {} MCP tool call(s) requested along with the client-defined functions - request_id: {}",
+        mcp_tool_calls.len(),
+        request_id
+    );
+    remove_tool_calls(message, &tool_names(mcp_tool_calls));
+
+    match serde_json::to_vec(&chat_completion) {
+        Ok(bytes) => {
+            response_headers.remove(CONTENT_LENGTH);
+            Bytes::from(bytes)
+        }
+        Err(_) => bytes,
+    }
+}
+
+/// Remove the calls of the given tools from a message or a delta, and renumber the remaining
+/// tool calls of a delta
+fn remove_tool_calls(message: &mut serde_json::Value, names: &HashSet<&str>) {
+    let Some(tool_calls) = message
+        .get_mut("tool_calls")
+        .and_then(|tool_calls| tool_calls.as_array_mut())
+    else {
+        return;
+    };
+
+    tool_calls.retain(|tool_call| {
+        tool_call
+            .pointer("/function/name")
+            .and_then(|name| name.as_str())
+            .is_none_or(|name| !names.contains(name))
+    });
+    for (idx, tool_call) in tool_calls.iter_mut().enumerate() {
+        if let Some(index) = tool_call.get_mut("index") {
+            *index = idx.into();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_remove_mcp_tool_calls() {
+    // `lookup` is an MCP tool shadowed by a client-defined function of the same name
+    {
+        let mut mcp_tools = MCP_TOOLS.get_or_init(Default::default).write().await;
+        for name in ["web_search", "lookup"] {
+            mcp_tools.insert(
+                name.to_string(),
+                McpToolTarget {
+                    server: "search".to_string(),
+                    tool: name.to_string(),
+                },
+            );
+        }
+    }
+    let agent = AgentLoop::new(
+        AgentConfig::default(),
+        Duration::from_secs(5),
+        true,
+        HashSet::from(["get_weather".to_string(), "lookup".to_string()]),
+    );
+    let tool_calls = [
+        ("call_1", "get_weather"),
+        ("call_2", "web_search"),
+        ("call_3", "lookup"),
+    ]
+    .iter()
+    .map(|(id, name)| {
+        serde_json::json!({
+            "id": id,
+            "type": "function",
+            "function": {"name": name, "arguments": "{}"},
+        })
+    })
+    .collect::<serde_json::Value>();
+    let (mcp_tool_calls, client_tool_calls) = agent
+        .partition_tool_calls(serde_json::from_value(tool_calls.clone()).unwrap())
+        .await;
+    assert_eq!(tool_names(&mcp_tool_calls), HashSet::from(["web_search"]));
+    assert_eq!(client_tool_calls.len(), 2);
+
+    // the non-stream response keeps the calls of the client-defined functions only
+    let body = serde_json::json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": null, "tool_calls": tool_calls},
+            "finish_reason": "tool_calls",
+        }],
+    });
+    let bytes = Bytes::from(body.to_string());
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+    let removed = remove_mcp_tool_calls(bytes.clone(), &mcp_tool_calls, &mut headers, "test");
+    let chat_completion = serde_json::from_slice::<serde_json::Value>(&removed).unwrap();
+    let ids = chat_completion["choices"][0]["message"]["tool_calls"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|tool_call| tool_call["id"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, ["call_1", "call_3"]);
+    assert!(headers.get(CONTENT_LENGTH).is_none());
+
+    // the response is unchanged if no MCP tool is called
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+    assert_eq!(
+        remove_mcp_tool_calls(bytes.clone(), &[], &mut headers, "test"),
+        bytes
+    );
+    assert!(headers.get(CONTENT_LENGTH).is_some());
+
+    // the calls are removed from the SSE chunks and the remaining calls are renumbered
+    let delta_tool_calls = tool_calls
+        .as_array()
+        .unwrap()
+        .iter()
+        .enumerate()
+        .map(|(idx, tool_call)| {
+            let mut tool_call = tool_call.clone();
+            tool_call["index"] = idx.into();
+            tool_call
+        })
+        .collect::<Vec<_>>();
+    let chunk = serde_json::json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion.chunk",
+        "choices": [{"index": 0, "delta": {"tool_calls": delta_tool_calls}}],
+    });
+    let chunks = Bytes::from(format!("data: {chunk}\n\ndata: [DONE]\n\n"));
+    let removed = remove_tool_calls_from_chunks(&chunks, &tool_names(&mcp_tool_calls));
+    let text = std::str::from_utf8(&removed).unwrap();
+    let mut lines = text.split('\n');
+    let first = lines.next().unwrap().strip_prefix("data: ").unwrap();
+    let chunk = serde_json::from_str::<serde_json::Value>(first).unwrap();
+    let remaining = chunk["choices"][0]["delta"]["tool_calls"]
+        .as_array()
+        .unwrap();
+    assert_eq!(remaining.len(), 2);
+    assert_eq!(remaining[0]["id"], "call_1");
+    assert_eq!(remaining[0]["index"], 0);
+    assert_eq!(remaining[1]["id"], "call_3");
+    assert_eq!(remaining[1]["index"], 1);
+    // the other lines of the stream are kept as they are
+    assert_eq!(lines.collect::<Vec<_>>(), ["", "data: [DONE]", "", ""]);
+}
+
+fn tool_names(tool_calls: &[ToolCall]) -> HashSet<&str> {
+    tool_calls
+        .iter()
+        .map(|tool_call| tool_call.function.name.as_str())
+        .collect()
+}
+
+fn parse_chat_completion(bytes: &Bytes, request_id: &str) -> ServerResult<ChatCompletionObject> {
+    serde_json::from_slice(bytes).map_err(|e| {
+        let value = serde_json::from_slice::<serde_json::Value>(bytes).unwrap();
+
+        dual_error!(
+            "The response body received from the downstream server - request_id: {}:\n{}",
+            request_id,
+            serde_json::to_string_pretty(&value).unwrap()
+        );
+
+        let err_msg = format!("Failed to parse the response: {e}");
+
+        dual_error!("{} - request_id: {}", err_msg, request_id);
+
+        ServerError::Operation(err_msg)
+    })
+}
+
+/// Copy HTTP response headers to response builder
+///
+/// Selectively copy response headers based on whether it's a streaming response.
+///
+/// # Arguments
+///
+/// * `response_builder` - Response builder
+/// * `headers` - Source response headers
+fn copy_response_headers(
+    response_builder: axum::http::response::Builder,
+    headers: &HeaderMap,
+) -> axum::http::response::Builder {
+    let allowed_headers = [
+        "access-control-allow-origin",
+        "access-control-allow-headers",
+        "access-control-allow-methods",
+        "content-type",
+        "content-length",
+        "cache-control",
+        "connection",
+        "user",
+        "date",
+        "requires-tool-call",
+    ];
+
+    headers
+        .iter()
+        .fold(response_builder, |builder, (name, value)| {
+            if allowed_headers.contains(&name.as_str()) {
+                dual_debug!("copy header: {} - {}", name, value.to_str().unwrap());
+                builder.header(name, value)
+            } else {
+                dual_debug!("ignore header: {} - {}", name, value.to_str().unwrap());
+                builder
+            }
+        })
+}
 
-    // check the status code
-    match status {
-        StatusCode::OK => {
-            let response_headers = response.headers().clone();
+/// Execute the MCP tools requested by the model, and send their results back to the chat server
+///
+/// The tools requested in the follow-up responses are executed in the same way, until the model
+/// answers without calling an MCP tool or the limits of the agent loop are reached. The calls of
+/// the client-defined functions are returned to the client. The response of
+/// each follow-up request is read in full, so this is used for the non-stream requests. See
+/// `stream_tool_calls` for the stream requests.
+#[allow(clippy::too_many_arguments)]
+async fn call_mcp_server(
+    state: &Arc<AppState>,
+    mut tool_calls: Vec<ToolCall>,
+    mut agent: AgentLoop,
+    request: &mut ChatCompletionRequest,
+    headers: &HeaderMap,
+    chat_server: &TargetServerInfo,
+    request_id: impl AsRef<str>,
+    cancel_token: CancellationToken,
+) -> ServerResult<axum::response::Response> {
+    let request_id = request_id.as_ref();
 
-            // Check if the response requires tool call
-            let requires_tool_call = parse_requires_tool_call_header(&response_headers);
+    if tool_calls.is_empty() {
+        let err_msg = "No tool call found in the response of the chat server";
+        dual_error!("{} - request_id: {}", err_msg, request_id);
+        return Err(ServerError::Operation(err_msg.to_string()));
+    }
 
-            if requires_tool_call {
-                // Handle tool call in stream mode
-                handle_tool_call_stream(
-                    response,
-                    request,
-                    headers,
-                    chat_server,
-                    request_id,
-                    cancel_token,
-                )
-                .await
-            } else {
-                // Handle normal response in stream mode
-                handle_normal_stream(response, status, response_headers, request_id, cancel_token)
-                    .await
+    loop {
+        dual_debug!(
+            "tool calls:\n{}",
+            serde_json::to_string_pretty(&tool_calls).unwrap()
+        );
+
+        let iteration = agent.next_iteration();
+        let results = execute_mcp_tools(
+            &tool_calls,
+            agent.parallel_tool_calls,
+            agent.deadline,
+            request_id,
+        )
+        .await;
+
+        let mut contents = Vec::with_capacity(results.len());
+        for (tool_call, (result, elapsed)) in tool_calls.iter().zip(results) {
+            let server = mcp_server_of(&tool_call.function.name).await;
+            agent.record(
+                iteration,
+                tool_call,
+                server.as_deref(),
+                &result,
+                elapsed,
+                request_id,
+            );
+            // a failed tool call is reported to the model, so that it can recover
+            contents.push(match result {
+                Ok(tool_result) => tool_result.content,
+                Err(e) => tool_error_content(&e),
+            });
+        }
+
+        append_tool_results(request, &tool_calls, &contents);
+        let tools_enabled = agent.prepare_follow_up(request, request_id);
+
+        let follow_up = async {
+            let ds_response = send_tool_result_request(
+                state,
+                request,
+                headers,
+                chat_server,
+                request_id,
+                &cancel_token,
+            )
+            .await?;
+
+            let status = ds_response.status();
+            let response_headers = ds_response.headers().clone();
+            let bytes = read_response_bytes(ds_response, request_id, cancel_token.clone()).await?;
+            Ok::<_, ServerError>((status, response_headers, bytes))
+        };
+        let (status, mut response_headers, mut bytes) =
+            match timeout_at(agent.follow_up_deadline(tools_enabled), follow_up).await {
+                Ok(result) => result?,
+                Err(_) => return Err(agent.follow_up_timed_out(request_id)),
+            };
+
+        // continue the loop if the model calls the MCP tools again
+        if tools_enabled
+            && status.is_success()
+            && let Ok(chat_completion) = serde_json::from_slice::<ChatCompletionObject>(&bytes)
+            && let Some(choice) = chat_completion.choices.first()
+            && !choice.message.tool_calls.is_empty()
+        {
+            let (mcp_tool_calls, client_tool_calls) = agent
+                .partition_tool_calls(choice.message.tool_calls.clone())
+                .await;
+            if client_tool_calls.is_empty() {
+                tool_calls = mcp_tool_calls;
+                continue;
             }
+            bytes =
+                remove_mcp_tool_calls(bytes, &mcp_tool_calls, &mut response_headers, request_id);
         }
-        _ => {
-            let err = response.error_for_status().unwrap_err();
-            let err_msg = format!("{err}");
 
+        agent.finish(request_id);
+        if agent.config.return_steps
+            && status.is_success()
+            && let Some(with_steps) = agent.attach_steps(&bytes)
+        {
+            bytes = with_steps;
+            response_headers.remove(CONTENT_LENGTH);
+        }
+
+        return build_response(status, response_headers, bytes, request_id);
+    }
+}
+
+/// Result of an MCP tool executed by the gateway
+struct McpToolResult {
+    /// Content of the tool message sent back to the chat server
+    content: String,
+}
+
+/// Get the name of the MCP server providing the tool
+async fn mcp_server_of(tool_name: &str) -> Option<String> {
+    let mcp_tools = MCP_TOOLS.get()?.read().await;
+    mcp_tools.get(tool_name).map(|target| target.server.clone())
+}
+
+/// Call the MCP tool requested by the model
+///
+/// The results of the search servers are wrapped in a prompt asking the model to answer based on
+/// them only.
+async fn execute_mcp_tool(tool_call: &ToolCall, request_id: &str) -> ServerResult<McpToolResult> {
+    let tool_name = tool_call.function.name.as_str();
+    let tool_args = &tool_call.function.arguments;
+
+    dual_debug!(
+        "tool name: {}, tool args: {} - request_id: {}",
+        tool_name,
+        tool_args,
+        request_id
+    );
+
+    // convert the func_args to a json object
+    let arguments =
+        serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(tool_args).ok();
+
+    // find mcp client by tool name
+    let mcp_tools = match MCP_TOOLS.get() {
+        Some(mcp_tools) => mcp_tools,
+        None => {
+            let err_msg = "Empty MCP TOOLS";
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::Operation(err_msg.to_string()));
+        }
+    };
+    // the advertised name of a namespaced tool differs from its name on the mcp server
+    let McpToolTarget {
+        server: mcp_client_name,
+        tool: mcp_tool_name,
+    } = match mcp_tools.read().await.get(tool_name) {
+        Some(target) => target.clone(),
+        None => {
+            let err_msg = format!("Failed to find the MCP client with tool name: {tool_name}");
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::McpNotFoundClient);
+        }
+    };
+    let services = match MCP_SERVICES.get() {
+        Some(services) => services,
+        None => {
+            let err_msg = "Empty MCP CLIENTS";
             dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::Operation(err_msg.to_string()));
+        }
+    };
 
-            return Err(ServerError::Operation(err_msg));
+    let service_map = services.read().await;
+    // get the mcp client
+    let service = match service_map.get(&mcp_client_name) {
+        Some(mcp_client) => mcp_client,
+        None => {
+            let err_msg = format!("Tool not found: {tool_name}");
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::Operation(err_msg.to_string()));
         }
+    };
+
+    // get the server name from the peer info
+    let raw_server_name = match service.read().await.raw.peer_info() {
+        Some(peer_info) => {
+            let server_name = peer_info.server_info.name.clone();
+            dual_debug!(
+                "server name from peer info: {} - request_id: {}",
+                server_name,
+                request_id
+            );
+            server_name
+        }
+        None => {
+            dual_warn!("Failed to get peer info from the MCP client: {mcp_client_name}");
+
+            String::new()
+        }
+    };
+
+    dual_info!(
+        "Call `{}::{}` mcp tool - request_id: {}",
+        raw_server_name,
+        mcp_tool_name,
+        request_id
+    );
+
+    // call a tool
+    let request_param = CallToolRequestParam {
+        name: mcp_tool_name.into(),
+        arguments,
+    };
+    let res = match service.read().await.raw.call_tool(request_param).await {
+        Ok(res) => res,
+        Err(e) => {
+            dual_error!("Failed to call the tool: {}", e);
+
+            // the connection may be lost
+            mcp::check_mcp_server(&mcp_client_name).await;
+
+            return Err(ServerError::Operation(e.to_string()));
+        }
+    };
+    dual_debug!("{}", serde_json::to_string_pretty(&res).unwrap());
+
+    if res.is_error != Some(false) {
+        let err_msg = format!("Failed to call the tool: {tool_name}");
+        dual_error!("{} - request_id: {}", err_msg, request_id);
+        return Err(ServerError::Operation(err_msg));
+    }
+
+    let text = match res.content.first().map(|content| &content.raw) {
+        Some(RawContent::Text(text)) => &text.text,
+        Some(_) => {
+            let err_msg = "Only text content is supported for tool call results";
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::Operation(err_msg.to_string()));
+        }
+        None => {
+            let err_msg = "The mcp tool result is empty";
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::McpEmptyContent);
+        }
+    };
+    dual_info!("The mcp tool call result: {:#?}", text);
+
+    if !SEARCH_MCP_SERVER_NAMES.contains(&raw_server_name.as_str()) {
+        return Ok(McpToolResult {
+            content: text.clone(),
+        });
     }
+
+    // get the fallback message from the mcp client
+    let fallback = if service.read().await.has_fallback_message() {
+        service.read().await.fallback_message.clone().unwrap()
+    } else {
+        DEFAULT_SEARCH_FALLBACK_MESSAGE.to_string()
+    };
+
+    dual_debug!(
+        "fallback message: {} - request_id: {}",
+        fallback,
+        request_id
+    );
+
+    // format the content
+    let content = format!(
+        "Please answer the question based on the information between **---BEGIN CONTEXT---** and **---END CONTEXT---**. Do not use any external knowledge. If the information between **---BEGIN CONTEXT---** and **---END CONTEXT---** is empty, please respond with `{fallback}`. Note that DO NOT use any tools if provided.\n\n---BEGIN CONTEXT---\n\n{text}\n\n---END CONTEXT---"
+    );
+
+    Ok(McpToolResult { content })
 }
 
-/// Handle non-streaming chat responses, supporting both tool calls and normal responses
-///
-/// This function implements the following features:
-/// 1. Read complete response data from downstream server (with cancellation support)
-/// 2. Parse tool call identifier from response headers
-/// 3. Choose different processing paths based on tool call requirements:
-///    - Tool call needed: Parse response and call MCP server
-///    - Normal response: Directly build and return response
-/// 4. Provide complete error handling and cancellation support
-///
-/// # Arguments
-///
-/// * `response` - HTTP response object from downstream server
-/// * `request` - Chat completion request, may be modified (e.g., add tool call results)
-/// * `headers` - HTTP request headers for subsequent requests
-/// * `chat_service_url` - Chat service URL for re-requesting after tool calls
-/// * `request_id` - Request ID for log tracking and error handling
-/// * `cancel_token` - Cancellation token for request cancellation support
-///
-/// # Returns
-/// * `Ok(response)` - Successfully built HTTP response
-/// * `Err(ServerError)` - Error occurred during processing or was cancelled
+/// Call the MCP tool requested by the model within the time budget of the agent loop, and
+/// measure its execution time
+async fn execute_timed_mcp_tool(
+    tool_call: &ToolCall,
+    deadline: Instant,
+    request_id: &str,
+) -> (ServerResult<McpToolResult>, Duration) {
+    let start = Instant::now();
+    let result = match timeout_at(deadline, execute_mcp_tool(tool_call, request_id)).await {
+        Ok(result) => result,
+        Err(_) => {
+            let err_msg = format!(
+                "The tool call `{}` exceeded the time budget of the agent loop",
+                tool_call.function.name
+            );
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            Err(ServerError::McpOperation(err_msg))
+        }
+    };
+
+    (result, start.elapsed())
+}
+
+/// Call the MCP tools requested by the model, concurrently if `parallel` is set
 ///
-/// # Processing Flow
-/// 1. Extract response status code and header information
-/// 2. Read response body data (with cancellation support)
-/// 3. Check if tool call is required
-/// 4. Choose processing path based on tool call requirements:
-///    - Tool call path: Parse response → Call MCP → Re-request → Return result
-///    - Normal path: Directly build response and return
+/// The results are in the order of `tool_calls`. A failed call does not prevent the next ones, so
+/// that every tool call gets a result.
+async fn execute_mcp_tools(
+    tool_calls: &[ToolCall],
+    parallel: bool,
+    deadline: Instant,
+    request_id: &str,
+) -> Vec<(ServerResult<McpToolResult>, Duration)> {
+    if parallel {
+        let calls = tool_calls
+            .iter()
+            .map(|tool_call| execute_timed_mcp_tool(tool_call, deadline, request_id));
+        return futures_util::future::join_all(calls).await;
+    }
+
+    let mut results = Vec::with_capacity(tool_calls.len());
+    for tool_call in tool_calls {
+        results.push(execute_timed_mcp_tool(tool_call, deadline, request_id).await);
+    }
+    results
+}
+
+/// Append the tool calls of the model and the results of the tools to the request messages
 ///
-/// # Error Handling
-/// * Response reading error: Return detailed error information
-/// * Cancellation operation: Log warning and return cancellation error
-/// * Tool call error: Decide whether to continue based on error type
-/// * Response building error: Return build failure error
-async fn handle_non_stream_response(
-    response: reqwest::Response,
+/// `contents` holds the result of each tool call, in the order of `tool_calls`.
+fn append_tool_results(
     request: &mut ChatCompletionRequest,
-    headers: &HeaderMap,
-    chat_server: &TargetServerInfo,
-    request_id: &str,
-    cancel_token: CancellationToken,
-) -> ServerResult<axum::response::Response> {
-    let status = response.status();
+    tool_calls: &[ToolCall],
+    contents: &[String],
+) {
+    // append assistant message with tool calls to request messages
+    let assistant_completion_message = ChatCompletionRequestMessage::Assistant(
+        ChatCompletionAssistantMessage::new(None, None, Some(tool_calls.to_vec())),
+    );
+    request.messages.push(assistant_completion_message);
+
+    // append a tool message with the result of each tool call to request messages
+    for (tool_call, content) in tool_calls.iter().zip(contents) {
+        let tool_completion_message = ChatCompletionRequestMessage::Tool(
+            ChatCompletionToolMessage::new(content, Some(tool_call.id.clone())),
+        );
+        request.messages.push(tool_completion_message);
+    }
+}
+
+/// Content of the tool message reporting a failed tool call to the model
+fn tool_error_content(error: &ServerError) -> String {
+    format!("Error: the tool call failed: {error}")
+}
+
+/// Tool call executed by the agent loop
+#[derive(Debug, Clone, Serialize)]
+struct AgentStep {
+    /// Tool-calling round of the step, starting at 1
+    iteration: usize,
+    /// Id of the tool call generated by the model
+    id: String,
+    name: String,
+    arguments: String,
+    /// Name of the MCP server providing the tool
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<String>,
+    status: ToolCallState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Execution time of the tool in milliseconds
+    duration_ms: u64,
+}
+
+/// Agent loop of a chat request, which keeps executing the tools requested by the model and
+/// re-querying the chat server until the model produces a final answer
+pub(crate) struct AgentLoop {
+    config: AgentConfig,
+    /// Whether the tools requested in one message can be executed concurrently
+    parallel_tool_calls: bool,
+    /// Names of the functions defined by the client, whose calls are returned to the client
+    client_tools: HashSet<String>,
+    /// End of the time budget of the loop
+    deadline: Instant,
+    /// Time given to the follow-up request producing the final answer once the tools are disabled
+    final_answer_timeout: Duration,
+    /// Number of the tool-calling rounds started so far
+    iteration: usize,
+    steps: Vec<AgentStep>,
+}
+impl AgentLoop {
+    fn new(
+        config: AgentConfig,
+        final_answer_timeout: Duration,
+        parallel_tool_calls: bool,
+        client_tools: HashSet<String>,
+    ) -> Self {
+        let deadline = Instant::now() + Duration::from_secs(config.timeout);
+        Self {
+            config,
+            parallel_tool_calls,
+            client_tools,
+            deadline,
+            final_answer_timeout,
+            iteration: 0,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Split the tool calls of the model into the calls of the MCP tools, which are executed by
+    /// the gateway, and the calls of the functions defined by the client
+    async fn partition_tool_calls(
+        &self,
+        tool_calls: Vec<ToolCall>,
+    ) -> (Vec<ToolCall>, Vec<ToolCall>) {
+        let mut mcp_tool_calls = Vec::new();
+        let mut client_tool_calls = Vec::new();
+        for tool_call in tool_calls {
+            let name = &tool_call.function.name;
+            if !self.client_tools.contains(name) && mcp_server_of(name).await.is_some() {
+                mcp_tool_calls.push(tool_call);
+            } else {
+                client_tool_calls.push(tool_call);
+            }
+        }
+
+        (mcp_tool_calls, client_tool_calls)
+    }
+
+    /// Start a new tool-calling round, and get its number
+    fn next_iteration(&mut self) -> usize {
+        self.iteration += 1;
+        self.iteration
+    }
+
+    /// Record an executed tool call
+    fn record(
+        &mut self,
+        iteration: usize,
+        tool_call: &ToolCall,
+        server: Option<&str>,
+        result: &ServerResult<McpToolResult>,
+        elapsed: Duration,
+        request_id: &str,
+    ) {
+        let step = AgentStep {
+            iteration,
+            id: tool_call.id.clone(),
+            name: tool_call.function.name.clone(),
+            arguments: tool_call.function.arguments.clone(),
+            server: server.map(|server| server.to_string()),
+            status: match result {
+                Ok(_) => ToolCallState::Completed,
+                Err(_) => ToolCallState::Failed,
+            },
+            error: result.as_ref().err().map(|e| e.to_string()),
+            duration_ms: elapsed.as_millis() as u64,
+        };
+
+        dual_info!(
+            "Agent step {}: tool `{}` of {} with arguments {} {} in {} ms - request_id: {}",
+            step.iteration,
+            step.name,
+            step.server.as_deref().unwrap_or("unknown server"),
+            step.arguments,
+            if step.error.is_none() {
+                "completed"
+            } else {
+                "failed"
+            },
+            step.duration_ms,
+            request_id
+        );
+
+        self.steps.push(step);
+    }
+
+    /// Set the tool choice of the follow-up request carrying the tool results
+    ///
+    /// The tools stay enabled until the maximum number of rounds is reached or the time budget
+    /// has expired, then they are disabled so that the model produces a final answer. Returns
+    /// whether the tools are enabled.
+    fn prepare_follow_up(&self, request: &mut ChatCompletionRequest, request_id: &str) -> bool {
+        if self.iteration >= self.config.max_tool_iterations {
+            dual_warn!(
+                "The agent loop reached the maximum of {} tool-calling rounds, disabling the tools - request_id: {}",
+                self.config.max_tool_iterations,
+                request_id
+            );
+        } else if Instant::now() >= self.deadline {
+            dual_warn!(
+                "The agent loop exceeded its time budget of {}s, disabling the tools - request_id: {}",
+                self.config.timeout,
+                request_id
+            );
+        } else {
+            // a forced tool choice would make the model call the tools forever
+            if let Some(ToolChoice::Required | ToolChoice::Tool(_)) = request.tool_choice {
+                request.tool_choice = Some(ToolChoice::Auto);
+            }
+            return true;
+        }
+
+        request.tool_choice = Some(ToolChoice::None);
+        false
+    }
+
+    /// Get the deadline of the next follow-up request. The follow-up requests with the tools
+    /// enabled are bounded by the time budget of the loop, while the final answer is given
+    /// `final_answer_timeout`, so that it is produced even if the budget has expired.
+    fn follow_up_deadline(&self, tools_enabled: bool) -> Instant {
+        match tools_enabled {
+            true => self.deadline,
+            false => Instant::now() + self.final_answer_timeout,
+        }
+    }
+
+    /// Get the error of a follow-up request that has not completed by its deadline
+    fn follow_up_timed_out(&self, request_id: &str) -> ServerError {
+        let err_msg = format!(
+            "The follow-up request of the agent loop did not complete within the time budget of {}s",
+            self.config.timeout
+        );
+        dual_error!("{} - request_id: {}", err_msg, request_id);
+        ServerError::Operation(err_msg)
+    }
+
+    /// Log the summary of the loop once the model has produced its final answer
+    fn finish(&self, request_id: &str) {
+        dual_info!(
+            "Agent loop finished after {} tool-calling round(s) and {} tool call(s) - request_id: {}",
+            self.iteration,
+            self.steps.len(),
+            request_id
+        );
+    }
+
+    /// Add the executed steps to the `agent_steps` field of a chat completion
+    fn attach_steps(&self, bytes: &Bytes) -> Option<Bytes> {
+        let mut chat_completion = serde_json::from_slice::<serde_json::Value>(bytes).ok()?;
+        chat_completion
+            .as_object_mut()?
+            .insert("agent_steps".to_string(), serde_json::json!(self.steps));
+        serde_json::to_vec(&chat_completion).ok().map(Bytes::from)
+    }
+}
 
-    // check the status code
-    match status {
-        StatusCode::OK => {
-            let response_headers = response.headers().clone();
+#[test]
+fn test_agent_loop_prepare_follow_up() {
+    let new_request = || -> ChatCompletionRequest {
+        serde_json::from_value(serde_json::json!({
+            "model": "llama",
+            "messages": [{"role": "user", "content": "What is the weather in Paris?"}],
+            "tool_choice": "required",
+        }))
+        .unwrap()
+    };
+    let config = AgentConfig {
+        max_tool_iterations: 2,
+        timeout: 60,
+        return_steps: false,
+    };
 
-            // Read the response body
-            let bytes = read_response_bytes(response, request_id, cancel_token.clone()).await?;
-            let chat_completion = parse_chat_completion(&bytes, request_id)?;
+    // a forced tool choice is downgraded to `auto` while the tools are enabled
+    let mut agent = AgentLoop::new(config.clone(), Duration::from_secs(5), true, HashSet::new());
+    let mut request = new_request();
+    agent.next_iteration();
+    assert!(agent.prepare_follow_up(&mut request, "test"));
+    assert_eq!(request.tool_choice, Some(ToolChoice::Auto));
+    assert_eq!(agent.follow_up_deadline(true), agent.deadline);
+
+    // the tools are disabled once the maximum number of rounds is reached
+    agent.next_iteration();
+    assert!(!agent.prepare_follow_up(&mut request, "test"));
+    assert_eq!(request.tool_choice, Some(ToolChoice::None));
+
+    // the tools are disabled once the time budget has expired, while the final answer still
+    // gets its own timeout
+    let config = AgentConfig {
+        timeout: 0,
+        ..config
+    };
+    let mut agent = AgentLoop::new(config, Duration::from_secs(5), true, HashSet::new());
+    let mut request = new_request();
+    agent.next_iteration();
+    assert!(!agent.prepare_follow_up(&mut request, "test"));
+    assert_eq!(request.tool_choice, Some(ToolChoice::None));
+    assert!(agent.follow_up_deadline(false) > Instant::now());
+}
 
-            // Check if the response requires tool call
-            let requires_tool_call = !chat_completion.choices[0].message.tool_calls.is_empty();
+#[tokio::test]
+async fn test_execute_sequential_mcp_tools() {
+    use rmcp::{
+        model::{ClientCapabilities, ClientInfo, Implementation},
+        service::ServiceExt,
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        sync::RwLock as TokioRwLock,
+    };
 
-            if requires_tool_call {
-                call_mcp_server(
-                    chat_completion.choices[0].message.tool_calls.as_slice(),
-                    request,
-                    headers,
-                    chat_server,
-                    request_id,
-                    cancel_token,
-                )
+    // a minimal mcp server, whose `fail` tool reports an error
+    let (client_io, server_io) = tokio::io::duplex(4096);
+    tokio::spawn(async move {
+        let (reader, mut writer) = tokio::io::split(server_io);
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let message: serde_json::Value = serde_json::from_str(&line).unwrap();
+            let result = match message["method"].as_str() {
+                Some("initialize") => serde_json::json!({
+                    "protocolVersion": "2025-03-26",
+                    "capabilities": {"tools": {}},
+                    "serverInfo": {"name": "sequential-test", "version": "0.1.0"}
+                }),
+                Some("tools/call") => serde_json::json!({
+                    "content": [{"type": "text", "text": "done"}],
+                    "isError": message["params"]["name"] == "fail"
+                }),
+                // notifications
+                _ => continue,
+            };
+            let response =
+                serde_json::json!({"jsonrpc": "2.0", "id": message["id"], "result": result});
+            writer
+                .write_all(format!("{response}\n").as_bytes())
                 .await
-            } else {
-                // Handle normal response in non-stream mode
-                build_response(status, response_headers, bytes, request_id)
-            }
+                .unwrap();
         }
-        _ => {
-            let err = response.error_for_status().unwrap_err();
-            let err_msg = format!("{err}");
-
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+    });
 
-            return Err(ServerError::Operation(err_msg));
+    let client_info = ClientInfo {
+        protocol_version: Default::default(),
+        capabilities: ClientCapabilities::default(),
+        client_info: Implementation {
+            name: "test".to_string(),
+            version: "0.1.0".to_string(),
+        },
+    };
+    let raw = client_info.into_dyn().serve(client_io).await.unwrap();
+    let server = "sequential-test-server".to_string();
+    MCP_SERVICES
+        .get_or_init(|| TokioRwLock::new(HashMap::new()))
+        .write()
+        .await
+        .insert(
+            server.clone(),
+            TokioRwLock::new(mcp::McpService::new(server.clone(), raw)),
+        );
+    {
+        let mut mcp_tools = MCP_TOOLS
+            .get_or_init(|| TokioRwLock::new(HashMap::new()))
+            .write()
+            .await;
+        for tool in ["fail", "ok"] {
+            let target = McpToolTarget {
+                server: server.clone(),
+                tool: tool.to_string(),
+            };
+            mcp_tools.insert(format!("sequential_{tool}"), target);
         }
     }
+
+    let tool_call = |id: &str, name: &str| -> ToolCall {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "type": "function",
+            "function": {"name": name, "arguments": "{}"}
+        }))
+        .unwrap()
+    };
+    let tool_calls = [
+        tool_call("call_1", "sequential_fail"),
+        tool_call("call_2", "sequential_ok"),
+    ];
+    let deadline = Instant::now() + Duration::from_secs(10);
+
+    // the call following a failed call is still executed
+    let results = execute_mcp_tools(&tool_calls, false, deadline, "test").await;
+    assert_eq!(results.len(), 2);
+    assert!(results[0].0.is_err());
+    assert_eq!(results[1].0.as_ref().unwrap().content, "done");
+
+    // every tool call gets a tool message
+    let contents = results
+        .into_iter()
+        .map(|(result, _)| match result {
+            Ok(tool_result) => tool_result.content,
+            Err(e) => tool_error_content(&e),
+        })
+        .collect::<Vec<_>>();
+    let mut request = ChatCompletionRequest::default();
+    append_tool_results(&mut request, &tool_calls, &contents);
+    let tool_call_ids = request
+        .messages
+        .iter()
+        .filter_map(|message| match message {
+            ChatCompletionRequestMessage::Tool(message) => message.tool_call_id(),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(tool_call_ids, ["call_1", "call_2"]);
+
+    MCP_TOOLS
+        .get()
+        .unwrap()
+        .write()
+        .await
+        .retain(|_, target| target.server != server);
+    MCP_SERVICES.get().unwrap().write().await.remove(&server);
 }
 
-/// Handle tool calls in streaming responses
-///
-/// Parse tool call information from streaming response, call MCP server to execute tools,
-/// then add tool execution results to the request and re-send the request.
-///
-/// # Arguments
-///
-/// * `response` - HTTP response from downstream server
-/// * `request` - Chat request, will be modified to include tool call results
-/// * `headers` - HTTP request headers
-/// * `chat_server` - Chat server information
-/// * `request_id` - Request ID
-/// * `cancel_token` - Cancellation token
-async fn handle_tool_call_stream(
-    response: reqwest::Response,
-    request: &mut ChatCompletionRequest,
+#[test]
+fn test_agent_loop_attach_steps() {
+    let config = AgentConfig {
+        return_steps: true,
+        ..Default::default()
+    };
+    let mut agent = AgentLoop::new(config, Duration::from_secs(5), true, HashSet::new());
+    let iteration = agent.next_iteration();
+    for (id, result) in [
+        (
+            "call_1",
+            Ok(McpToolResult {
+                content: "sunny".to_string(),
+            }),
+        ),
+        ("call_2", Err(ServerError::McpEmptyContent)),
+    ] {
+        let tool_call: ToolCall = serde_json::from_value(serde_json::json!({
+            "id": id,
+            "type": "function",
+            "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"},
+        }))
+        .unwrap();
+        agent.record(
+            iteration,
+            &tool_call,
+            Some("weather"),
+            &result,
+            Duration::from_millis(20),
+            "test",
+        );
+    }
+
+    let bytes = Bytes::from(r#"{"id":"chatcmpl-1","object":"chat.completion","choices":[]}"#);
+    let with_steps = agent.attach_steps(&bytes).unwrap();
+    let chat_completion = serde_json::from_slice::<serde_json::Value>(&with_steps).unwrap();
+    assert_eq!(chat_completion["id"], "chatcmpl-1");
+    let steps = chat_completion["agent_steps"].as_array().unwrap();
+    assert_eq!(steps.len(), 2);
+    assert_eq!(steps[0]["id"], "call_1");
+    assert_eq!(steps[0]["server"], "weather");
+    assert_eq!(steps[0]["status"], "completed");
+    assert_eq!(steps[0]["duration_ms"], 20);
+    assert!(steps[0].get("error").is_none());
+    assert_eq!(steps[1]["status"], "failed");
+    assert_eq!(steps[1]["error"], "Mcp server returned empty content");
+
+    // a body that is not a JSON object is left as it is
+    assert!(agent.attach_steps(&Bytes::from("data: [DONE]")).is_none());
+}
+
+/// Send the request carrying the tool result to the chat server that requested the tool call
+async fn send_tool_result_request(
+    state: &AppState,
+    request: &ChatCompletionRequest,
     headers: &HeaderMap,
     chat_server: &TargetServerInfo,
     request_id: &str,
-    cancel_token: CancellationToken,
-) -> ServerResult<axum::response::Response> {
-    let tool_calls = extract_tool_calls_from_stream(response, request_id).await?;
-    call_mcp_server(
-        tool_calls.as_slice(),
+    cancel_token: &CancellationToken,
+) -> ServerResult<reqwest::Response> {
+    let ds_request = build_chat_request(
+        &state.http_client,
+        chat_server,
         request,
         headers,
-        chat_server,
         request_id,
-        cancel_token,
-    )
-    .await
-}
-
-/// Parse tool call identifier from HTTP response headers
-///
-/// Check if the "requires-tool-call" field exists in response headers and parse it as boolean.
-/// Returns false if the field doesn't exist or parsing fails.
-fn parse_requires_tool_call_header(headers: &HeaderMap) -> bool {
-    headers
-        .get("requires-tool-call")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.parse::<bool>().ok())
-        .unwrap_or(false)
-}
+    );
 
-async fn handle_normal_stream(
-    response: reqwest::Response,
-    status: StatusCode,
-    response_headers: HeaderMap,
-    request_id: &str,
-    cancel_token: CancellationToken,
-) -> ServerResult<axum::response::Response> {
-    // Handle response body reading with cancellation
-    let bytes = select! {
-        bytes = response.bytes() => {
-            bytes.map_err(|e| {
-                let err_msg = format!("Failed to get the full response as bytes: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                ServerError::Operation(err_msg)
-            })?
-        }
+    // Use select! to handle request cancellation
+    let result = select! {
+        result = ds_request.send() => result,
         _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled while reading response";
+            let warn_msg = "Request was cancelled by client";
             dual_warn!("{} - request_id: {}", warn_msg, request_id);
             return Err(ServerError::Operation(warn_msg.to_string()));
         }
     };
 
-    // build the response builder
-    let response_builder = Response::builder().status(status);
-
-    // copy the response headers
-    let response_builder = copy_response_headers(response_builder, &response_headers);
-
-    match response_builder.body(Body::from(bytes)) {
+    // record the outcome in the circuit breaker of the chat server, as `send_with_failover` does
+    let (failover, circuit_breaker) = {
+        let config = state.config.read().await;
+        (config.failover.clone(), config.circuit_breaker.clone())
+    };
+    match result {
         Ok(response) => {
-            dual_info!(
-                "Chat request completed successfully - request_id: {}",
-                request_id
-            );
+            if failover.is_retryable_status(response.status().as_u16()) {
+                record_server_failure(chat_server, ServerKind::chat, &circuit_breaker, request_id);
+            } else {
+                record_server_success(chat_server, ServerKind::chat, request_id);
+            }
             Ok(response)
         }
         Err(e) => {
-            let err_msg = format!("Failed to create the response: {e}");
+            if e.is_connect() || e.is_timeout() {
+                record_server_failure(chat_server, ServerKind::chat, &circuit_breaker, request_id);
+            }
+            let err_msg = format!("Failed to forward the request to the downstream server: {e}");
             dual_error!("{} - request_id: {}", err_msg, request_id);
             Err(ServerError::Operation(err_msg))
         }
     }
 }
 
-/// Read HTTP response body data with cancellation support
-///
-/// This function uses select! macro to simultaneously monitor response reading and cancellation signals.
-/// When the request is cancelled, it immediately returns an error to avoid resource waste.
-async fn read_response_bytes(
-    response: reqwest::Response,
-    request_id: &str,
-    cancel_token: CancellationToken,
-) -> ServerResult<Bytes> {
-    select! {
-        bytes = response.bytes() => {
-            bytes.map_err(|e| {
-                let err_msg = format!("Failed to get the full response as bytes: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                ServerError::Operation(err_msg)
-            })
-        }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled while reading response";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            Err(ServerError::Operation(warn_msg.to_string()))
+/// State of a tool call executed by the gateway
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ToolCallState {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Progress of a tool call executed by the gateway, announced to the stream clients
+#[derive(Debug, Serialize)]
+struct ToolStatus<'a> {
+    /// Id of the tool call generated by the model
+    id: &'a str,
+    name: &'a str,
+    /// Name of the MCP server providing the tool
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<&'a str>,
+    status: ToolCallState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Tool-calling round of the agent loop, starting at 1
+    iteration: usize,
+}
+
+/// Gateway-generated chunks sent to a stream client while the tools requested by the model are
+/// executed
+struct ToolStatusEvents {
+    chunk_id: String,
+    model: String,
+    created: u64,
+}
+impl ToolStatusEvents {
+    fn new(request: &ChatCompletionRequest) -> Self {
+        Self {
+            chunk_id: gen_chat_id(),
+            model: request.model.clone().unwrap_or_default(),
+            created: chrono::Utc::now().timestamp() as u64,
         }
     }
-}
 
-/// Build HTTP response object
-///
-/// Build complete HTTP response based on status code, response headers and response body data.
-/// Copy all response headers to the new response and log success message.
-///
-/// # Arguments
-///
-/// * `status` - HTTP status code
-/// * `response_headers` - Response headers
-/// * `bytes` - Response body data
-/// * `request_id` - Request ID for logging
-fn build_response(
-    status: StatusCode,
-    response_headers: HeaderMap,
-    bytes: Bytes,
-    request_id: &str,
-) -> ServerResult<axum::response::Response> {
-    // build the response builder
-    let mut response_builder = Response::builder().status(status);
+    /// Create a `chat.completion.chunk` event announcing the status of a tool call. The delta of
+    /// the chunk is empty, so that the clients unaware of `tool_status` ignore it.
+    fn status(&self, status: &ToolStatus) -> Bytes {
+        let chunk = serde_json::json!({
+            "id": self.chunk_id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "delta": {},
+                "finish_reason": null,
+            }],
+            "tool_status": status,
+        });
 
-    // copy the response headers
-    response_builder = copy_response_headers(response_builder, &response_headers);
+        Bytes::from(format!("data: {chunk}\n\n"))
+    }
 
-    match response_builder.body(Body::from(bytes)) {
-        Ok(response) => {
-            dual_info!(
-                "Chat request completed successfully - request_id: {}",
-                request_id
-            );
+    /// Create a `chat.completion.chunk` event carrying the steps executed by the agent loop in
+    /// its `agent_steps` field
+    fn steps(&self, steps: &[AgentStep]) -> Bytes {
+        let chunk = serde_json::json!({
+            "id": self.chunk_id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "delta": {},
+                "finish_reason": null,
+            }],
+            "agent_steps": steps,
+        });
 
-            Ok(response)
-        }
-        Err(e) => {
-            let err_msg = format!("Failed to create the response: {e}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            Err(ServerError::Operation(err_msg))
-        }
+        Bytes::from(format!("data: {chunk}\n\n"))
+    }
+
+    /// Create an event reporting an error that occurred after the stream has started
+    fn error(&self, message: &str) -> Bytes {
+        let error = serde_json::json!({
+            "error": {
+                "message": message,
+                "type": "server_error",
+            }
+        });
+
+        Bytes::from(format!("data: {error}\n\n"))
     }
 }
 
-/// Extract tool call information from streaming response
+/// Execute the MCP tools requested by the model, and stream the answer of the model to the client
 ///
-/// Parse streaming response data and extract tool call information.
-/// Process SSE format data stream, parse ChatCompletionChunk and extract tool_calls.
-async fn extract_tool_calls_from_stream(
-    response: reqwest::Response,
-    request_id: &str,
-) -> ServerResult<Vec<ToolCall>> {
-    let mut ds_stream = response.bytes_stream();
-    let mut tool_calls: Vec<ToolCall> = Vec::new();
+/// The client first receives the chunks announcing each tool call and its status, and then the
+/// SSE chunks of the follow-up request as they arrive. If the model calls the tools again in the
+/// follow-up response, the agent loop goes on until it produces a final answer or its limits are
+/// reached. The tools of a round are executed concurrently if parallel tool calls are allowed,
+/// and one after another otherwise. The events are sent through `tx`, so the execution stops
+/// once the client disconnects. The connection to the chat server is released when this
+/// function returns.
+#[allow(clippy::too_many_arguments)]
+async fn stream_tool_calls(
+    state: Arc<AppState>,
+    tx: tokio::sync::mpsc::Sender<Result<Bytes, std::io::Error>>,
+    mut tool_calls: Vec<ToolCall>,
+    mut agent: AgentLoop,
+    mut request: ChatCompletionRequest,
+    headers: HeaderMap,
+    chat_server: TargetServerInfo,
+    request_id: String,
+    cancel_token: CancellationToken,
+) {
+    let request_id = request_id.as_str();
+    let events = ToolStatusEvents::new(&request);
 
-    while let Some(item) = ds_stream.next().await {
-        match item {
-            Ok(bytes) => {
-                match String::from_utf8(bytes.to_vec()) {
-                    Ok(s) => {
-                        let x = s
-                            .trim_start_matches("data:")
-                            .trim()
-                            .split("data:")
-                            .collect::<Vec<_>>();
-                        let s = x[0];
+    let mut chunks = loop {
+        let iteration = agent.next_iteration();
 
-                        dual_debug!("s: {}", s);
+        let mut servers = Vec::with_capacity(tool_calls.len());
+        for tool_call in tool_calls.iter() {
+            servers.push(mcp_server_of(&tool_call.function.name).await);
+        }
+        let status_event = |idx: usize, status: ToolCallState, error: Option<String>| {
+            events.status(&ToolStatus {
+                id: &tool_calls[idx].id,
+                name: &tool_calls[idx].function.name,
+                server: servers[idx].as_deref(),
+                status,
+                error,
+                iteration,
+            })
+        };
 
-                        // convert the bytes to ChatCompletionChunk
-                        if let Ok(chunk) = serde_json::from_str::<ChatCompletionChunk>(s) {
-                            dual_debug!("chunk: {:?} - request_id: {}", &chunk, request_id);
+        let mut contents: Vec<Option<String>> = vec![None; tool_calls.len()];
+        let mut calls = futures_util::stream::FuturesUnordered::new();
+        for (idx, tool_call) in tool_calls.iter().enumerate() {
+            if tx
+                .send(Ok(status_event(idx, ToolCallState::Running, None)))
+                .await
+                .is_err()
+            {
+                dual_warn!(
+                    "The client disconnected before the tool calls - request_id: {}",
+                    request_id
+                );
+                return;
+            }
+            let deadline = agent.deadline;
+            calls.push(async move {
+                (
+                    idx,
+                    execute_timed_mcp_tool(tool_call, deadline, request_id).await,
+                )
+            });
 
-                            if !chunk.choices.is_empty() {
-                                for tool in chunk.choices[0].delta.tool_calls.iter() {
-                                    let tool_call = tool.clone().into();
+            // with parallel tool calls, wait for the results once all the tools are started
+            if agent.parallel_tool_calls && idx + 1 < tool_calls.len() {
+                continue;
+            }
+
+            loop {
+                let completed = select! {
+                    completed = calls.next() => completed,
+                    _ = cancel_token.cancelled() => {
+                        dual_warn!("Request was cancelled during the tool calls - request_id: {}", request_id);
+                        return;
+                    }
+                };
+                let Some((idx, (result, elapsed))) = completed else {
+                    break;
+                };
+
+                agent.record(
+                    iteration,
+                    &tool_calls[idx],
+                    servers[idx].as_deref(),
+                    &result,
+                    elapsed,
+                    request_id,
+                );
+                match result {
+                    Ok(tool_result) => {
+                        if tx
+                            .send(Ok(status_event(idx, ToolCallState::Completed, None)))
+                            .await
+                            .is_err()
+                        {
+                            dual_warn!(
+                                "The client disconnected during the tool calls - request_id: {}",
+                                request_id
+                            );
+                            return;
+                        }
+                        contents[idx] = Some(tool_result.content);
+                    }
+                    Err(e) => {
+                        if tx
+                            .send(Ok(status_event(
+                                idx,
+                                ToolCallState::Failed,
+                                Some(e.to_string()),
+                            )))
+                            .await
+                            .is_err()
+                        {
+                            dual_warn!(
+                                "The client disconnected during the tool calls - request_id: {}",
+                                request_id
+                            );
+                            return;
+                        }
+                        // the failure is reported to the model, so that it can recover
+                        contents[idx] = Some(tool_error_content(&e));
+                    }
+                }
+            }
+        }
+        drop(calls);
+
+        let contents = contents.into_iter().flatten().collect::<Vec<_>>();
+        append_tool_results(&mut request, &tool_calls, &contents);
+        let tools_enabled = agent.prepare_follow_up(&mut request, request_id);
+
+        let deadline = agent.follow_up_deadline(tools_enabled);
+        let ds_response = match timeout_at(
+            deadline,
+            send_tool_result_request(
+                &state,
+                &request,
+                &headers,
+                &chat_server,
+                request_id,
+                &cancel_token,
+            ),
+        )
+        .await
+        .unwrap_or_else(|_| Err(agent.follow_up_timed_out(request_id)))
+        .and_then(|response| {
+            response.error_for_status().map_err(|e| {
+                let err_msg = e.to_string();
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                ServerError::Operation(err_msg)
+            })
+        }) {
+            Ok(ds_response) => ds_response,
+            Err(e) => {
+                let _ = tx.send(Ok(events.error(&e.to_string()))).await;
+                return;
+            }
+        };
+
+        // continue the loop if the model calls the MCP tools again
+        if !tools_enabled || !parse_requires_tool_call_header(ds_response.headers()) {
+            break ds_response.bytes_stream().boxed();
+        }
+        let stream_tool_calls = match timeout_at(
+            deadline,
+            extract_tool_calls_from_stream(ds_response, request_id),
+        )
+        .await
+        .unwrap_or_else(|_| Err(agent.follow_up_timed_out(request_id)))
+        {
+            Ok(stream_tool_calls) if !stream_tool_calls.tool_calls.is_empty() => stream_tool_calls,
+            Ok(_) => {
+                let err_msg = "No tool call found in the stream of the chat server";
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                let _ = tx.send(Ok(events.error(err_msg))).await;
+                return;
+            }
+            Err(e) => {
+                let _ = tx.send(Ok(events.error(&e.to_string()))).await;
+                return;
+            }
+        };
+        let (mcp_tool_calls, client_tool_calls) = agent
+            .partition_tool_calls(stream_tool_calls.tool_calls)
+            .await;
+        if !client_tool_calls.is_empty() {
+            break replay_tool_call_stream(
+                stream_tool_calls.consumed,
+                stream_tool_calls.rest,
+                &mcp_tool_calls,
+                request_id,
+            );
+        }
+        tool_calls = mcp_tool_calls;
+    };
 
-                                    dual_debug!("tool_call: {:?}", &tool_call);
+    agent.finish(request_id);
+    if agent.config.return_steps && tx.send(Ok(events.steps(&agent.steps))).await.is_err() {
+        dual_warn!(
+            "The client disconnected before the answer of the model - request_id: {}",
+            request_id
+        );
+        return;
+    }
 
-                                    tool_calls.push(tool_call);
-                                }
+    // forward the answer of the model as it is generated
+    loop {
+        let chunk = select! {
+            chunk = chunks.next() => chunk,
+            _ = cancel_token.cancelled() => {
+                dual_warn!("Request was cancelled while streaming response - request_id: {}", request_id);
+                return;
+            }
+        };
 
-                                break;
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        let err_msg = format!(
-                            "Failed to convert bytes from downstream server into string: {e}"
-                        );
-                        dual_error!("{} - request_id: {}", err_msg, request_id);
-                        return Err(ServerError::Operation(err_msg));
-                    }
+        match chunk {
+            Some(Ok(bytes)) => {
+                if tx.send(Ok(bytes)).await.is_err() {
+                    dual_warn!(
+                        "The client disconnected before the stream from the chat server {} ended - request_id: {}",
+                        chat_server.id,
+                        request_id
+                    );
+                    return;
                 }
             }
-            Err(e) => {
-                let err_msg = format!("Failed to get the full response as bytes: {e}");
+            Some(Err(e)) => {
+                let err_msg = format!(
+                    "Failed to read the stream from the chat server {}: {e}",
+                    chat_server.id
+                );
                 dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg));
+                let _ = tx.send(Err(std::io::Error::other(err_msg))).await;
+                return;
             }
+            None => break,
         }
     }
 
-    Ok(tool_calls)
+    dual_info!(
+        "Chat request completed successfully - request_id: {}",
+        request_id
+    );
 }
 
-fn parse_chat_completion(bytes: &Bytes, request_id: &str) -> ServerResult<ChatCompletionObject> {
-    serde_json::from_slice(bytes).map_err(|e| {
-        let value = serde_json::from_slice::<serde_json::Value>(bytes).unwrap();
+pub(crate) mod responses {
+    use super::*;
+    use crate::responses::{
+        DeleteResponseResult, InputItemList, InputTypes, ResponseObject, ResponseRequest,
+        ResponseStreamBuilder, SseDecoder,
+    };
+    use axum::extract::{Path, Query};
+    use chrono::Utc;
+    use endpoints::chat::StreamOptions;
+    use serde::Deserialize;
 
-        dual_error!(
-            "The response body received from the downstream server - request_id: {}:\n{}",
-            request_id,
-            serde_json::to_string_pretty(&value).unwrap()
+    pub(crate) async fn create_response_handler(
+        State(state): State<Arc<AppState>>,
+        Extension(cancel_token): Extension<CancellationToken>,
+        headers: HeaderMap,
+        Json(request): Json<ResponseRequest>,
+    ) -> ServerResult<axum::response::Response> {
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+
+        dual_info!(
+            "Received a new response request for model: {} - request_id: {}",
+            request.model,
+            request_id
         );
 
-        let err_msg = format!("Failed to parse the response: {e}");
+        if let Err(err_msg) = request.validate_input().and(request.validate_tools()) {
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::BadRequest(err_msg));
+        }
 
-        dual_error!("{} - request_id: {}", err_msg, request_id);
+        let background = request.background.unwrap_or(false);
+        if background && request.stream.unwrap_or(false) {
+            let err_msg = "Streaming is not supported for background responses".to_string();
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::BadRequest(err_msg));
+        }
+        if background && request.store == Some(false) {
+            let err_msg =
+                "Background responses must be stored, `store` cannot be false".to_string();
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::BadRequest(err_msg));
+        }
 
-        ServerError::Operation(err_msg)
-    })
-}
+        let response_id = ResponseRequest::generate_id();
+        let created_at = Utc::now().timestamp();
 
-/// Copy HTTP response headers to response builder
-///
-/// Selectively copy response headers based on whether it's a streaming response.
-///
-/// # Arguments
-///
-/// * `response_builder` - Response builder
-/// * `headers` - Source response headers
-fn copy_response_headers(
-    response_builder: axum::http::response::Builder,
-    headers: &HeaderMap,
-) -> axum::http::response::Builder {
-    let allowed_headers = [
-        "access-control-allow-origin",
-        "access-control-allow-headers",
-        "access-control-allow-methods",
-        "content-type",
-        "content-length",
-        "cache-control",
-        "connection",
-        "user",
-        "date",
-        "requires-tool-call",
-    ];
+        // Get conversation history if previous_response_id is provided
+        let conversation_history = if let Some(prev_id) = &request.previous_response_id {
+            match build_conversation_history(&state.database, prev_id.clone(), &request_id).await {
+                Ok(history) => history,
+                Err(e) => {
+                    dual_warn!("Failed to build conversation history for {}: {} - request_id: {}", prev_id, e, request_id);
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
 
-    headers
-        .iter()
-        .fold(response_builder, |builder, (name, value)| {
-            if allowed_headers.contains(&name.as_str()) {
-                dual_debug!("copy header: {} - {}", name, value.to_str().unwrap());
-                builder.header(name, value)
-            } else {
-                dual_debug!("ignore header: {} - {}", name, value.to_str().unwrap());
-                builder
+        // Convert ResponseRequest to ChatCompletionRequest
+        let chat_request = request.to_chat_completion_request(conversation_history);
+
+        // Send request to a downstream chat server
+        let routing_key = routing_key(
+            &state,
+            &headers,
+            request
+                .prompt_cache_key
+                .as_deref()
+                .or(request.user.as_deref()),
+        )
+        .await;
+        let mut mutable_chat_request = chat_request;
+        if background {
+            let mut response_obj = request.to_response_object(response_id, created_at);
+            response_obj.status = "queued".to_string();
+            return start_background_response(
+                state,
+                headers,
+                request,
+                mutable_chat_request,
+                routing_key,
+                response_obj,
+                request_id,
+            )
+            .await;
+        }
+        if request.stream.unwrap_or(false) {
+            // ask for the token usage in the last chunk of the stream
+            mutable_chat_request.stream_options = Some(StreamOptions {
+                include_usage: Some(true),
+            });
+        }
+        let FailoverResponse {
+            server: chat_server,
+            response,
+            attempted: _,
+        } = send_request_with_retry(
+            &state,
+            &mut mutable_chat_request,
+            &headers,
+            routing_key.as_deref(),
+            &request_id,
+            cancel_token.clone(),
+        )
+        .await?;
+
+        // Handle the response based on stream mode
+        let response_obj = match request.stream.unwrap_or(false) {
+            true => {
+                let response_obj = request.to_response_object(response_id, created_at);
+                return stream_response(
+                    response,
+                    request,
+                    response_obj,
+                    state.database.clone(),
+                    &chat_server,
+                    &request_id,
+                    cancel_token,
+                );
             }
-        })
-}
+            false => {
+                complete_response(response, &request, response_id, &request_id, cancel_token)
+                    .await?
+            }
+        };
 
-async fn call_mcp_server(
-    tool_calls: &[ToolCall],
-    request: &mut ChatCompletionRequest,
-    headers: &HeaderMap,
-    chat_server: &TargetServerInfo,
-    request_id: impl AsRef<str>,
-    cancel_token: CancellationToken,
-) -> ServerResult<axum::response::Response> {
-    let request_id = request_id.as_ref();
-    // let chat_service_url = chat_service_url.as_ref();
-    let chat_service_url = format!("{}/chat/completions", chat_server.url.trim_end_matches('/'));
+        // Store response in database
+        store_response_object(&state.database, &request, &response_obj, &request_id).await;
 
-    dual_debug!(
-        "tool calls:\n{}",
-        serde_json::to_string_pretty(tool_calls).unwrap()
-    );
-    dual_debug!(
-        "first tool call:\n{}",
-        serde_json::to_string_pretty(&tool_calls[0]).unwrap()
-    );
+        let json_body = serde_json::to_string(&response_obj).map_err(|e| {
+            let err_msg = format!("Failed to serialize response: {e}");
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            ServerError::Operation(err_msg)
+        })?;
 
-    let tool_call = &tool_calls[0];
-    let tool_call_id = tool_call.id.as_str();
-    let tool_name = tool_call.function.name.as_str();
-    let tool_args = &tool_call.function.arguments;
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                ServerError::Operation(err_msg)
+            })
+    }
 
-    dual_debug!(
-        "tool name: {}, tool args: {} - request_id: {}",
-        tool_name,
-        tool_args,
-        request_id
-    );
+    /// Read the chat completion of the downstream server and convert it to a response object
+    async fn complete_response(
+        response: reqwest::Response,
+        request: &ResponseRequest,
+        response_id: String,
+        request_id: &str,
+        cancel_token: CancellationToken,
+    ) -> ServerResult<ResponseObject> {
+        let bytes = read_response_bytes(response, request_id, cancel_token).await?;
+        let chat_completion = parse_chat_completion(&bytes, request_id)?;
+
+        // Convert to ResponseObject
+        let mut response_obj = ResponseObject::from(chat_completion);
+        response_obj.id = response_id;
+        response_obj.previous_response_id = request.previous_response_id.clone();
+        response_obj.instructions = request.instructions.clone();
+        response_obj.max_output_tokens = request.max_output_tokens;
+        response_obj.temperature = request.temperature;
+        response_obj.top_p = request.top_p;
+        response_obj.store = request.store;
+        response_obj.metadata = request.metadata.clone();
+        response_obj.user = request.user.clone();
+        response_obj.safety_identifier = request.safety_identifier.clone();
+        response_obj.prompt_cache_key = request.prompt_cache_key.clone();
+        response_obj.tools = request.tools.clone();
+        response_obj.tool_choice = request.tool_choice.clone();
+        response_obj.parallel_tool_calls = request.parallel_tool_calls;
+        response_obj.truncation = request.truncation.clone();
+        response_obj.verbosity = request.verbosity.clone();
+
+        Ok(response_obj)
+    }
 
-    // convert the func_args to a json object
-    let arguments =
-        serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(tool_args).ok();
+    /// Store the queued response and generate it in a background task
+    ///
+    /// The queued response is returned right away. The task can be aborted with
+    /// `POST /v1/responses/{response_id}/cancel` while it is running.
+    async fn start_background_response(
+        state: Arc<AppState>,
+        headers: HeaderMap,
+        request: ResponseRequest,
+        chat_request: ChatCompletionRequest,
+        routing_key: Option<String>,
+        response_obj: ResponseObject,
+        request_id: String,
+    ) -> ServerResult<axum::response::Response> {
+        store_response_object(&state.database, &request, &response_obj, &request_id).await;
 
-    // find mcp client by tool name
-    if let Some(mcp_tools) = MCP_TOOLS.get() {
-        let tools = mcp_tools.read().await;
-        dual_debug!("mcp_tools: {:?}", mcp_tools);
-
-        // look up the tool name in MCP_TOOLS
-        if let Some(mcp_client_name) = tools.get(tool_name) {
-            if let Some(services) = MCP_SERVICES.get() {
-                let service_map = services.read().await;
-                // get the mcp client
-                let service = match service_map.get(mcp_client_name) {
-                    Some(mcp_client) => mcp_client,
-                    None => {
-                        let err_msg = format!("Tool not found: {tool_name}");
-                        dual_error!("{} - request_id: {}", err_msg, request_id);
-                        return Err(ServerError::Operation(err_msg.to_string()));
-                    }
-                };
+        // the task outlives the HTTP request, so it gets its own cancellation token
+        let cancel_token = CancellationToken::new();
+        state
+            .background_responses
+            .write()
+            .await
+            .insert(response_obj.id.clone(), cancel_token.clone());
 
-                // get the server name from the peer info
-                let raw_server_name = match service.read().await.raw.peer_info() {
-                    Some(peer_info) => {
-                        let server_name = peer_info.server_info.name.clone();
-                        dual_debug!(
-                            "server name from peer info: {} - request_id: {}",
-                            server_name,
-                            request_id
-                        );
-                        server_name
-                    }
-                    None => {
-                        dual_warn!(
-                            "Failed to get peer info from the MCP client: {mcp_client_name}"
-                        );
+        dual_info!(
+            "Response {} queued in the background - request_id: {}",
+            response_obj.id,
+            request_id
+        );
 
-                        String::new()
-                    }
-                };
+        let json_body = serde_json::to_string(&response_obj).map_err(|e| {
+            let err_msg = format!("Failed to serialize response: {e}");
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            ServerError::Operation(err_msg)
+        })?;
 
-                dual_info!(
-                    "Call `{}::{}` mcp tool - request_id: {}",
-                    raw_server_name,
-                    tool_name,
-                    request_id
-                );
+        tokio::spawn(run_background_response(
+            state,
+            headers,
+            request,
+            chat_request,
+            routing_key,
+            response_obj.id,
+            request_id.clone(),
+            cancel_token,
+        ));
 
-                // call a tool
-                let request_param = CallToolRequestParam {
-                    name: tool_name.to_string().into(),
-                    arguments,
-                };
-                let res = service
-                    .read()
-                    .await
-                    .raw
-                    .call_tool(request_param)
-                    .await
-                    .map_err(|e| {
-                        dual_error!("Failed to call the tool: {}", e);
-                        ServerError::Operation(e.to_string())
-                    })?;
-                dual_debug!("{}", serde_json::to_string_pretty(&res).unwrap());
-
-                match res.is_error {
-                    Some(false) => {
-                        match res.content.is_empty() {
-                            true => {
-                                let err_msg = "The mcp tool result is empty";
-                                dual_error!("{} - request_id: {}", err_msg, request_id);
-                                Err(ServerError::McpEmptyContent)
-                            }
-                            false => {
-                                let content = &res.content[0];
-                                match &content.raw {
-                                    RawContent::Text(text) => {
-                                        dual_info!("The mcp tool call result: {:#?}", text.text);
-
-                                        match SEARCH_MCP_SERVER_NAMES
-                                            .contains(&raw_server_name.as_str())
-                                        {
-                                            true => {
-                                                // get the fallback message from the mcp client
-                                                let fallback = if service
-                                                    .read()
-                                                    .await
-                                                    .has_fallback_message()
-                                                {
-                                                    service
-                                                        .read()
-                                                        .await
-                                                        .fallback_message
-                                                        .clone()
-                                                        .unwrap()
-                                                } else {
-                                                    DEFAULT_SEARCH_FALLBACK_MESSAGE.to_string()
-                                                };
-
-                                                dual_debug!(
-                                                    "fallback message: {} - request_id: {}",
-                                                    fallback,
-                                                    request_id
-                                                );
-
-                                                // format the content
-                                                let content = format!(
-                                                    "Please answer the question based on the information between **---BEGIN CONTEXT---** and **---END CONTEXT---**. Do not use any external knowledge. If the information between **---BEGIN CONTEXT---** and **---END CONTEXT---** is empty, please respond with `{fallback}`. Note that DO NOT use any tools if provided.\n\n---BEGIN CONTEXT---\n\n{context}\n\n---END CONTEXT---",
-                                                    fallback = fallback,
-                                                    context = &text.text,
-                                                );
-
-                                                // append assistant message with tool call to request messages
-                                                let assistant_completion_message =
-                                                    ChatCompletionRequestMessage::Assistant(
-                                                        ChatCompletionAssistantMessage::new(
-                                                            None,
-                                                            None,
-                                                            Some(tool_calls.to_vec()),
-                                                        ),
-                                                    );
-                                                request.messages.push(assistant_completion_message);
-
-                                                // append tool message with tool result to request messages
-                                                let tool_completion_message =
-                                                    ChatCompletionRequestMessage::Tool(
-                                                        ChatCompletionToolMessage::new(
-                                                            &content,
-                                                            Some(tool_call_id.to_string()),
-                                                        ),
-                                                    );
-                                                request.messages.push(tool_completion_message);
-
-                                                // disable tool choice
-                                                if request.tool_choice.is_some() {
-                                                    request.tool_choice = Some(ToolChoice::None);
-                                                }
-
-                                                // Create a request client that can be cancelled
-                                                let ds_request = if let Some(api_key) =
-                                                    &chat_server.api_key
-                                                    && !api_key.is_empty()
-                                                {
-                                                    reqwest::Client::new()
-                                                        .post(&chat_service_url)
-                                                        .header(CONTENT_TYPE, "application/json")
-                                                        .header(AUTHORIZATION, api_key)
-                                                        .json(&request)
-                                                } else if headers.contains_key("authorization") {
-                                                    let authorization = headers
-                                                        .get("authorization")
-                                                        .unwrap()
-                                                        .to_str()
-                                                        .unwrap()
-                                                        .to_string();
-
-                                                    reqwest::Client::new()
-                                                        .post(&chat_service_url)
-                                                        .header(CONTENT_TYPE, "application/json")
-                                                        .header(AUTHORIZATION, authorization)
-                                                        .json(&request)
-                                                } else {
-                                                    reqwest::Client::new()
-                                                        .post(&chat_service_url)
-                                                        .header(CONTENT_TYPE, "application/json")
-                                                        .json(&request)
-                                                };
-
-                                                dual_info!(
-                                                    "Request to downstream chat server - request_id: {}\n{}",
-                                                    request_id,
-                                                    serde_json::to_string_pretty(&request).unwrap()
-                                                );
-
-                                                // Use select! to handle request cancellation
-                                                let ds_response = select! {
-                                                    response = ds_request.send() => {
-                                                        response.map_err(|e| {
-                                                            let err_msg = format!(
-                                                                "Failed to forward the request to the downstream server: {e}"
-                                                            );
-                                                            dual_error!("{} - request_id: {}", err_msg, request_id);
-                                                            ServerError::Operation(err_msg)
-                                                        })?
-                                                    }
-                                                    _ = cancel_token.cancelled() => {
-                                                        let warn_msg = "Request was cancelled by client";
-                                                        dual_warn!("{} - request_id: {}", warn_msg, request_id);
-                                                        return Err(ServerError::Operation(warn_msg.to_string()));
-                                                    }
-                                                };
-
-                                                let status = ds_response.status();
-                                                let headers = ds_response.headers().clone();
-
-                                                // Handle response body reading with cancellation
-                                                let bytes = select! {
-                                                    bytes = ds_response.bytes() => {
-                                                        bytes.map_err(|e| {
-                                                            let err_msg = format!("Failed to get the full response as bytes: {e}");
-                                                            dual_error!("{} - request_id: {}", err_msg, request_id);
-                                                            ServerError::Operation(err_msg)
-                                                        })?
-                                                    }
-                                                    _ = cancel_token.cancelled() => {
-                                                        let warn_msg = "Request was cancelled while reading response";
-                                                        dual_warn!("{} - request_id: {}", warn_msg, request_id);
-                                                        return Err(ServerError::Operation(warn_msg.to_string()));
-                                                    }
-                                                };
-
-                                                let mut response_builder =
-                                                    Response::builder().status(status);
-
-                                                // Copy all headers from downstream response
-                                                match request.stream {
-                                                    Some(true) => {
-                                                        for (name, value) in headers.iter() {
-                                                            match name.as_str() {
-                                                                "access-control-allow-origin" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "access-control-allow-headers" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "access-control-allow-methods" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "content-type" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "cache-control" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "connection" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "user" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "date" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                _ => {
-                                                                    dual_debug!(
-                                                                        "ignore header: {} - {}",
-                                                                        name,
-                                                                        value.to_str().unwrap()
-                                                                    );
-                                                                }
-                                                            }
-                                                        }
-                                                    }
-                                                    Some(false) | None => {
-                                                        for (name, value) in headers.iter() {
-                                                            dual_debug!(
-                                                                "{}: {}",
-                                                                name,
-                                                                value.to_str().unwrap()
-                                                            );
-                                                            response_builder = response_builder
-                                                                .header(name, value);
-                                                        }
-                                                    }
-                                                }
-
-                                                match response_builder.body(Body::from(bytes)) {
-                                                    Ok(response) => {
-                                                        dual_info!(
-                                                            "Chat request completed successfully - request_id: {}",
-                                                            request_id
-                                                        );
-                                                        Ok(response)
-                                                    }
-                                                    Err(e) => {
-                                                        let err_msg = format!(
-                                                            "Failed to create the response: {e}"
-                                                        );
-                                                        dual_error!(
-                                                            "{} - request_id: {}",
-                                                            err_msg,
-                                                            request_id
-                                                        );
-                                                        Err(ServerError::Operation(err_msg))
-                                                    }
-                                                }
-                                            }
-                                            false => {
-                                                // create an assistant message
-                                                let tool_completion_message =
-                                                    ChatCompletionRequestMessage::Tool(
-                                                        ChatCompletionToolMessage::new(
-                                                            &text.text,
-                                                            Some(tool_call_id.to_string()),
-                                                        ),
-                                                    );
-
-                                                // append assistant message with tool call to request messages
-                                                let assistant_completion_message =
-                                                    ChatCompletionRequestMessage::Assistant(
-                                                        ChatCompletionAssistantMessage::new(
-                                                            None,
-                                                            None,
-                                                            Some(tool_calls.to_vec()),
-                                                        ),
-                                                    );
-                                                request.messages.push(assistant_completion_message);
-                                                // append tool message with tool result to request messages
-                                                request.messages.push(tool_completion_message);
-
-                                                // disable tool choice
-                                                if request.tool_choice.is_some() {
-                                                    request.tool_choice = Some(ToolChoice::None);
-                                                }
-
-                                                // Create a request client that can be cancelled
-                                                let ds_request = if let Some(api_key) =
-                                                    &chat_server.api_key
-                                                    && !api_key.is_empty()
-                                                {
-                                                    reqwest::Client::new()
-                                                        .post(&chat_service_url)
-                                                        .header(CONTENT_TYPE, "application/json")
-                                                        .header(AUTHORIZATION, api_key)
-                                                        .json(&request)
-                                                } else if headers.contains_key("authorization") {
-                                                    let authorization = headers
-                                                        .get("authorization")
-                                                        .unwrap()
-                                                        .to_str()
-                                                        .unwrap()
-                                                        .to_string();
-
-                                                    reqwest::Client::new()
-                                                        .post(&chat_service_url)
-                                                        .header(CONTENT_TYPE, "application/json")
-                                                        .header(AUTHORIZATION, authorization)
-                                                        .json(&request)
-                                                } else {
-                                                    reqwest::Client::new()
-                                                        .post(&chat_service_url)
-                                                        .header(CONTENT_TYPE, "application/json")
-                                                        .json(&request)
-                                                };
-
-                                                dual_info!(
-                                                    "Request to downstream chat server - request_id: {}\n{}",
-                                                    request_id,
-                                                    serde_json::to_string_pretty(&request).unwrap()
-                                                );
-
-                                                // Use select! to handle request cancellation
-                                                let ds_response = select! {
-                                                    response = ds_request.send() => {
-                                                        response.map_err(|e| {
-                                                            let err_msg = format!(
-                                                                "Failed to forward the request to the downstream server: {e}"
-                                                            );
-                                                            dual_error!("{} - request_id: {}", err_msg, request_id);
-                                                            ServerError::Operation(err_msg)
-                                                        })?
-                                                    }
-                                                    _ = cancel_token.cancelled() => {
-                                                        let warn_msg = "Request was cancelled by client";
-                                                        dual_warn!("{} - request_id: {}", warn_msg, request_id);
-                                                        return Err(ServerError::Operation(warn_msg.to_string()));
-                                                    }
-                                                };
-
-                                                let status = ds_response.status();
-                                                let mut response_builder =
-                                                    Response::builder().status(status);
-
-                                                // copy the response headers
-                                                response_builder = copy_response_headers(
-                                                    response_builder,
-                                                    ds_response.headers(),
-                                                );
-
-                                                // Handle response body reading with cancellation
-                                                let bytes = select! {
-                                                    bytes = ds_response.bytes() => {
-                                                        bytes.map_err(|e| {
-                                                            let err_msg = format!("Failed to get the full response as bytes: {e}");
-                                                            dual_error!("{} - request_id: {}", err_msg, request_id);
-                                                            ServerError::Operation(err_msg)
-                                                        })?
-                                                    }
-                                                    _ = cancel_token.cancelled() => {
-                                                        let warn_msg = "Request was cancelled while reading response";
-                                                        dual_warn!("{} - request_id: {}", warn_msg, request_id);
-                                                        return Err(ServerError::Operation(warn_msg.to_string()));
-                                                    }
-                                                };
-
-                                                match response_builder.body(Body::from(bytes)) {
-                                                    Ok(response) => {
-                                                        dual_info!(
-                                                            "Chat request completed successfully - request_id: {}",
-                                                            request_id
-                                                        );
-                                                        Ok(response)
-                                                    }
-                                                    Err(e) => {
-                                                        let err_msg = format!(
-                                                            "Failed to create the response: {e}"
-                                                        );
-                                                        dual_error!(
-                                                            "{} - request_id: {}",
-                                                            err_msg,
-                                                            request_id
-                                                        );
-                                                        Err(ServerError::Operation(err_msg))
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                    _ => {
-                                        let err_msg =
-                                            "Only text content is supported for tool call results";
-                                        dual_error!("{} - request_id: {}", err_msg, request_id);
-                                        Err(ServerError::Operation(err_msg.to_string()))
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    _ => {
-                        let err_msg = format!("Failed to call the tool: {tool_name}");
-                        dual_error!("{} - request_id: {}", err_msg, request_id);
-                        Err(ServerError::Operation(err_msg))
-                    }
-                }
-            } else {
-                let err_msg = "Empty MCP CLIENTS";
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
                 dual_error!("{} - request_id: {}", err_msg, request_id);
-                Err(ServerError::Operation(err_msg.to_string()))
+                ServerError::Operation(err_msg)
+            })
+    }
+
+    /// Generate a background response and record its status transitions in the database
+    ///
+    /// Nothing is written once the response has been cancelled, since the cancel handler has
+    /// already recorded the `cancelled` status.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_background_response(
+        state: Arc<AppState>,
+        headers: HeaderMap,
+        request: ResponseRequest,
+        mut chat_request: ChatCompletionRequest,
+        routing_key: Option<String>,
+        response_id: String,
+        request_id: String,
+        cancel_token: CancellationToken,
+    ) {
+        let request_id = request_id.as_str();
+
+        // hold the lock so that the status written by a concurrent cancellation is kept
+        let background_responses = state.background_responses.read().await;
+        if !background_responses.contains_key(&response_id) {
+            dual_info!(
+                "Background response {} was cancelled before it started - request_id: {}",
+                response_id,
+                request_id
+            );
+            return;
+        }
+        if let Err(e) = state
+            .database
+            .update_response_status(&response_id, "in_progress")
+            .await
+        {
+            dual_warn!(
+                "Failed to update the status of the response {}: {} - request_id: {}",
+                response_id,
+                e,
+                request_id
+            );
+        }
+        drop(background_responses);
+
+        let result = match send_request_with_retry(
+            &state,
+            &mut chat_request,
+            &headers,
+            routing_key.as_deref(),
+            request_id,
+            cancel_token.clone(),
+        )
+        .await
+        {
+            Ok(FailoverResponse { response, .. }) => {
+                complete_response(
+                    response,
+                    &request,
+                    response_id.clone(),
+                    request_id,
+                    cancel_token,
+                )
+                .await
             }
-        } else {
-            let err_msg = format!("Failed to find the MCP client with tool name: {tool_name}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            Err(ServerError::McpNotFoundClient)
-        }
-    } else {
-        let err_msg = "Empty MCP TOOLS";
-        dual_error!("{} - request_id: {}", err_msg, request_id);
-        Err(ServerError::Operation(err_msg.to_string()))
-    }
-}
+            Err(e) => Err(e),
+        };
 
-pub(crate) mod responses {
-    use super::*;
-    use crate::{
-        responses::{ResponseRequest, ResponseObject, DeleteResponseResult, InputItemList},
-    };
-    use axum::extract::Path;
-    use chrono::Utc;
+        // the cancel handler removes the token before cancelling it
+        if state
+            .background_responses
+            .write()
+            .await
+            .remove(&response_id)
+            .is_none()
+        {
+            dual_info!(
+                "Background response {} was cancelled - request_id: {}",
+                response_id,
+                request_id
+            );
+            return;
+        }
 
-    pub(crate) async fn create_response_handler(
-        State(state): State<Arc<AppState>>,
-        Extension(cancel_token): Extension<CancellationToken>,
-        headers: HeaderMap,
-        Json(request): Json<ResponseRequest>,
-    ) -> ServerResult<axum::response::Response> {
-        let request_id = headers
-            .get("x-request-id")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("unknown")
-            .to_string();
+        let response_obj = match result {
+            Ok(response_obj) => response_obj,
+            Err(e) => {
+                dual_error!(
+                    "Background response {} failed: {} - request_id: {}",
+                    response_id,
+                    e,
+                    request_id
+                );
+                let mut response_obj = request.to_response_object(response_id, 0);
+                response_obj.status = "failed".to_string();
+                response_obj.error = Some(crate::responses::ResponseError {
+                    error_type: "server_error".to_string(),
+                    code: "server_error".to_string(),
+                    message: e.to_string(),
+                    param: None,
+                });
+                response_obj
+            }
+        };
 
         dual_info!(
-            "Received a new response request for model: {} - request_id: {}",
-            request.model,
+            "Background response {} finished with status {} - request_id: {}",
+            response_obj.id,
+            response_obj.status,
             request_id
         );
+        update_response_object(&state.database, &response_obj, request_id).await;
+    }
 
-        let response_id = ResponseRequest::generate_id();
-        let _created_at = Utc::now().timestamp();
-
-        // Get conversation history if previous_response_id is provided
-        let conversation_history = if let Some(prev_id) = &request.previous_response_id {
-            match build_conversation_history(&state.database, prev_id.clone(), &request_id).await {
-                Ok(history) => history,
-                Err(e) => {
-                    dual_warn!("Failed to build conversation history for {}: {} - request_id: {}", prev_id, e, request_id);
-                    Vec::new()
-                }
-            }
-        } else {
-            Vec::new()
-        };
-
-        // Convert ResponseRequest to ChatCompletionRequest
-        let chat_request = request.to_chat_completion_request(conversation_history);
+    /// Translate the chat completion stream of the downstream server into the event stream of the
+    /// Responses API
+    ///
+    /// The assembled response is stored in the database once the stream has ended.
+    fn stream_response(
+        response: reqwest::Response,
+        request: ResponseRequest,
+        response_obj: ResponseObject,
+        database: Arc<crate::database::DatabaseManager>,
+        chat_server: &TargetServerInfo,
+        request_id: &str,
+        cancel_token: CancellationToken,
+    ) -> ServerResult<axum::response::Response> {
+        if let Err(e) = response.error_for_status_ref() {
+            let err_msg = e.to_string();
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::Operation(err_msg));
+        }
 
-        // Get target chat server
-        let chat_server = get_chat_server(&state, &request_id).await?;
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(forward_response_events(
+            tx,
+            response,
+            ResponseStreamBuilder::new(response_obj),
+            request,
+            database,
+            chat_server.clone(),
+            request_id.to_string(),
+            cancel_token,
+        ));
+        let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|event| (event, rx))
+        });
 
-        // Send request to downstream server
-        let mut mutable_chat_request = chat_request;
-        let response = send_request_with_retry(
-            &chat_server,
-            &mut mutable_chat_request,
-            &headers,
-            &request_id,
-            cancel_token.clone(),
-        )
-        .await?;
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header("cache-control", "no-cache")
+            .body(Body::from_stream(stream))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create the response: {e}");
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                ServerError::Operation(err_msg)
+            })
+    }
 
-        // Handle the response based on stream mode
-        let response_obj = match request.stream.unwrap_or(false) {
-            true => {
-                // For streaming responses, we need different handling
-                // For now, return an error as streaming needs special implementation
-                return Err(ServerError::Operation("Streaming responses not yet implemented".to_string()));
+    /// Send the events of the Responses API translated from the downstream chunks through `tx`
+    ///
+    /// The connection to the chat server is released when this function returns, and the
+    /// response is stored in the database, including when the client disconnects early.
+    #[allow(clippy::too_many_arguments)]
+    async fn forward_response_events(
+        tx: tokio::sync::mpsc::Sender<Result<Bytes, std::io::Error>>,
+        response: reqwest::Response,
+        mut builder: ResponseStreamBuilder,
+        request: ResponseRequest,
+        database: Arc<crate::database::DatabaseManager>,
+        chat_server: TargetServerInfo,
+        request_id: String,
+        cancel_token: CancellationToken,
+    ) {
+        let request_id = request_id.as_str();
+        let mut decoder = SseDecoder::default();
+        let mut chunks = response.bytes_stream();
+        let mut events = builder.start();
+        let mut finished = false;
+
+        loop {
+            for event in events.drain(..) {
+                if tx.send(Ok(Bytes::from(event))).await.is_err() {
+                    dual_warn!(
+                        "The client disconnected before the response {} was completed - request_id: {}",
+                        builder.response().id,
+                        request_id
+                    );
+                    if !finished {
+                        builder.interrupt("client_disconnected");
+                    }
+                    store_response_object(&database, &request, builder.response(), request_id)
+                        .await;
+                    return;
+                }
+            }
+            if finished {
+                break;
             }
-            false => {
-                // Handle non-stream response
-                let bytes = read_response_bytes(response, &request_id, cancel_token).await?;
-                let chat_completion = parse_chat_completion(&bytes, &request_id)?;
-                
-                // Convert to ResponseObject
-                let mut response_obj = ResponseObject::from(chat_completion);
-                response_obj.id = response_id.clone();
-                response_obj.previous_response_id = request.previous_response_id.clone();
-                response_obj.instructions = request.instructions.clone();
-                response_obj.temperature = request.temperature;
-                response_obj.top_p = request.top_p;
-                response_obj.store = request.store;
-                response_obj.metadata = request.metadata.clone();
-                response_obj.user = request.user.clone();
-                response_obj.safety_identifier = request.safety_identifier.clone();
-                response_obj.prompt_cache_key = request.prompt_cache_key.clone();
-                response_obj.truncation = request.truncation.clone();
-                response_obj.verbosity = request.verbosity.clone();
 
-                response_obj
+            let chunk = select! {
+                chunk = chunks.next() => chunk,
+                _ = cancel_token.cancelled() => {
+                    dual_warn!("Request was cancelled while streaming response - request_id: {}", request_id);
+                    builder.interrupt("cancelled");
+                    store_response_object(&database, &request, builder.response(), request_id).await;
+                    return;
+                }
+            };
+
+            match chunk {
+                Some(Ok(bytes)) => {
+                    for payload in decoder.push(&bytes) {
+                        if payload == "[DONE]" {
+                            events.extend(builder.finish());
+                            finished = true;
+                            break;
+                        }
+
+                        match serde_json::from_str::<serde_json::Value>(&payload) {
+                            Ok(chunk) => events.extend(builder.push_chunk(&chunk)),
+                            Err(e) => dual_warn!(
+                                "Skipping an invalid chunk from the chat server {}: {} - request_id: {}",
+                                chat_server.id,
+                                e,
+                                request_id
+                            ),
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    let err_msg = format!(
+                        "Failed to read the stream from the chat server {}: {e}",
+                        chat_server.id
+                    );
+                    dual_error!("{} - request_id: {}", err_msg, request_id);
+                    events.extend(builder.fail(&err_msg));
+                    finished = true;
+                }
+                None => {
+                    // the stream ended without `data: [DONE]`
+                    events.extend(builder.finish());
+                    finished = true;
+                }
             }
-        };
+        }
 
-        // Store response in database
-        let db_response = crate::database::ResponseSession {
-            id: response_obj.id.clone(),
-            object: response_obj.object.clone(),
-            created_at: response_obj.created_at,
-            status: response_obj.status.clone(),
-            model: response_obj.model.clone(),
-            previous_response_id: response_obj.previous_response_id.clone(),
-            instructions: response_obj.instructions.clone(),
-            max_output_tokens: response_obj.max_output_tokens,
-            temperature: response_obj.temperature,
-            top_p: response_obj.top_p,
-            store: response_obj.store.unwrap_or(true),
-            metadata: response_obj.metadata.clone(),
-            user_id: response_obj.user.clone(),
-            safety_identifier: response_obj.safety_identifier.clone(),
-            prompt_cache_key: response_obj.prompt_cache_key.clone(),
-            usage_input_tokens: response_obj.usage.as_ref().map(|u| u.input_tokens),
-            usage_output_tokens: response_obj.usage.as_ref().map(|u| u.output_tokens),
-            usage_total_tokens: response_obj.usage.as_ref().map(|u| u.total_tokens),
-            error: response_obj.error.as_ref().map(|e| serde_json::to_string(e).unwrap_or_default()),
-            incomplete_details: response_obj.incomplete_details.as_ref().map(|d| serde_json::to_string(d).unwrap_or_default()),
-        };
+        dual_info!(
+            "Response {} completed with status {} - request_id: {}",
+            builder.response().id,
+            builder.response().status,
+            request_id
+        );
+        store_response_object(&database, &request, builder.response(), request_id).await;
+    }
 
-        if let Err(e) = state.database.store_response(db_response).await {
+    /// Store a response object along with the input of its request in the database
+    async fn store_response_object(
+        database: &Arc<crate::database::DatabaseManager>,
+        request: &ResponseRequest,
+        response: &ResponseObject,
+        request_id: &str,
+    ) {
+        if let Err(e) = database.store_response(to_response_session(response)).await {
             dual_warn!("Failed to store response in database: {} - request_id: {}", e, request_id);
         } else {
-            // Store input items
-            if let Some(input) = &request.input {
-                let input_content = serde_json::to_string(input).unwrap_or_default();
+            // Store input items, one row per item so that they can be listed with their own ids
+            let inputs = match &request.input {
+                Some(input @ InputTypes::Text(_)) => {
+                    vec![("message".to_string(), Some(Role::User), input.clone())]
+                }
+                Some(InputTypes::Array(items)) => items
+                    .iter()
+                    .map(|item| {
+                        (
+                            item.item_type.clone(),
+                            // function call items have no role
+                            item.role
+                                .clone()
+                                .or((item.item_type == "message").then_some(Role::User)),
+                            InputTypes::Array(vec![item.clone()]),
+                        )
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+            for (item_type, role, input) in inputs {
+                let input_content = serde_json::to_string(&input).unwrap_or_default();
                 let input_item = crate::database::InputItem {
                     id: ResponseRequest::generate_message_id(),
-                    response_id: response_obj.id.clone(),
-                    item_type: "message".to_string(),
-                    role: Some(Role::User),
+                    response_id: response.id.clone(),
+                    item_type,
+                    role,
                     content: input_content,
-                    created_at: response_obj.created_at,
+                    created_at: response.created_at,
                 };
                 
-                if let Err(e) = state.database.store_input_item(input_item).await {
+                if let Err(e) = database.store_input_item(input_item).await {
                     dual_warn!("Failed to store input item in database: {} - request_id: {}", e, request_id);
                 }
             }
             
-            // Store output items
-            for output_item in &response_obj.output {
-                let output_content = serde_json::to_string(output_item).unwrap_or_default();
-                let db_output_item = crate::database::OutputItem {
-                    id: output_item.id.clone(),
-                    response_id: response_obj.id.clone(),
-                    item_type: output_item.item_type.clone(),
-                    role: output_item.role.clone(),
-                    content: output_content,
-                    status: output_item.status.clone(),
-                    created_at: response_obj.created_at,
-                };
-                
-                if let Err(e) = state.database.store_output_item(db_output_item).await {
-                    dual_warn!("Failed to store output item in database: {} - request_id: {}", e, request_id);
-                }
-            }
+            store_output_items(database, response, request_id).await;
         }
+    }
 
-        let json_body = serde_json::to_string(&response_obj).map_err(|e| {
-            let err_msg = format!("Failed to serialize response: {}", e);
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            ServerError::Operation(err_msg)
-        })?;
+    /// Update a stored response with its outcome and store its output items
+    async fn update_response_object(
+        database: &Arc<crate::database::DatabaseManager>,
+        response: &ResponseObject,
+        request_id: &str,
+    ) {
+        match database
+            .update_response(to_response_session(response))
+            .await
+        {
+            Ok(true) => store_output_items(database, response, request_id).await,
+            Ok(false) => dual_warn!(
+                "Response {} not found in database, maybe it has been deleted - request_id: {}",
+                response.id,
+                request_id
+            ),
+            Err(e) => dual_warn!(
+                "Failed to update response in database: {} - request_id: {}",
+                e,
+                request_id
+            ),
+        }
+    }
 
-        Response::builder()
-            .status(StatusCode::OK)
-            .header("Content-Type", "application/json")
-            .body(Body::from(json_body))
-            .map_err(|e| {
-                let err_msg = format!("Failed to create response: {}", e);
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                ServerError::Operation(err_msg)
-            })
+    async fn store_output_items(
+        database: &Arc<crate::database::DatabaseManager>,
+        response: &ResponseObject,
+        request_id: &str,
+    ) {
+        for output_item in &response.output {
+            let output_content = serde_json::to_string(output_item).unwrap_or_default();
+            let db_output_item = crate::database::OutputItem {
+                id: output_item.id.clone(),
+                response_id: response.id.clone(),
+                item_type: output_item.item_type.clone(),
+                role: output_item.role.clone(),
+                content: output_content,
+                status: output_item.status.clone(),
+                created_at: response.created_at,
+            };
+            
+            if let Err(e) = database.store_output_item(db_output_item).await {
+                dual_warn!(
+                    "Failed to store output item in database: {} - request_id: {}",
+                    e,
+                    request_id
+                );
+            }
+        }
+    }
+
+    fn to_response_session(response: &ResponseObject) -> crate::database::ResponseSession {
+        crate::database::ResponseSession {
+            id: response.id.clone(),
+            object: response.object.clone(),
+            created_at: response.created_at,
+            status: response.status.clone(),
+            model: response.model.clone(),
+            previous_response_id: response.previous_response_id.clone(),
+            instructions: response.instructions.clone(),
+            max_output_tokens: response.max_output_tokens,
+            temperature: response.temperature,
+            top_p: response.top_p,
+            store: response.store.unwrap_or(true),
+            metadata: response.metadata.clone(),
+            user_id: response.user.clone(),
+            safety_identifier: response.safety_identifier.clone(),
+            prompt_cache_key: response.prompt_cache_key.clone(),
+            usage_input_tokens: response.usage.as_ref().map(|u| u.input_tokens),
+            usage_output_tokens: response.usage.as_ref().map(|u| u.output_tokens),
+            usage_total_tokens: response.usage.as_ref().map(|u| u.total_tokens),
+            error: response
+                .error
+                .as_ref()
+                .map(|e| serde_json::to_string(e).unwrap_or_default()),
+            incomplete_details: response
+                .incomplete_details
+                .as_ref()
+                .map(|d| serde_json::to_string(d).unwrap_or_default()),
+            tools: response
+                .tools
+                .as_ref()
+                .map(|t| serde_json::to_string(t).unwrap_or_default()),
+            tool_choice: response
+                .tool_choice
+                .as_ref()
+                .map(|t| serde_json::to_string(t).unwrap_or_default()),
+            parallel_tool_calls: response.parallel_tool_calls,
+            truncation: response.truncation.clone(),
+            verbosity: response.verbosity.clone(),
+        }
     }
 
     pub(crate) async fn get_response_handler(
@@ -2610,48 +5248,21 @@ pub(crate) mod responses {
 
         match state.database.get_response(&response_id).await {
             Ok(Some(db_response)) => {
+                let output_items = state
+                    .database
+                    .get_output_items(&response_id)
+                    .await
+                    .map_err(|e| {
+                        let err_msg = format!("Database error: {e}");
+                        dual_error!("{} - request_id: {}", err_msg, request_id);
+                        ServerError::Operation(err_msg)
+                    })?;
+
                 // Convert database response to API response format
-                let response_obj = ResponseObject {
-                    id: db_response.id,
-                    object: db_response.object,
-                    created_at: db_response.created_at,
-                    model: db_response.model,
-                    status: db_response.status,
-                    previous_response_id: db_response.previous_response_id,
-                    instructions: db_response.instructions,
-                    max_output_tokens: db_response.max_output_tokens,
-                    temperature: db_response.temperature,
-                    top_p: db_response.top_p,
-                    store: Some(db_response.store),
-                    metadata: db_response.metadata,
-                    user: db_response.user_id,
-                    safety_identifier: db_response.safety_identifier,
-                    prompt_cache_key: db_response.prompt_cache_key,
-                    tools: None, // TODO: Implement tool retrieval
-                    tool_choice: None, // TODO: Implement tool choice retrieval
-                    parallel_tool_calls: None,
-                    output: Vec::new(), // TODO: Get from output_items table
-                    error: db_response.error.and_then(|e| serde_json::from_str(&e).ok()),
-                    incomplete_details: db_response.incomplete_details.and_then(|d| serde_json::from_str(&d).ok()),
-                    usage: if let (Some(input), Some(output), Some(total)) = 
-                        (db_response.usage_input_tokens, db_response.usage_output_tokens, db_response.usage_total_tokens) {
-                        Some(crate::responses::Usage {
-                            input_tokens: input,
-                            output_tokens: output,
-                            total_tokens: total,
-                            input_tokens_details: None,
-                            output_tokens_details: None,
-                        })
-                    } else {
-                        None
-                    },
-                    reasoning: None,
-                    truncation: None,
-                    verbosity: None,
-                };
+                let response_obj = from_response_session(db_response, output_items);
 
                 let json_body = serde_json::to_string(&response_obj).map_err(|e| {
-                    let err_msg = format!("Failed to serialize response: {}", e);
+                    let err_msg = format!("Failed to serialize response: {e}");
                     dual_error!("{} - request_id: {}", err_msg, request_id);
                     ServerError::Operation(err_msg)
                 })?;
@@ -2661,7 +5272,7 @@ pub(crate) mod responses {
                     .header("Content-Type", "application/json")
                     .body(Body::from(json_body))
                     .map_err(|e| {
-                        let err_msg = format!("Failed to create response: {}", e);
+                        let err_msg = format!("Failed to create response: {e}");
                         dual_error!("{} - request_id: {}", err_msg, request_id);
                         ServerError::Operation(err_msg)
                     })
@@ -2670,13 +5281,73 @@ pub(crate) mod responses {
                 Err(ServerError::Operation("Response not found".to_string()))
             }
             Err(e) => {
-                let err_msg = format!("Database error: {}", e);
+                let err_msg = format!("Database error: {e}");
                 dual_error!("{} - request_id: {}", err_msg, request_id);
                 Err(ServerError::Operation(err_msg))
             }
         }
     }
 
+    /// Convert a response stored in the database, along with its output items, to the API
+    /// response format
+    fn from_response_session(
+        db_response: crate::database::ResponseSession,
+        output_items: Vec<crate::database::OutputItem>,
+    ) -> ResponseObject {
+        ResponseObject {
+            id: db_response.id,
+            object: db_response.object,
+            created_at: db_response.created_at,
+            model: db_response.model,
+            status: db_response.status,
+            previous_response_id: db_response.previous_response_id,
+            instructions: db_response.instructions,
+            max_output_tokens: db_response.max_output_tokens,
+            temperature: db_response.temperature,
+            top_p: db_response.top_p,
+            store: Some(db_response.store),
+            metadata: db_response.metadata,
+            user: db_response.user_id,
+            safety_identifier: db_response.safety_identifier,
+            prompt_cache_key: db_response.prompt_cache_key,
+            tools: db_response
+                .tools
+                .and_then(|t| serde_json::from_str(&t).ok()),
+            tool_choice: db_response
+                .tool_choice
+                .and_then(|t| serde_json::from_str(&t).ok()),
+            parallel_tool_calls: db_response.parallel_tool_calls,
+            output: output_items
+                .iter()
+                .filter_map(|item| serde_json::from_str(&item.content).ok())
+                .collect(),
+            error: db_response
+                .error
+                .and_then(|e| serde_json::from_str(&e).ok()),
+            incomplete_details: db_response
+                .incomplete_details
+                .and_then(|d| serde_json::from_str(&d).ok()),
+            usage: if let (Some(input), Some(output), Some(total)) = (
+                db_response.usage_input_tokens,
+                db_response.usage_output_tokens,
+                db_response.usage_total_tokens,
+            ) {
+                Some(crate::responses::Usage {
+                    input_tokens: input,
+                    output_tokens: output,
+                    total_tokens: total,
+                    input_tokens_details: None,
+                    output_tokens_details: None,
+                })
+            } else {
+                None
+            },
+            reasoning: None,
+            truncation: db_response.truncation,
+            verbosity: db_response.verbosity,
+        }
+    }
+
     pub(crate) async fn delete_response_handler(
         State(state): State<Arc<AppState>>,
         headers: HeaderMap,
@@ -2704,7 +5375,7 @@ pub(crate) mod responses {
                     };
 
                     let json_body = serde_json::to_string(&result).map_err(|e| {
-                        let err_msg = format!("Failed to serialize delete result: {}", e);
+                        let err_msg = format!("Failed to serialize delete result: {e}");
                         dual_error!("{} - request_id: {}", err_msg, request_id);
                         ServerError::Operation(err_msg)
                     })?;
@@ -2714,7 +5385,7 @@ pub(crate) mod responses {
                         .header("Content-Type", "application/json")
                         .body(Body::from(json_body))
                         .map_err(|e| {
-                            let err_msg = format!("Failed to create response: {}", e);
+                            let err_msg = format!("Failed to create response: {e}");
                             dual_error!("{} - request_id: {}", err_msg, request_id);
                             ServerError::Operation(err_msg)
                         })
@@ -2723,7 +5394,7 @@ pub(crate) mod responses {
                 }
             }
             Err(e) => {
-                let err_msg = format!("Database error: {}", e);
+                let err_msg = format!("Database error: {e}");
                 dual_error!("{} - request_id: {}", err_msg, request_id);
                 Err(ServerError::Operation(err_msg))
             }
@@ -2731,7 +5402,7 @@ pub(crate) mod responses {
     }
 
     pub(crate) async fn cancel_response_handler(
-        State(_state): State<Arc<AppState>>,
+        State(state): State<Arc<AppState>>,
         headers: HeaderMap,
         Path(response_id): Path<String>,
     ) -> ServerResult<axum::response::Response> {
@@ -2747,15 +5418,83 @@ pub(crate) mod responses {
             request_id
         );
 
-        // TODO: Implement response cancellation
-        // For now, return not found
-        Err(ServerError::Operation("Response cancellation not yet implemented".to_string()))
+        let cancel_token = state
+            .background_responses
+            .write()
+            .await
+            .remove(&response_id);
+        if let Some(cancel_token) = &cancel_token {
+            // abort the in-flight request to the downstream server
+            cancel_token.cancel();
+
+            if let Err(e) = state
+                .database
+                .update_response_status(&response_id, "cancelled")
+                .await
+            {
+                let err_msg = format!("Database error: {e}");
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                return Err(ServerError::Operation(err_msg));
+            }
+        }
+
+        let db_response = match state.database.get_response(&response_id).await {
+            Ok(Some(db_response)) => db_response,
+            Ok(None) => return Err(ServerError::Operation("Response not found".to_string())),
+            Err(e) => {
+                let err_msg = format!("Database error: {e}");
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                return Err(ServerError::Operation(err_msg));
+            }
+        };
+
+        if cancel_token.is_none() {
+            let err_msg = format!(
+                "Only background responses that are still running can be cancelled, the status of the response {} is {}",
+                response_id, db_response.status
+            );
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::BadRequest(err_msg));
+        }
+
+        dual_info!(
+            "Response {} cancelled - request_id: {}",
+            response_id,
+            request_id
+        );
+
+        let json_body =
+            // the response was cancelled before producing any output
+            serde_json::to_string(&from_response_session(db_response, Vec::new())).map_err(|e| {
+                let err_msg = format!("Failed to serialize response: {e}");
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                ServerError::Operation(err_msg)
+            })?;
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                ServerError::Operation(err_msg)
+            })
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct ListInputItemsQuery {
+        limit: Option<usize>,
+        after: Option<String>,
+        before: Option<String>,
+        order: Option<String>,
     }
 
     pub(crate) async fn list_input_items_handler(
-        State(_state): State<Arc<AppState>>,
+        State(state): State<Arc<AppState>>,
         headers: HeaderMap,
         Path(response_id): Path<String>,
+        Query(query): Query<ListInputItemsQuery>,
     ) -> ServerResult<axum::response::Response> {
         let request_id = headers
             .get("x-request-id")
@@ -2769,18 +5508,77 @@ pub(crate) mod responses {
             request_id
         );
 
-        // TODO: Implement input items listing from database
-        // For now, return empty list
-        let result = InputItemList {
-            object: "list".to_string(),
-            data: Vec::new(),
-            first_id: "".to_string(),
-            last_id: "".to_string(),
-            has_more: false,
-        };
+        let limit = query.limit.unwrap_or(20);
+        if !(1..=100).contains(&limit) {
+            let err_msg = format!("Invalid limit: {limit}, expected a value between 1 and 100");
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            return Err(ServerError::BadRequest(err_msg));
+        }
+
+        match state.database.get_response(&response_id).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(ServerError::Operation("Response not found".to_string())),
+            Err(e) => {
+                let err_msg = format!("Database error: {e}");
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                return Err(ServerError::Operation(err_msg));
+            }
+        }
+
+        let db_items = state
+            .database
+            .get_input_items(&response_id)
+            .await
+            .map_err(|e| {
+                let err_msg = format!("Database error: {e}");
+                dual_error!("{} - request_id: {}", err_msg, request_id);
+                ServerError::Operation(err_msg)
+            })?;
+
+        // convert the stored inputs to input items
+        let mut items = Vec::new();
+        for db_item in db_items {
+            match serde_json::from_str::<InputTypes>(&db_item.content) {
+                Ok(InputTypes::Text(text)) => items.push(crate::responses::InputItem {
+                    id: Some(db_item.id),
+                    item_type: db_item.item_type,
+                    role: db_item.role,
+                    content: crate::responses::InputContent::Text { text },
+                }),
+                Ok(InputTypes::Array(array)) => {
+                    // the inputs stored before each item got its own row share the same id
+                    let count = array.len();
+                    for (i, mut item) in array.into_iter().enumerate() {
+                        item.id = Some(match count {
+                            1 => db_item.id.clone(),
+                            _ => format!("{}_{i}", db_item.id),
+                        });
+                        items.push(item);
+                    }
+                }
+                Err(e) => dual_warn!(
+                    "Skipping the invalid input item {}: {} - request_id: {}",
+                    db_item.id,
+                    e,
+                    request_id
+                ),
+            }
+        }
+
+        let result = InputItemList::paginate(
+            items,
+            limit,
+            query.after.as_deref(),
+            query.before.as_deref(),
+            query.order.as_deref().unwrap_or("desc"),
+        )
+        .map_err(|err_msg| {
+            dual_error!("{} - request_id: {}", err_msg, request_id);
+            ServerError::BadRequest(err_msg)
+        })?;
 
         let json_body = serde_json::to_string(&result).map_err(|e| {
-            let err_msg = format!("Failed to serialize input items: {}", e);
+            let err_msg = format!("Failed to serialize input items: {e}");
             dual_error!("{} - request_id: {}", err_msg, request_id);
             ServerError::Operation(err_msg)
         })?;
@@ -2790,7 +5588,7 @@ pub(crate) mod responses {
             .header("Content-Type", "application/json")
             .body(Body::from(json_body))
             .map_err(|e| {
-                let err_msg = format!("Failed to create response: {}", e);
+                let err_msg = format!("Failed to create response: {e}");
                 dual_error!("{} - request_id: {}", err_msg, request_id);
                 ServerError::Operation(err_msg)
             })
@@ -2810,7 +5608,9 @@ pub(crate) mod responses {
                 history
             }
             Err(e) => {
-                return Err(crate::error::ServerError::Operation(format!("Failed to get conversation history: {}", e)));
+                return Err(crate::error::ServerError::Operation(format!(
+                    "Failed to get conversation history: {e}"
+                )));
             }
         };
 
@@ -2823,106 +5623,47 @@ pub(crate) mod responses {
                 ));
             }
 
-            // Get and add input items (user messages)
+            // Get and add input items (user messages and function call outputs)
             match database.get_input_items(&response.id).await {
                 Ok(input_items) => {
+                    let mut items = Vec::new();
                     for input_item in input_items {
                         // Parse the stored JSON content back to InputTypes
                         if let Ok(input_content) = serde_json::from_str::<crate::responses::InputTypes>(&input_item.content) {
                             match input_content {
                                 crate::responses::InputTypes::Text(text) => {
-                                    let role = input_item.role.as_ref().unwrap_or(&Role::User);
-                                    match role {
-                                        Role::User => {
-                                            messages.push(endpoints::chat::ChatCompletionRequestMessage::User(
-                                                endpoints::chat::ChatCompletionUserMessage::new(
-                                                    endpoints::chat::ChatCompletionUserMessageContent::Text(text),
-                                                    None
-                                                )
-                                            ));
-                                        }
-                                        Role::System => {
-                                            messages.push(endpoints::chat::ChatCompletionRequestMessage::System(
-                                                endpoints::chat::ChatCompletionSystemMessage::new(text, None)
-                                            ));
-                                        }
-                                        _ => {
-                                            // Default to user message
-                                            messages.push(endpoints::chat::ChatCompletionRequestMessage::User(
-                                                endpoints::chat::ChatCompletionUserMessage::new(
-                                                    endpoints::chat::ChatCompletionUserMessageContent::Text(text),
-                                                    None
-                                                )
-                                            ));
-                                        }
-                                    }
-                                }
-                                crate::responses::InputTypes::Array(items) => {
-                                    for item in items {
-                                        match &item.content {
-                                            crate::responses::InputContent::Text { text } => {
-                                                let role = item.role.as_ref().unwrap_or(&Role::User);
-                                                match role {
-                                                    Role::User => {
-                                                        messages.push(endpoints::chat::ChatCompletionRequestMessage::User(
-                                                            endpoints::chat::ChatCompletionUserMessage::new(
-                                                                endpoints::chat::ChatCompletionUserMessageContent::Text(text.clone()),
-                                                                None
-                                                            )
-                                                        ));
-                                                    }
-                                                    Role::System => {
-                                                        messages.push(endpoints::chat::ChatCompletionRequestMessage::System(
-                                                            endpoints::chat::ChatCompletionSystemMessage::new(text.clone(), None)
-                                                        ));
-                                                    }
-                                                    _ => {
-                                                        messages.push(endpoints::chat::ChatCompletionRequestMessage::User(
-                                                            endpoints::chat::ChatCompletionUserMessage::new(
-                                                                endpoints::chat::ChatCompletionUserMessageContent::Text(text.clone()),
-                                                                None
-                                                            )
-                                                        ));
-                                                    }
-                                                }
-                                            }
-                                            _ => {
-                                                // Skip non-text inputs for now
-                                            }
-                                        }
-                                    }
+                                    items.push(crate::responses::InputItem {
+                                        id: None,
+                                        item_type: "message".to_string(),
+                                        role: input_item.role,
+                                        content: crate::responses::InputContent::Text { text },
+                                    });
                                 }
+                                crate::responses::InputTypes::Array(array) => items.extend(array),
                             }
                         }
                     }
+                    messages.extend(crate::responses::input_items_to_messages(&items));
                 }
                 Err(e) => {
                     dual_warn!("Failed to get input items for response {}: {} - request_id: {}", response.id, e, request_id);
                 }
             }
 
-            // Get and add output items (assistant messages)
+            // Get and add output items (assistant messages and function calls)
             match database.get_output_items(&response.id).await {
                 Ok(output_items) => {
-                    for output_item in output_items {
-                        // Parse the stored JSON content back to OutputItem
-                        if let Ok(parsed_output) = serde_json::from_str::<crate::responses::OutputItem>(&output_item.content) {
-                            if let Some(content) = parsed_output.content {
-                                for content_item in content {
-                                    match content_item.content_data {
-                                        crate::responses::OutputContentData::Text { text, .. } => {
-                                            messages.push(endpoints::chat::ChatCompletionRequestMessage::Assistant(
-                                                endpoints::chat::ChatCompletionAssistantMessage::new(Some(text), None, None)
-                                            ));
-                                        }
-                                        _ => {
-                                            // Skip non-text outputs for now
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
+                    // Parse the stored JSON content back to OutputItem
+                    let items = output_items
+                        .iter()
+                        .filter_map(|output_item| {
+                            serde_json::from_str::<crate::responses::OutputItem>(
+                                &output_item.content,
+                            )
+                            .ok()
+                        })
+                        .collect::<Vec<_>>();
+                    messages.extend(crate::responses::output_items_to_message(&items));
                 }
                 Err(e) => {
                     dual_warn!("Failed to get output items for response {}: {} - request_id: {}", response.id, e, request_id);
@@ -2933,4 +5674,113 @@ pub(crate) mod responses {
         dual_debug!("Built conversation history with {} messages - request_id: {}", messages.len(), request_id);
         Ok(messages)
     }
+
+    #[tokio::test]
+    async fn test_cancel_background_response() {
+        async fn new_state(url: &str, db_path: &std::path::Path) -> Arc<AppState> {
+            let state = AppState::new(
+                crate::config::Config::default(),
+                crate::info::ServerInfo::default(),
+                db_path.to_str().unwrap(),
+            )
+            .await
+            .unwrap();
+            let server: Server =
+                serde_json::from_value(serde_json::json!({"url": url, "kind": "chat"})).unwrap();
+            state.register_downstream_server(server).await.unwrap();
+            Arc::new(state)
+        }
+
+        async fn create(state: &Arc<AppState>) -> String {
+            let request: ResponseRequest = serde_json::from_value(
+                serde_json::json!({"model": "test", "input": "hi", "background": true}),
+            )
+            .unwrap();
+            let response = create_response_handler(
+                State(state.clone()),
+                Extension(CancellationToken::new()),
+                HeaderMap::new(),
+                Json(request),
+            )
+            .await
+            .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(response["status"], "queued");
+            response["id"].as_str().unwrap().to_string()
+        }
+
+        async fn cancel(
+            state: &Arc<AppState>,
+            response_id: &str,
+        ) -> ServerResult<axum::response::Response> {
+            cancel_response_handler(
+                State(state.clone()),
+                HeaderMap::new(),
+                Path(response_id.to_string()),
+            )
+            .await
+        }
+
+        async fn status(state: &Arc<AppState>, response_id: &str) -> String {
+            let response = state.database.get_response(response_id).await.unwrap();
+            response.unwrap().status
+        }
+
+        let db_path =
+            std::env::temp_dir().join(format!("llama-nexus-test-{}.db", uuid::Uuid::new_v4()));
+
+        // a chat server accepting the connections but never responding
+        let hung_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hung_url = format!("http://{}", hung_listener.local_addr().unwrap());
+        let state = new_state(&hung_url, &db_path).await;
+
+        // cancel a running response
+        let response_id = create(&state).await;
+        let response = cancel(&state, &response_id).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["status"], "cancelled");
+
+        // the background task stops without overwriting the status
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(status(&state, &response_id).await, "cancelled");
+
+        // a cancelled response cannot be cancelled again
+        let err = cancel(&state, &response_id).await.unwrap_err();
+        assert!(matches!(err, ServerError::BadRequest(_)));
+
+        // an unknown response
+        let err = cancel(&state, "resp_unknown").await.unwrap_err();
+        assert!(err.to_string().contains("Response not found"));
+
+        // a finished response cannot be cancelled
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = axum::Router::new().fallback(|| async { StatusCode::BAD_REQUEST });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let state = new_state(&url, &db_path).await;
+        let response_id = create(&state).await;
+        while state
+            .background_responses
+            .read()
+            .await
+            .contains_key(&response_id)
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let finished_status = status(&state, &response_id).await;
+        assert!(!["queued", "in_progress", "cancelled"].contains(&finished_status.as_str()));
+        let err = cancel(&state, &response_id).await.unwrap_err();
+        assert!(matches!(err, ServerError::BadRequest(_)));
+        assert_eq!(status(&state, &response_id).await, finished_status);
+
+        drop(hung_listener);
+        let _ = std::fs::remove_file(&db_path);
+    }
 }
@@ -13,12 +13,12 @@ use std::{
     collections::{HashMap, HashSet},
     net::{IpAddr, SocketAddr},
     path::PathBuf,
-    str::FromStr,
     sync::Arc,
 };
 
 use axum::{
     body::Body,
+    extract::State,
     http::{self, HeaderValue, Request},
     routing::{Router, get, post, delete},
 };
@@ -40,11 +40,13 @@ use uuid::Uuid;
 use crate::{
     database::DatabaseManager,
     info::ServerInfo,
-    server::{Server, ServerGroup, ServerId, ServerKind},
+    server::{CircuitState, HealthCheckRecord, Server, ServerGroup, ServerId, ServerKind},
 };
 
 // Global health check interval for downstream servers in seconds
 pub(crate) static HEALTH_CHECK_INTERVAL: OnceCell<u64> = OnceCell::new();
+// Granularity of the health check intervals of downstream servers
+const HEALTH_CHECK_TICK: std::time::Duration = std::time::Duration::from_secs(1);
 
 #[derive(Debug, Parser)]
 #[command(version = env!("CARGO_PKG_VERSION"), about = "LlamaEdge Nexus - A gateway service for LLM backends")]
@@ -125,6 +127,12 @@ async fn main() -> ServerResult<()> {
     let database_path = "responses.db";
     let state = Arc::new(AppState::new(config, ServerInfo::default(), database_path).await?);
 
+    // Restore the downstream servers registered before the last shutdown
+    state.restore_downstream_servers().await?;
+
+    // Register the downstream servers declared in the config file
+    state.register_static_downstream_servers().await?;
+
     // Start the health check task if enabled
     if cli.check_health {
         dual_info!("Health check is enabled");
@@ -174,6 +182,35 @@ async fn main() -> ServerResult<()> {
                 "/admin/servers",
                 get(handlers::admin::list_downstream_servers_handler),
             )
+            .route(
+                "/admin/servers/{id}",
+                get(handlers::admin::get_downstream_server_handler)
+                    .patch(handlers::admin::update_downstream_server_handler),
+            )
+            .route(
+                "/admin/servers/{id}/drain",
+                post(handlers::admin::drain_downstream_server_handler)
+                    .delete(handlers::admin::resume_downstream_server_handler),
+            )
+            .route(
+                "/admin/servers/{id}/health",
+                get(handlers::admin::server_health_handler),
+            )
+            .route(
+                "/admin/mcp/servers",
+                get(handlers::admin::list_mcp_servers_handler)
+                    .post(handlers::admin::add_mcp_server_handler),
+            )
+            .route(
+                "/admin/mcp/servers/{name}",
+                get(handlers::admin::get_mcp_server_handler)
+                    .patch(handlers::admin::update_mcp_server_handler)
+                    .delete(handlers::admin::remove_mcp_server_handler),
+            )
+            .route(
+                "/admin/mcp/servers/{name}/refresh",
+                post(handlers::admin::refresh_mcp_server_handler),
+            )
             .layer(cors)
             .layer(TraceLayer::new_for_http())
             .layer(axum::middleware::from_fn(
@@ -220,7 +257,12 @@ async fn main() -> ServerResult<()> {
         axum::serve(listener, app.into_make_service()).with_graceful_shutdown(shutdown_signal());
 
     // Start the server
-    match server.await {
+    let result = server.await;
+
+    // Disconnect the mcp servers, and stop the stdio ones spawned by the gateway
+    mcp::stop_mcp_servers().await;
+
+    match result {
         Ok(_) => {
             dual_info!("Server shutdown completed");
             Ok(())
@@ -380,11 +422,26 @@ pub(crate) struct AppState {
     server_info: Arc<RwLock<ServerInfo>>,
     models: Arc<RwLock<HashMap<ServerId, Vec<endpoints::models::Model>>>>,
     database: Arc<DatabaseManager>,
+    /// Cancellation tokens of the background responses that are still running, keyed by the
+    /// response id
+    background_responses: Arc<RwLock<HashMap<String, CancellationToken>>>,
+    /// Client of the requests forwarded to the downstream servers, shared so that the connections
+    /// are pooled
+    http_client: reqwest::Client,
 }
 impl AppState {
     pub(crate) async fn new(config: Config, server_info: ServerInfo, database_path: &str) -> ServerResult<Self> {
         let database = DatabaseManager::new(database_path).await
-            .map_err(|e| ServerError::Operation(format!("Failed to initialize database: {}", e)))?;
+            .map_err(|e| ServerError::Operation(format!("Failed to initialize database: {e}")))?;
+
+        let http_client = reqwest::Client::builder()
+            .connect_timeout(std::time::Duration::from_secs(
+                config.failover.connect_timeout,
+            ))
+            .build()
+            .map_err(|e| {
+                ServerError::Operation(format!("Failed to create the HTTP client: {e}"))
+            })?;
 
         Ok(Self {
             server_group: Arc::new(RwLock::new(HashMap::new())),
@@ -392,92 +449,162 @@ impl AppState {
             server_info: Arc::new(RwLock::new(server_info)),
             models: Arc::new(RwLock::new(HashMap::new())),
             database: Arc::new(database),
+            background_responses: Arc::new(RwLock::new(HashMap::new())),
+            http_client,
         })
     }
 
     pub(crate) async fn register_downstream_server(&self, server: Server) -> ServerResult<()> {
-        if server.kind.contains(ServerKind::chat) {
-            self.server_group
-                .write()
-                .await
-                .entry(ServerKind::chat)
-                .or_insert(ServerGroup::new(ServerKind::chat))
-                .register(server.clone())
-                .await?;
-        }
-        if server.kind.contains(ServerKind::embeddings) {
-            self.server_group
-                .write()
-                .await
-                .entry(ServerKind::embeddings)
-                .or_insert(ServerGroup::new(ServerKind::embeddings))
-                .register(server.clone())
-                .await?;
-        }
-        if server.kind.contains(ServerKind::image) {
-            self.server_group
-                .write()
-                .await
-                .entry(ServerKind::image)
-                .or_insert(ServerGroup::new(ServerKind::image))
-                .register(server.clone())
-                .await?;
-        }
-        if server.kind.contains(ServerKind::tts) {
+        for kind in server.kind.iter() {
+            let policy = self.config.read().await.routing.policy_for(kind);
+
             self.server_group
                 .write()
                 .await
-                .entry(ServerKind::tts)
-                .or_insert(ServerGroup::new(ServerKind::tts))
+                .entry(kind)
+                .or_insert_with(|| ServerGroup::new(kind, policy))
                 .register(server.clone())
                 .await?;
         }
-        if server.kind.contains(ServerKind::translate) {
-            self.server_group
-                .write()
+
+        Ok(())
+    }
+
+    /// Restore the downstream servers persisted in the database.
+    ///
+    /// Each server is re-verified by fetching its model list. The servers failing the verification,
+    /// e.g. as they are temporarily down, are registered with an open circuit.
+    pub(crate) async fn restore_downstream_servers(self: &Arc<Self>) -> ServerResult<()> {
+        let records = self.database.get_servers().await.map_err(|e| {
+            let err_msg = format!("Failed to load the downstream servers from database: {e}");
+            dual_error!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })?;
+
+        for record in records {
+            let server = match Server::try_from(record) {
+                Ok(server) => server,
+                Err(e) => {
+                    dual_warn!("Skip restoring a downstream server: {}", e);
+                    continue;
+                }
+            };
+
+            let (server_id, server_kind) = (server.id.clone(), server.kind);
+            match self
+                .verify_and_register_downstream_server(server.clone())
                 .await
-                .entry(ServerKind::translate)
-                .or_insert(ServerGroup::new(ServerKind::translate))
-                .register(server.clone())
-                .await?;
+            {
+                Ok(()) => dual_info!("Restored {} server: {}", server_kind, server_id),
+                Err(e) => {
+                    dual_warn!(
+                        "Failed to verify the restored {} server {}: {}",
+                        server_kind,
+                        server_id,
+                        e
+                    );
+                    if let Err(e) = self.register_unverified_downstream_server(server).await {
+                        dual_warn!("Skip restoring {} server {}: {}", server_kind, server_id, e);
+                    }
+                }
+            }
         }
-        if server.kind.contains(ServerKind::transcribe) {
-            self.server_group
-                .write()
+
+        Ok(())
+    }
+
+    /// Register the downstream servers declared in the `[[downstream]]` section of the config.
+    ///
+    /// Invalid declarations abort the startup, while the servers failing the verification, e.g. as
+    /// they are temporarily down, are registered with an open circuit. The declared servers are
+    /// not persisted in the database.
+    pub(crate) async fn register_static_downstream_servers(self: &Arc<Self>) -> ServerResult<()> {
+        let declared = self.config.read().await.downstream.clone();
+
+        for downstream in declared.iter() {
+            let server = Server::try_from(downstream).inspect_err(|e| dual_error!("{}", e))?;
+
+            let (server_id, server_kind) = (server.id.clone(), server.kind);
+            match self
+                .verify_and_register_downstream_server(server.clone())
                 .await
-                .entry(ServerKind::transcribe)
-                .or_insert(ServerGroup::new(ServerKind::transcribe))
-                .register(server.clone())
-                .await?;
+            {
+                Ok(()) => dual_info!(
+                    "Registered {} server {} from config: {}",
+                    server_kind,
+                    server_id,
+                    downstream.url
+                ),
+                Err(e) => {
+                    dual_warn!(
+                        "Failed to verify the {} server {} from config: {}",
+                        server_kind,
+                        server_id,
+                        e
+                    );
+                    if let Err(e) = self.register_unverified_downstream_server(server).await {
+                        dual_warn!(
+                            "Skip registering {} server {} from config: {}",
+                            server_kind,
+                            server_id,
+                            e
+                        );
+                    }
+                }
+            }
         }
 
         Ok(())
     }
 
+    /// Fetch the model list of the server and register it if the model list is available
+    async fn verify_and_register_downstream_server(
+        self: &Arc<Self>,
+        server: Server,
+    ) -> ServerResult<()> {
+        handlers::admin::update_model_list(
+            State(Arc::clone(self)),
+            &http::HeaderMap::new(),
+            "startup",
+            &server,
+        )
+        .await?;
+
+        self.register_downstream_server(server).await
+    }
+
+    /// Register a server whose model list is unavailable with an open circuit, so that it is
+    /// skipped until the health check finds it healthy and fetches its model list
+    async fn register_unverified_downstream_server(&self, server: Server) -> ServerResult<()> {
+        let circuit_breaker = self.config.read().await.circuit_breaker.clone();
+        server.stats().trip(&circuit_breaker);
+
+        let (server_id, server_kind) = (server.id.clone(), server.kind);
+        self.register_downstream_server(server).await?;
+        dual_warn!(
+            "Registered {} server {} with an open circuit, its model list is fetched once it is healthy",
+            server_kind,
+            server_id
+        );
+
+        Ok(())
+    }
+
     pub(crate) async fn unregister_downstream_server(
         &self,
         server_id: impl AsRef<str>,
     ) -> ServerResult<()> {
         let mut found = false;
 
-        // unregister the server from the servers
+        // unregister the server from the server groups containing it. The server kinds are not
+        // parsed from the server id, since the servers declared in the config may have custom ids.
         {
-            // parse server kind from server id
-            let kinds = server_id
-                .as_ref()
-                .split("-server-")
-                .next()
-                .unwrap()
-                .split("-")
-                .collect::<Vec<&str>>();
-
             let group_map = self.server_group.read().await;
 
-            for kind in kinds {
-                let kind = ServerKind::from_str(kind).unwrap();
-                if let Some(group) = group_map.get(&kind) {
+            for (kind, group) in group_map.iter() {
+                if group.contains(server_id.as_ref()).await {
                     group.unregister(server_id.as_ref()).await?;
-                    dual_info!("Unregistered {} server: {}", &kind, server_id.as_ref());
+                    dual_info!("Unregistered {} server: {}", kind, server_id.as_ref());
 
                     if !found {
                         found = true;
@@ -506,6 +633,47 @@ impl AppState {
         Ok(())
     }
 
+    /// Replace a registered downstream server with its updated copy. The server is moved to the
+    /// server groups of its new kinds if they have changed.
+    ///
+    /// Fails if the server is no longer registered, e.g. as it has been removed concurrently.
+    pub(crate) async fn update_downstream_server(&self, server: Server) -> ServerResult<()> {
+        let routing = self.config.read().await.routing.clone();
+        let mut group_map = self.server_group.write().await;
+
+        let mut registered = false;
+        for group in group_map.values() {
+            if group.contains(&server.id).await {
+                registered = true;
+                break;
+            }
+        }
+        if !registered {
+            return Err(ServerError::ServerNotFound(server.id));
+        }
+
+        // remove the server from the groups of the kinds it no longer has
+        for (kind, group) in group_map.iter() {
+            if !server.kind.contains(*kind) && group.contains(&server.id).await {
+                group.unregister(&server.id).await?;
+                dual_info!("Unregistered {} server: {}", kind, &server.id);
+            }
+        }
+
+        for kind in server.kind.iter() {
+            let group = group_map
+                .entry(kind)
+                .or_insert_with(|| ServerGroup::new(kind, routing.policy_for(kind)));
+
+            if !group.replace(server.clone()).await {
+                group.register(server.clone()).await?;
+                dual_info!("Registered {} server: {}", kind, &server.id);
+            }
+        }
+
+        Ok(())
+    }
+
     pub(crate) async fn list_downstream_servers(
         &self,
     ) -> ServerResult<HashMap<ServerKind, Vec<Server>>> {
@@ -532,51 +700,102 @@ impl AppState {
         Ok(server_groups)
     }
 
-    pub(crate) async fn check_server_health(&self) -> ServerResult<()> {
-        if !self.server_group.read().await.is_empty() {
-            let mut unhealthy_servers = Vec::new();
+    /// Returns the ids of the downstream servers whose model list contains the given model
+    pub(crate) async fn servers_for_model(&self, model: impl AsRef<str>) -> HashSet<ServerId> {
+        let model = model.as_ref();
+
+        self.models
+            .read()
+            .await
+            .iter()
+            .filter(|(_, models)| models.iter().any(|m| m.id == model))
+            .map(|(server_id, _)| server_id.clone())
+            .collect()
+    }
 
+    pub(crate) async fn check_server_health(self: &Arc<Self>) -> ServerResult<()> {
+        if !self.server_group.read().await.is_empty() {
             // Check health status of downstream servers
-            // 1. Get all registered downstream servers
+            // 1. Get all registered downstream servers whose health check interval has elapsed
             // 2. Check health status of downstream servers
             //   2.1 If a downstream server has multiple types, only perform one health check
             //   2.2 If there are multiple downstream servers of the same type, health checks are needed for all
-            //   2.3 If two or more downstream servers have different types but the same URL, only perform one health check
-            // 3. Remove unhealthy downstream servers
+            //   2.3 If two or more downstream servers have different types but the same probe URL, only perform one health check
+            // 3. Open the circuit of unhealthy downstream servers, and close the circuit of recovered ones
+            let mut due_servers: HashMap<ServerId, Server> = HashMap::new();
             {
                 let group_map = self.server_group.read().await;
-
-                // check health of unique servers
-                let mut unique_server_ids = HashSet::new();
-                for (kind, group) in group_map.iter() {
-                    if !group.is_empty().await {
-                        let servers = group.servers.read().await;
-                        for server_lock in servers.iter() {
-                            let mut server = server_lock.write().await;
-
-                            if !unique_server_ids.contains(&server.id)
-                                && unique_server_ids.contains(&server.url)
-                            {
-                                dual_info!("Checking health of {}", &server.id);
-
-                                unique_server_ids.insert(server.id.clone());
-                                unique_server_ids.insert(server.url.clone());
-
-                                let is_healthy = server.check_health().await;
-                                if !is_healthy {
-                                    dual_warn!("{} server {} is unhealthy", kind, &server.id);
-                                    unhealthy_servers.push(server.id.clone());
-                                }
-                            }
+                for group in group_map.values() {
+                    for server_lock in group.servers.read().await.iter() {
+                        let server = server_lock.read().await;
+                        if !due_servers.contains_key(&server.id) && server.is_health_check_due() {
+                            // the clone shares the health history and the circuit breaker with
+                            // the registered server
+                            due_servers.insert(server.id.clone(), server.clone());
                         }
                     }
                 }
             }
+            if due_servers.is_empty() {
+                return Ok(());
+            }
+
+            let circuit_breaker = self.config.read().await.circuit_breaker.clone();
+            let mut probed: HashMap<String, HealthCheckRecord> = HashMap::new();
+            for server in due_servers.values() {
+                let probe_url = server.health_probe_url();
+                let is_healthy = match probed.get(&probe_url) {
+                    Some(record) => {
+                        server.stats().record_health(record.clone());
+                        record.healthy
+                    }
+                    None => {
+                        dual_debug!("Checking health of {}", &server.id);
 
-            // Unregister unhealthy servers
-            if !unhealthy_servers.is_empty() {
-                for server_id in unhealthy_servers {
-                    self.unregister_downstream_server(&server_id).await?;
+                        let record = server.check_health().await;
+                        let is_healthy = record.healthy;
+                        probed.insert(probe_url, record);
+                        is_healthy
+                    }
+                };
+
+                // a server registered while it was down has no model list yet
+                if is_healthy && !self.models.read().await.contains_key(&server.id) {
+                    let model_list = handlers::admin::update_model_list(
+                        State(Arc::clone(self)),
+                        &http::HeaderMap::new(),
+                        "health-check",
+                        server,
+                    )
+                    .await;
+                    if let Err(e) = model_list {
+                        dual_warn!(
+                            "Failed to fetch the model list of {} server {}: {}",
+                            server.kind,
+                            &server.id,
+                            e
+                        );
+                        server.stats().trip(&circuit_breaker);
+                        continue;
+                    }
+                    dual_info!(
+                        "Fetched the model list of {} server {}",
+                        server.kind,
+                        &server.id
+                    );
+                }
+
+                if is_healthy {
+                    if server.stats().record_success() {
+                        dual_info!("{} server {} has recovered", server.kind, &server.id);
+                    }
+                } else if server.stats().trip(&circuit_breaker) {
+                    dual_warn!(
+                        "The circuit of {} server {} is open for {} seconds",
+                        server.kind,
+                        &server.id,
+                        circuit_breaker.open_duration
+                    );
                 }
             }
 
@@ -587,14 +806,20 @@ impl AppState {
                 {
                     let group_map = self.server_group.read().await;
                     for (kind, group) in group_map.iter() {
-                        if group.is_empty().await {
+                        // the servers with an open circuit are not available
+                        let mut server_ids = Vec::new();
+                        for server_lock in group.servers.read().await.iter() {
+                            let server = server_lock.read().await;
+                            if server.stats().circuit_state() != CircuitState::Open {
+                                server_ids.push(server.id.clone());
+                            }
+                        }
+
+                        if server_ids.is_empty() {
                             dual_warn!("No {} servers available after health check", kind);
                         }
 
-                        healthy_servers.insert(
-                            *kind,
-                            group.healthy_servers.read().await.iter().cloned().collect(),
-                        );
+                        healthy_servers.insert(*kind, server_ids);
                     }
                 }
 
@@ -623,26 +848,126 @@ impl AppState {
                     })?;
             }
         } else {
-            dual_warn!("No servers registered, skipping health check");
+            dual_debug!("No servers registered, skipping health check");
         }
 
         Ok(())
     }
 
+    /// Start the task probing the downstream servers. Each server is probed once its own health
+    /// check interval has elapsed, so the task wakes up every `HEALTH_CHECK_TICK`.
     pub(crate) async fn start_health_check_task(self: Arc<Self>) {
-        let check_interval = HEALTH_CHECK_INTERVAL.get().unwrap_or(&60);
-        let check_interval = tokio::time::Duration::from_secs(*check_interval);
-
         tokio::spawn(async move {
             loop {
-                dual_debug!("Starting health check");
-
                 if let Err(e) = self.check_server_health().await {
                     dual_error!("Health check error: {}", e);
                 }
 
-                tokio::time::sleep(check_interval).await;
+                tokio::time::sleep(HEALTH_CHECK_TICK).await;
             }
         });
     }
+
+    /// Find a registered downstream server by its id
+    pub(crate) async fn find_downstream_server(
+        &self,
+        server_id: impl AsRef<str>,
+    ) -> Option<Server> {
+        let group_map = self.server_group.read().await;
+        for group in group_map.values() {
+            for server_lock in group.servers.read().await.iter() {
+                let server = server_lock.read().await;
+                if server.id == server_id.as_ref() {
+                    return Some(server.clone());
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[tokio::test]
+async fn test_register_unreachable_static_downstream_server() {
+    use crate::{config::DownstreamConfig, server::CircuitState};
+
+    // nothing listens on the port
+    let url = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+    let downstream: DownstreamConfig =
+        serde_json::from_value(serde_json::json!({"url": url, "kind": "chat"})).unwrap();
+    let mut config = Config::default();
+    config.downstream.push(downstream);
+    let state = AppState::new(config, ServerInfo::default(), "sqlite::memory:")
+        .await
+        .unwrap();
+    let state = Arc::new(state);
+
+    // the server is registered with an open circuit instead of being dropped
+    state.register_static_downstream_servers().await.unwrap();
+    let servers = state.list_downstream_servers().await.unwrap();
+    let server = servers[&ServerKind::chat][0].clone();
+    assert_eq!(server.stats().circuit_state(), CircuitState::Open);
+}
+
+#[tokio::test]
+async fn test_restore_unreachable_downstream_server() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use axum::response::IntoResponse;
+
+    use crate::server::CircuitState;
+
+    // a chat server whose model list is unavailable until it is up
+    let up = Arc::new(AtomicBool::new(false));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let models = {
+        let up = up.clone();
+        move || async move {
+            match up.load(Ordering::Relaxed) {
+                true => axum::Json(serde_json::json!({
+                    "object": "list",
+                    "data": [{"id": "llama", "created": 0, "object": "model", "owned_by": "test"}]
+                }))
+                .into_response(),
+                false => http::StatusCode::SERVICE_UNAVAILABLE.into_response(),
+            }
+        }
+    };
+    let app = Router::new().route("/models", get(models));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let db_path = std::env::temp_dir().join(format!("llama-nexus-test-{}.db", Uuid::new_v4()));
+    let server: Server =
+        serde_json::from_value(serde_json::json!({"url": url, "kind": "chat"})).unwrap();
+    let state = AppState::new(
+        Config::default(),
+        ServerInfo::default(),
+        db_path.to_str().unwrap(),
+    )
+    .await
+    .unwrap();
+    state
+        .database
+        .store_server(server.to_record())
+        .await
+        .unwrap();
+    let state = Arc::new(state);
+
+    // the server is restored with an open circuit
+    state.restore_downstream_servers().await.unwrap();
+    let restored = state.find_downstream_server(&server.id).await.unwrap();
+    assert_eq!(restored.stats().circuit_state(), CircuitState::Open);
+    assert!(state.servers_for_model("llama").await.is_empty());
+
+    // the health check fetches its model list once it is up
+    up.store(true, Ordering::Relaxed);
+    state.check_server_health().await.unwrap();
+    assert_eq!(restored.stats().circuit_state(), CircuitState::Closed);
+    assert!(state.servers_for_model("llama").await.contains(&server.id));
+
+    let _ = std::fs::remove_file(&db_path);
 }
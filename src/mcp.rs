@@ -1,18 +1,38 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use once_cell::sync::OnceCell;
+use endpoints::chat::McpTransport;
+use once_cell::sync::{Lazy, OnceCell};
 use rmcp::{
     RoleClient,
+    model::Tool as RmcpTool,
     service::{DynService, RunningService},
 };
-use tokio::sync::RwLock as TokioRwLock;
+use serde::Serialize;
+use tokio::{
+    sync::{Mutex, Notify, RwLock as TokioRwLock},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
 
-// Global MCP tools and clients
-pub static MCP_TOOLS: OnceCell<TokioRwLock<HashMap<McpToolName, ServiceName>>> = OnceCell::new();
+// Global MCP tools and clients, by the tool names advertised to the model
+pub static MCP_TOOLS: OnceCell<TokioRwLock<HashMap<McpToolName, McpToolTarget>>> = OnceCell::new();
 // Global MCP clients
 pub static MCP_SERVICES: OnceCell<TokioRwLock<HashMap<ServiceName, TokioRwLock<McpService>>>> =
     OnceCell::new();
 
+// Shutdown signal for the supervisors of the mcp servers
+pub(crate) static MCP_SHUTDOWN: Lazy<CancellationToken> = Lazy::new(CancellationToken::new);
+// Supervisor tasks of the mcp servers
+pub(crate) static MCP_SUPERVISORS: Lazy<Mutex<HashMap<ServiceName, JoinHandle<()>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// Connection status of the mcp servers, maintained by their supervisors
+pub(crate) static MCP_SERVER_STATUS: Lazy<TokioRwLock<HashMap<ServiceName, McpServerStatus>>> =
+    Lazy::new(|| TokioRwLock::new(HashMap::new()));
+
 pub(crate) const SEARCH_MCP_SERVER_NAMES: [&str; 5] = [
     "cardea-agentic-search-mcp-server",
     "cardea-tidb-mcp-server",
@@ -26,6 +46,16 @@ pub type RawMcpService = RunningService<RoleClient, Box<dyn DynService<RoleClien
 pub type ServiceName = String;
 pub type McpToolName = String;
 
+/// Separator of the namespace and the name of a namespaced tool, e.g. `server__tool`
+pub(crate) const MCP_TOOL_NAMESPACE_SEPARATOR: &str = "__";
+
+/// The mcp server providing a tool, and the name of the tool on that server
+#[derive(Debug, Clone)]
+pub struct McpToolTarget {
+    pub server: ServiceName,
+    pub tool: McpToolName,
+}
+
 #[allow(dead_code)]
 pub struct McpService {
     pub name: ServiceName,
@@ -51,3 +81,212 @@ impl McpService {
         }
     }
 }
+
+/// Connection state of an mcp server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum McpConnectionState {
+    /// The first connection attempt is in progress
+    Connecting,
+    /// The server is connected and its tools are available
+    Connected,
+    /// The connection failed or was lost, and a new attempt is scheduled
+    Reconnecting,
+    /// The server is not enabled
+    Disabled,
+}
+
+/// Runtime status of an mcp server
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct McpServerStatus {
+    pub name: ServiceName,
+    pub transport: McpTransport,
+    pub state: McpConnectionState,
+    pub tools: Vec<McpToolName>,
+    /// Unix timestamp of the last successful connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connected_at: Option<u64>,
+    /// Number of consecutive failed connection attempts
+    pub failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// Unix timestamp of the next connection attempt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_retry_at: Option<u64>,
+    /// Tools exposed to the model while the server is connected
+    #[serde(skip)]
+    pub definitions: Vec<RmcpTool>,
+    /// Wakes up the supervisor to check the connection
+    #[serde(skip)]
+    pub check: Arc<Notify>,
+    /// Stops the supervisor, disconnecting the server
+    #[serde(skip)]
+    pub stop: CancellationToken,
+    /// Position of the server in the config. A tool name provided by several servers is given to
+    /// the first one.
+    #[serde(skip)]
+    pub order: usize,
+}
+impl McpServerStatus {
+    pub(crate) fn new(name: ServiceName, transport: McpTransport) -> Self {
+        Self {
+            name,
+            transport,
+            state: McpConnectionState::Connecting,
+            tools: Vec::new(),
+            connected_at: None,
+            failures: 0,
+            last_error: None,
+            next_retry_at: None,
+            definitions: Vec::new(),
+            check: Arc::new(Notify::new()),
+            stop: MCP_SHUTDOWN.child_token(),
+            order: usize::MAX,
+        }
+    }
+
+    pub(crate) fn disabled(name: ServiceName, transport: McpTransport) -> Self {
+        Self {
+            state: McpConnectionState::Disabled,
+            ..Self::new(name, transport)
+        }
+    }
+
+    pub(crate) fn set_connected(&mut self, tools: &[RmcpTool]) {
+        if self.state != McpConnectionState::Connected {
+            self.connected_at = Some(unix_now());
+        }
+        self.state = McpConnectionState::Connected;
+        self.tools = tools.iter().map(|tool| tool.name.to_string()).collect();
+        self.definitions = tools.to_vec();
+        self.failures = 0;
+        self.next_retry_at = None;
+    }
+
+    /// Stop offering the tool, whose name has been given to a server coming first in the config
+    pub(crate) fn remove_tool(&mut self, name: &str) {
+        self.tools.retain(|tool| tool != name);
+        self.definitions.retain(|tool| tool.name != name);
+    }
+
+    /// Record a failed or lost connection, to be retried in `retry_in` if it is set
+    pub(crate) fn set_unavailable(&mut self, error: String, retry_in: Option<Duration>) {
+        // a lost connection is not a failed attempt
+        if self.state != McpConnectionState::Connected {
+            self.failures += 1;
+        }
+        self.state = McpConnectionState::Reconnecting;
+        self.definitions.clear();
+        self.last_error = Some(error);
+        self.next_retry_at = retry_in.map(|retry_in| unix_now() + retry_in.as_secs());
+    }
+}
+
+#[test]
+fn test_mcp_server_status() {
+    let tools = [RmcpTool::new(
+        "search",
+        "",
+        Arc::new(serde_json::Map::new()),
+    )];
+    let mut status = McpServerStatus::new("search-server".to_string(), McpTransport::Sse);
+
+    // failed connection attempts are counted
+    status.set_unavailable(
+        "connection refused".to_string(),
+        Some(Duration::from_secs(1)),
+    );
+    status.set_unavailable(
+        "connection refused".to_string(),
+        Some(Duration::from_secs(2)),
+    );
+    assert_eq!(status.state, McpConnectionState::Reconnecting);
+    assert_eq!(status.failures, 2);
+    assert_eq!(status.last_error.as_deref(), Some("connection refused"));
+    let next_retry_at = status.next_retry_at.unwrap();
+    assert!((unix_now() + 1..=unix_now() + 2).contains(&next_retry_at));
+
+    // connecting resets the failures
+    status.set_connected(&tools);
+    assert_eq!(status.state, McpConnectionState::Connected);
+    assert_eq!(status.failures, 0);
+    assert_eq!(status.next_retry_at, None);
+    assert!(status.connected_at.is_some());
+    assert_eq!(status.tools, ["search"]);
+    assert_eq!(status.definitions.len(), 1);
+
+    // a lost connection is not a failed attempt, but its tools are withdrawn
+    status.set_unavailable("connection lost".to_string(), Some(Duration::from_secs(60)));
+    assert_eq!(status.state, McpConnectionState::Reconnecting);
+    assert_eq!(status.failures, 0);
+    assert!(status.definitions.is_empty());
+    assert_eq!(status.last_error.as_deref(), Some("connection lost"));
+    assert!(status.next_retry_at.unwrap() >= unix_now() + 59);
+
+    // the reconnection attempts fail again
+    status.set_unavailable(
+        "connection refused".to_string(),
+        Some(Duration::from_secs(1)),
+    );
+    assert_eq!(status.failures, 1);
+
+    // without a retry, e.g. for an oauth server
+    status.set_unavailable("authorization denied".to_string(), None);
+    assert_eq!(status.failures, 2);
+    assert_eq!(status.next_retry_at, None);
+}
+
+/// Get the tools of the mcp server, or `None` if the server is not connected
+pub(crate) async fn available_mcp_tools(name: &str) -> Option<Vec<RmcpTool>> {
+    MCP_SERVER_STATUS
+        .read()
+        .await
+        .get(name)
+        .filter(|status| status.state == McpConnectionState::Connected)
+        .map(|status| status.definitions.clone())
+}
+
+/// Ask the supervisor of the mcp server to check its connection, e.g. after a failed tool call
+pub(crate) async fn check_mcp_server(name: &str) {
+    if let Some(status) = MCP_SERVER_STATUS.read().await.get(name) {
+        status.check.notify_one();
+    }
+}
+
+/// Get the status of the mcp server, which is disabled if it has no supervisor
+pub(crate) async fn mcp_server_status(name: &str, transport: McpTransport) -> McpServerStatus {
+    match MCP_SERVER_STATUS.read().await.get(name) {
+        Some(status) => status.clone(),
+        None => McpServerStatus::disabled(name.to_string(), transport),
+    }
+}
+
+/// Stop the supervisor of the mcp server, disconnecting the server and removing its tools
+pub(crate) async fn stop_mcp_server(name: &str) {
+    if let Some(status) = MCP_SERVER_STATUS.write().await.remove(name) {
+        status.stop.cancel();
+    }
+
+    let supervisor = MCP_SUPERVISORS.lock().await.remove(name);
+    if let Some(supervisor) = supervisor {
+        let _ = supervisor.await;
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Disconnect the mcp servers, killing the child processes of the stdio servers, and wait for
+/// their supervisors to exit
+pub(crate) async fn stop_mcp_servers() {
+    MCP_SHUTDOWN.cancel();
+
+    let supervisors = std::mem::take(&mut *MCP_SUPERVISORS.lock().await);
+    for supervisor in supervisors.into_values() {
+        let _ = supervisor.await;
+    }
+}
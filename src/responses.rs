@@ -1,3 +1,4 @@
+use base64::Engine;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use crate::types::{Role, Metadata};
@@ -54,7 +55,10 @@ pub enum InputTypes {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InputItem {
-    #[serde(rename = "type")]
+    /// Id of the stored item, only set in the input item list of a response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", default = "default_item_type")]
     pub item_type: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub role: Option<Role>,
@@ -76,6 +80,134 @@ pub enum InputContent {
         #[serde(skip_serializing_if = "Option::is_none")]
         purpose: Option<String>,
     },
+    /// A function call of the model, replayed by the client
+    FunctionCall {
+        call_id: String,
+        name: String,
+        arguments: String,
+    },
+    /// The result of a function call, returned by the client
+    FunctionCallOutput {
+        call_id: String,
+        output: String,
+    },
+    /// A message with a text content or a list of content parts
+    Message {
+        content: MessageContent,
+    },
+}
+
+fn default_item_type() -> String {
+    "message".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<InputContentPart>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum InputContentPart {
+    /// A text, or the text output of the model replayed by the client
+    #[serde(rename = "input_text", alias = "output_text")]
+    Text { text: String },
+    #[serde(rename = "input_image")]
+    Image {
+        /// URL of the image, or base64 encoded image in a data URL
+        #[serde(skip_serializing_if = "Option::is_none")]
+        image_url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        file_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+    #[serde(rename = "input_file")]
+    File {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        file_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        filename: Option<String>,
+        /// Base64 encoded content of the file in a data URL
+        #[serde(skip_serializing_if = "Option::is_none")]
+        file_data: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        file_url: Option<String>,
+    },
+}
+impl InputContentPart {
+    /// Convert the part to a content part of a chat completion user message
+    ///
+    /// Images are passed by URL or data URL. Text files are decoded and inlined as text, since
+    /// the chat completions API has no file content part.
+    pub fn to_content_part(&self) -> Result<endpoints::chat::ContentPart, String> {
+        use endpoints::chat::{ContentPart, Image, ImageContentPart, TextContentPart};
+
+        match self {
+            InputContentPart::Text { text } => Ok(ContentPart::Text(TextContentPart::new(text))),
+            InputContentPart::Image {
+                image_url: Some(url),
+                detail,
+                ..
+            } => Ok(ContentPart::Image(ImageContentPart::new(Image {
+                url: url.clone(),
+                detail: detail.clone(),
+            }))),
+            InputContentPart::Image { .. } => Err(
+                "Input images must be given by `image_url`, `file_id` is not supported".to_string(),
+            ),
+            InputContentPart::File {
+                filename,
+                file_data: Some(file_data),
+                ..
+            } => {
+                let (mime, data) = file_data
+                    .strip_prefix("data:")
+                    .and_then(|data_url| data_url.split_once(','))
+                    .and_then(|(meta, data)| Some((meta.strip_suffix(";base64")?, data)))
+                    .ok_or_else(|| {
+                        "The `file_data` of an input file must be a base64 data URL".to_string()
+                    })?;
+                let filename = filename.as_deref().unwrap_or("file");
+
+                if mime.starts_with("image/") {
+                    return Ok(ContentPart::Image(ImageContentPart::new(Image {
+                        url: file_data.clone(),
+                        detail: None,
+                    })));
+                }
+                let is_text = mime.starts_with("text/")
+                    || matches!(
+                        mime,
+                        "application/json"
+                            | "application/xml"
+                            | "application/yaml"
+                            | "application/x-yaml"
+                    );
+                if !is_text {
+                    return Err(format!(
+                        "Unsupported type of the input file {filename}: {mime}, only text and image files are supported"
+                    ));
+                }
+
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(data)
+                    .map_err(|e| format!("Failed to decode the input file {filename}: {e}"))?;
+                let text = String::from_utf8(bytes)
+                    .map_err(|_| format!("The input file {filename} is not UTF-8 text"))?;
+
+                Ok(ContentPart::Text(TextContentPart::new(format!(
+                    "File: {filename}\n{text}"
+                ))))
+            }
+            InputContentPart::File { .. } => Err(
+                "Input files must be given by `file_data`, `file_id` and `file_url` are not supported"
+                    .to_string(),
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -106,6 +238,8 @@ pub enum ToolData {
     CodeInterpreter {
         code_interpreter: CodeInterpreterTool,
     },
+    /// A function defined at the top level of the tool, as in the OpenAI Responses API
+    FunctionDefinition(FunctionTool),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -129,14 +263,54 @@ pub struct FileSearchTool {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CodeInterpreterTool {}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
+#[derive(Debug, Clone)]
 pub enum ToolChoice {
     Auto,
     None,
     Required,
     Function { function: FunctionChoice },
 }
+impl Serialize for ToolChoice {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ToolChoice::Auto => serializer.serialize_str("auto"),
+            ToolChoice::None => serializer.serialize_str("none"),
+            ToolChoice::Required => serializer.serialize_str("required"),
+            ToolChoice::Function { function } => serde_json::json!({
+                "type": "function",
+                "name": function.name,
+            })
+            .serialize(serializer),
+        }
+    }
+}
+impl<'de> Deserialize<'de> for ToolChoice {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Choice {
+            Mode(String),
+            Function { name: String },
+            // the form of the chat completions API
+            ChatFunction { function: FunctionChoice },
+        }
+
+        match Choice::deserialize(deserializer)? {
+            Choice::Mode(mode) => match mode.as_str() {
+                "auto" => Ok(ToolChoice::Auto),
+                "none" => Ok(ToolChoice::None),
+                "required" => Ok(ToolChoice::Required),
+                _ => Err(serde::de::Error::custom(format!(
+                    "unknown tool choice: {mode}"
+                ))),
+            },
+            Choice::Function { name } => Ok(ToolChoice::Function {
+                function: FunctionChoice { name },
+            }),
+            Choice::ChatFunction { function } => Ok(ToolChoice::Function { function }),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FunctionChoice {
@@ -201,6 +375,34 @@ pub struct OutputItem {
     pub role: Option<Role>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<Vec<OutputContent>>,
+    /// Id of the call of a `function_call` item, to be referenced by its `function_call_output`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub call_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+}
+impl OutputItem {
+    /// Create a `function_call` item
+    pub fn function_call(
+        id: String,
+        call_id: String,
+        name: String,
+        arguments: String,
+        status: &str,
+    ) -> Self {
+        Self {
+            id,
+            item_type: "function_call".to_string(),
+            status: status.to_string(),
+            role: None,
+            content: None,
+            call_id: Some(call_id),
+            name: Some(name),
+            arguments: Some(arguments),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -294,6 +496,60 @@ pub struct InputItemList {
     pub has_more: bool,
 }
 
+impl InputItemList {
+    /// Select a page of the input items, which are given in the order they were stored
+    ///
+    /// `after` and `before` are the ids of the items delimiting the page, in the requested
+    /// `order` (`asc` or `desc`).
+    pub fn paginate(
+        mut items: Vec<InputItem>,
+        limit: usize,
+        after: Option<&str>,
+        before: Option<&str>,
+        order: &str,
+    ) -> Result<Self, String> {
+        match order {
+            "asc" => {}
+            "desc" => items.reverse(),
+            _ => return Err(format!("Invalid order: {order}, expected `asc` or `desc`")),
+        }
+
+        let position = |cursor: &str| {
+            items
+                .iter()
+                .position(|item| item.id.as_deref() == Some(cursor))
+                .ok_or_else(|| format!("Input item not found: {cursor}"))
+        };
+        let start = after
+            .map(|id| position(id).map(|i| i + 1))
+            .transpose()?
+            .unwrap_or(0);
+        let end = before.map(position).transpose()?.unwrap_or(items.len());
+
+        let window = &items[start.min(end)..end];
+        let has_more = window.len() > limit;
+        // with only `before`, the page is made of the items right before the cursor
+        let data = match after.is_none() && before.is_some() {
+            true => window[window.len().saturating_sub(limit)..].to_vec(),
+            false => window.iter().take(limit).cloned().collect(),
+        };
+
+        Ok(Self {
+            object: "list".to_string(),
+            first_id: data
+                .first()
+                .and_then(|item| item.id.clone())
+                .unwrap_or_default(),
+            last_id: data
+                .last()
+                .and_then(|item| item.id.clone())
+                .unwrap_or_default(),
+            data,
+            has_more,
+        })
+    }
+}
+
 impl ResponseRequest {
     pub fn generate_id() -> String {
         format!("resp_{}", Uuid::new_v4().simple())
@@ -303,6 +559,96 @@ impl ResponseRequest {
         format!("msg_{}", Uuid::new_v4().simple())
     }
 
+    pub fn generate_function_call_id() -> String {
+        format!("fc_{}", Uuid::new_v4().simple())
+    }
+
+    /// Check that the images and files of the input can be passed to the chat servers
+    pub fn validate_input(&self) -> Result<(), String> {
+        for item in self.input.iter().flat_map(|input| input.to_items()) {
+            match &item.content {
+                InputContent::Message {
+                    content: MessageContent::Parts(parts),
+                } => {
+                    for part in parts {
+                        part.to_content_part()?;
+                    }
+                }
+                InputContent::File { .. } => {
+                    return Err(
+                        "Input files must be given by `file_data` in the content of a message"
+                            .to_string(),
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the tools of the request can be provided by the chat servers
+    pub fn validate_tools(&self) -> Result<(), String> {
+        let mut names = Vec::new();
+        for tool in self.tools.iter().flatten() {
+            match &tool.tool_data {
+                ToolData::Function { function } | ToolData::FunctionDefinition(function)
+                    if tool.tool_type == "function" =>
+                {
+                    names.push(function.name.as_str())
+                }
+                _ => {
+                    return Err(format!(
+                        "Unsupported tool type: {}, only function tools are supported",
+                        tool.tool_type
+                    ));
+                }
+            }
+        }
+
+        if let Some(ToolChoice::Function { function }) = &self.tool_choice
+            && !names.contains(&function.name.as_str())
+        {
+            return Err(format!(
+                "The function {} of the tool choice is not defined in the tools",
+                function.name
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Create the response object of the request, in progress and without output yet
+    pub fn to_response_object(&self, id: String, created_at: i64) -> ResponseObject {
+        ResponseObject {
+            id,
+            object: "response".to_string(),
+            created_at,
+            model: self.model.clone(),
+            status: "in_progress".to_string(),
+            previous_response_id: self.previous_response_id.clone(),
+            instructions: self.instructions.clone(),
+            max_output_tokens: self.max_output_tokens,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            store: self.store,
+            metadata: self.metadata.clone(),
+            user: self.user.clone(),
+            safety_identifier: self.safety_identifier.clone(),
+            prompt_cache_key: self.prompt_cache_key.clone(),
+            tools: self.tools.clone(),
+            tool_choice: self.tool_choice.clone(),
+            parallel_tool_calls: self.parallel_tool_calls,
+            output: Vec::new(),
+            error: None,
+            incomplete_details: None,
+            usage: None,
+            reasoning: None,
+            truncation: self.truncation.clone(),
+            verbosity: self.verbosity.clone(),
+        }
+    }
+
     pub fn to_chat_completion_request(&self, conversation_history: Vec<endpoints::chat::ChatCompletionRequestMessage>) -> endpoints::chat::ChatCompletionRequest {
         let mut messages = Vec::new();
 
@@ -316,63 +662,16 @@ impl ResponseRequest {
             ));
         }
 
-        // Convert input to user messages
+        // Convert input items to messages
         if let Some(input) = &self.input {
-            match input {
-                InputTypes::Text(text) => {
-                    messages.push(endpoints::chat::ChatCompletionRequestMessage::User(
-                        endpoints::chat::ChatCompletionUserMessage::new(
-                            endpoints::chat::ChatCompletionUserMessageContent::Text(text.clone()),
-                            None
-                        )
-                    ));
-                }
-                InputTypes::Array(items) => {
-                    for item in items {
-                        // Convert input items to appropriate message types
-                        match &item.content {
-                            InputContent::Text { text } => {
-                                let role = item.role.as_ref().unwrap_or(&Role::User);
-                                match role {
-                                    Role::User => {
-                                        messages.push(endpoints::chat::ChatCompletionRequestMessage::User(
-                                            endpoints::chat::ChatCompletionUserMessage::new(
-                                                endpoints::chat::ChatCompletionUserMessageContent::Text(text.clone()),
-                                                None
-                                            )
-                                        ));
-                                    }
-                                    Role::System => {
-                                        messages.push(endpoints::chat::ChatCompletionRequestMessage::System(
-                                            endpoints::chat::ChatCompletionSystemMessage::new(text.clone(), None)
-                                        ));
-                                    }
-                                    _ => {
-                                        // Default to user message
-                                        messages.push(endpoints::chat::ChatCompletionRequestMessage::User(
-                                            endpoints::chat::ChatCompletionUserMessage::new(
-                                                endpoints::chat::ChatCompletionUserMessageContent::Text(text.clone()),
-                                                None
-                                            )
-                                        ));
-                                    }
-                                }
-                            }
-                            _ => {
-                                // For now, skip non-text inputs
-                                // TODO: Implement image and file support
-                            }
-                        }
-                    }
-                }
-            }
+            messages.extend(input_items_to_messages(&input.to_items()));
         }
 
         // Convert tools
         let tools = self.tools.as_ref().map(|response_tools| {
             response_tools.iter().filter_map(|tool| {
                 match &tool.tool_data {
-                    ToolData::Function { function } => {
+                    ToolData::Function { function } | ToolData::FunctionDefinition(function) => {
                         Some(endpoints::chat::Tool::new(endpoints::chat::ToolFunction {
                             name: function.name.clone(),
                             description: function.description.clone(),
@@ -390,10 +689,13 @@ impl ResponseRequest {
                 ToolChoice::Auto => endpoints::chat::ToolChoice::Auto,
                 ToolChoice::None => endpoints::chat::ToolChoice::None,
                 ToolChoice::Required => endpoints::chat::ToolChoice::Required,
-                ToolChoice::Function { .. } => {
-                    // For now, default to Auto when Function is specified
-                    // TODO: Implement proper function tool choice support
-                    endpoints::chat::ToolChoice::Auto
+                ToolChoice::Function { function } => {
+                    endpoints::chat::ToolChoice::Tool(endpoints::chat::ToolChoiceTool {
+                        ty: endpoints::chat::ToolType::Function,
+                        function: endpoints::chat::ToolChoiceToolFunction {
+                            name: function.name.clone(),
+                        },
+                    })
                 }
             }
         });
@@ -415,23 +717,44 @@ impl ResponseRequest {
 
 impl From<endpoints::chat::ChatCompletionObject> for ResponseObject {
     fn from(completion: endpoints::chat::ChatCompletionObject) -> Self {
-        let output = completion.choices.into_iter().map(|choice| {
-            let content = vec![OutputContent {
-                content_type: "output_text".to_string(),
-                content_data: OutputContentData::Text {
-                    text: choice.message.content.unwrap_or_default(),
-                    annotations: None,
-                },
-            }];
+        let mut output = Vec::new();
+        for choice in completion.choices {
+            let message = choice.message;
 
-            OutputItem {
-                id: ResponseRequest::generate_message_id(),
-                item_type: "message".to_string(),
-                status: "completed".to_string(),
-                role: Some(Role::Assistant),
-                content: Some(content),
+            // a message with tool calls only has no text output
+            if message.tool_calls.is_empty()
+                || message.content.as_deref().is_some_and(|c| !c.is_empty())
+            {
+                let content = vec![OutputContent {
+                    content_type: "output_text".to_string(),
+                    content_data: OutputContentData::Text {
+                        text: message.content.unwrap_or_default(),
+                        annotations: None,
+                    },
+                }];
+
+                output.push(OutputItem {
+                    id: ResponseRequest::generate_message_id(),
+                    item_type: "message".to_string(),
+                    status: "completed".to_string(),
+                    role: Some(Role::Assistant),
+                    content: Some(content),
+                    call_id: None,
+                    name: None,
+                    arguments: None,
+                });
+            }
+
+            for tool_call in message.tool_calls {
+                output.push(OutputItem::function_call(
+                    ResponseRequest::generate_function_call_id(),
+                    tool_call.id,
+                    tool_call.function.name,
+                    tool_call.function.arguments,
+                    "completed",
+                ));
             }
-        }).collect();
+        }
 
         ResponseObject {
             id: completion.id,
@@ -467,4 +790,844 @@ impl From<endpoints::chat::ChatCompletionObject> for ResponseObject {
             verbosity: None,
         }
     }
-}
\ No newline at end of file
+}
+
+impl InputTypes {
+    /// Get the input as a list of items, a text input being a user message
+    pub fn to_items(&self) -> Vec<InputItem> {
+        match self {
+            InputTypes::Text(text) => vec![InputItem {
+                id: None,
+                item_type: "message".to_string(),
+                role: Some(Role::User),
+                content: InputContent::Text { text: text.clone() },
+            }],
+            InputTypes::Array(items) => items.clone(),
+        }
+    }
+}
+
+/// Convert input items to the messages of a chat completion request
+///
+/// Consecutive `function_call` items are merged into a single assistant message, like the
+/// parallel tool calls of a chat completion.
+pub fn input_items_to_messages(
+    items: &[InputItem],
+) -> Vec<endpoints::chat::ChatCompletionRequestMessage> {
+    let mut messages = Vec::new();
+    let mut tool_calls = Vec::new();
+
+    for item in items {
+        if let InputContent::FunctionCall {
+            call_id,
+            name,
+            arguments,
+        } = &item.content
+        {
+            tool_calls.push(endpoints::chat::ToolCall {
+                id: call_id.clone(),
+                ty: "function".to_string(),
+                function: endpoints::chat::Function {
+                    name: name.clone(),
+                    arguments: arguments.clone(),
+                },
+            });
+            continue;
+        }
+        if !tool_calls.is_empty() {
+            messages.push(endpoints::chat::ChatCompletionRequestMessage::Assistant(
+                endpoints::chat::ChatCompletionAssistantMessage::new(
+                    None,
+                    None,
+                    Some(std::mem::take(&mut tool_calls)),
+                ),
+            ));
+        }
+
+        let role = item.role.as_ref().unwrap_or(&Role::User);
+        match &item.content {
+            InputContent::Text { text }
+            | InputContent::Message {
+                content: MessageContent::Text(text),
+            } => messages.push(text_message(role, text.clone())),
+            InputContent::Message {
+                content: MessageContent::Parts(parts),
+            } => match role {
+                Role::User => {
+                    // the parts which cannot be converted are rejected by `validate_input`
+                    let parts = parts
+                        .iter()
+                        .filter_map(|part| part.to_content_part().ok())
+                        .collect();
+                    messages.push(endpoints::chat::ChatCompletionRequestMessage::User(
+                        endpoints::chat::ChatCompletionUserMessage::new(
+                            endpoints::chat::ChatCompletionUserMessageContent::Parts(parts),
+                            None,
+                        ),
+                    ));
+                }
+                // only user messages can have images
+                _ => {
+                    let text = parts
+                        .iter()
+                        .filter_map(|part| match part {
+                            InputContentPart::Text { text } => Some(text.as_str()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    messages.push(text_message(role, text));
+                }
+            },
+            InputContent::Image { image_url, detail } => {
+                let part = endpoints::chat::ContentPart::Image(
+                    endpoints::chat::ImageContentPart::new(endpoints::chat::Image {
+                        url: image_url.url.clone(),
+                        detail: detail.clone(),
+                    }),
+                );
+                messages.push(endpoints::chat::ChatCompletionRequestMessage::User(
+                    endpoints::chat::ChatCompletionUserMessage::new(
+                        endpoints::chat::ChatCompletionUserMessageContent::Parts(vec![part]),
+                        None,
+                    ),
+                ));
+            }
+            InputContent::FunctionCallOutput { call_id, output } => {
+                messages.push(endpoints::chat::ChatCompletionRequestMessage::Tool(
+                    endpoints::chat::ChatCompletionToolMessage::new(
+                        output.clone(),
+                        Some(call_id.clone()),
+                    ),
+                ));
+            }
+            // files are rejected by `validate_input`, and function calls are merged above
+            InputContent::File { .. } | InputContent::FunctionCall { .. } => {}
+        }
+    }
+    if !tool_calls.is_empty() {
+        messages.push(endpoints::chat::ChatCompletionRequestMessage::Assistant(
+            endpoints::chat::ChatCompletionAssistantMessage::new(None, None, Some(tool_calls)),
+        ));
+    }
+
+    messages
+}
+
+/// Create the chat message of a text input with the given role
+fn text_message(role: &Role, text: String) -> endpoints::chat::ChatCompletionRequestMessage {
+    match role {
+        Role::System => endpoints::chat::ChatCompletionRequestMessage::System(
+            endpoints::chat::ChatCompletionSystemMessage::new(text, None),
+        ),
+        Role::Assistant => endpoints::chat::ChatCompletionRequestMessage::Assistant(
+            endpoints::chat::ChatCompletionAssistantMessage::new(Some(text), None, None),
+        ),
+        Role::User => endpoints::chat::ChatCompletionRequestMessage::User(
+            endpoints::chat::ChatCompletionUserMessage::new(
+                endpoints::chat::ChatCompletionUserMessageContent::Text(text),
+                None,
+            ),
+        ),
+    }
+}
+
+/// Convert the output items of a response to the assistant message of a chat completion
+/// request, with the text of the messages and the function calls
+pub fn output_items_to_message(
+    items: &[OutputItem],
+) -> Option<endpoints::chat::ChatCompletionRequestMessage> {
+    let mut text = String::new();
+    let mut tool_calls = Vec::new();
+
+    for item in items {
+        match (item.item_type.as_str(), &item.call_id, &item.name) {
+            ("function_call", Some(call_id), Some(name)) => {
+                tool_calls.push(endpoints::chat::ToolCall {
+                    id: call_id.clone(),
+                    ty: "function".to_string(),
+                    function: endpoints::chat::Function {
+                        name: name.clone(),
+                        arguments: item.arguments.clone().unwrap_or_default(),
+                    },
+                });
+            }
+            _ => {
+                for content in item.content.iter().flatten() {
+                    if let OutputContentData::Text { text: part, .. } = &content.content_data {
+                        text.push_str(part);
+                    }
+                }
+            }
+        }
+    }
+
+    if text.is_empty() && tool_calls.is_empty() {
+        return None;
+    }
+    Some(endpoints::chat::ChatCompletionRequestMessage::Assistant(
+        endpoints::chat::ChatCompletionAssistantMessage::new(
+            (!text.is_empty()).then_some(text),
+            None,
+            (!tool_calls.is_empty()).then_some(tool_calls),
+        ),
+    ))
+}
+
+/// Splits the body of a `text/event-stream` response into the `data` payloads of its events
+///
+/// The network chunks may end in the middle of an event, so the incomplete tail is kept until the
+/// next chunk arrives.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+}
+impl SseDecoder {
+    /// Feed a network chunk, and get the payloads of the events completed by it
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        // the events are decoded once complete, so a character split across chunks is kept whole
+        self.buffer
+            .extend(bytes.iter().filter(|&&byte| byte != b'\r'));
+
+        let mut payloads = Vec::new();
+        while let Some(end) = self.buffer.windows(2).position(|window| window == b"\n\n") {
+            let event = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+            self.buffer.drain(..end + 2);
+
+            let data = event
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| data.trim())
+                .collect::<Vec<_>>()
+                .join("\n");
+            if !data.is_empty() {
+                payloads.push(data);
+            }
+        }
+
+        payloads
+    }
+}
+
+/// Translates the chunks of a downstream chat completion stream into the semantic events of the
+/// Responses API, and assembles the final response object
+///
+/// The events are `response.created` and `response.in_progress`, then the `output_item`,
+/// `content_part` and `output_text` events of the assistant message, the `output_item` and
+/// `function_call_arguments` events of the function calls, and finally `response.completed`,
+/// `response.incomplete` or `response.failed`.
+#[derive(Debug)]
+pub struct ResponseStreamBuilder {
+    response: ResponseObject,
+    sequence_number: u64,
+    message_id: String,
+    text: String,
+    /// Output index of the assistant message, once its output item has been announced
+    message_index: Option<usize>,
+    function_calls: Vec<StreamedFunctionCall>,
+    /// Number of output items announced so far
+    output_count: usize,
+    finish_reason: Option<String>,
+}
+
+/// A function call being assembled from the tool call deltas of the chunks
+#[derive(Debug)]
+struct StreamedFunctionCall {
+    /// Index of the tool call in the chunks
+    index: u64,
+    output_index: usize,
+    item_id: String,
+    call_id: String,
+    name: String,
+    arguments: String,
+}
+impl StreamedFunctionCall {
+    fn item(&self, status: &str) -> OutputItem {
+        OutputItem::function_call(
+            self.item_id.clone(),
+            self.call_id.clone(),
+            self.name.clone(),
+            self.arguments.clone(),
+            status,
+        )
+    }
+}
+
+impl ResponseStreamBuilder {
+    pub fn new(response: ResponseObject) -> Self {
+        Self {
+            response,
+            sequence_number: 0,
+            message_id: ResponseRequest::generate_message_id(),
+            text: String::new(),
+            message_index: None,
+            function_calls: Vec::new(),
+            output_count: 0,
+            finish_reason: None,
+        }
+    }
+
+    /// Get the response object assembled so far
+    pub fn response(&self) -> &ResponseObject {
+        &self.response
+    }
+
+    /// Create the events announcing the response
+    pub fn start(&mut self) -> Vec<String> {
+        let response = serde_json::json!(self.response);
+        vec![
+            self.event(
+                "response.created",
+                serde_json::json!({ "response": response }),
+            ),
+            self.event(
+                "response.in_progress",
+                serde_json::json!({ "response": response }),
+            ),
+        ]
+    }
+
+    /// Translate a `chat.completion.chunk` of the downstream stream
+    pub fn push_chunk(&mut self, chunk: &serde_json::Value) -> Vec<String> {
+        let mut events = Vec::new();
+
+        if let Some(usage) = chunk.get("usage").filter(|usage| !usage.is_null()) {
+            let tokens = |name: &str| usage.get(name).and_then(|v| v.as_i64()).unwrap_or(0);
+            self.response.usage = Some(Usage {
+                input_tokens: tokens("prompt_tokens"),
+                output_tokens: tokens("completion_tokens"),
+                total_tokens: tokens("total_tokens"),
+                input_tokens_details: None,
+                output_tokens_details: None,
+            });
+        }
+
+        let Some(choice) = chunk.pointer("/choices/0") else {
+            return events;
+        };
+        if let Some(delta) = choice
+            .pointer("/delta/content")
+            .and_then(|content| content.as_str())
+            .filter(|content| !content.is_empty())
+        {
+            let output_index = match self.message_index {
+                Some(output_index) => output_index,
+                None => {
+                    let output_index = self.output_count;
+                    self.output_count += 1;
+                    self.message_index = Some(output_index);
+                    let item = self.message_item("in_progress", Vec::new());
+                    events.push(self.event(
+                        "response.output_item.added",
+                        serde_json::json!({ "output_index": output_index, "item": item }),
+                    ));
+                    let part = Self::text_part("");
+                    events.push(self.event(
+                        "response.content_part.added",
+                        serde_json::json!({
+                            "item_id": self.message_id,
+                            "output_index": output_index,
+                            "content_index": 0,
+                            "part": part,
+                        }),
+                    ));
+                    output_index
+                }
+            };
+
+            self.text.push_str(delta);
+            events.push(self.event(
+                "response.output_text.delta",
+                serde_json::json!({
+                    "item_id": self.message_id,
+                    "output_index": output_index,
+                    "content_index": 0,
+                    "delta": delta,
+                }),
+            ));
+        }
+        for tool_call in choice
+            .pointer("/delta/tool_calls")
+            .and_then(|tool_calls| tool_calls.as_array())
+            .into_iter()
+            .flatten()
+        {
+            events.extend(self.push_tool_call(tool_call));
+        }
+        if let Some(finish_reason) = choice.get("finish_reason").and_then(|v| v.as_str()) {
+            self.finish_reason = Some(finish_reason.to_string());
+        }
+
+        events
+    }
+
+    /// Translate the delta of a tool call, which starts a new function call item or extends the
+    /// arguments of the current one
+    fn push_tool_call(&mut self, tool_call: &serde_json::Value) -> Vec<String> {
+        let mut events = Vec::new();
+        let text = |pointer: &str| {
+            tool_call
+                .pointer(pointer)
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        let index = tool_call.get("index").and_then(|v| v.as_u64()).unwrap_or(0);
+        let position = match self
+            .function_calls
+            .iter()
+            .position(|call| call.index == index)
+        {
+            Some(position) => position,
+            None => {
+                let call = StreamedFunctionCall {
+                    index,
+                    output_index: self.output_count,
+                    item_id: ResponseRequest::generate_function_call_id(),
+                    call_id: text("/id"),
+                    name: text("/function/name"),
+                    arguments: String::new(),
+                };
+                self.output_count += 1;
+                let item = call.item("in_progress");
+                events.push(self.event(
+                    "response.output_item.added",
+                    serde_json::json!({ "output_index": call.output_index, "item": item }),
+                ));
+                self.function_calls.push(call);
+                self.function_calls.len() - 1
+            }
+        };
+
+        let delta = text("/function/arguments");
+        if !delta.is_empty() {
+            let call = &mut self.function_calls[position];
+            call.arguments.push_str(&delta);
+            let data = serde_json::json!({
+                "item_id": call.item_id,
+                "output_index": call.output_index,
+                "delta": delta,
+            });
+            events.push(self.event("response.function_call_arguments.delta", data));
+        }
+
+        events
+    }
+
+    /// Create the events closing the output items and the response, once the downstream
+    /// stream has ended
+    pub fn finish(&mut self) -> Vec<String> {
+        let mut events = self.close_items("completed");
+
+        let event_type = if self.finish_reason.as_deref() == Some("length") {
+            self.response.status = "incomplete".to_string();
+            self.response.incomplete_details = Some(IncompleteDetails {
+                incomplete_type: "incomplete".to_string(),
+                reason: Some("max_output_tokens".to_string()),
+            });
+            "response.incomplete"
+        } else {
+            self.response.status = "completed".to_string();
+            "response.completed"
+        };
+        let response = serde_json::json!(self.response);
+        events.push(self.event(event_type, serde_json::json!({ "response": response })));
+
+        events
+    }
+
+    /// Create the events reporting a failure of the downstream stream
+    pub fn fail(&mut self, message: &str) -> Vec<String> {
+        let mut events = self.close_items("incomplete");
+
+        self.response.status = "failed".to_string();
+        self.response.error = Some(ResponseError {
+            error_type: "server_error".to_string(),
+            code: "server_error".to_string(),
+            message: message.to_string(),
+            param: None,
+        });
+        let response = serde_json::json!(self.response);
+        events.push(self.event(
+            "response.failed",
+            serde_json::json!({ "response": response }),
+        ));
+
+        events
+    }
+
+    /// Mark the response as interrupted before the end of the downstream stream, e.g. because
+    /// the client has disconnected
+    pub fn interrupt(&mut self, reason: &str) {
+        self.close_items("incomplete");
+        self.response.status = "incomplete".to_string();
+        self.response.incomplete_details = Some(IncompleteDetails {
+            incomplete_type: "incomplete".to_string(),
+            reason: Some(reason.to_string()),
+        });
+    }
+
+    /// Close the output items which are still open, and add them to the output of the response
+    /// in the order they were announced
+    fn close_items(&mut self, status: &str) -> Vec<String> {
+        let mut events = Vec::new();
+        let mut function_calls = std::mem::take(&mut self.function_calls)
+            .into_iter()
+            .peekable();
+
+        for output_index in 0..self.output_count {
+            if self.message_index == Some(output_index) {
+                events.extend(self.close_message(output_index, status));
+            } else if let Some(call) =
+                function_calls.next_if(|call| call.output_index == output_index)
+            {
+                let data = serde_json::json!({
+                    "item_id": call.item_id,
+                    "output_index": output_index,
+                    "arguments": call.arguments,
+                });
+                events.push(self.event("response.function_call_arguments.done", data));
+                let item = call.item(status);
+                events.push(self.event(
+                    "response.output_item.done",
+                    serde_json::json!({ "output_index": output_index, "item": item }),
+                ));
+                self.response.output.push(item);
+            }
+        }
+
+        events
+    }
+
+    /// Close the assistant message and add it to the output of the response
+    fn close_message(&mut self, output_index: usize, status: &str) -> Vec<String> {
+        self.message_index = None;
+
+        let mut events = Vec::new();
+        events.push(self.event(
+            "response.output_text.done",
+            serde_json::json!({
+                "item_id": self.message_id,
+                "output_index": output_index,
+                "content_index": 0,
+                "text": self.text,
+            }),
+        ));
+        let part = Self::text_part(&self.text);
+        events.push(self.event(
+            "response.content_part.done",
+            serde_json::json!({
+                "item_id": self.message_id,
+                "output_index": output_index,
+                "content_index": 0,
+                "part": part,
+            }),
+        ));
+
+        let item = self.message_item(status, vec![part]);
+        events.push(self.event(
+            "response.output_item.done",
+            serde_json::json!({ "output_index": output_index, "item": item }),
+        ));
+        self.response.output.push(item);
+
+        events
+    }
+
+    fn message_item(&self, status: &str, content: Vec<OutputContent>) -> OutputItem {
+        OutputItem {
+            id: self.message_id.clone(),
+            item_type: "message".to_string(),
+            status: status.to_string(),
+            role: Some(Role::Assistant),
+            content: Some(content),
+            call_id: None,
+            name: None,
+            arguments: None,
+        }
+    }
+
+    fn text_part(text: &str) -> OutputContent {
+        OutputContent {
+            content_type: "output_text".to_string(),
+            content_data: OutputContentData::Text {
+                text: text.to_string(),
+                annotations: Some(Vec::new()),
+            },
+        }
+    }
+
+    /// Create an SSE event of the given type with the next sequence number
+    fn event(&mut self, event_type: &str, mut data: serde_json::Value) -> String {
+        if let Some(fields) = data.as_object_mut() {
+            fields.insert("type".to_string(), serde_json::json!(event_type));
+            fields.insert(
+                "sequence_number".to_string(),
+                serde_json::json!(self.sequence_number),
+            );
+        }
+        self.sequence_number += 1;
+
+        format!("event: {event_type}\ndata: {data}\n\n")
+    }
+}
+
+#[test]
+fn test_response_stream_events() {
+    let request: ResponseRequest =
+        serde_json::from_str(r#"{"model":"m","input":"hi","stream":true}"#).unwrap();
+    let mut builder =
+        ResponseStreamBuilder::new(request.to_response_object("resp_1".to_string(), 0));
+
+    let mut events = builder.start();
+    let mut decoder = SseDecoder::default();
+    let body = concat!(
+        "data: {\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"Hel\"}}]}\n\n",
+        "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"lo\"},\"finish_reason\":\"stop\"}]}\n\n",
+        "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":2,\"total_tokens\":5}}\n\n",
+        "data: [DONE]\n\n",
+    );
+    // the network chunks may split the events anywhere
+    let (head, tail) = body.split_at(30);
+    let payloads = [decoder.push(head.as_bytes()), decoder.push(tail.as_bytes())].concat();
+    assert_eq!(payloads.len(), 4);
+    assert_eq!(payloads[3], "[DONE]");
+    for payload in &payloads[..3] {
+        events.extend(builder.push_chunk(&serde_json::from_str(payload).unwrap()));
+    }
+    events.extend(builder.finish());
+
+    let types = events
+        .iter()
+        .map(|event| event.lines().next().unwrap().trim_start_matches("event: "))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        types,
+        [
+            "response.created",
+            "response.in_progress",
+            "response.output_item.added",
+            "response.content_part.added",
+            "response.output_text.delta",
+            "response.output_text.delta",
+            "response.output_text.done",
+            "response.content_part.done",
+            "response.output_item.done",
+            "response.completed",
+        ]
+    );
+    assert!(events[9].contains(r#""sequence_number":9"#));
+
+    let response = builder.response();
+    assert_eq!(response.status, "completed");
+    assert_eq!(response.usage.as_ref().unwrap().total_tokens, 5);
+    match &response.output[0].content.as_ref().unwrap()[0].content_data {
+        OutputContentData::Text { text, .. } => assert_eq!(text, "Hello"),
+        _ => panic!("unexpected content"),
+    }
+}
+
+#[test]
+fn test_input_item_list_pagination() {
+    let items = (1..=5)
+        .map(|i| InputItem {
+            id: Some(format!("msg_{i}")),
+            item_type: "message".to_string(),
+            role: Some(Role::User),
+            content: InputContent::Text {
+                text: format!("message {i}"),
+            },
+        })
+        .collect::<Vec<_>>();
+    let ids = |list: &InputItemList| {
+        list.data
+            .iter()
+            .map(|item| item.id.clone().unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    let list = InputItemList::paginate(items.clone(), 2, None, None, "desc").unwrap();
+    assert_eq!(ids(&list), ["msg_5", "msg_4"]);
+    assert_eq!(
+        (list.first_id.as_str(), list.last_id.as_str()),
+        ("msg_5", "msg_4")
+    );
+    assert!(list.has_more);
+
+    let list = InputItemList::paginate(items.clone(), 2, Some("msg_4"), None, "desc").unwrap();
+    assert_eq!(ids(&list), ["msg_3", "msg_2"]);
+    assert!(list.has_more);
+
+    let list = InputItemList::paginate(items.clone(), 2, Some("msg_3"), None, "asc").unwrap();
+    assert_eq!(ids(&list), ["msg_4", "msg_5"]);
+    assert!(!list.has_more);
+
+    let list = InputItemList::paginate(items.clone(), 2, None, Some("msg_4"), "asc").unwrap();
+    assert_eq!(ids(&list), ["msg_2", "msg_3"]);
+    assert!(list.has_more);
+
+    let list =
+        InputItemList::paginate(items.clone(), 10, Some("msg_1"), Some("msg_4"), "asc").unwrap();
+    assert_eq!(ids(&list), ["msg_2", "msg_3"]);
+    assert!(!list.has_more);
+
+    let list = InputItemList::paginate(items.clone(), 10, Some("msg_5"), None, "asc").unwrap();
+    assert!(list.data.is_empty());
+    assert_eq!(list.first_id, "");
+
+    assert!(InputItemList::paginate(items.clone(), 2, Some("msg_9"), None, "asc").is_err());
+    assert!(InputItemList::paginate(items, 2, None, None, "newest").is_err());
+}
+
+#[test]
+fn test_response_stream_function_calls() {
+    let request: ResponseRequest =
+        serde_json::from_str(r#"{"model":"m","input":"hi","stream":true}"#).unwrap();
+    let mut builder =
+        ResponseStreamBuilder::new(request.to_response_object("resp_1".to_string(), 0));
+
+    let mut events = builder.start();
+    for chunk in [
+        r#"{"choices":[{"index":0,"delta":{"role":"assistant","tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"get_weather","arguments":""}}]}}]}"#,
+        r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"city\":"}}]}}]}"#,
+        r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"Paris\"}"}},{"index":1,"id":"call_2","type":"function","function":{"name":"get_time","arguments":"{}"}}]},"finish_reason":"tool_calls"}]}"#,
+    ] {
+        events.extend(builder.push_chunk(&serde_json::from_str(chunk).unwrap()));
+    }
+    events.extend(builder.finish());
+
+    let types = events
+        .iter()
+        .map(|event| event.lines().next().unwrap().trim_start_matches("event: "))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        types,
+        [
+            "response.created",
+            "response.in_progress",
+            "response.output_item.added",
+            "response.function_call_arguments.delta",
+            "response.function_call_arguments.delta",
+            "response.output_item.added",
+            "response.function_call_arguments.delta",
+            "response.function_call_arguments.done",
+            "response.output_item.done",
+            "response.function_call_arguments.done",
+            "response.output_item.done",
+            "response.completed",
+        ]
+    );
+
+    let response = builder.response();
+    assert_eq!(response.status, "completed");
+    assert_eq!(response.output.len(), 2);
+    assert_eq!(response.output[0].item_type, "function_call");
+    assert_eq!(response.output[0].call_id.as_deref(), Some("call_1"));
+    assert_eq!(
+        response.output[0].arguments.as_deref(),
+        Some(r#"{"city":"Paris"}"#)
+    );
+    assert_eq!(response.output[1].name.as_deref(), Some("get_time"));
+}
+
+#[test]
+fn test_function_call_round_trip() {
+    let request: ResponseRequest = serde_json::from_str(
+        r#"{
+            "model": "m",
+            "input": [
+                {"type": "function_call", "call_id": "call_1", "name": "get_weather", "arguments": "{}"},
+                {"type": "function_call", "call_id": "call_2", "name": "get_time", "arguments": "{}"},
+                {"type": "function_call_output", "call_id": "call_1", "output": "sunny"},
+                {"type": "function_call_output", "call_id": "call_2", "output": "noon"}
+            ],
+            "tools": [
+                {"type": "function", "name": "get_weather", "parameters": {"type": "object"}},
+                {"type": "function", "function": {"name": "get_time"}}
+            ],
+            "tool_choice": {"type": "function", "name": "get_weather"}
+        }"#,
+    )
+    .unwrap();
+    assert!(request.validate_tools().is_ok());
+
+    let chat_request = request.to_chat_completion_request(Vec::new());
+    let messages = serde_json::to_value(&chat_request.messages).unwrap();
+    assert_eq!(messages.as_array().unwrap().len(), 3);
+    assert_eq!(messages[0]["role"], "assistant");
+    assert_eq!(messages[0]["tool_calls"][1]["id"], "call_2");
+    assert_eq!(messages[1]["role"], "tool");
+    assert_eq!(messages[1]["tool_call_id"], "call_1");
+    assert_eq!(chat_request.tools.as_ref().unwrap().len(), 2);
+    assert_eq!(
+        serde_json::to_value(&chat_request.tool_choice).unwrap(),
+        serde_json::json!({"type": "function", "function": {"name": "get_weather"}})
+    );
+
+    for (json, valid) in [
+        (r#""auto""#, true),
+        (r#""required""#, true),
+        (r#"{"type":"function","function":{"name":"f"}}"#, true),
+        (r#""sometimes""#, false),
+    ] {
+        assert_eq!(
+            serde_json::from_str::<ToolChoice>(json).is_ok(),
+            valid,
+            "{json}"
+        );
+    }
+
+    let request: ResponseRequest = serde_json::from_str(
+        r#"{"model":"m","tools":[{"type":"function","name":"f"}],"tool_choice":{"type":"function","name":"g"}}"#,
+    )
+    .unwrap();
+    assert!(request.validate_tools().is_err());
+}
+
+#[test]
+fn test_image_and_file_inputs() {
+    let request: ResponseRequest = serde_json::from_str(
+        r#"{
+            "model": "m",
+            "input": [{
+                "role": "user",
+                "content": [
+                    {"type": "input_text", "text": "Compare them"},
+                    {"type": "input_image", "image_url": "https://example.com/a.png", "detail": "low"},
+                    {"type": "input_image", "image_url": "data:image/png;base64,iVBORw0KGgo="},
+                    {"type": "input_file", "filename": "notes.txt", "file_data": "data:text/plain;base64,aGVsbG8="}
+                ]
+            }]
+        }"#,
+    )
+    .unwrap();
+    assert!(request.validate_input().is_ok());
+
+    // the items are stored one by one and replayed from the database
+    let stored =
+        serde_json::to_string(&InputTypes::Array(request.input.unwrap().to_items())).unwrap();
+    let items = serde_json::from_str::<InputTypes>(&stored)
+        .unwrap()
+        .to_items();
+    let messages = serde_json::to_value(input_items_to_messages(&items)).unwrap();
+    assert_eq!(
+        messages[0]["content"],
+        serde_json::json!([
+            {"type": "text", "text": "Compare them"},
+            {"type": "image_url", "image_url": {"url": "https://example.com/a.png", "detail": "low"}},
+            {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+            {"type": "text", "text": "File: notes.txt\nhello"},
+        ])
+    );
+
+    for part in [
+        r#"{"type": "input_image", "file_id": "file_1"}"#,
+        r#"{"type": "input_file", "file_id": "file_1"}"#,
+        r#"{"type": "input_file", "filename": "a.pdf", "file_data": "data:application/pdf;base64,JVBERg=="}"#,
+        r#"{"type": "input_file", "file_data": "aGVsbG8="}"#,
+    ] {
+        let part = serde_json::from_str::<InputContentPart>(part).unwrap();
+        assert!(part.to_content_part().is_err());
+    }
+}
@@ -1,21 +1,38 @@
 use std::{
-    collections::HashSet,
-    sync::atomic::{AtomicUsize, Ordering},
-    time::{Duration, SystemTime},
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use async_trait::async_trait;
 use bitflags::bitflags;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use crate::{
-    HEALTH_CHECK_INTERVAL, dual_error, dual_warn,
+    HEALTH_CHECK_INTERVAL,
+    config::{CircuitBreakerConfig, DownstreamConfig},
+    database::ServerRecord,
+    dual_debug, dual_error, dual_info, dual_warn,
     error::{ServerError, ServerResult},
 };
 
 /// Timeout duration for health checks (in seconds)
 const TIMEOUT: u64 = 10;
+/// Number of health probe results kept per server
+const HEALTH_HISTORY_SIZE: usize = 20;
+/// Smoothing factor of the exponentially weighted moving average of request latencies
+const LATENCY_EWMA_ALPHA: f64 = 0.3;
+/// Default weight of a server for the weighted routing policies
+const DEFAULT_WEIGHT: u32 = 1;
+/// Default priority tier of a server. The servers of lower tiers are preferred.
+const DEFAULT_PRIORITY: u32 = 0;
+/// Time after which a client may retry a request rejected because all the servers are busy
+const BUSY_RETRY_AFTER: Duration = Duration::from_secs(1);
 
 pub(crate) type ServerId = String;
 
@@ -24,22 +41,72 @@ pub(crate) struct ServerIdToRemove {
     pub server_id: ServerId,
 }
 
-/// Represents the health status of a server
-#[derive(Debug, Clone)]
-pub struct HealthStatus {
-    pub is_healthy: bool,
-    pub last_check: SystemTime,
+/// Endpoint probed by the health checks of a server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HealthProbe {
+    /// `GET {url}/info`, served by the LlamaEdge API servers
+    Info,
+    /// `GET {url}/models`, served by the OpenAI-compatible API servers
+    Models,
 }
+impl HealthProbe {
+    /// Get the default probe of the given server kind
+    pub(crate) fn default_for(kind: ServerKind) -> Self {
+        if kind.intersects(ServerKind::chat | ServerKind::embeddings) {
+            HealthProbe::Models
+        } else {
+            HealthProbe::Info
+        }
+    }
 
-impl Default for HealthStatus {
-    fn default() -> Self {
-        Self {
-            is_healthy: true,
-            last_check: SystemTime::now(),
+    fn path(&self) -> &'static str {
+        match self {
+            HealthProbe::Info => "/info",
+            HealthProbe::Models => "/models",
         }
     }
 }
 
+/// Health check settings of a server. The unset items fall back to the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthCheckConfig {
+    /// Endpoint to probe. Defaults to `models` for chat and embeddings servers, `info` otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe: Option<HealthProbe>,
+    /// Probe timeout in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Interval between two probes in seconds. Defaults to `--check-health-interval`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+}
+impl HealthCheckConfig {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Result of a health probe
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct HealthCheckRecord {
+    /// Unix timestamp of the probe in seconds
+    pub checked_at: u64,
+    pub healthy: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    last_probe_at: Option<Instant>,
+    /// Most recent results, oldest first
+    history: VecDeque<HealthCheckRecord>,
+}
+
 /// Represents a LlamaEdge API server
 #[derive(Debug, Serialize)]
 pub struct Server {
@@ -48,10 +115,21 @@ pub struct Server {
     pub kind: ServerKind,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub api_key: Option<String>,
+    #[serde(skip_serializing_if = "is_default_weight")]
+    pub weight: u32,
+    /// Priority tier, e.g. `0` for local servers and `1` for cloud providers. The requests
+    /// overflow to a higher tier only if no server of the lower tiers is eligible.
+    #[serde(skip_serializing_if = "is_default_priority")]
+    pub priority: u32,
+    /// Maximum number of in-flight requests. The server is saturated once it is reached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<u32>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "HealthCheckConfig::is_default")]
+    pub health: HealthCheckConfig,
     #[serde(skip)]
-    connections: AtomicUsize,
-    #[serde(skip)]
-    pub health_status: HealthStatus,
+    stats: Arc<ServerStats>,
 }
 impl<'de> Deserialize<'de> for Server {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -64,6 +142,11 @@ impl<'de> Deserialize<'de> for Server {
             url: String,
             kind: ServerKind,
             api_key: Option<String>,
+            weight: Option<u32>,
+            priority: Option<u32>,
+            max_connections: Option<u32>,
+            labels: Option<HashMap<String, String>>,
+            health: Option<HealthCheckConfig>,
         }
 
         // Deserialize into the helper struct
@@ -72,14 +155,146 @@ impl<'de> Deserialize<'de> for Server {
         let kind = helper.kind.to_string().trim().replace(',', "-");
         let id = format!("{}-server-{}", kind, uuid::Uuid::new_v4());
 
+        let weight = helper.weight.unwrap_or(DEFAULT_WEIGHT);
+        if weight == 0 {
+            return Err(serde::de::Error::custom(
+                "The weight of a server must be positive",
+            ));
+        }
+        if helper.max_connections == Some(0) {
+            return Err(serde::de::Error::custom(
+                "The max_connections of a server must be positive",
+            ));
+        }
+
         // Create the actual Server instance
         Ok(Server {
             id,
             url: helper.url,
             kind: helper.kind,
             api_key: helper.api_key,
-            connections: AtomicUsize::new(0),
-            health_status: HealthStatus::default(),
+            weight,
+            priority: helper.priority.unwrap_or(DEFAULT_PRIORITY),
+            max_connections: helper.max_connections,
+            labels: helper.labels.unwrap_or_default(),
+            health: helper.health.unwrap_or_default(),
+            stats: Arc::new(ServerStats::default()),
+        })
+    }
+}
+impl TryFrom<ServerRecord> for Server {
+    type Error = ServerError;
+
+    fn try_from(record: ServerRecord) -> Result<Self, Self::Error> {
+        let kind = record.kind.parse::<ServerKind>()?;
+
+        let weight = u32::try_from(record.weight)
+            .ok()
+            .filter(|weight| *weight > 0)
+            .ok_or_else(|| {
+                ServerError::Operation(format!(
+                    "Invalid weight of server {}: {}",
+                    record.id, record.weight
+                ))
+            })?;
+
+        let priority = u32::try_from(record.priority).map_err(|_| {
+            ServerError::Operation(format!(
+                "Invalid priority of server {}: {}",
+                record.id, record.priority
+            ))
+        })?;
+
+        let max_connections = match record.max_connections {
+            Some(max_connections) => Some(
+                u32::try_from(max_connections)
+                    .ok()
+                    .filter(|max_connections| *max_connections > 0)
+                    .ok_or_else(|| {
+                        ServerError::Operation(format!(
+                            "Invalid max_connections of server {}: {max_connections}",
+                            record.id
+                        ))
+                    })?,
+            ),
+            None => None,
+        };
+
+        let labels = match record.labels {
+            Some(labels) => serde_json::from_str(&labels).map_err(|e| {
+                ServerError::Operation(format!("Invalid labels of server {}: {e}", record.id))
+            })?,
+            None => HashMap::new(),
+        };
+
+        let health = match record.health {
+            Some(health) => serde_json::from_str(&health).map_err(|e| {
+                ServerError::Operation(format!(
+                    "Invalid health check settings of server {}: {e}",
+                    record.id
+                ))
+            })?,
+            None => HealthCheckConfig::default(),
+        };
+
+        // Keep the id assigned at registration so that it is stable across restarts
+        Ok(Server {
+            id: record.id,
+            url: record.url,
+            kind,
+            api_key: record.api_key,
+            weight,
+            priority,
+            max_connections,
+            labels,
+            health,
+            stats: Arc::new(ServerStats::default()),
+        })
+    }
+}
+impl TryFrom<&DownstreamConfig> for Server {
+    type Error = ServerError;
+
+    fn try_from(config: &DownstreamConfig) -> Result<Self, Self::Error> {
+        let id = match &config.id {
+            Some(id) if id.trim().is_empty() => {
+                return Err(ServerError::FailedToLoadConfig(format!(
+                    "The id of the downstream server {} must not be empty",
+                    config.url
+                )));
+            }
+            Some(id) => id.clone(),
+            None => {
+                let kind = config.kind.to_string().trim().replace(',', "-");
+                format!("{}-server-{}", kind, uuid::Uuid::new_v4())
+            }
+        };
+
+        let weight = config.weight.unwrap_or(DEFAULT_WEIGHT);
+        if weight == 0 {
+            return Err(ServerError::FailedToLoadConfig(format!(
+                "The weight of the downstream server {} must be positive",
+                config.url
+            )));
+        }
+        if config.max_connections == Some(0) {
+            return Err(ServerError::FailedToLoadConfig(format!(
+                "The max_connections of the downstream server {} must be positive",
+                config.url
+            )));
+        }
+
+        Ok(Server {
+            id,
+            url: config.url.clone(),
+            kind: config.kind,
+            api_key: config.resolve_api_key()?,
+            weight,
+            priority: config.priority.unwrap_or(DEFAULT_PRIORITY),
+            max_connections: config.max_connections,
+            labels: config.labels.clone(),
+            health: config.health.clone(),
+            stats: Arc::new(ServerStats::default()),
         })
     }
 }
@@ -90,55 +305,501 @@ impl Clone for Server {
             url: self.url.clone(),
             kind: self.kind,
             api_key: self.api_key.clone(),
-            connections: AtomicUsize::new(self.connections.load(Ordering::Relaxed)),
-            health_status: self.health_status.clone(),
+            weight: self.weight,
+            priority: self.priority,
+            max_connections: self.max_connections,
+            labels: self.labels.clone(),
+            health: self.health.clone(),
+            // the clones registered in different server groups share the same statistics
+            stats: Arc::clone(&self.stats),
         }
     }
 }
 impl Server {
-    pub(crate) async fn check_health(&mut self) -> bool {
-        // If the server is currently healthy, check if a new health check is needed
-        if self.health_status.is_healthy {
-            let now = SystemTime::now();
-            if let Ok(duration) = now.duration_since(self.health_status.last_check) {
-                let check_interval =
-                    Duration::from_secs(*HEALTH_CHECK_INTERVAL.get().unwrap_or(&60));
-                if duration < check_interval {
-                    // If the time since last check is less than the interval, return current status
-                    return true;
-                }
+    /// Runtime statistics of the server, shared by all its clones
+    pub(crate) fn stats(&self) -> &ServerStats {
+        &self.stats
+    }
+
+    /// Get a copy of the server with the given changes applied. The copy keeps the id and shares
+    /// the runtime statistics of the server.
+    pub(crate) fn updated(&self, update: ServerUpdate) -> ServerResult<Server> {
+        let mut server = self.clone();
+
+        if let Some(kind) = update.kind {
+            if kind.is_empty() {
+                return Err(ServerError::InvalidServerKind(
+                    "The kind of a server must not be empty".to_string(),
+                ));
             }
+            server.kind = kind;
         }
-
-        // Perform new health check
-        let client = reqwest::Client::new();
-        let health_url = format!("{}/info", self.url);
-
-        // Use configured timeout duration
-        let timeout = Duration::from_secs(TIMEOUT);
-        let is_healthy = match client.get(&health_url).timeout(timeout).send().await {
-            Ok(response) => {
-                // Consider server healthy if response is timeout (408)
-                if response.status() == reqwest::StatusCode::REQUEST_TIMEOUT {
-                    dual_warn!("Health check: {} server {} is in use", self.kind, self.id);
-                    true
-                } else {
-                    response.status().is_success()
-                }
+        if let Some(api_key) = update.api_key {
+            server.api_key = Some(api_key).filter(|api_key| !api_key.is_empty());
+        }
+        if let Some(weight) = update.weight {
+            if weight == 0 {
+                return Err(ServerError::Operation(
+                    "The weight of a server must be positive".to_string(),
+                ));
             }
-            Err(e) => {
-                // Consider server healthy if error is timeout
-                dual_warn!("Health check: {} server {} is in use", self.kind, self.id);
-                e.is_timeout()
+            server.weight = weight;
+        }
+        if let Some(priority) = update.priority {
+            server.priority = priority;
+        }
+        if let Some(max_connections) = update.max_connections {
+            if max_connections == Some(0) {
+                return Err(ServerError::Operation(
+                    "The max_connections of a server must be positive".to_string(),
+                ));
             }
+            server.max_connections = max_connections;
+        }
+        if let Some(labels) = update.labels {
+            server.labels = labels;
+        }
+        if let Some(health) = update.health {
+            server.health = health;
+        }
+
+        Ok(server)
+    }
+
+    /// Check if the in-flight requests of the server have reached its concurrency cap
+    pub(crate) fn is_saturated(&self) -> bool {
+        self.max_connections
+            .is_some_and(|max_connections| self.stats.connections() >= max_connections as usize)
+    }
+
+    /// Convert the server into the record persisted in the database
+    pub(crate) fn to_record(&self) -> ServerRecord {
+        let labels = match self.labels.is_empty() {
+            true => None,
+            false => serde_json::to_string(&self.labels).ok(),
         };
 
-        self.health_status = HealthStatus {
-            is_healthy,
-            last_check: SystemTime::now(),
+        ServerRecord {
+            id: self.id.clone(),
+            url: self.url.clone(),
+            kind: self.kind.to_string(),
+            api_key: self.api_key.clone(),
+            weight: self.weight as i64,
+            priority: self.priority as i64,
+            max_connections: self.max_connections.map(i64::from),
+            labels,
+            health: match self.health.is_default() {
+                true => None,
+                false => serde_json::to_string(&self.health).ok(),
+            },
+            created_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Get the endpoint probed by the health checks
+    pub(crate) fn health_probe(&self) -> HealthProbe {
+        self.health
+            .probe
+            .unwrap_or_else(|| HealthProbe::default_for(self.kind))
+    }
+
+    pub(crate) fn health_probe_url(&self) -> String {
+        format!(
+            "{}{}",
+            self.url.trim_end_matches('/'),
+            self.health_probe().path()
+        )
+    }
+
+    pub(crate) fn health_timeout(&self) -> Duration {
+        Duration::from_secs(self.health.timeout.unwrap_or(TIMEOUT))
+    }
+
+    pub(crate) fn health_interval(&self) -> Duration {
+        let default_interval = *HEALTH_CHECK_INTERVAL.get().unwrap_or(&60);
+        Duration::from_secs(self.health.interval.unwrap_or(default_interval))
+    }
+
+    /// Check if the interval since the last probe has elapsed
+    pub(crate) fn is_health_check_due(&self) -> bool {
+        match self.stats.health.lock().unwrap().last_probe_at {
+            Some(last_probe_at) => last_probe_at.elapsed() >= self.health_interval(),
+            None => true,
+        }
+    }
+
+    /// Probe the server and record the result in its health history.
+    ///
+    /// The server is considered ready only if the probe endpoint responds with a success status
+    /// within the timeout.
+    pub(crate) async fn check_health(&self) -> HealthCheckRecord {
+        let mut request = reqwest::Client::new()
+            .get(self.health_probe_url())
+            .timeout(self.health_timeout());
+        if let Some(api_key) = &self.api_key
+            && !api_key.is_empty()
+        {
+            request = request.header(reqwest::header::AUTHORIZATION, api_key);
+        }
+
+        let started_at = Instant::now();
+        let (healthy, status, error) = match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                (true, Some(response.status().as_u16()), None)
+            }
+            Ok(response) => (
+                false,
+                Some(response.status().as_u16()),
+                Some(format!("Unexpected status: {}", response.status())),
+            ),
+            Err(e) if e.is_timeout() => (
+                false,
+                None,
+                Some(format!(
+                    "Timed out after {} seconds",
+                    self.health_timeout().as_secs()
+                )),
+            ),
+            Err(e) => (false, None, Some(e.to_string())),
         };
 
-        is_healthy
+        let record = HealthCheckRecord {
+            checked_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            healthy,
+            latency_ms: started_at.elapsed().as_millis() as u64,
+            status,
+            error,
+        };
+        if !healthy {
+            dual_warn!(
+                "Health check: {} server {} is not ready: {}",
+                self.kind,
+                self.id,
+                record.error.as_deref().unwrap_or_default()
+            );
+        }
+
+        self.stats.record_health(record.clone());
+
+        record
+    }
+}
+
+fn is_default_weight(weight: &u32) -> bool {
+    *weight == DEFAULT_WEIGHT
+}
+
+fn is_default_priority(priority: &u32) -> bool {
+    *priority == DEFAULT_PRIORITY
+}
+
+/// Changes applied to a registered server through `PATCH /admin/servers/{id}`. The omitted
+/// fields are left unchanged.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ServerUpdate {
+    pub kind: Option<ServerKind>,
+    /// New api key. An empty string removes the api key.
+    pub api_key: Option<String>,
+    pub weight: Option<u32>,
+    pub priority: Option<u32>,
+    /// New concurrency cap. `null` removes the cap, so that the server is unlimited.
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    pub max_connections: Option<Option<u32>>,
+    pub labels: Option<HashMap<String, String>>,
+    pub health: Option<HealthCheckConfig>,
+}
+
+/// Deserialize a field that is present, including `null`, as `Some`, so that an omitted field
+/// (`None`) can be told apart from a field set to `null` (`Some(None)`)
+fn deserialize_nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Runtime view of a server listed by the `/admin/servers` endpoint
+#[derive(Debug, Serialize)]
+pub(crate) struct ServerStatus {
+    #[serde(flatten)]
+    pub server: Server,
+    pub circuit_state: CircuitState,
+    pub connections: usize,
+    /// Number of requests routed to the server because the servers of lower tiers were not
+    /// eligible
+    pub overflow_requests: u64,
+    pub draining: bool,
+}
+impl From<Server> for ServerStatus {
+    fn from(server: Server) -> Self {
+        Self {
+            circuit_state: server.stats.circuit_state(),
+            connections: server.stats.connections(),
+            overflow_requests: server.stats.overflow_requests(),
+            draining: server.stats.is_draining(),
+            server,
+        }
+    }
+}
+
+/// State of the circuit breaker of a server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitState {
+    /// Requests are routed to the server
+    #[default]
+    Closed,
+    /// The server is failing and skipped by the routing policies
+    Open,
+    /// A trial request is allowed to check if the server has recovered
+    HalfOpen,
+}
+impl std::fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open => write!(f, "open"),
+            CircuitState::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    state: CircuitState,
+    consecutive_failures: u32,
+    /// Time at which the open circuit lets a trial request through
+    retry_at: Option<Instant>,
+    /// Time at which the trial request of the half-open circuit was sent
+    trial_started_at: Option<Instant>,
+    open_duration: Duration,
+}
+
+/// Runtime statistics of a server used by the routing policies
+#[derive(Debug, Default)]
+pub(crate) struct ServerStats {
+    /// Number of in-flight requests
+    connections: AtomicUsize,
+    /// EWMA of the request latency in microseconds. Zero if no request has completed yet.
+    latency_ewma_us: AtomicU64,
+    /// Number of requests overflowed to the server from the lower tiers
+    overflow_requests: AtomicU64,
+    /// A draining server receives no new requests
+    draining: AtomicBool,
+    circuit: Mutex<CircuitBreaker>,
+    health: Mutex<HealthState>,
+}
+impl ServerStats {
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Stop or resume routing new requests to the server. Returns the previous state.
+    pub(crate) fn set_draining(&self, draining: bool) -> bool {
+        self.draining.swap(draining, Ordering::Relaxed)
+    }
+
+    pub(crate) fn overflow_requests(&self) -> u64 {
+        self.overflow_requests.load(Ordering::Relaxed)
+    }
+
+    fn record_overflow(&self) {
+        self.overflow_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the result of a health probe
+    pub(crate) fn record_health(&self, record: HealthCheckRecord) {
+        let mut health = self.health.lock().unwrap();
+        health.last_probe_at = Some(Instant::now());
+        if health.history.len() == HEALTH_HISTORY_SIZE {
+            health.history.pop_front();
+        }
+        health.history.push_back(record);
+    }
+
+    /// Get the recorded health probe results, oldest first
+    pub(crate) fn health_history(&self) -> Vec<HealthCheckRecord> {
+        self.health
+            .lock()
+            .unwrap()
+            .history
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Reserve a connection slot unless the in-flight requests have reached `max_connections`.
+    /// The check and the reservation are atomic, so that concurrent requests cannot exceed the cap.
+    fn try_acquire(&self, max_connections: Option<u32>) -> bool {
+        self.connections
+            .fetch_update(
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+                |connections| match max_connections {
+                    Some(max_connections) if connections >= max_connections as usize => None,
+                    _ => Some(connections + 1),
+                },
+            )
+            .is_ok()
+    }
+
+    pub(crate) fn latency_ewma(&self) -> Option<Duration> {
+        match self.latency_ewma_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+
+    /// Get the state of the circuit breaker. An open circuit whose open duration has elapsed is
+    /// reported as half-open.
+    pub(crate) fn circuit_state(&self) -> CircuitState {
+        let circuit = self.circuit.lock().unwrap();
+        match (circuit.state, circuit.retry_at) {
+            (CircuitState::Open, Some(retry_at)) if Instant::now() >= retry_at => {
+                CircuitState::HalfOpen
+            }
+            (state, _) => state,
+        }
+    }
+
+    /// Check if the circuit breaker lets a request through
+    fn is_available(&self) -> bool {
+        let circuit = self.circuit.lock().unwrap();
+        let now = Instant::now();
+        match circuit.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => circuit.retry_at.is_some_and(|retry_at| now >= retry_at),
+            // allow another trial if the previous one has not reported back in time
+            CircuitState::HalfOpen => circuit
+                .trial_started_at
+                .is_none_or(|started_at| now >= started_at + circuit.open_duration),
+        }
+    }
+
+    /// Get the time until the circuit breaker lets a trial request through
+    fn circuit_retry_in(&self) -> Duration {
+        let circuit = self.circuit.lock().unwrap();
+        let retry_at = match circuit.state {
+            CircuitState::Closed => return Duration::ZERO,
+            CircuitState::Open => circuit.retry_at,
+            CircuitState::HalfOpen => circuit
+                .trial_started_at
+                .map(|started_at| started_at + circuit.open_duration),
+        };
+
+        retry_at.map_or(Duration::ZERO, |retry_at| {
+            retry_at.saturating_duration_since(Instant::now())
+        })
+    }
+
+    /// Mark the request routed to the server as the trial request if the circuit is not closed
+    fn on_selected(&self) {
+        let mut circuit = self.circuit.lock().unwrap();
+        if circuit.state != CircuitState::Closed {
+            circuit.state = CircuitState::HalfOpen;
+            circuit.trial_started_at = Some(Instant::now());
+        }
+    }
+
+    /// Record a successful request or health probe. Returns `true` if the circuit is closed by it.
+    pub(crate) fn record_success(&self) -> bool {
+        let mut circuit = self.circuit.lock().unwrap();
+        let recovered = circuit.state != CircuitState::Closed;
+        *circuit = CircuitBreaker::default();
+
+        recovered
+    }
+
+    /// Record a failed request. The circuit is opened once `failure_threshold` consecutive requests
+    /// have failed, or immediately if the trial request of the half-open circuit failed.
+    ///
+    /// Returns `true` if the circuit is opened by this failure.
+    pub(crate) fn record_failure(&self, config: &CircuitBreakerConfig) -> bool {
+        let mut circuit = self.circuit.lock().unwrap();
+        circuit.consecutive_failures += 1;
+
+        match circuit.state {
+            CircuitState::Closed if circuit.consecutive_failures < config.failure_threshold => {
+                false
+            }
+            CircuitState::Open => false,
+            _ => {
+                Self::open(&mut circuit, config);
+                true
+            }
+        }
+    }
+
+    /// Open the circuit regardless of the failure count, e.g. when a health probe failed.
+    ///
+    /// Returns `true` if the circuit was not open already.
+    pub(crate) fn trip(&self, config: &CircuitBreakerConfig) -> bool {
+        let mut circuit = self.circuit.lock().unwrap();
+        let tripped = circuit.state != CircuitState::Open;
+        Self::open(&mut circuit, config);
+
+        tripped
+    }
+
+    fn open(circuit: &mut CircuitBreaker, config: &CircuitBreakerConfig) {
+        let open_duration = Duration::from_secs(config.open_duration);
+        circuit.state = CircuitState::Open;
+        circuit.retry_at = Some(Instant::now() + open_duration);
+        circuit.trial_started_at = None;
+        circuit.open_duration = open_duration;
+    }
+
+    fn record_latency(&self, latency: Duration) {
+        let sample = latency.as_micros().max(1) as f64;
+        let _ =
+            self.latency_ewma_us
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                    let ewma = match current {
+                        0 => sample,
+                        current => {
+                            LATENCY_EWMA_ALPHA * sample
+                                + (1.0 - LATENCY_EWMA_ALPHA) * current as f64
+                        }
+                    };
+                    Some(ewma.max(1.0) as u64)
+                });
+    }
+}
+
+/// Tracks an in-flight request to a downstream server.
+///
+/// The connection count of the server is incremented when the guard is created and decremented
+/// when it is dropped, at which point the duration of the request is recorded as a latency sample.
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard {
+    stats: Arc<ServerStats>,
+    started_at: Instant,
+}
+impl ConnectionGuard {
+    /// Reserve a connection slot of the server. Returns `None` if the server is saturated.
+    fn acquire(stats: Arc<ServerStats>, max_connections: Option<u32>) -> Option<Self> {
+        stats.try_acquire(max_connections).then(|| Self {
+            stats,
+            started_at: Instant::now(),
+        })
+    }
+}
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.stats.connections.fetch_sub(1, Ordering::Relaxed);
+        self.stats.record_latency(self.started_at.elapsed());
     }
 }
 
@@ -165,8 +826,12 @@ fn test_serialize_server() {
         url: "http://localhost:8000".to_string(),
         kind: ServerKind::chat | ServerKind::tts,
         api_key: None,
-        connections: AtomicUsize::new(0),
-        health_status: HealthStatus::default(),
+        weight: DEFAULT_WEIGHT,
+        priority: DEFAULT_PRIORITY,
+        max_connections: None,
+        labels: HashMap::new(),
+        health: HealthCheckConfig::default(),
+        stats: Arc::new(ServerStats::default()),
     };
     let serialized = serde_json::to_string(&server).unwrap();
     assert_eq!(
@@ -180,8 +845,12 @@ fn test_serialize_server() {
         url: "http://localhost:8000".to_string(),
         kind: ServerKind::chat,
         api_key: Some("test-api-key".to_string()),
-        connections: AtomicUsize::new(0),
-        health_status: HealthStatus::default(),
+        weight: DEFAULT_WEIGHT,
+        priority: DEFAULT_PRIORITY,
+        max_connections: None,
+        labels: HashMap::new(),
+        health: HealthCheckConfig::default(),
+        stats: Arc::new(ServerStats::default()),
     };
     let serialized = serde_json::to_string(&server).unwrap();
     assert_eq!(
@@ -190,6 +859,86 @@ fn test_serialize_server() {
     );
 }
 
+#[test]
+fn test_server_record_roundtrip() {
+    let serialized = r#"{"url": "http://localhost:8000", "kind": "chat,embeddings", "weight": 2, "labels": {"gpu": "a100"}}"#;
+    let server: Server = serde_json::from_str(serialized).unwrap();
+
+    // the restored server keeps the id assigned at registration
+    let restored = Server::try_from(server.to_record()).unwrap();
+    assert_eq!(restored.id, server.id);
+    assert_eq!(restored.url, server.url);
+    assert_eq!(restored.kind, ServerKind::chat | ServerKind::embeddings);
+    assert_eq!(restored.weight, 2);
+    assert_eq!(restored.labels.get("gpu").map(String::as_str), Some("a100"));
+
+    // a corrupted record is rejected
+    let mut record = server.to_record();
+    record.weight = 0;
+    assert!(Server::try_from(record).is_err());
+}
+
+#[test]
+fn test_server_from_downstream_config() {
+    let mut config = DownstreamConfig {
+        id: Some("gpu-box-1".to_string()),
+        url: "http://localhost:8000/v1".to_string(),
+        kind: ServerKind::chat,
+        api_key: Some("Bearer test-api-key".to_string()),
+        api_key_env: None,
+        weight: Some(4),
+        priority: None,
+        max_connections: None,
+        labels: HashMap::from([("gpu".to_string(), "a100".to_string())]),
+        health: HealthCheckConfig::default(),
+    };
+    let server = Server::try_from(&config).unwrap();
+    assert_eq!(server.id, "gpu-box-1");
+    assert_eq!(server.api_key.as_deref(), Some("Bearer test-api-key"));
+    assert_eq!(server.weight, 4);
+
+    // a random id is assigned if not set
+    config.id = None;
+    let server = Server::try_from(&config).unwrap();
+    assert!(server.id.starts_with("chat-server-"));
+
+    // `api_key` and `api_key_env` are exclusive
+    config.api_key_env = Some("LLAMA_NEXUS_TEST_API_KEY".to_string());
+    assert!(Server::try_from(&config).is_err());
+}
+
+#[test]
+fn test_health_probe() {
+    let serialized = r#"{"url": "http://localhost:8000/v1/", "kind": "chat"}"#;
+    let server: Server = serde_json::from_str(serialized).unwrap();
+    assert_eq!(server.health_probe(), HealthProbe::Models);
+    assert_eq!(server.health_probe_url(), "http://localhost:8000/v1/models");
+    assert!(server.is_health_check_due());
+
+    let serialized = r#"{"url": "http://localhost:8000/v1", "kind": "tts", "health": {"timeout": 3, "interval": 5}}"#;
+    let server: Server = serde_json::from_str(serialized).unwrap();
+    assert_eq!(server.health_probe_url(), "http://localhost:8000/v1/info");
+    assert_eq!(server.health_timeout(), Duration::from_secs(3));
+    assert_eq!(server.health_interval(), Duration::from_secs(5));
+
+    // the probe can be overridden per server
+    let serialized =
+        r#"{"url": "http://localhost:8000/v1", "kind": "image", "health": {"probe": "models"}}"#;
+    let server: Server = serde_json::from_str(serialized).unwrap();
+    assert_eq!(server.health_probe_url(), "http://localhost:8000/v1/models");
+
+    // the probe is due again only after the interval elapses
+    server.stats().record_health(HealthCheckRecord {
+        checked_at: 0,
+        healthy: true,
+        latency_ms: 1,
+        status: Some(200),
+        error: None,
+    });
+    assert!(!server.is_health_check_due());
+    assert_eq!(server.stats().health_history().len(), 1);
+}
+
 bitflags! {
     /// Represents the kind of server
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -336,18 +1085,59 @@ fn test_deserialize_server_kind() {
     // assert_eq!(kind, ServerKind::vdb);
 }
 
+/// Strategy used by a server group to select the server for the next request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoutingPolicyKind {
+    /// Cycle through the servers in registration order
+    RoundRobin,
+    /// Cycle through the servers in proportion to their weights (smooth weighted round-robin)
+    WeightedRoundRobin,
+    /// Pick a random server with probability proportional to its weight
+    Random,
+    /// Pick the server with the fewest in-flight requests
+    #[default]
+    LeastConnections,
+    /// Pick the server with the lowest EWMA of request latency
+    LowestLatency,
+    /// Keep the requests with the same routing key (e.g. the `user` of a chat request) on the
+    /// same server by weighted rendezvous hashing, so that only the keys of a joining or leaving
+    /// server are remapped. The requests without a routing key fall back to least-connections.
+    ConsistentHash,
+}
+impl std::fmt::Display for RoutingPolicyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoutingPolicyKind::RoundRobin => write!(f, "round-robin"),
+            RoutingPolicyKind::WeightedRoundRobin => write!(f, "weighted-round-robin"),
+            RoutingPolicyKind::Random => write!(f, "random"),
+            RoutingPolicyKind::LeastConnections => write!(f, "least-connections"),
+            RoutingPolicyKind::LowestLatency => write!(f, "lowest-latency"),
+            RoutingPolicyKind::ConsistentHash => write!(f, "consistent-hash"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct ServerGroup {
     pub(crate) servers: RwLock<Vec<RwLock<Server>>>,
     pub(crate) healthy_servers: RwLock<HashSet<ServerId>>,
     ty: ServerKind,
+    policy: RoutingPolicyKind,
+    // cursor of the round-robin policy
+    next_index: AtomicUsize,
+    // current weights of the smooth weighted round-robin policy
+    current_weights: Mutex<HashMap<ServerId, i64>>,
 }
 impl ServerGroup {
-    pub(crate) fn new(ty: ServerKind) -> Self {
+    pub(crate) fn new(ty: ServerKind, policy: RoutingPolicyKind) -> Self {
         Self {
             servers: RwLock::new(Vec::new()),
             healthy_servers: RwLock::new(HashSet::new()),
             ty,
+            policy,
+            next_index: AtomicUsize::new(0),
+            current_weights: Mutex::new(HashMap::new()),
         }
     }
 
@@ -365,6 +1155,30 @@ impl ServerGroup {
         Ok(())
     }
 
+    /// Replace the registered server having the same id. Returns `false` if it is not found.
+    pub(crate) async fn replace(&self, server: Server) -> bool {
+        for server_lock in self.servers.read().await.iter() {
+            let mut registered = server_lock.write().await;
+            if registered.id == server.id {
+                *registered = server;
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Check if the server is registered in the group
+    pub(crate) async fn contains(&self, server_id: impl AsRef<str>) -> bool {
+        for server_lock in self.servers.read().await.iter() {
+            if server_lock.read().await.id == server_id.as_ref() {
+                return true;
+            }
+        }
+
+        false
+    }
+
     pub(crate) async fn unregister(&self, server_id: impl AsRef<str>) -> ServerResult<()> {
         let id_to_remove = server_id.as_ref();
 
@@ -406,13 +1220,119 @@ impl ServerGroup {
         self.ty
     }
 
+    /// Select the index of the candidate to route the next request to
+    fn select(&self, candidates: &[Candidate], ctx: &RoutingContext) -> usize {
+        if candidates.len() == 1 {
+            return 0;
+        }
+
+        match self.policy {
+            RoutingPolicyKind::ConsistentHash if ctx.routing_key.is_some() => {
+                let routing_key = ctx.routing_key.as_deref().unwrap_or_default();
+                candidates
+                    .iter()
+                    .enumerate()
+                    .max_by(|(_, a), (_, b)| {
+                        a.rendezvous_score(routing_key)
+                            .total_cmp(&b.rendezvous_score(routing_key))
+                    })
+                    .map(|(idx, _)| idx)
+                    .unwrap_or(0)
+            }
+            RoutingPolicyKind::RoundRobin => {
+                self.next_index.fetch_add(1, Ordering::Relaxed) % candidates.len()
+            }
+            RoutingPolicyKind::WeightedRoundRobin => {
+                let mut current_weights = self.current_weights.lock().unwrap();
+                current_weights.retain(|id, _| candidates.iter().any(|c| &c.id == id));
+
+                let total_weight: i64 = candidates.iter().map(|c| c.weight as i64).sum();
+                let mut selected = 0;
+                let mut max_weight = i64::MIN;
+                for (idx, candidate) in candidates.iter().enumerate() {
+                    let current = current_weights.entry(candidate.id.clone()).or_insert(0);
+                    *current += candidate.weight as i64;
+                    if *current > max_weight {
+                        max_weight = *current;
+                        selected = idx;
+                    }
+                }
+                if let Some(current) = current_weights.get_mut(&candidates[selected].id) {
+                    *current -= total_weight;
+                }
+
+                selected
+            }
+            RoutingPolicyKind::Random => {
+                let total_weight: u64 = candidates.iter().map(|c| c.weight as u64).sum();
+                let mut point = rand::rng().random_range(0..total_weight);
+                candidates
+                    .iter()
+                    .position(|c| {
+                        if point < c.weight as u64 {
+                            true
+                        } else {
+                            point -= c.weight as u64;
+                            false
+                        }
+                    })
+                    .unwrap_or(0)
+            }
+            RoutingPolicyKind::LeastConnections | RoutingPolicyKind::ConsistentHash => candidates
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, c)| c.stats.connections())
+                .map(|(idx, _)| idx)
+                .unwrap_or(0),
+            RoutingPolicyKind::LowestLatency => candidates
+                .iter()
+                .enumerate()
+                // servers without latency samples are preferred so that they get measured
+                .min_by_key(|(_, c)| {
+                    (
+                        c.stats.latency_ewma().unwrap_or_default(),
+                        c.stats.connections(),
+                    )
+                })
+                .map(|(idx, _)| idx)
+                .unwrap_or(0),
+        }
+    }
+
+    /// Get the error of a request for which no server is eligible. The servers skipped by their
+    /// circuit breakers, concurrency caps or draining make the request retryable later.
+    fn no_candidate_error(
+        &self,
+        unavailable: usize,
+        saturated: usize,
+        draining: usize,
+        retry_in: Duration,
+    ) -> ServerError {
+        let (reason, retry_after) = if saturated > 0 {
+            ("busy: concurrency cap reached", BUSY_RETRY_AFTER)
+        } else if unavailable > 0 {
+            ("temporarily unavailable: circuit open", retry_in)
+        } else if draining > 0 {
+            ("draining: no new requests accepted", BUSY_RETRY_AFTER)
+        } else {
+            dual_error!("No eligible {} server found", self.ty);
+            return ServerError::NotFoundServer(self.ty.to_string());
+        };
+
+        let err_msg = format!("All the eligible {} servers are {}", self.ty, reason);
+        dual_error!("{}", &err_msg);
+        // round up so that the client does not retry before the server is available
+        let retry_after = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        ServerError::ServerBusy(err_msg, retry_after.max(1))
+    }
+
     pub(crate) async fn is_empty(&self) -> bool {
         self.healthy_servers.read().await.is_empty()
     }
 }
 #[async_trait]
 impl RoutingPolicy for ServerGroup {
-    async fn next(&self) -> Result<TargetServerInfo, ServerError> {
+    async fn next(&self, ctx: &RoutingContext) -> Result<TargetServerInfo, ServerError> {
         let servers = self.servers.read().await;
         if servers.is_empty() {
             let err_msg = format!("No {} server found", self.ty);
@@ -420,37 +1340,156 @@ impl RoutingPolicy for ServerGroup {
             return Err(ServerError::NotFoundServer(self.ty.to_string()));
         }
 
-        let server_lock = if servers.len() == 1 {
-            servers.first().unwrap()
-        } else {
-            // Find server with minimum connections - need to read each server
-            let mut min_connections = usize::MAX;
-            let mut min_server = &servers[0];
-
-            for server in servers.iter() {
-                let guard = server.read().await;
-                let connections = guard.connections.load(Ordering::Relaxed);
-                if connections < min_connections {
-                    min_connections = connections;
-                    min_server = server;
+        // Collect the servers allowed by the routing context, the circuit breakers and the
+        // concurrency caps
+        let mut candidates = Vec::with_capacity(servers.len());
+        let mut unavailable = 0;
+        let mut saturated = 0;
+        let mut draining = 0;
+        let mut retry_in = Duration::MAX;
+        let mut preferred_priority = u32::MAX;
+        for server_lock in servers.iter() {
+            let server = server_lock.read().await;
+            if ctx.allows(&server.id) {
+                // the servers excluded by the routing context never serve the request, so their
+                // tier is not the one the request overflows from
+                preferred_priority = preferred_priority.min(server.priority);
+                if server.stats.is_draining() {
+                    draining += 1;
+                    continue;
+                }
+                if !server.stats.is_available() {
+                    unavailable += 1;
+                    retry_in = retry_in.min(server.stats.circuit_retry_in());
+                    continue;
                 }
+                if server.is_saturated() {
+                    saturated += 1;
+                    continue;
+                }
+
+                candidates.push(Candidate {
+                    id: server.id.clone(),
+                    url: server.url.clone(),
+                    api_key: server.api_key.clone(),
+                    weight: server.weight,
+                    priority: server.priority,
+                    max_connections: server.max_connections,
+                    stats: Arc::clone(&server.stats),
+                });
             }
-            min_server
-        };
+        }
+        // Reserve a connection slot of the selected server. A server saturated by the concurrent
+        // requests since the candidates were collected is skipped, and the requests overflow to
+        // the next tier once all the servers of the preferred tier are skipped.
+        loop {
+            if candidates.is_empty() {
+                return Err(self.no_candidate_error(unavailable, saturated, draining, retry_in));
+            }
+
+            // Only the servers of the most preferred tier with an eligible server compete
+            let priority = candidates
+                .iter()
+                .map(|c| c.priority)
+                .min()
+                .unwrap_or_default();
+            let (mut tier, rest): (Vec<_>, Vec<_>) =
+                candidates.into_iter().partition(|c| c.priority == priority);
+            candidates = rest;
+
+            while !tier.is_empty() {
+                let selected = tier.swap_remove(self.select(&tier, ctx));
+                let Some(connection) =
+                    ConnectionGuard::acquire(Arc::clone(&selected.stats), selected.max_connections)
+                else {
+                    saturated += 1;
+                    continue;
+                };
+
+                selected.stats.on_selected();
+                if priority > preferred_priority {
+                    selected.stats.record_overflow();
+                    dual_info!(
+                        "Overflowed the {} request from tier {} to tier {}: {} server(s) circuit open, {} saturated, {} draining. Selected server: {}",
+                        self.ty,
+                        preferred_priority,
+                        priority,
+                        unavailable,
+                        saturated,
+                        draining,
+                        selected.id
+                    );
+                }
+                dual_debug!(
+                    "Selected {} server {} by {} policy",
+                    self.ty,
+                    selected.id,
+                    self.policy
+                );
 
-        // Access the chosen server
-        let target_server_info = {
-            let server = server_lock.write().await;
-            server.connections.fetch_add(1, Ordering::Relaxed);
-            TargetServerInfo {
-                id: server.id.clone(),
-                url: server.url.clone(),
-                api_key: server.api_key.clone(),
+                return Ok(TargetServerInfo {
+                    id: selected.id,
+                    url: selected.url,
+                    api_key: selected.api_key,
+                    connection: Arc::new(connection),
+                });
             }
-        };
+        }
+    }
+}
 
-        Ok(target_server_info)
+/// Snapshot of a server eligible for the next request
+struct Candidate {
+    id: ServerId,
+    url: String,
+    api_key: Option<String>,
+    weight: u32,
+    priority: u32,
+    max_connections: Option<u32>,
+    stats: Arc<ServerStats>,
+}
+impl Candidate {
+    /// Score of the candidate for the routing key. The candidate with the highest score serves
+    /// the key, which is stable as long as the candidate stays eligible.
+    fn rendezvous_score(&self, routing_key: &str) -> f64 {
+        let hash = stable_hash(&[routing_key.as_bytes(), self.id.as_bytes()]);
+        // map the hash to a uniform number in (0, 1)
+        let unit = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+        self.weight as f64 / -unit.ln()
+    }
+}
+
+/// Hash the parts with 64-bit FNV-1a, followed by the `fmix64` finalizer of MurmurHash3 to spread
+/// the bits. Unlike `DefaultHasher`, the result is fixed across Rust releases and processes, so
+/// that all the gateway replicas route a routing key to the same server.
+fn stable_hash(parts: &[&[u8]]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = FNV_OFFSET_BASIS;
+    for (idx, part) in parts.iter().enumerate() {
+        // 0xff never occurs in UTF-8, so the parts are delimited unambiguously
+        let separator: &[u8] = if idx > 0 { &[0xff] } else { &[] };
+        for byte in separator.iter().chain(part.iter()) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
     }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[test]
+fn test_stable_hash() {
+    // the hash must not change, otherwise the sessions are remapped after an upgrade
+    let hash = stable_hash(&[b"session-1", b"chat-server-a"]);
+    assert_eq!(hash, 0x8759_7451_6e44_a264);
+    // the parts are not concatenated
+    assert_ne!(hash, stable_hash(&[b"session-1chat", b"-server-a"]));
 }
 
 #[allow(dead_code)]
@@ -459,9 +1498,439 @@ pub struct TargetServerInfo {
     pub id: ServerId,
     pub url: String,
     pub api_key: Option<String>,
+    /// Keeps the request counted as in-flight until all clones are dropped
+    pub(crate) connection: Arc<ConnectionGuard>,
+}
+impl TargetServerInfo {
+    /// Statistics of the server, shared with the server group
+    pub(crate) fn stats(&self) -> &ServerStats {
+        &self.connection.stats
+    }
+}
+
+/// Constraints applied when selecting a downstream server
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RoutingContext {
+    /// Ids of the servers allowed to serve the request. `None` means any server in the group.
+    pub candidates: Option<HashSet<ServerId>>,
+    /// Ids of the servers that must not serve the request, e.g. the servers already tried
+    pub excluded: HashSet<ServerId>,
+    /// Session key of the request used by the consistent-hash policy
+    pub routing_key: Option<String>,
+}
+impl RoutingContext {
+    /// Restrict the selection to the given servers
+    pub(crate) fn with_candidates(candidates: HashSet<ServerId>) -> Self {
+        Self {
+            candidates: Some(candidates),
+            ..Default::default()
+        }
+    }
+
+    /// Set the session key of the request
+    pub(crate) fn with_routing_key(mut self, routing_key: Option<&str>) -> Self {
+        self.routing_key = routing_key.map(|key| key.to_string());
+        self
+    }
+
+    /// Exclude the given servers from the selection
+    pub(crate) fn excluding(mut self, server_ids: impl IntoIterator<Item = ServerId>) -> Self {
+        self.excluded.extend(server_ids);
+        self
+    }
+
+    pub(crate) fn allows(&self, server_id: &ServerId) -> bool {
+        if self.excluded.contains(server_id) {
+            return false;
+        }
+
+        match &self.candidates {
+            Some(candidates) => candidates.contains(server_id),
+            None => true,
+        }
+    }
 }
 
 #[async_trait]
 pub(crate) trait RoutingPolicy: Sync + Send {
-    async fn next(&self) -> Result<TargetServerInfo, ServerError>;
+    async fn next(&self, ctx: &RoutingContext) -> Result<TargetServerInfo, ServerError>;
+}
+
+#[tokio::test]
+async fn test_next_with_candidates() {
+    let group = ServerGroup::new(ServerKind::chat, RoutingPolicyKind::LeastConnections);
+    for (id, url) in [
+        ("chat-server-llama", "http://localhost:8001"),
+        ("chat-server-qwen", "http://localhost:8002"),
+    ] {
+        let server = Server {
+            id: id.to_string(),
+            url: url.to_string(),
+            kind: ServerKind::chat,
+            api_key: None,
+            weight: DEFAULT_WEIGHT,
+            priority: DEFAULT_PRIORITY,
+            max_connections: None,
+            labels: HashMap::new(),
+            health: HealthCheckConfig::default(),
+            stats: Arc::new(ServerStats::default()),
+        };
+        group.register(server).await.unwrap();
+    }
+
+    // only the candidate server is selected, regardless of its connections
+    let ctx = RoutingContext::with_candidates(HashSet::from(["chat-server-qwen".to_string()]));
+    let mut in_flight = Vec::new();
+    for _ in 0..3 {
+        let target = group.next(&ctx).await.unwrap();
+        assert_eq!(target.id, "chat-server-qwen");
+        in_flight.push(target);
+    }
+
+    // without candidates, the least loaded server is selected
+    let target = group.next(&RoutingContext::default()).await.unwrap();
+    assert_eq!(target.id, "chat-server-llama");
+
+    // excluded servers are skipped
+    let ctx = RoutingContext::default().excluding(["chat-server-llama".to_string()]);
+    assert_eq!(group.next(&ctx).await.unwrap().id, "chat-server-qwen");
+
+    // no eligible server
+    let ctx = RoutingContext::with_candidates(HashSet::from(["chat-server-gone".to_string()]));
+    assert!(group.next(&ctx).await.is_err());
+    let ctx = RoutingContext::with_candidates(HashSet::from(["chat-server-qwen".to_string()]))
+        .excluding(["chat-server-qwen".to_string()]);
+    assert!(group.next(&ctx).await.is_err());
+}
+
+#[tokio::test]
+async fn test_routing_policies() {
+    async fn new_group(policy: RoutingPolicyKind) -> ServerGroup {
+        let group = ServerGroup::new(ServerKind::chat, policy);
+        for (id, weight) in [("chat-server-a", 3), ("chat-server-b", 1)] {
+            let server = Server {
+                id: id.to_string(),
+                url: format!("http://{id}"),
+                kind: ServerKind::chat,
+                api_key: None,
+                weight,
+                priority: DEFAULT_PRIORITY,
+                max_connections: None,
+                labels: HashMap::new(),
+                health: HealthCheckConfig::default(),
+                stats: Arc::new(ServerStats::default()),
+            };
+            group.register(server).await.unwrap();
+        }
+        group
+    }
+
+    async fn count_a(group: &ServerGroup, n: usize) -> usize {
+        let mut count = 0;
+        for _ in 0..n {
+            if group.next(&RoutingContext::default()).await.unwrap().id == "chat-server-a" {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    let group = new_group(RoutingPolicyKind::RoundRobin).await;
+    assert_eq!(count_a(&group, 8).await, 4);
+
+    let group = new_group(RoutingPolicyKind::WeightedRoundRobin).await;
+    assert_eq!(count_a(&group, 8).await, 6);
+
+    // the connection count is released once the target is dropped
+    let group = new_group(RoutingPolicyKind::LeastConnections).await;
+    let first = group.next(&RoutingContext::default()).await.unwrap();
+    let second = group.next(&RoutingContext::default()).await.unwrap();
+    assert_ne!(first.id, second.id);
+    let second_id = second.id.clone();
+    drop(second);
+    let third = group.next(&RoutingContext::default()).await.unwrap();
+    assert_eq!(third.id, second_id);
+    drop(first);
+    drop(third);
+    for server in group.servers.read().await.iter() {
+        assert_eq!(server.read().await.stats.connections(), 0);
+    }
+}
+
+#[tokio::test]
+async fn test_consistent_hash_routing() {
+    fn new_server(id: &str) -> Server {
+        Server {
+            id: id.to_string(),
+            url: format!("http://{id}"),
+            kind: ServerKind::chat,
+            api_key: None,
+            weight: DEFAULT_WEIGHT,
+            priority: DEFAULT_PRIORITY,
+            max_connections: None,
+            labels: HashMap::new(),
+            health: HealthCheckConfig::default(),
+            stats: Arc::new(ServerStats::default()),
+        }
+    }
+
+    async fn assignments(group: &ServerGroup) -> Vec<ServerId> {
+        let mut assignments = Vec::new();
+        for user in 0..200 {
+            let ctx = RoutingContext::default().with_routing_key(Some(&format!("user-{user}")));
+            assignments.push(group.next(&ctx).await.unwrap().id);
+        }
+        assignments
+    }
+
+    let group = ServerGroup::new(ServerKind::chat, RoutingPolicyKind::ConsistentHash);
+    for id in ["chat-server-a", "chat-server-b", "chat-server-c"] {
+        group.register(new_server(id)).await.unwrap();
+    }
+
+    // the same key is routed to the same server
+    let before = assignments(&group).await;
+    assert_eq!(before, assignments(&group).await);
+    assert!(before.iter().any(|id| id == "chat-server-c"));
+
+    // a session moves away only while its server is excluded
+    let ctx = RoutingContext::default().with_routing_key(Some("user-0"));
+    let excluded = RoutingContext::default()
+        .with_routing_key(Some("user-0"))
+        .excluding([before[0].clone()]);
+    assert_ne!(group.next(&excluded).await.unwrap().id, before[0]);
+    assert_eq!(group.next(&ctx).await.unwrap().id, before[0]);
+
+    // only the keys of the joining server are remapped
+    group.register(new_server("chat-server-d")).await.unwrap();
+    let joined = assignments(&group).await;
+    for (old, new) in before.iter().zip(joined.iter()) {
+        assert!(old == new || new == "chat-server-d");
+    }
+    assert!(joined.iter().any(|id| id == "chat-server-d"));
+
+    // only the keys of the leaving server are remapped
+    group.unregister("chat-server-c").await.unwrap();
+    let left = assignments(&group).await;
+    for (old, new) in joined.iter().zip(left.iter()) {
+        assert!(old == new || old == "chat-server-c");
+    }
+}
+
+#[tokio::test]
+async fn test_priority_tiers() {
+    let group = ServerGroup::new(ServerKind::chat, RoutingPolicyKind::RoundRobin);
+    for (id, priority) in [("chat-server-local", 0), ("chat-server-cloud", 1)] {
+        let server = Server {
+            id: id.to_string(),
+            url: format!("http://{id}"),
+            kind: ServerKind::chat,
+            api_key: None,
+            weight: DEFAULT_WEIGHT,
+            priority,
+            max_connections: Some(1),
+            labels: HashMap::new(),
+            health: HealthCheckConfig::default(),
+            stats: Arc::new(ServerStats::default()),
+        };
+        group.register(server).await.unwrap();
+    }
+    let ctx = RoutingContext::default();
+
+    // the local server is preferred while it has capacity
+    let first = group.next(&ctx).await.unwrap();
+    assert_eq!(first.id, "chat-server-local");
+
+    // the request overflows to the cloud once the local server is saturated
+    let second = group.next(&ctx).await.unwrap();
+    assert_eq!(second.id, "chat-server-cloud");
+    assert_eq!(second.stats().overflow_requests(), 1);
+
+    // all the servers are saturated
+    assert!(matches!(
+        group.next(&ctx).await,
+        Err(ServerError::ServerBusy(_, 1))
+    ));
+
+    drop(first);
+    drop(second);
+    assert_eq!(group.next(&ctx).await.unwrap().id, "chat-server-local");
+
+    // the request is not an overflow if no local server serves the model
+    let ctx = RoutingContext::with_candidates(HashSet::from(["chat-server-cloud".to_string()]));
+    let target = group.next(&ctx).await.unwrap();
+    assert_eq!(target.id, "chat-server-cloud");
+    assert_eq!(target.stats().overflow_requests(), 1);
+    drop(target);
+
+    // nor if the local server was already tried by the request
+    let ctx = RoutingContext::default().excluding(["chat-server-local".to_string()]);
+    let target = group.next(&ctx).await.unwrap();
+    assert_eq!(target.id, "chat-server-cloud");
+    assert_eq!(target.stats().overflow_requests(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrency_cap_under_contention() {
+    let group = Arc::new(ServerGroup::new(
+        ServerKind::chat,
+        RoutingPolicyKind::LeastConnections,
+    ));
+    let server = Server {
+        id: "chat-server-capped".to_string(),
+        url: "http://chat-server-capped".to_string(),
+        kind: ServerKind::chat,
+        api_key: None,
+        weight: DEFAULT_WEIGHT,
+        priority: DEFAULT_PRIORITY,
+        max_connections: Some(2),
+        labels: HashMap::new(),
+        health: HealthCheckConfig::default(),
+        stats: Arc::new(ServerStats::default()),
+    };
+    group.register(server.clone()).await.unwrap();
+
+    // the concurrent requests never exceed the cap of the server
+    let barrier = Arc::new(tokio::sync::Barrier::new(16));
+    let mut tasks = Vec::new();
+    for _ in 0..16 {
+        let (group, barrier) = (Arc::clone(&group), Arc::clone(&barrier));
+        tasks.push(tokio::spawn(async move {
+            barrier.wait().await;
+            group.next(&RoutingContext::default()).await
+        }));
+    }
+    let mut in_flight = Vec::new();
+    for task in tasks {
+        match task.await.unwrap() {
+            Ok(target) => in_flight.push(target),
+            Err(e) => assert!(matches!(e, ServerError::ServerBusy(..))),
+        }
+    }
+    assert_eq!(in_flight.len(), 2);
+    assert_eq!(server.stats().connections(), 2);
+
+    drop(in_flight);
+    assert_eq!(server.stats().connections(), 0);
+}
+
+#[tokio::test]
+async fn test_update_and_drain_server() {
+    let serialized = r#"{"url": "http://localhost:8000/v1", "kind": "chat", "api_key": "old"}"#;
+    let server: Server = serde_json::from_str(serialized).unwrap();
+
+    let update: ServerUpdate = serde_json::from_str(
+        r#"{"api_key": "", "weight": 3, "priority": 1, "labels": {"gpu": "h100"}}"#,
+    )
+    .unwrap();
+    let updated = server.updated(update).unwrap();
+    assert_eq!(updated.id, server.id);
+    assert_eq!(updated.api_key, None);
+    assert_eq!(updated.weight, 3);
+    assert_eq!(updated.priority, 1);
+    assert_eq!(updated.labels.get("gpu").map(String::as_str), Some("h100"));
+    assert!(
+        server
+            .updated(ServerUpdate {
+                weight: Some(0),
+                ..Default::default()
+            })
+            .is_err()
+    );
+    assert!(serde_json::from_str::<ServerUpdate>(r#"{"url": "http://other"}"#).is_err());
+
+    // `max_connections` is unchanged if omitted, and removed if `null`
+    let capped = server
+        .updated(serde_json::from_str(r#"{"max_connections": 4}"#).unwrap())
+        .unwrap();
+    assert_eq!(capped.max_connections, Some(4));
+    let unchanged = capped
+        .updated(serde_json::from_str(r#"{"weight": 2}"#).unwrap())
+        .unwrap();
+    assert_eq!(unchanged.max_connections, Some(4));
+    let unlimited = capped
+        .updated(serde_json::from_str(r#"{"max_connections": null}"#).unwrap())
+        .unwrap();
+    assert_eq!(unlimited.max_connections, None);
+
+    let group = ServerGroup::new(ServerKind::chat, RoutingPolicyKind::RoundRobin);
+    group.register(server.clone()).await.unwrap();
+    assert!(group.replace(updated).await);
+    assert_eq!(group.servers.read().await[0].read().await.weight, 3);
+
+    // a draining server receives no new requests, while the in-flight ones complete
+    let in_flight = group.next(&RoutingContext::default()).await.unwrap();
+    assert!(!server.stats().set_draining(true));
+    assert!(group.next(&RoutingContext::default()).await.is_err());
+    assert_eq!(server.stats().connections(), 1);
+    drop(in_flight);
+    assert_eq!(server.stats().connections(), 0);
+
+    assert!(server.stats().set_draining(false));
+    assert!(group.next(&RoutingContext::default()).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_circuit_breaker() {
+    let group = ServerGroup::new(ServerKind::chat, RoutingPolicyKind::RoundRobin);
+    for id in ["chat-server-a", "chat-server-b"] {
+        let server = Server {
+            id: id.to_string(),
+            url: format!("http://{id}"),
+            kind: ServerKind::chat,
+            api_key: None,
+            weight: DEFAULT_WEIGHT,
+            priority: DEFAULT_PRIORITY,
+            max_connections: None,
+            labels: HashMap::new(),
+            health: HealthCheckConfig::default(),
+            stats: Arc::new(ServerStats::default()),
+        };
+        group.register(server).await.unwrap();
+    }
+    let ctx = RoutingContext::default();
+    let config = CircuitBreakerConfig {
+        failure_threshold: 2,
+        open_duration: 3600,
+    };
+
+    // the circuit opens after `failure_threshold` consecutive failures
+    let target = group.next(&ctx).await.unwrap();
+    let failing_id = target.id.clone();
+    assert!(!target.stats().record_failure(&config));
+    assert!(target.stats().record_failure(&config));
+    assert_eq!(target.stats().circuit_state(), CircuitState::Open);
+
+    // the failing server is skipped
+    for _ in 0..4 {
+        assert_ne!(group.next(&ctx).await.unwrap().id, failing_id);
+    }
+
+    // once the open duration elapses, a single trial request is let through
+    let config = CircuitBreakerConfig {
+        failure_threshold: 2,
+        open_duration: 0,
+    };
+    assert!(!target.stats().trip(&config));
+    let trial = group
+        .next(&RoutingContext::with_candidates(HashSet::from([
+            failing_id.clone(),
+        ])))
+        .await
+        .unwrap();
+    assert_eq!(trial.stats().circuit_state(), CircuitState::HalfOpen);
+
+    // a successful trial closes the circuit
+    assert!(trial.stats().record_success());
+    assert_eq!(trial.stats().circuit_state(), CircuitState::Closed);
+
+    // a failed trial opens the circuit again, regardless of the threshold
+    trial.stats().trip(&config);
+    let trial = group
+        .next(&RoutingContext::with_candidates(HashSet::from([
+            failing_id.clone(),
+        ])))
+        .await
+        .unwrap();
+    assert!(trial.stats().record_failure(&config));
 }
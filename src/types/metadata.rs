@@ -16,11 +16,6 @@ impl<'de> Deserialize<'de> for Metadata {
 }
 
 impl Metadata {
-    /// Create a new empty Metadata
-    pub fn new() -> Self {
-        Self(HashMap::new())
-    }
-
     /// Create Metadata from a HashMap, validating constraints
     pub fn from_map(map: HashMap<String, String>) -> Result<Self, MetadataError> {
         let metadata = Self(map);
@@ -28,58 +23,6 @@ impl Metadata {
         Ok(metadata)
     }
 
-    /// Insert a key-value pair, validating constraints
-    pub fn insert(&mut self, key: String, value: String) -> Result<(), MetadataError> {
-        // Validate constraints
-        if self.0.len() >= 16 && !self.0.contains_key(&key) {
-            return Err(MetadataError::TooManyKeys);
-        }
-        if key.len() > 64 {
-            return Err(MetadataError::KeyTooLong(key.len()));
-        }
-        if value.len() > 512 {
-            return Err(MetadataError::ValueTooLong(value.len()));
-        }
-
-        self.0.insert(key, value);
-        Ok(())
-    }
-
-    /// Get a value by key
-    pub fn get(&self, key: &str) -> Option<&String> {
-        self.0.get(key)
-    }
-
-    /// Remove a key-value pair
-    pub fn remove(&mut self, key: &str) -> Option<String> {
-        self.0.remove(key)
-    }
-
-    /// Get the number of key-value pairs
-    pub fn len(&self) -> usize {
-        self.0.len()
-    }
-
-    /// Check if metadata is empty
-    pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
-    }
-
-    /// Get an iterator over key-value pairs
-    pub fn iter(&self) -> std::collections::hash_map::Iter<String, String> {
-        self.0.iter()
-    }
-
-    /// Convert to HashMap
-    pub fn into_inner(self) -> HashMap<String, String> {
-        self.0
-    }
-
-    /// Get reference to inner HashMap
-    pub fn as_map(&self) -> &HashMap<String, String> {
-        &self.0
-    }
-
     /// Validate all constraints
     fn validate(&self) -> Result<(), MetadataError> {
         if self.0.len() > 16 {
@@ -111,20 +54,6 @@ impl TryFrom<HashMap<String, String>> for Metadata {
 // Removed the non-validating From<HashMap> trait for safety
 // Always use TryFrom or from_map() to ensure validation
 
-// JSON serialization/deserialization methods for sqlx compatibility
-impl Metadata {
-    pub fn to_json(&self) -> Result<String, serde_json::Error> {
-        serde_json::to_string(&self.0)
-    }
-    
-    pub fn from_json(json_str: &str) -> Result<Self, serde_json::Error> {
-        let data: HashMap<String, String> = serde_json::from_str(json_str)?;
-        // Note: Database data is assumed to be already validated
-        // If you want strict validation, use from_map() instead
-        Ok(Metadata(data))
-    }
-}
-
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MetadataError {
     TooManyKeys,
@@ -139,10 +68,10 @@ impl std::fmt::Display for MetadataError {
                 write!(f, "Metadata cannot have more than 16 key-value pairs")
             }
             MetadataError::KeyTooLong(len) => {
-                write!(f, "Metadata key too long: {} characters (max 64)", len)
+                write!(f, "Metadata key too long: {len} characters (max 64)")
             }
             MetadataError::ValueTooLong(len) => {
-                write!(f, "Metadata value too long: {} characters (max 512)", len)
+                write!(f, "Metadata value too long: {len} characters (max 512)")
             }
         }
     }
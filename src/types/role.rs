@@ -47,10 +47,10 @@ impl From<String> for Role {
     }
 }
 
-// Implement ToString for sqlx compatibility
-impl ToString for Role {
-    fn to_string(&self) -> String {
-        self.as_str().to_string()
+// Implement Display for sqlx compatibility
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
@@ -65,7 +65,7 @@ impl FromStr for Role {
             "system" => Ok(Role::System),
             other => {
                 // Log warning for unknown role, default to User
-                eprintln!("Warning: Unknown role '{}', defaulting to 'user'", other);
+                eprintln!("Warning: Unknown role '{other}', defaulting to 'user'");
                 Ok(Role::User)
             }
         }